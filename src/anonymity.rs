@@ -0,0 +1,128 @@
+// src/anonymity.rs
+// How much a proxy tells the server about its client, as a judge endpoint
+// that echoes the request headers it got sees it. A transparent proxy
+// passes the client's own IP on, an anonymous one hides it but announces
+// itself with headers such as Via, and an elite one does neither.
+use crate::client::HttpProbe;
+use crate::retry::Failure;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Default for --judge-url: answers with the request headers it got.
+pub const DEFAULT_JUDGE_URL: &str = "https://httpbin.org/headers";
+/// Headers a proxy adds when it tells the server about the client.
+pub const PROXY_MARKERS: [&str; 5] = [
+    "x-forwarded-for",
+    "via",
+    "forwarded",
+    "x-real-ip",
+    "client-ip",
+];
+
+/// Ordered from least to most anonymous.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Anonymity {
+    /// Passes this machine's own IP on
+    Transparent,
+    /// Hides the own IP but adds headers telling of a proxy
+    Anonymous,
+    /// Neither passes the own IP on nor tells of a proxy
+    Elite,
+}
+
+impl Anonymity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Anonymity::Transparent => "transparent",
+            Anonymity::Anonymous => "anonymous",
+            Anonymity::Elite => "elite",
+        }
+    }
+}
+
+/// What the judge saw through one proxy.
+pub struct Judgement {
+    pub anonymity: Anonymity,
+    /// The [`PROXY_MARKERS`] it was sent.
+    pub markers: Vec<&'static str>,
+}
+
+/// A header-echo endpoint and what it must not be told.
+pub struct Judge {
+    pub url: String,
+    /// This machine's own IP. Without it a proxy that passes it on under
+    /// a header not in [`PROXY_MARKERS`] passes for elite.
+    pub own_ip: Option<String>,
+}
+
+impl Judge {
+    /// Asks the judge through `client` and classifies what it got.
+    pub fn classify(&self, client: &dyn HttpProbe) -> Result<Judgement, String> {
+        let body = client
+            .get_text(&self.url)
+            .map_err(|(Failure::Retry(e) | Failure::Stop(e))| e)?;
+        let headers = echoed_headers(&body)
+            .ok_or_else(|| format!("{} did not answer with the headers it got", self.url))?;
+        let markers: Vec<&'static str> = PROXY_MARKERS
+            .into_iter()
+            .filter(|marker| headers.keys().any(|key| key.eq_ignore_ascii_case(marker)))
+            .collect();
+        let anonymity = if self.own_ip.as_deref().is_some_and(|own| body.contains(own)) {
+            Anonymity::Transparent
+        } else if !markers.is_empty() {
+            Anonymity::Anonymous
+        } else {
+            Anonymity::Elite
+        };
+        Ok(Judgement { anonymity, markers })
+    }
+}
+
+/// The `headers` object of a header-echo answer such as httpbin's.
+pub fn echoed_headers(body: &str) -> Option<Map<String, Value>> {
+    serde_json::from_str::<Value>(body)
+        .ok()?
+        .get("headers")?
+        .as_object()
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{MockAnswer, MockProbe};
+
+    fn judge(echoed: &'static str) -> Result<Judgement, String> {
+        let judge = Judge {
+            url: DEFAULT_JUDGE_URL.to_string(),
+            own_ip: Some("198.51.100.20".to_string()),
+        };
+        judge.classify(&MockProbe::default().answer(DEFAULT_JUDGE_URL, MockAnswer::Body(echoed)))
+    }
+
+    #[test]
+    fn own_ip_anywhere_is_transparent() {
+        let judgement =
+            judge(r#"{"headers":{"X-Custom-Client":"198.51.100.20","Via":"1.1 squid"}}"#).unwrap();
+        assert!(judgement.anonymity == Anonymity::Transparent);
+    }
+
+    #[test]
+    fn proxy_headers_are_anonymous() {
+        let judgement = judge(r#"{"headers":{"Host":"httpbin.org","via":"1.1 squid"}}"#).unwrap();
+        assert!(judgement.anonymity == Anonymity::Anonymous);
+        assert_eq!(judgement.markers, ["via"]);
+    }
+
+    #[test]
+    fn clean_headers_are_elite() {
+        let judgement = judge(r#"{"headers":{"Host":"httpbin.org","Accept":"*/*"}}"#).unwrap();
+        assert!(judgement.anonymity == Anonymity::Elite);
+    }
+
+    #[test]
+    fn other_answers_are_refused() {
+        assert!(judge("<html>blocked</html>").is_err());
+    }
+}
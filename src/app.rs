@@ -0,0 +1,6861 @@
+// src/app.rs
+// The veko_dome command: its options, the commands that talk to a running
+// session, and `start`, which settles the options and runs the library's
+// session steps in order, from loading proxies and launching Tor to the
+// rotation loop and the control socket. Whatever stops a session is
+// reported here, and only here does the process exit.
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use reqwest::blocking::Client;
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt, fs,
+    io::{self, IsTerminal, Read},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+mod compat;
+mod config;
+mod validate;
+
+#[cfg(any(unix, windows))]
+use veko_dome::daemon;
+#[cfg(unix)]
+use veko_dome::dashboard;
+#[cfg(feature = "scripting")]
+use veko_dome::scripting;
+#[cfg(windows)]
+use veko_dome::windows;
+use veko_dome::{
+    anonymity, audit, capture, client, control, datasets, decisions, decoy, dns, doctor, engine,
+    errln, events, fallback, forwarder, geo, hooks, integrity, kill_switch, listener, logging,
+    metrics, no_proxy, outln, output, pool, portal, probe, profile, redact, retry, rotation,
+    shutdown, socks, state, status_page, system_proxy, throttle, tor_integration, workers,
+};
+
+use anonymity::{Anonymity, Judge, Judgement};
+use audit::Blocklist;
+use capture::{Capture, CaptureFormat};
+use client::{
+    check_tor_connection, create_http_client, get_public_ip, get_public_ipv6, ip_services,
+    ipv4_only_local_address, parse_ip_service, public_ip_label, reqwest_proxy, set_ip_services,
+    strip_credentials, HttpProbe, PublicIp,
+};
+use control::{
+    ClientError, ProxyLoad, ReloadResult, Reply, RotateResult, StatsSnapshot, StatusSnapshot,
+};
+use datasets::data_dir;
+use decisions::{Journal, RotationStrategy};
+use dns::{DohProvider, DohUrl, DotServer};
+use engine::{
+    client_route, route_verified, since_verified, ChainMode, ChainRotate, ExitHistory,
+    ListenerSettings, ProfileOptions, RotationFollowUp, RotationTriggers, StartedTor,
+    TorBackendKind,
+};
+use events::{
+    Event, EventKind, EventLog, HookEvent, HookKind, ProxyStats, RotationReason, SessionStats,
+    WorkerCrashedEvent,
+};
+use fallback::{Fallback, FallbackOrder, Health, Transport};
+use forwarder::{Chain, Forwarder, Hop};
+use geo::{ExitGeo, GeoCache, GeoClient, GeoDb, GeoInfo, ProviderHealth, ProviderState};
+use integrity::ListCheck;
+use kill_switch::{Cause, KillSwitch};
+use listener::{ListenSpec, Listener};
+use logging::LogFormat;
+use no_proxy::{NoProxy, NoProxyRule, Policy};
+use pool::ProxyPool;
+use portal::Network;
+use probe::ProbeLevel;
+use profile::{parse_user_agent, HttpVersion, SecurityProfile, DEFAULT_MODE};
+use rotation::{
+    describe_identity_bounds, describe_rotation_policy, normalize_asn, Distinct, ProxyEntry,
+    ProxyRotator,
+};
+use state::State;
+use system_proxy::{Endpoints, Shell};
+use tor_integration::{FailedTor, TorBackend, TorEvent, TorOptions};
+
+#[derive(Parser)]
+#[command(name = "Veko Dome")]
+#[command(version = "1.0")]
+#[command(about = "High-security network anonymization tool", long_about = None)]
+struct Cli {
+    /// Name of the session to start or talk to; lets several sessions run
+    /// side by side
+    #[arg(long, global = true, value_parser = parse_session_name)]
+    session: Option<String>,
+    /// Refuse v1.0 spellings whose meaning has changed, such as
+    /// --rotate 0, instead of warning and running them as v1.0 did
+    #[arg(long, global = true)]
+    strict_flags: bool,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(clap::Subcommand)]
+enum Commands {
+    /// Start anonymization session with all security features
+    Start(Box<StartArgs>),
+    /// Show current connection status, probing the route for how it
+    /// answers now
+    Status {
+        /// Only show what the session already knows, sending nothing
+        #[arg(long)]
+        no_probe: bool,
+    },
+    /// Rotate the running session's proxy now
+    Rotate,
+    /// Shut the running session down cleanly and wait for it to exit
+    Stop(StopArgs),
+    /// Stop a session started with --daemon and start it again as it was
+    Restart(StopArgs),
+    /// Have the running session re-read its proxy list, as SIGHUP does
+    Reload,
+    /// Show statistics about Veko Dome's local state
+    Stats {
+        /// Include cache internals such as geolocation hit/miss counters
+        #[arg(long)]
+        internals: bool,
+    },
+    /// Show per-proxy connection usage of the running session
+    Connections,
+    /// Print what the running session's current identity presents: its
+    /// proxy, user agent, headers and DNS, for tools that must look the same
+    Identity {
+        /// Keep the proxy's credentials in its URL
+        #[arg(long)]
+        include_secrets: bool,
+        #[arg(long, value_enum, default_value_t = IdentityFormat::Json)]
+        identity_format: IdentityFormat,
+    },
+    /// Follow the running session on a live dashboard
+    Watch {
+        /// Print the status every few seconds instead, as on terminals
+        /// the dashboard cannot draw on
+        #[arg(long)]
+        plain: bool,
+    },
+    /// Re-run the decisions in a --debug-decisions journal and report any
+    /// that come out differently
+    Replay {
+        /// Journal written by --debug-decisions
+        path: PathBuf,
+    },
+    /// Delete locally stored data
+    Purge {
+        /// Remove the on-disk geolocation cache
+        #[arg(long)]
+        geo_cache: bool,
+    },
+    /// Work with --config files
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+    /// List the profiles a config file defines
+    Profiles {
+        /// Config file to read instead of the default one
+        #[arg(long, value_name = "PATH")]
+        config: Option<PathBuf>,
+    },
+    /// Manage the bundled user agent, decoy target and hosting ASN lists
+    Data {
+        #[command(subcommand)]
+        action: DataCommand,
+    },
+    /// Inspect what a session presents and exposes
+    Profile {
+        #[command(subcommand)]
+        action: ProfileCommand,
+    },
+    /// Check what the route gives away and score it; exits 1 if any check
+    /// fails
+    Audit(AuditArgs),
+    /// Health-check each proxy in the list and classify how anonymous it
+    /// is; exits 1 if none pass
+    TestProxies(TestProxiesArgs),
+    /// Check that Tor, the proxy list, the network and the directories a
+    /// session needs are in order; exits 1 if anything fails
+    Doctor(DoctorArgs),
+    /// Print the completion script for a shell
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page, in roff
+    Manpage,
+    /// Print shell commands pointing http_proxy, https_proxy, all_proxy
+    /// and no_proxy at the running session's listeners, for
+    /// eval $(veko_dome env)
+    Env {
+        #[arg(long, value_enum, default_value_t = Shell::Sh)]
+        shell: Shell,
+    },
+    /// Write a PAC file sending everything through the running session's
+    /// listeners, except destinations --no-proxy sends direct
+    Pac {
+        /// File to write [default: stdout]
+        #[arg(long, value_name = "PATH")]
+        out: Option<PathBuf>,
+    },
+    /// Show recorded session events as a timeline
+    #[command(args_conflicts_with_subcommands = true)]
+    Events {
+        #[command(subcommand)]
+        action: Option<EventsCommand>,
+        /// Only show events of this type
+        #[arg(long = "type", value_enum)]
+        kind: Option<EventKind>,
+        /// Number of most recent events to show
+        #[arg(long, default_value_t = 20)]
+        last: usize,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum ConfigCommand {
+    /// Check a config file and print the settings `start` would use with it
+    Check {
+        path: PathBuf,
+        /// Check with this profile laid over the top-level settings
+        #[arg(long, value_name = "NAME")]
+        profile: Option<String>,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum DataCommand {
+    /// Install newer datasets published at a URL, after checking their
+    /// signatures
+    Update {
+        /// Base URL holding each dataset file and its .sig
+        #[arg(long)]
+        url: String,
+    },
+    /// Show whether each dataset is the embedded one or updated, and its
+    /// age
+    Status,
+}
+
+#[derive(clap::Subcommand)]
+enum ProfileCommand {
+    /// Show the security profile's user agents and headers
+    Show {
+        /// Document what selection scripts are given instead
+        #[arg(long)]
+        script_context: bool,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum EventsCommand {
+    /// Print an example event log line, for developing consumers without a
+    /// running session
+    EmitSample {
+        #[arg(long = "type", value_enum)]
+        kind: EventKind,
+    },
+    /// Print the JSON schema of event log lines
+    #[cfg(feature = "schema")]
+    Schema,
+}
+
+#[derive(clap::Args)]
+struct AuditArgs {
+    /// Audit the route through this proxy, instead of the running
+    /// session's first listener or, without one, Tor
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+    /// Security profile the route should present, as for start
+    #[arg(long, value_name = "NAME", default_value = DEFAULT_MODE)]
+    mode: String,
+    /// Config file defining --mode, when it is not built in
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Fail unless the route exits through Tor
+    #[arg(long)]
+    expect_tor: bool,
+    /// Datacenter and VPN exit IPs or CIDR ranges, one per line
+    #[arg(long, value_name = "PATH")]
+    blocklist: Option<PathBuf>,
+    /// Endpoint that answers with the request headers it got, as JSON
+    #[arg(long, value_name = "URL", default_value = audit::DEFAULT_ECHO_URL)]
+    echo_url: String,
+    /// IP service to ask instead of the built-in ones, as for start.
+    /// Repeatable
+    #[arg(long = "ip-service", value_name = "URL", value_parser = parse_ip_service)]
+    ip_services: Vec<String>,
+    /// Accept http:// --ip-service URLs
+    #[arg(long)]
+    allow_http_ip_check: bool,
+    /// Leave a check out; may be repeated
+    #[arg(long, value_enum, value_name = "CHECK")]
+    skip: Vec<audit::Check>,
+    #[arg(long, value_enum, default_value_t = SummaryFormat::Text)]
+    output: SummaryFormat,
+}
+
+#[derive(clap::Args)]
+struct TestProxiesArgs {
+    /// Where to load proxies from, as for start
+    #[arg(long, value_name = "SOURCE")]
+    proxy: Option<String>,
+    #[arg(long, value_enum, default_value_t = ProxyFormat::Auto)]
+    proxy_format: ProxyFormat,
+    /// Proxies checked at the same time
+    #[arg(long, value_name = "N", default_value_t = PRECHECK_PARALLELISM as u32, value_parser = clap::value_parser!(u32).range(1..=1024))]
+    concurrency: u32,
+    /// Seconds each proxy gets to answer
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+    /// What is sent through each proxy before the judge is asked
+    #[arg(long, value_enum, default_value_t = ProbeLevel::Ip)]
+    probe: ProbeLevel,
+    /// IP service to ask instead of the built-in ones, as for start.
+    /// Repeatable
+    #[arg(long = "ip-service", value_name = "URL", value_parser = parse_ip_service)]
+    ip_services: Vec<String>,
+    /// Accept http:// --ip-service URLs
+    #[arg(long)]
+    allow_http_ip_check: bool,
+    /// Endpoint that answers with the request headers it got, as JSON
+    #[arg(long, value_name = "URL", default_value = anonymity::DEFAULT_JUDGE_URL)]
+    judge_url: String,
+    /// Don't look up this machine's own IP; proxies that pass it on under
+    /// an unknown header then count as elite
+    #[arg(long)]
+    no_baseline: bool,
+    /// MaxMind-format database to look up the country of each exit IP;
+    /// otherwise the list's country is shown
+    #[arg(long, value_name = "PATH")]
+    geoip_db: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = SummaryFormat::Text)]
+    output: SummaryFormat,
+    /// Write the entries that passed to this file, as the list and in its
+    /// format
+    #[arg(long, value_name = "PATH")]
+    save_alive: Option<PathBuf>,
+    /// Exit 1 unless at least this many proxies pass
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    min_alive: usize,
+}
+
+#[derive(clap::Args)]
+struct DoctorArgs {
+    /// The tor executable to check, as for start
+    #[arg(long, value_name = "PATH")]
+    tor_binary: Option<PathBuf>,
+    /// Proxy source to check, as for start
+    #[arg(long, value_name = "SOURCE")]
+    proxy: Option<String>,
+    #[arg(long, value_enum, default_value_t = ProxyFormat::Auto)]
+    proxy_format: ProxyFormat,
+    /// Also check that the directory of this --log-file is writable
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// Create the data, config and --log-file directories if they are
+    /// missing
+    #[arg(long)]
+    fix: bool,
+}
+
+#[derive(clap::Args)]
+struct StartArgs {
+    /// TOML file with defaults for mode, rotate, proxy, proxy_format, tor
+    /// (as --tor-weight), tor_grace, tor_max_restarts, doh, no_log,
+    /// no_referer, insecure_tls, on_rotate, on_failure and webhook_url.
+    /// Options given here win over it. Defaults to veko-dome/config.toml in
+    /// the user's config dir, if there is one
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Lay the [profiles.NAME] table of the config over its top-level
+    /// settings
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+    /// Present this user agent on every route instead of the profile's
+    #[arg(long, value_name = "STRING", value_parser = parse_user_agent)]
+    pin_user_agent: Option<String>,
+    /// Send no Referer, which gives away requests that no page linked to
+    #[arg(long)]
+    no_referer: bool,
+    /// Accept any TLS certificate, for routes through a proxy that
+    /// intercepts TLS. Anyone on the path can then read and change every
+    /// request. The paranoid profile refuses it
+    #[arg(long)]
+    insecure_tls: bool,
+    /// Seconds a request may take, instead of the profile's. Defaults to
+    /// at least 60 when Tor is in use
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: Option<u64>,
+    /// Redirects followed before a request fails, instead of the
+    /// profile's; 0 follows none
+    #[arg(long, value_name = "N")]
+    max_redirects: Option<usize>,
+    /// Open a new connection for every request
+    #[arg(long)]
+    no_keepalive: bool,
+    /// Send back cookies servers set, from a jar that is emptied at every
+    /// rotation so no cookie links two identities
+    #[arg(long)]
+    cookies: bool,
+    /// Keep Tor's circuits across rotations instead of giving every
+    /// identity its own through new SOCKS credentials; rotations onto Tor
+    /// then ask for NEWNYM over the control port
+    #[arg(long)]
+    no_tor_isolation: bool,
+    /// Speak HTTP/1.1 only, instead of what the profile's user agents
+    /// offer
+    #[arg(long)]
+    http1: bool,
+    /// Speak HTTP/2 from the first byte, without negotiating it
+    #[arg(long)]
+    http2_prior_knowledge: bool,
+    /// Seconds between heartbeats: HEAD requests to an IP service through
+    /// the route, to notice a dead proxy before the next rotation. 0 turns
+    /// them off
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    heartbeat: u64,
+    /// Missed heartbeats in a row that quarantine the proxy and rotate at
+    /// once
+    #[arg(long, value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    heartbeat_failures: u32,
+    /// Send cover traffic: GETs to widely visited sites through the route,
+    /// at random times, whose answers are thrown away
+    #[arg(long)]
+    decoy: bool,
+    /// Decoy requests a minute, on average [default: 2]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=60))]
+    decoy_rate: Option<u32>,
+    /// Send decoy requests to the http(s) URLs in this file instead of the
+    /// built-in list: one per line, with # starting a comment
+    #[arg(long, value_name = "PATH")]
+    decoy_targets: Option<PathBuf>,
+    /// Pick user agents from this file instead of the profile's: one per
+    /// line, with # starting a comment
+    #[arg(long, value_name = "PATH")]
+    user_agents: Option<PathBuf>,
+    /// Security profile to present: a [profile.NAME] of the config, a
+    /// .toml file holding one, or a preset in the user agent list
+    #[arg(long, value_name = "NAME", default_value = DEFAULT_MODE)]
+    mode: String,
+    /// Rotation interval in seconds; the longest interval with
+    /// --rotate-adaptive. off turns timed rotation off
+    #[arg(short, long, default_value = "15", value_parser = parse_rotate)]
+    rotate: u64,
+    /// Also rotate after this many listener requests through the route,
+    /// whichever of the two comes first. 0 turns this off
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    rotate_requests: u64,
+    /// Move each timed rotation up to this many seconds earlier or later,
+    /// drawn afresh after every rotation
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    rotate_jitter: u64,
+    /// Rotate sooner while listener tunnels through the current route fail
+    /// or slow down, down to --rotate-min
+    #[arg(long)]
+    rotate_adaptive: bool,
+    /// Shortest rotation interval in seconds with --rotate-adaptive
+    #[arg(long, value_name = "SECS")]
+    rotate_min: Option<u64>,
+    /// Seconds to wait for Tor to exit on shutdown before killing it
+    #[arg(long, default_value_t = 10)]
+    tor_grace: u64,
+    /// Longest shutdown may take, e.g. 1m, before Tor is killed and the
+    /// process exits regardless; at least --tor-grace plus 5s
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "30s")]
+    shutdown_grace: Duration,
+    /// How many times to relaunch Tor if it dies before giving up
+    #[arg(long, default_value_t = 3)]
+    tor_max_restarts: u32,
+    /// The tor executable to run [default: tor from PATH; on Windows, an
+    /// installed Tor Browser or expert bundle if there is none]
+    #[arg(long, value_name = "PATH")]
+    tor_binary: Option<PathBuf>,
+    /// What runs Tor: the tor binary as a child process, or Arti in this
+    /// process, which builds with --features arti
+    #[arg(long, value_enum, default_value_t = TorBackendKind::Binary)]
+    tor_backend: TorBackendKind,
+    /// Look up the country and ASN of every proxy at startup. The lookup
+    /// goes over the route to ip-api.com in plain HTTP, so the route's exit
+    /// can read the proxy addresses
+    #[arg(long)]
+    geolocate_proxies: bool,
+    /// Days before a cached geolocation result is looked up again
+    #[arg(long, default_value_t = 30)]
+    geo_cache_days: u64,
+    /// Don't look up where the exit IP is
+    #[arg(long)]
+    no_geo: bool,
+    /// HTTPS service asked, through the route, where the exit IP is; {ip}
+    /// is replaced by the address. Unused with --geoip-db
+    #[arg(
+        long,
+        value_name = "URL",
+        value_parser = geo::parse_exit_service,
+        default_value = geo::DEFAULT_EXIT_SERVICE
+    )]
+    geo_service: String,
+    /// Chain the rotating proxy with Tor in the given order, or go through
+    /// the proxies of --chain-file
+    #[arg(long, value_enum)]
+    chain: Option<ChainMode>,
+    /// The proxies --chain hops goes through, one URL per line in order,
+    /// e.g. socks5://a:1080 then http://c:3128
+    #[arg(long, value_name = "PATH")]
+    chain_file: Option<PathBuf>,
+    /// The hops a config file's chain = [...] lists
+    #[arg(skip)]
+    chain_hops: Vec<String>,
+    /// Which hops of --chain hops rotation replaces with the rotating
+    /// proxies [default: last]
+    #[arg(long, value_enum)]
+    chain_rotate: Option<ChainRotate>,
+    /// Most hops --chain hops accepts
+    #[arg(long, value_name = "N", default_value_t = 8)]
+    chain_max_hops: usize,
+    /// Accept connections from other applications, e.g. socks5://127.0.0.1:1080
+    /// or http://127.0.0.1:8080
+    #[arg(long, value_parser = ListenSpec::parse)]
+    listen: Vec<ListenSpec>,
+    /// Allow listeners to bind to non-loopback addresses
+    #[arg(long)]
+    listen_allow_remote: bool,
+    /// Keep a destination of the listeners off the route: a CIDR range, a
+    /// host or a *.domain wildcard. It is refused, or with =direct
+    /// connected to from this machine, e.g. 10.0.0.0/8 or
+    /// *.corp.example.com=direct
+    #[arg(
+        long,
+        value_name = "RULE",
+        value_parser = NoProxyRule::parse,
+        value_delimiter = ','
+    )]
+    no_proxy: Vec<NoProxyRule>,
+    /// Log nothing but fatal errors, and leave out the connection status
+    #[arg(long)]
+    no_log: bool,
+    /// Only log warnings and errors
+    #[arg(long)]
+    quiet: bool,
+    /// How log lines are written
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Show IPs and proxy hosts in logs and the connection status as they
+    /// are, not as short hashes. Credentials are hidden either way
+    #[arg(long)]
+    log_sensitive: bool,
+    /// Also write what is logged to this file, appending to it [default
+    /// with --daemon: SESSION.log in the data dir]
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// Detach from the terminal and run in the background, logging only
+    /// to --log-file; end it with `stop`
+    #[arg(long)]
+    daemon: bool,
+    /// Resolve and check everything a session would use, print the plan
+    /// and exit without sending anything; the exit status says whether it
+    /// is valid
+    #[arg(long)]
+    dry_run: bool,
+    /// Where --daemon writes its PID [default: SESSION.pid in the runtime
+    /// dir, where the control socket is]
+    #[arg(long, value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+    /// Bytes the log file may grow to before it is rotated; 0 never
+    /// rotates it
+    #[arg(long, value_name = "BYTES", default_value_t = 10 * 1024 * 1024)]
+    log_max_size: u64,
+    /// Rotated log files kept, as PATH.1 (newest) to PATH.N
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    log_keep: usize,
+    /// Overwrite and remove the log file and its rotated copies, and the
+    /// --capture file, once the session ends
+    #[arg(long)]
+    log_shred: bool,
+    /// Record each request through the http:// listeners to this file:
+    /// method, host, path, headers, status, sizes, timing and the identity
+    /// and proxy that carried it. Bodies are never recorded, and
+    /// Authorization and Cookie values are hashed
+    #[arg(long, value_name = "PATH")]
+    capture: Option<PathBuf>,
+    /// How --capture writes requests
+    #[arg(long, value_enum, default_value_t = CaptureFormat::Jsonl)]
+    capture_format: CaptureFormat,
+    /// Also log debug lines, such as malformed requests to the listeners
+    #[arg(short, long)]
+    verbose: bool,
+    /// Concurrent listener connections allowed per proxy, unless its entry
+    /// in proxies.txt sets max_connections=N
+    #[arg(long, default_value_t = pool::DEFAULT_MAX_CONNECTIONS)]
+    max_connections_per_proxy: usize,
+    /// Use every loaded proxy without checking that it responds first
+    #[arg(long)]
+    no_precheck: bool,
+    /// Seconds each proxy gets to answer the startup health check
+    #[arg(long, default_value_t = 5)]
+    precheck_timeout: u64,
+    /// What the startup health check sends through each proxy
+    #[arg(long, value_enum, default_value_t = ProbeLevel::Ip)]
+    precheck_probe: ProbeLevel,
+    /// Longest the startup may take, e.g. 10m. The baseline IP lookup, Tor
+    /// starting and bootstrapping, and the health check run side by side
+    /// within it; proxies not checked by then are left out
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "5m")]
+    startup_timeout: Duration,
+    /// Also classify each proxy in the health check as transparent,
+    /// anonymous or elite, by the headers a judge endpoint gets through it
+    #[arg(long)]
+    anonymity_check: bool,
+    /// Leave proxies less anonymous than this, or unclassified, out of
+    /// the rotation. Implies --anonymity-check
+    #[arg(long, value_enum, value_name = "LEVEL")]
+    min_anonymity: Option<Anonymity>,
+    /// Endpoint that answers with the request headers it got, as JSON,
+    /// for --anonymity-check
+    #[arg(long, value_name = "URL", default_value = anonymity::DEFAULT_JUDGE_URL)]
+    judge_url: String,
+    /// Times an IP check, Tor check, health check or heartbeat is tried
+    /// before it counts as failed
+    #[arg(long, value_name = "N", default_value_t = retry::DEFAULT_ATTEMPTS, value_parser = clap::value_parser!(u32).range(1..=10))]
+    check_attempts: u32,
+    /// Milliseconds before a check is first tried again, doubling with
+    /// each further try
+    #[arg(long, value_name = "MS", default_value_t = retry::DEFAULT_BACKOFF_MS)]
+    check_backoff: u64,
+    /// Most requests a minute the session sends on its own: IP and Tor
+    /// checks, probes, heartbeats, judge, geolocation and decoy requests.
+    /// Those over it wait their turn. Listener traffic is not counted
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_checks_per_minute: Option<u32>,
+    /// Most of those requests a minute to any one host, such as an IP
+    /// service
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_checks_per_host: Option<u32>,
+    /// What is sent through the new route after each rotation. Only ip
+    /// checks that the exit IP changed; the others only that the route works
+    #[arg(long, value_enum, default_value_t = ProbeLevel::Ip)]
+    verify_probe: ProbeLevel,
+    /// Service that answers a GET with the caller's IP, asked in the order
+    /// given instead of the built-in ones. Repeatable
+    #[arg(long = "ip-service", value_name = "URL", value_parser = parse_ip_service)]
+    ip_services: Vec<String>,
+    /// Accept http:// --ip-service URLs, whose answers can be tampered with
+    #[arg(long)]
+    allow_http_ip_check: bool,
+    /// Never ask an IP service or Tor's check page, at startup or after
+    /// rotations; the exit IP is then unknown
+    #[arg(long)]
+    no_ip_check: bool,
+    /// Don't look up this machine's own IP at startup, so no request is
+    /// ever made without a proxy; exits through it then go unnoticed
+    #[arg(long)]
+    no_baseline: bool,
+    /// Don't check for a captive portal at startup, which asks a
+    /// generate_204 endpoint over the direct connection
+    #[arg(long)]
+    no_portal_check: bool,
+    /// On finding a captive portal, or no network, check again until this
+    /// has passed, e.g. 10m, instead of exiting
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    wait_for_network: Option<Duration>,
+    /// Refuse IPv6 in Veko Dome's own connections: to proxies, from the
+    /// listeners, and in name lookups
+    #[arg(long)]
+    block_ipv6: bool,
+    /// Resolve proxy hosts, and targets behind socks5:// proxies, over
+    /// DNS-over-HTTPS instead of the system resolver. Bare --doh uses
+    /// Cloudflare
+    #[arg(
+        long,
+        value_enum,
+        value_name = "PROVIDER",
+        num_args = 0..=1,
+        default_missing_value = "cloudflare"
+    )]
+    doh: Option<DohProvider>,
+    /// DoH server to use instead of a --doh provider, e.g.
+    /// https://doh.example/dns-query
+    #[arg(long, value_name = "URL", value_parser = dns::parse_doh_url)]
+    doh_url: Option<DohUrl>,
+    /// Address of the --doh-url server, when the URL names it by host
+    #[arg(long, value_name = "IP")]
+    doh_bootstrap: Option<IpAddr>,
+    /// Resolve over DNS-over-TLS with this server instead, for networks
+    /// that block DoH. The port defaults to 853, and the server's
+    /// certificate must match the host given
+    #[arg(long, value_name = "HOST[:PORT]", value_parser = dns::parse_dot)]
+    dot: Option<DotServer>,
+    /// Resolve listener targets over the encrypted DNS too, and give
+    /// proxies only addresses, instead of letting http:// and socks5h://
+    /// proxies resolve them
+    #[arg(long)]
+    resolve_locally: bool,
+    /// Consecutive failed connections before a proxy is quarantined
+    #[arg(long, default_value_t = 3)]
+    max_proxy_failures: u32,
+    /// Seconds a quarantined proxy sits out before it is tried again
+    #[arg(long, default_value_t = 300)]
+    proxy_cooldown: u64,
+    /// Where what the session learned about its proxies is kept for the
+    /// next one: latency, failures, anonymity and quarantines. Defaults to
+    /// proxy-state.json in the data dir
+    #[arg(long, value_name = "PATH")]
+    state_file: Option<PathBuf>,
+    /// Start without what earlier sessions learned about the proxies
+    #[arg(long)]
+    fresh_state: bool,
+    /// Oldest state file taken over, e.g. 12h; an older one is discarded
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1d")]
+    state_max_age: Duration,
+    /// Longest one exit IP may carry traffic in a row, e.g. 15m, however
+    /// rotation is otherwise set up. Measured by the exit IP check after
+    /// each rotation
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    max_time_per_exit: Option<Duration>,
+    /// Exit IPs not to use: addresses and CIDR ranges, one per line, and
+    /// Tor relay fingerprints, which Tor is told to exclude as exits along
+    /// with the ranges. An exit the check after a rotation finds listed is
+    /// rotated away from at once. Re-read on SIGHUP
+    #[arg(long, value_name = "PATH")]
+    exit_blocklist: Option<PathBuf>,
+    /// Shortest time a route is kept, e.g. 30s, so the exit IP never
+    /// churns faster. --rotate, --rotate-requests, SIGUSR1 and the rotate
+    /// command wait for it; a failing route and the exit and lifetime caps
+    /// do not
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    min_dwell: Option<Duration>,
+    /// Longest time a route is kept, e.g. 10m, rotating away from it
+    /// whatever --rotate and --rotate-requests say, even with --rotate off
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    max_identity_lifetime: Option<Duration>,
+    /// Rotate once the tunnels opened under one identity carried this
+    /// much both ways, e.g. 500MB or 2GiB
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_bytes_per_identity: Option<u64>,
+    /// Seconds connections open on the old route get to finish after a
+    /// rotation before they are cut; new connections take the new route
+    /// at once. 0 cuts them right away. The kill switch never waits
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    drain_timeout: u64,
+    /// End the session after this long, e.g. 2h or 1h30m, shutting down
+    /// as Ctrl-C does
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    duration: Option<Duration>,
+    /// Only forward and rotate between these local times, e.g.
+    /// 09:00-18:00; outside them forwarding stops as when the path breaks
+    #[arg(long, value_name = "HH:MM-HH:MM", value_parser = ActiveHours::parse)]
+    active_hours: Option<ActiveHours>,
+    /// Shut the session down when the exit IP does not change on rotation
+    /// or is this machine's own IP, instead of only warning
+    #[arg(long)]
+    strict: bool,
+    /// Stop forwarding while the proxies, Tor or the exit IP check show the
+    /// anonymization path is broken, and resume once it recovers. This is
+    /// the default
+    #[arg(long)]
+    fail_closed: bool,
+    /// Keep forwarding when Tor exits or traffic exits from this machine's
+    /// own IP, and shut down once every proxy is quarantined
+    #[arg(long)]
+    fail_open: bool,
+    /// How the next proxy is picked on rotation
+    #[arg(long, value_enum, default_value_t = RotationStrategy::Sequential)]
+    rotation_strategy: RotationStrategy,
+    /// What consecutive proxies may not share, on top of the strategy.
+    /// Country and ASN come from the list, --geoip-db or
+    /// --geolocate-proxies; proxies where it is unknown are skipped
+    #[arg(long, value_enum, value_name = "WHAT", default_value_t = Distinct::None)]
+    rotate_distinct: Distinct,
+    /// Rhai script whose select() picks the next proxy with
+    /// --rotation-strategy scripted; see profile show --script-context
+    #[cfg(feature = "scripting")]
+    #[arg(long, value_name = "PATH")]
+    selection_script: Option<PathBuf>,
+    /// Percentage of rotations that go to Tor instead of a proxy (0-100);
+    /// each one asks Tor for a new identity
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    tor_weight: u8,
+    /// Transports to carry traffic over, most preferred first, e.g.
+    /// tor,proxy,fail. When the one in use stops working the next one up
+    /// takes over, and `fail` ends the session; `direct` connects from this
+    /// machine and is only ever used when listed
+    #[arg(long, value_name = "ORDER", value_parser = FallbackOrder::parse)]
+    fallback_order: Option<FallbackOrder>,
+    /// How long a preferred transport must stay up before --fallback-order
+    /// moves back to it, e.g. 2m [default: 30s]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    fallback_recover: Option<Duration>,
+    /// Where to load proxies from: a file, an http(s):// URL serving a
+    /// list, or - for stdin. Defaults to proxies.txt, else the built-in list
+    #[arg(long, value_name = "SOURCE")]
+    proxy: Option<String>,
+    /// Format of the proxy list; auto goes by the file extension, then the
+    /// content
+    #[arg(long, value_enum, default_value_t = ProxyFormat::Auto)]
+    proxy_format: ProxyFormat,
+    /// Only rotate through proxies in these countries, e.g. DE,NL
+    #[arg(long, value_name = "CODES", value_delimiter = ',')]
+    proxy_country: Vec<String>,
+    /// Leave out proxies in these countries, e.g. US
+    #[arg(long, value_name = "CODES", value_delimiter = ',')]
+    proxy_exclude_country: Vec<String>,
+    /// MaxMind-format database (e.g. GeoLite2-Country.mmdb) for proxies the
+    /// list gives no country for, and for where the exit IP is
+    #[arg(long, value_name = "PATH")]
+    geoip_db: Option<PathBuf>,
+    /// Whether country filters let through proxies whose country is
+    /// unknown, e.g. while the lookup is unavailable
+    #[arg(long, value_enum, default_value_t = MissingGeo::Exclude)]
+    on_missing_geo: MissingGeo,
+    /// Re-fetch the --proxy URL every this many seconds and add any new
+    /// proxies to the rotation
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    proxy_refresh: Option<u64>,
+    /// Refuse a proxy list, as read or fetched, whose SHA-256 is not this
+    #[arg(long, value_name = "HEX", value_parser = integrity::parse_sha256)]
+    proxy_sha256: Option<[u8; 32]>,
+    /// File with the ed25519 signature of the proxy list, raw, base64 or
+    /// hex; read again for each fetch. Takes --proxy-pubkey
+    #[arg(long, value_name = "PATH")]
+    proxy_sig: Option<PathBuf>,
+    /// File with the ed25519 public key --proxy-sig must verify under
+    #[arg(long, value_name = "PATH")]
+    proxy_pubkey: Option<PathBuf>,
+    /// Serve an HTML status page on this loopback address, e.g.
+    /// 127.0.0.1:8090
+    #[arg(long, value_name = "ADDR")]
+    status_page: Option<std::net::SocketAddr>,
+    /// Serve Prometheus metrics at /metrics on this address, e.g.
+    /// 127.0.0.1:9900
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<std::net::SocketAddr>,
+    /// Allow --metrics-listen to bind to a non-loopback address
+    #[arg(long)]
+    metrics_allow_remote: bool,
+    /// Record every proxy selection decision to this file for replay
+    #[arg(long, value_name = "PATH")]
+    debug_decisions: Option<PathBuf>,
+    /// Shell command run after each rotation, told about it in
+    /// VEKO_EVENT, VEKO_REASON, VEKO_NEW_IP and VEKO_PROXY_INDEX
+    #[arg(long, value_name = "CMD")]
+    on_rotate: Option<String>,
+    /// Shell command run when the anonymization path breaks, told about it
+    /// like --on-rotate is
+    #[arg(long, value_name = "CMD")]
+    on_failure: Option<String>,
+    /// HTTPS endpoint POSTed each rotation and failure as JSON, through the
+    /// route, once it carries traffic
+    #[arg(long, value_name = "URL", value_parser = hooks::parse_webhook_url)]
+    webhook_url: Option<String>,
+    /// How the summary of the session is printed when it ends
+    #[arg(long, value_enum, default_value_t = SummaryFormat::Text)]
+    output: SummaryFormat,
+    /// Also write the summary of the session to this file, as JSON
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum SummaryFormat {
+    /// Lines for people, left out with --no-log or --quiet
+    Text,
+    /// One JSON object
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum IdentityFormat {
+    /// One JSON object
+    Json,
+    /// curl options: -A '...' -H '...' -x '...'
+    Curl,
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum MissingGeo {
+    Allow,
+    Exclude,
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum ProxyFormat {
+    Auto,
+    /// One URL per line, optionally followed by key=value settings
+    Text,
+    /// An array of objects with a "url" field
+    Json,
+    /// A header row naming a "url" column, then one proxy per row
+    Csv,
+}
+
+/// Decoy requests a minute unless --decoy-rate says otherwise.
+const DEFAULT_DECOY_RATE: u32 = 2;
+/// How long a preferred transport must stay up before --fallback-order
+/// moves back to it, unless --fallback-recover says otherwise.
+const DEFAULT_FALLBACK_RECOVER: Duration = Duration::from_secs(30);
+/// How long --fallback-order waits for Tor to bootstrap before it counts
+/// Tor as down.
+const TOR_BOOTSTRAP_GRACE: Duration = Duration::from_secs(120);
+/// What shutdown gets beyond --tor-grace at the least, for the rest of
+/// the cleanup.
+const SHUTDOWN_AFTER_TOR: Duration = Duration::from_secs(5);
+
+/// Shortest adaptive rotation interval when --rotate-min is not given.
+const DEFAULT_ROTATE_MIN: u64 = 5;
+
+/// Largest proxy list accepted from a URL.
+const MAX_PROXY_LIST_BYTES: u64 = 1024 * 1024;
+
+fn is_url_source(source: &str) -> bool {
+    source.starts_with("https://") || source.starts_with("http://")
+}
+
+/// Whether --fallback-order lists Tor.
+fn fallback_tor(args: &StartArgs) -> bool {
+    args.fallback_order
+        .as_ref()
+        .is_some_and(|order| order.uses(Transport::Tor))
+}
+
+/// Downloads a proxy list over a direct connection, since no proxy is
+/// known to work yet.
+fn fetch_proxy_list(url: &str) -> Result<String, String> {
+    let response = Client::builder()
+        .no_proxy()
+        .timeout(Duration::from_secs(15))
+        .build()
+        .and_then(|client| client.get(url).send())
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.without_url().to_string())?;
+    let mut text = String::new();
+    response
+        .take(MAX_PROXY_LIST_BYTES + 1)
+        .read_to_string(&mut text)
+        .map_err(|e| e.to_string())?;
+    if text.len() as u64 > MAX_PROXY_LIST_BYTES {
+        return Err(format!(
+            "list is larger than {} bytes",
+            MAX_PROXY_LIST_BYTES
+        ));
+    }
+    Ok(text)
+}
+
+/// Reads the proxy list named by `--proxy`: a file, an http(s) URL, or `-`
+/// for stdin. Without one, proxies.txt or else the built-in list is used.
+/// Returns the list with a description of where it came from.
+fn read_proxy_source(source: Option<&str>) -> Result<(String, String), String> {
+    match source {
+        None => match fs::read_to_string("proxies.txt") {
+            Ok(text) => Ok(("proxies.txt".to_string(), text)),
+            Err(_) => Ok((
+                "built-in proxy list".to_string(),
+                include_str!("../default_proxies.txt").to_string(),
+            )),
+        },
+        Some("-") => {
+            let mut text = String::new();
+            io::stdin()
+                .read_to_string(&mut text)
+                .map_err(|e| format!("cannot read stdin: {}", e))?;
+            Ok(("stdin".to_string(), text))
+        }
+        Some(url) if is_url_source(url) => {
+            let label = redact_url(url);
+            let text =
+                fetch_proxy_list(url).map_err(|e| format!("cannot fetch {}: {}", label, e))?;
+            Ok((label, text))
+        }
+        Some(path) => fs::read_to_string(path)
+            .map(|text| (path.to_string(), text))
+            .map_err(|e| format!("cannot read {}: {}", path, e)),
+    }
+}
+
+/// A list URL without credentials or query, which often carries a
+/// subscription token.
+fn redact_url(url: &str) -> String {
+    let mut stripped = strip_credentials(url);
+    if let Ok(mut parsed) = reqwest::Url::parse(&stripped) {
+        parsed.set_query(None);
+        stripped = parsed.to_string();
+    }
+    stripped
+}
+
+/// Parses a proxy list in `format`, skipping bad entries with a warning.
+/// Fails when the list as a whole cannot be read in that format.
+fn parse_proxy_list(
+    source: &str,
+    text: &str,
+    format: ProxyFormat,
+) -> Result<Vec<ProxyEntry>, String> {
+    match (list_format(source, text, format), format) {
+        (ProxyFormat::Json, _) => parse_json_list(source, text),
+        (ProxyFormat::Csv, _) => parse_csv_list(source, text),
+        (_, ProxyFormat::Auto) => match parse_text_list(source, text) {
+            (entries, Some((line_no, e))) if entries.is_empty() => Err(format!(
+                "{} is not a JSON, CSV or plain proxy list; line {} is not a proxy: {}",
+                source, line_no, e
+            )),
+            (entries, _) => Ok(entries),
+        },
+        _ => Ok(parse_text_list(source, text).0),
+    }
+}
+
+/// The format a list is in: `format`, unless that is auto, when it goes by
+/// the extension of `source` and then the first line.
+fn list_format(source: &str, text: &str, format: ProxyFormat) -> ProxyFormat {
+    if format != ProxyFormat::Auto {
+        return format;
+    }
+    let extension = source
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    let first = list_lines(text).next().map(|(_, line)| line);
+    match (extension.as_deref(), first) {
+        (Some("json"), _) => ProxyFormat::Json,
+        (Some("csv"), _) => ProxyFormat::Csv,
+        (_, Some(line)) if line.starts_with('[') => ProxyFormat::Json,
+        (_, Some(line)) if csv_fields(line).iter().any(|f| f == "url") => ProxyFormat::Csv,
+        _ => ProxyFormat::Text,
+    }
+}
+
+/// `text` with only the entries whose URL is in `keep`, in the list's own
+/// format. Comments and settings are kept as written; JSON is rewritten.
+fn keep_entries(
+    source: &str,
+    text: &str,
+    format: ProxyFormat,
+    keep: &HashSet<String>,
+) -> Result<String, String> {
+    let kept = |url: &str| {
+        ProxyEntry::parse(url)
+            .ok()
+            .is_some_and(|entry| keep.contains(&entry.url))
+    };
+    match list_format(source, text, format) {
+        ProxyFormat::Json => {
+            let entries: Vec<serde_json::Value> = serde_json::from_str(text)
+                .map_err(|e| format!("{} is not a JSON list: {}", source, e))?;
+            let entries: Vec<serde_json::Value> = entries
+                .into_iter()
+                .filter(|entry| entry.get("url").and_then(|u| u.as_str()).is_some_and(kept))
+                .collect();
+            serde_json::to_string_pretty(&entries)
+                .map(|json| json + "\n")
+                .map_err(|e| e.to_string())
+        }
+        ProxyFormat::Csv => {
+            let mut lines = list_lines(text);
+            let (_, header) = lines
+                .next()
+                .ok_or_else(|| format!("{} has no CSV header row", source))?;
+            let column = csv_fields(header)
+                .iter()
+                .position(|c| c.eq_ignore_ascii_case("url"))
+                .ok_or_else(|| format!("{}: CSV header has no url column", source))?;
+            let mut out = format!("{}\n", header);
+            for (_, line) in lines {
+                if csv_fields(line).get(column).is_some_and(|url| kept(url)) {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+            Ok(out)
+        }
+        _ => {
+            let mut out = String::new();
+            for line in text.lines() {
+                let entry = line.trim();
+                let listed = !entry.is_empty() && !entry.starts_with('#');
+                if !listed || entry.split_whitespace().next().is_some_and(kept) {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+            Ok(out)
+        }
+    }
+}
+
+/// Numbered lines that carry content, skipping blanks and # comments.
+fn list_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, s)| !s.is_empty() && !s.starts_with('#'))
+}
+
+/// The entries of a plain list, with the first line that failed to parse.
+fn parse_text_list(source: &str, text: &str) -> (Vec<ProxyEntry>, Option<(usize, String)>) {
+    let mut entries = Vec::new();
+    let mut first_error = None;
+    for (line_no, line) in list_lines(text) {
+        match ProxyEntry::parse(line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                log(
+                    &format!("Skipping {} line {}: {}", source, line_no, e),
+                    "ERROR",
+                );
+                first_error.get_or_insert((line_no, e));
+            }
+        }
+    }
+    (entries, first_error)
+}
+
+fn parse_json_list(source: &str, text: &str) -> Result<Vec<ProxyEntry>, String> {
+    let items: Vec<serde_json::Value> = serde_json::from_str(text).map_err(|e| {
+        format!(
+            "{} is not a JSON array of proxies: line {}: {}",
+            source,
+            e.line(),
+            e
+        )
+    })?;
+    Ok(items
+        .into_iter()
+        .enumerate()
+        .filter_map(|(n, item)| {
+            match serde_json::from_value::<ProxyEntry>(item)
+                .map_err(|e| e.to_string())
+                .and_then(ProxyEntry::normalized)
+            {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log(
+                        &format!("Skipping {} entry {}: {}", source, n + 1, e),
+                        "ERROR",
+                    );
+                    None
+                }
+            }
+        })
+        .collect())
+}
+
+/// Columns a CSV proxy list may use; any others are ignored.
+const CSV_COLUMNS: [&str; 6] = [
+    "url",
+    "max_connections",
+    "country",
+    "provider",
+    "label",
+    "latency_ms",
+];
+
+fn parse_csv_list(source: &str, text: &str) -> Result<Vec<ProxyEntry>, String> {
+    let mut lines = list_lines(text);
+    let (header_no, header) = lines
+        .next()
+        .ok_or_else(|| format!("{} has no CSV header row", source))?;
+    let columns: Vec<String> = csv_fields(header)
+        .into_iter()
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    if !columns.iter().any(|c| c == "url") {
+        return Err(format!(
+            "{} line {}: CSV header has no url column: {}",
+            source, header_no, header
+        ));
+    }
+    Ok(lines
+        .filter_map(|(line_no, line)| {
+            let fields = csv_fields(line);
+            let parsed = if fields.len() != columns.len() {
+                Err(format!(
+                    "expected {} fields, found {}",
+                    columns.len(),
+                    fields.len()
+                ))
+            } else {
+                let mut entry = ProxyEntry::default();
+                columns
+                    .iter()
+                    .zip(&fields)
+                    .filter(|(column, value)| {
+                        CSV_COLUMNS.contains(&column.as_str()) && !value.is_empty()
+                    })
+                    .try_for_each(|(column, value)| {
+                        entry
+                            .set(column, value)
+                            .map_err(|e| format!("{}: {}", column, e))
+                    })
+                    .and_then(|_| entry.normalized())
+            };
+            match parsed {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log(
+                        &format!("Skipping {} line {}: {}", source, line_no, e),
+                        "ERROR",
+                    );
+                    None
+                }
+            }
+        })
+        .collect())
+}
+
+/// Splits one CSV row on commas. Fields may be wrapped in double quotes to
+/// hold commas, with "" for a literal quote.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+fn load_proxies(
+    source: Option<&str>,
+    format: ProxyFormat,
+    check: Option<&ListCheck>,
+) -> Vec<ProxyEntry> {
+    let (label, text) = read_proxy_source(source).unwrap_or_else(|e| {
+        log(&format!("Cannot load proxies: {}", e), "FATAL");
+        process::exit(1);
+    });
+    if let Some(check) = check {
+        match check.verify(text.as_bytes()) {
+            Ok(passed) => log(
+                &format!("Proxy list {} verified: {}", label, passed),
+                "SECURITY",
+            ),
+            Err(e) => {
+                log(&format!("Refusing proxy list {}: {}", label, e), "SECURITY");
+                process::exit(1);
+            }
+        }
+    }
+    let entries = parse_proxy_list(&label, &text, format).unwrap_or_else(|e| {
+        log(&format!("Cannot load proxies: {}", e), "FATAL");
+        process::exit(1);
+    });
+    log(
+        &format!("Loaded {} proxies from {}", entries.len(), label),
+        "PROXY",
+    );
+    entries
+}
+
+/// Which proxies may enter the rotation, by --proxy-country and
+/// --proxy-exclude-country.
+struct CountryFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    /// Looks up countries the list does not give.
+    geoip: Option<GeoDb>,
+    /// What happens to proxies whose country stays unknown.
+    on_missing: MissingGeo,
+    /// Whether --rotate-distinct needs the country and ASN of proxies
+    /// even when none are filtered out.
+    locate: bool,
+}
+
+impl CountryFilter {
+    fn from_args(args: &StartArgs) -> Result<Self, String> {
+        let codes = |list: &[String]| list.iter().map(|c| c.trim().to_ascii_uppercase()).collect();
+        Ok(CountryFilter {
+            include: codes(&args.proxy_country),
+            exclude: codes(&args.proxy_exclude_country),
+            geoip: args.geoip_db.as_deref().map(GeoDb::open).transpose()?,
+            on_missing: args.on_missing_geo,
+            locate: args.rotate_distinct != Distinct::None,
+        })
+    }
+
+    fn is_active(&self) -> bool {
+        !self.include.is_empty() || !self.exclude.is_empty()
+    }
+
+    /// The entries allowed through, with why the others were dropped.
+    /// Proxies of unknown country follow `on_missing`. Where --geoip-db
+    /// is given it fills in what the list leaves out.
+    fn apply(&self, mut entries: Vec<ProxyEntry>) -> (Vec<ProxyEntry>, Vec<String>) {
+        let geoip = self
+            .geoip
+            .as_ref()
+            .filter(|_| self.is_active() || self.locate);
+        for entry in &mut entries {
+            if entry.country.is_some() && entry.asn.is_some() {
+                continue;
+            }
+            let info = geoip
+                .zip(proxy_host(&entry.url))
+                .and_then(|(db, host)| db.host_info(&host));
+            if let Some(info) = info {
+                fill_location(entry, &info);
+            }
+        }
+        if !self.is_active() {
+            return (entries, Vec::new());
+        }
+        let (mut excluded, mut outside, mut unknown) = (0, 0, 0);
+        let mut kept = Vec::new();
+        for entry in entries {
+            match &entry.country {
+                None if self.on_missing == MissingGeo::Allow => kept.push(entry),
+                None => unknown += 1,
+                Some(c) if self.exclude.contains(c) => excluded += 1,
+                Some(c) if !self.include.is_empty() && !self.include.contains(c) => outside += 1,
+                Some(_) => kept.push(entry),
+            }
+        }
+        let mut reasons = Vec::new();
+        if excluded > 0 {
+            reasons.push(format!(
+                "{} in excluded {}",
+                excluded,
+                self.exclude.join(", ")
+            ));
+        }
+        if outside > 0 {
+            reasons.push(format!("{} outside {}", outside, self.include.join(", ")));
+        }
+        if unknown > 0 {
+            let hint = if self.geoip.is_none() {
+                " (pass --geoip-db to look them up)"
+            } else {
+                ""
+            };
+            reasons.push(format!(
+                "{} of unknown country{}; --on-missing-geo allow keeps them",
+                unknown, hint
+            ));
+        }
+        (kept, reasons)
+    }
+}
+
+/// Fills in the country and ASN a proxy's list entry leaves out.
+fn fill_location(entry: &mut ProxyEntry, info: &GeoInfo) {
+    if entry.country.is_none() {
+        entry.country = info.country.clone();
+    }
+    if entry.asn.is_none() {
+        entry.asn = info.asn.as_deref().and_then(normalize_asn);
+    }
+}
+
+fn geo_cache_path() -> PathBuf {
+    data_dir().join("geo-cache.json")
+}
+
+fn event_log() -> EventLog {
+    EventLog::new(&data_dir().join("events.jsonl"))
+}
+
+fn proxy_host(proxy: &str) -> Option<String> {
+    reqwest::Url::parse(proxy)
+        .ok()
+        .and_then(|url| url.host_str().map(|h| h.to_string()))
+}
+
+fn geolocate_proxies(
+    geo: &mut GeoClient,
+    client: &Client,
+    proxies: &[ProxyEntry],
+    max_age: Duration,
+) -> HashMap<String, GeoInfo> {
+    let hosts: Vec<String> = proxies.iter().filter_map(|p| proxy_host(&p.url)).collect();
+    let mut cache = GeoCache::load(&geo_cache_path(), max_age);
+    let lookup = geo.lookup(client, &mut cache, &hosts);
+    if let Err(e) = cache.save() {
+        log(&format!("Could not write geolocation cache: {}", e), "GEO");
+    }
+
+    if lookup.throttled {
+        log(
+            "Geolocation provider unavailable or throttling, using cached data",
+            "GEO",
+        );
+    }
+    log(
+        &format!(
+            "Geolocated {}/{} proxies ({} cached, {} fetched)",
+            lookup.found.len(),
+            hosts.len(),
+            lookup.from_cache,
+            lookup.fetched
+        ),
+        "GEO",
+    );
+    for proxy in proxies {
+        if let Some(info) = proxy_host(&proxy.url).and_then(|h| lookup.found.get(&h)) {
+            log(
+                &format!("{} is in {}", strip_credentials(&proxy.url), info.summary()),
+                "GEO",
+            );
+        }
+    }
+    lookup.found
+}
+
+/// How many proxies the startup health check tries at once.
+const PRECHECK_PARALLELISM: usize = 16;
+
+/// What the health check asks of each proxy before it joins the rotation.
+#[derive(Clone)]
+struct HealthCheck {
+    timeout: Duration,
+    level: ProbeLevel,
+    /// Classifies each proxy, with --anonymity-check or --min-anonymity.
+    judge: Option<Arc<Judge>>,
+    min_anonymity: Option<Anonymity>,
+}
+
+impl HealthCheck {
+    fn from_args(args: &StartArgs, own_ip: Option<&str>) -> Option<Self> {
+        (!args.no_precheck).then(|| HealthCheck {
+            timeout: Duration::from_secs(args.precheck_timeout),
+            level: args.precheck_probe,
+            judge: (args.anonymity_check || args.min_anonymity.is_some()).then(|| {
+                Arc::new(Judge {
+                    url: args.judge_url.clone(),
+                    own_ip: own_ip.map(str::to_string),
+                })
+            }),
+            min_anonymity: args.min_anonymity,
+        })
+    }
+}
+
+/// How one proxy did in the health check.
+struct Checked {
+    latency: Duration,
+    /// When the probe level sees it.
+    exit_ip: Option<PublicIp>,
+    /// The judge's answer, when it was asked.
+    judgement: Option<Result<Judgement, String>>,
+}
+
+/// Probes `proxy`, then asks the judge through it if `check` has one.
+fn check_proxy(proxy: &str, check: &HealthCheck) -> Result<Checked, String> {
+    let started = Instant::now();
+    let client = reqwest_proxy(proxy).and_then(|p| {
+        Client::builder()
+            .proxy(p)
+            .local_address(ipv4_only_local_address())
+            .timeout(check.timeout)
+            .build()
+            .map_err(|e| e.to_string())
+    })?;
+    let exit_ip = probe::run(check.level, &client, proxy, check.timeout)?;
+    let latency = started.elapsed();
+    Ok(Checked {
+        latency,
+        exit_ip,
+        judgement: check.judge.as_ref().map(|judge| judge.classify(&client)),
+    })
+}
+
+/// Runs [`check_proxy`] on every proxy, `parallelism` at a time, and
+/// returns the results in list order. `progress` hears how many are done
+/// as they finish. Once `stop` is set no further proxy is started, and
+/// those never checked are left `None`.
+fn check_proxies(
+    proxies: &[ProxyEntry],
+    check: &HealthCheck,
+    parallelism: usize,
+    stop: &Arc<AtomicBool>,
+    progress: &mut dyn FnMut(usize),
+) -> Vec<Option<Result<Checked, String>>> {
+    let urls: Arc<Vec<String>> = Arc::new(proxies.iter().map(|p| p.url.clone()).collect());
+    let results = Arc::new(Mutex::new(
+        (0..urls.len()).map(|_| None).collect::<Vec<_>>(),
+    ));
+    let (next, done) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let workers: Vec<_> = (0..parallelism.clamp(1, urls.len().max(1)))
+        .map(|_| {
+            let (urls, results) = (urls.clone(), results.clone());
+            let (next, done, stop) = (next.clone(), done.clone(), stop.clone());
+            let check = check.clone();
+            workers::spawn("health-check", 3, move || loop {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some(proxy) = urls.get(i) else {
+                    break;
+                };
+                let result = check_proxy(proxy, &check);
+                results.lock().unwrap()[i] = Some(result);
+                done.fetch_add(1, Ordering::SeqCst);
+            })
+        })
+        .collect();
+    let mut reported = 0;
+    while !workers.iter().all(|worker| worker.is_finished()) {
+        thread::sleep(Duration::from_millis(100));
+        let now = done.load(Ordering::SeqCst);
+        if now != reported {
+            reported = now;
+            progress(now);
+        }
+    }
+    for worker in workers {
+        let _ = worker.join();
+    }
+    let mut results = results.lock().unwrap();
+    std::mem::take(&mut *results)
+}
+
+/// How often the startup tells how far the health check has come.
+const STARTUP_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a piece of startup work run on its own thread reports once done.
+enum StartupDone {
+    Baseline(Baseline),
+    Tor(Result<StartedTor, String>),
+    /// The proxies that passed the health check.
+    Precheck(Vec<(ProxyEntry, Duration)>),
+}
+
+/// Time between captive portal checks under --wait-for-network.
+const PORTAL_RECHECK: Duration = Duration::from_secs(10);
+
+/// Returns once the portal check gets through, or straight away when it
+/// cannot reach anything and `wait` is not given, since a network with no
+/// portal may still block that one endpoint. Exits with
+/// [`portal::EXIT_CODE`] when a portal is still in the way after `wait`.
+fn await_network(wait: Option<Duration>) {
+    let deadline = wait.map(|wait| Instant::now() + wait);
+    let mut reported = None;
+    loop {
+        let waiting = deadline.is_some_and(|deadline| Instant::now() < deadline);
+        let message = match portal::check() {
+            Network::Open => {
+                log("No captive portal in the way", "DEBUG");
+                return;
+            }
+            Network::Portal(url) => {
+                let message = format!(
+                    "This network shows a captive portal{}; log in to it with a browser",
+                    url.map(|url| format!(" at {}", url)).unwrap_or_default()
+                );
+                if !waiting {
+                    log(
+                        &format!("{}, then start again or pass --wait-for-network", message),
+                        "FATAL",
+                    );
+                    process::exit(portal::EXIT_CODE);
+                }
+                message
+            }
+            Network::Unreachable(e) => {
+                let message = format!("Cannot reach {}: {}", portal::CHECK_URL, e);
+                if !waiting {
+                    log(
+                        &format!("{}; going on, though checks may fail", message),
+                        "WARNING",
+                    );
+                    return;
+                }
+                message
+            }
+        };
+        // Said once, not every time the check is made again
+        if reported.as_ref() != Some(&message) {
+            log(&format!("{}; waiting for the network", message), "WARNING");
+            reported = Some(message);
+        }
+        thread::sleep(PORTAL_RECHECK);
+    }
+}
+
+/// This machine's own IPs, looked up without a proxy.
+#[derive(Default)]
+struct Baseline {
+    ip: Option<String>,
+    ipv6: Option<PublicIp>,
+}
+
+fn capture_baseline() -> Baseline {
+    let Ok(direct) = Client::builder()
+        .no_proxy()
+        .timeout(Duration::from_secs(10))
+        .build()
+    else {
+        return Baseline::default();
+    };
+    Baseline {
+        ip: get_public_ip(&direct).map(|found| found.ip),
+        ipv6: get_public_ipv6(&direct),
+    }
+}
+
+/// How the session runs Tor, with the chain's forwarder at `forwarder`
+/// under --chain proxy-then-tor.
+fn tor_options(
+    args: &StartArgs,
+    profile: &SecurityProfile,
+    forwarder: Option<std::net::SocketAddr>,
+    exit_blocklist: Option<&Blocklist>,
+) -> TorOptions {
+    let mut options = TorOptions {
+        binary: tor_integration::find_tor(args.tor_binary.as_deref()),
+        grace_period: Duration::from_secs(args.tor_grace),
+        max_restarts: args.tor_max_restarts,
+        ..TorOptions::default()
+    };
+    if let (Some(ChainMode::ProxyThenTor), Some(forwarder)) = (args.chain, forwarder) {
+        options.extra_args = vec!["--Socks5Proxy".to_string(), forwarder.to_string()];
+    }
+    if tor_isolation(args, profile) {
+        options.extra_args.extend(tor_integration::isolation_args());
+    }
+    if let Some(nodes) = exit_blocklist.and_then(Blocklist::exclude_exit_nodes) {
+        options.extra_args.push("--ExcludeExitNodes".to_string());
+        options.extra_args.push(nodes);
+    }
+    if tor_control(args) {
+        options.control_cookie = Some(data_dir().join("tor_control_cookie"));
+    }
+    options
+}
+
+/// Isolation is only of use where sessions go through Tor.
+fn tor_isolation(args: &StartArgs, profile: &SecurityProfile) -> bool {
+    profile.tor_isolation
+        && (args.tor_weight > 0
+            || args.chain == Some(ChainMode::ProxyThenTor)
+            || fallback_tor(args))
+}
+
+/// Whether the session needs Tor's control port: blending and a fallback
+/// to Tor use it for NEWNYM and bootstrap state.
+fn tor_control(args: &StartArgs) -> bool {
+    args.tor_weight > 0 || fallback_tor(args)
+}
+
+/// Whether `url` is Tor's own SOCKS port, as the built-in proxies are.
+fn points_at_tor(url: &str) -> bool {
+    let tor_port = tor_integration::SOCKS_ADDR
+        .parse::<std::net::SocketAddr>()
+        .ok()
+        .map(|addr| addr.port());
+    reqwest::Url::parse(url).is_ok_and(|url| {
+        url.port() == tor_port
+            && matches!(url.host_str(), Some("127.0.0.1" | "localhost" | "[::1]"))
+    })
+}
+
+/// Logs every [`STARTUP_PROGRESS_INTERVAL`] how many of `total` proxies
+/// the health check has done.
+fn startup_progress(total: usize) -> impl FnMut(usize) {
+    let mut last = Instant::now();
+    move |done| {
+        if last.elapsed() >= STARTUP_PROGRESS_INTERVAL {
+            last = Instant::now();
+            log(
+                &format!("Startup: {}/{} proxies health-checked", done, total),
+                "SYSTEM",
+            );
+        }
+    }
+}
+
+/// The time since `start`, e.g. "3.2s".
+fn since(start: Instant) -> String {
+    format!("{:.1}s", start.elapsed().as_secs_f64())
+}
+
+/// Keeps the proxies that pass `check`, and are anonymous enough for it,
+/// in their original order with how long each took. Once `stop` is set
+/// the proxies not checked yet are left out. `progress` hears how many
+/// are done as they finish.
+fn precheck_proxies(
+    proxies: Vec<ProxyEntry>,
+    check: &HealthCheck,
+    stop: &Arc<AtomicBool>,
+    progress: &mut dyn FnMut(usize),
+) -> Vec<(ProxyEntry, Duration)> {
+    let total = proxies.len();
+    let results = check_proxies(&proxies, check, PRECHECK_PARALLELISM, stop, progress);
+    let alive = results
+        .iter()
+        .filter(|result| matches!(result, Some(Ok(_))))
+        .count();
+    let stopped = stop.load(Ordering::SeqCst);
+    let mut unchecked = 0;
+    let mut survivors = Vec::new();
+    for (mut proxy, result) in proxies.into_iter().zip(results) {
+        let name = strip_credentials(&proxy.url);
+        let checked = match result {
+            Some(Ok(checked)) => checked,
+            None if stopped => {
+                unchecked += 1;
+                continue;
+            }
+            Some(Err(_)) | None => {
+                log(&format!("Proxy {} is not responding", name), "PROXY");
+                continue;
+            }
+        };
+        match checked.judgement {
+            Some(Ok(judgement)) => proxy.anonymity = Some(judgement.anonymity),
+            Some(Err(e)) => log(
+                &format!("Could not classify proxy {}: {}", name, e),
+                "PROXY",
+            ),
+            None => {}
+        }
+        if let Some(min) = check.min_anonymity {
+            if proxy.anonymity.is_none_or(|level| level < min) {
+                log(
+                    &format!(
+                        "Leaving proxy {} out: it is {}, below --min-anonymity {}",
+                        name,
+                        proxy.anonymity.map_or("unclassified", Anonymity::name),
+                        min.name()
+                    ),
+                    "PROXY",
+                );
+                continue;
+            }
+        }
+        if proxy.anonymity == Some(Anonymity::Transparent) {
+            log(
+                &format!(
+                    "Proxy {} passes this machine's own IP on; --min-anonymity anonymous \
+                     leaves such proxies out",
+                    name
+                ),
+                "SECURITY",
+            );
+        }
+        survivors.push((proxy, checked.latency));
+    }
+    log(&format!("{}/{} proxies alive", alive, total), "PROXY");
+    if unchecked > 0 {
+        log(
+            &format!(
+                "{} proxies were not checked before --startup-timeout and are left out",
+                unchecked
+            ),
+            "PROXY",
+        );
+    }
+    if survivors.len() < alive {
+        log(
+            &format!(
+                "{} of them anonymous enough to rotate through",
+                survivors.len()
+            ),
+            "PROXY",
+        );
+    }
+    survivors
+}
+
+fn log(message: &str, category: &str) {
+    logging::write(message, category);
+}
+
+/// [`log`], with `fields` (a JSON object) added to JSON log lines.
+fn log_fields(message: &str, category: &str, fields: serde_json::Value) {
+    logging::write_json(message, category, fields);
+}
+
+/// The command line as the binary is installed, with the environment
+/// variables each option can be set from.
+fn installed_command() -> clap::Command {
+    config::with_env(Cli::command()).name(env!("CARGO_PKG_NAME"))
+}
+
+fn print_completions(shell: clap_complete::Shell) {
+    output::write(format_args!("{}", completion_script(shell)));
+}
+
+fn completion_script(shell: clap_complete::Shell) -> String {
+    let mut script = Vec::new();
+    clap_complete::generate(
+        shell,
+        &mut installed_command(),
+        env!("CARGO_PKG_NAME"),
+        &mut script,
+    );
+    String::from_utf8_lossy(&script).into_owned()
+}
+
+/// The running session's listeners, for `env` and `pac`. Errors go to
+/// stderr, since stdout is read by a shell or written to a file.
+fn session_endpoints(session: Option<&str>) -> (String, Endpoints) {
+    let fail = |message: String| -> ! {
+        errln!("{}", message);
+        process::exit(1);
+    };
+    let status = match control::Client::connect(session).and_then(|c| c.status()) {
+        Ok(status) => status,
+        Err(ClientError::NoSession) => fail(
+            "Veko Dome is not active. Start a session with --listen to point programs at it."
+                .to_string(),
+        ),
+        Err(e) => fail(e.to_string()),
+    };
+    let specs: Vec<String> = status.listeners.iter().map(|l| l.spec.clone()).collect();
+    match Endpoints::new(&specs, status.bypass) {
+        Some(endpoints) => (status.session, endpoints),
+        None => fail(format!(
+            "Session '{}' has no listeners; start it with --listen, e.g. http://127.0.0.1:8080",
+            status.session
+        )),
+    }
+}
+
+fn print_proxy_env(session: Option<&str>, shell: Shell) {
+    let (_, endpoints) = session_endpoints(session);
+    output::write(format_args!("{}", endpoints.env(shell)));
+}
+
+fn write_pac(session: Option<&str>, out: Option<&Path>) {
+    let (name, endpoints) = session_endpoints(session);
+    let pac = endpoints.pac(&name);
+    let Some(path) = out else {
+        output::write(format_args!("{}", pac));
+        return;
+    };
+    if let Err(e) = fs::write(path, pac) {
+        errln!("Cannot write {}: {}", path.display(), e);
+        process::exit(1);
+    }
+    errln!(
+        "PAC file for session '{}' written to {}",
+        name,
+        path.display()
+    );
+}
+
+fn print_manpage() {
+    match manpage() {
+        Ok(page) => output::write(format_args!("{}", page)),
+        Err(e) => {
+            log(&format!("Cannot render the man page: {}", e), "FATAL");
+            process::exit(1);
+        }
+    }
+}
+
+fn manpage() -> io::Result<String> {
+    let mut page = Vec::new();
+    clap_mangen::Man::new(installed_command()).render(&mut page)?;
+    Ok(String::from_utf8_lossy(&page).into_owned())
+}
+
+fn print_veko_logo() {
+    outln!(
+        r#"
+    ██▒   █▓ ▓█████  ██▀███   ▒█████    ██████ 
+    ▓██░   █▒▓█   ▀ ▓██ ▒ ██▒▒██▒  ██▒▒██    ▒ 
+     ▓██  █▒░▒███   ▓██ ░▄█ ▒▒██░  ██▒░ ▓██▄   
+      ▒██ █░░▒▓█  ▄ ▒██▀▀█▄  ▒██   ██░  ▒   ██▒
+       ▒▀█░  ░▒████▒░██▓ ▒██▒░ ████▓▒░▒██████▒▒
+       ░ ▐░  ░░ ▒░ ░░ ▒▓ ░▒▓░░ ▒░▒░▒░ ▒ ▒▓▒ ▒ ░
+       ░ ░░   ░ ░  ░  ░▒ ░ ▒░  ░ ▒ ▒░ ░ ░▒  ░ ░
+         ░░     ░     ░░   ░ ░ ░ ░ ▒  ░  ░  ░  
+          ░     ░  ░   ░         ░ ░        ░  
+                                               
+    High-Security Network Anonymization Toolkit v1.0
+    "#,
+    );
+}
+
+/// Runs the command `matches`, from [`parse_args`], asks for.
+pub fn run(matches: &ArgMatches) {
+    let mut cli = Cli::from_arg_matches(matches).unwrap_or_else(|e| e.exit());
+    // Scripts and man pages are read by programs, which the logo would break
+    if !matches!(
+        cli.command,
+        Commands::Completions { .. }
+            | Commands::Manpage
+            | Commands::Env { .. }
+            | Commands::Pac { .. }
+            | Commands::Identity { .. }
+    ) {
+        print_veko_logo();
+    }
+    if let (Commands::Start(args), Some(start)) =
+        (&mut cli.command, matches.subcommand_matches("start"))
+    {
+        match compat::resolve(start, args, cli.strict_flags) {
+            Ok(warnings) => {
+                for warning in warnings {
+                    log(&warning, "WARNING");
+                }
+            }
+            Err(e) => {
+                log(&e, "FATAL");
+                process::exit(1);
+            }
+        }
+    }
+    let session = cli.session.clone();
+    let session = session.as_deref();
+    match &mut cli.command {
+        Commands::Start(args) => {
+            let given = matches.subcommand_matches("start").unwrap_or(matches);
+            let session = session.unwrap_or(control::DEFAULT_SESSION);
+            if let Err(e) = start_session(args, given, session) {
+                log(&e, "FATAL");
+                process::exit(1);
+            }
+        }
+        Commands::Status { no_probe } => check_status(session, !*no_probe),
+        Commands::Rotate => rotate_session(session),
+        Commands::Stop(args) => stop_session(session, args),
+        Commands::Restart(args) => restart_session(session, args),
+        Commands::Reload => reload_session(session),
+        Commands::Stats { internals } => show_stats(session, *internals),
+        Commands::Connections => show_connections(session),
+        Commands::Identity {
+            include_secrets,
+            identity_format,
+        } => print_identity(session, *include_secrets, *identity_format),
+        Commands::Watch { plain } => watch_session(session, *plain),
+        Commands::Replay { path } => replay_decisions(path),
+        Commands::Purge { geo_cache } => purge(*geo_cache),
+        Commands::Config {
+            action: ConfigCommand::Check { path, profile },
+        } => check_config(path, profile.as_deref()),
+        Commands::Profiles { config } => list_profiles(config.as_deref()),
+        Commands::Data {
+            action: DataCommand::Update { url },
+        } => update_datasets(url),
+        Commands::Data {
+            action: DataCommand::Status,
+        } => show_datasets(),
+        Commands::Profile {
+            action: ProfileCommand::Show { script_context },
+        } => show_profile(*script_context),
+        Commands::Audit(args) => audit_route(args, session),
+        Commands::TestProxies(args) => test_proxies(args),
+        Commands::Doctor(args) => run_doctor(args),
+        Commands::Completions { shell } => print_completions(*shell),
+        Commands::Env { shell } => print_proxy_env(session, *shell),
+        Commands::Pac { out } => write_pac(session, out.as_deref()),
+        Commands::Manpage => print_manpage(),
+        Commands::Events {
+            action: None,
+            kind,
+            last,
+        } => show_events(*kind, *last),
+        Commands::Events {
+            action: Some(EventsCommand::EmitSample { kind }),
+            ..
+        } => outln!(
+            "{}",
+            serde_json::to_string(&events::payloads::sample(*kind)).unwrap_or_default()
+        ),
+        #[cfg(feature = "schema")]
+        Commands::Events {
+            action: Some(EventsCommand::Schema),
+            ..
+        } => outln!("{}", events::payloads::schema()),
+    }
+}
+
+/// Parses the command line, with start options also read from VEKO_*
+/// variables. Exits on errors, naming the variable when one is at fault.
+pub fn parse_args(argv: Vec<std::ffi::OsString>) -> ArgMatches {
+    let cli = config::with_env(Cli::command());
+    cli.clone().try_get_matches_from(argv).unwrap_or_else(|e| {
+        print_veko_logo();
+        let errors = config::env_errors(&cli);
+        if errors.is_empty() {
+            e.exit();
+        }
+        for error in &errors {
+            log(error, "FATAL");
+        }
+        process::exit(1);
+    })
+}
+
+fn load_decoy_targets(path: &Path) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read decoy targets {}: {}", path.display(), e))?;
+    let mut targets = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !is_url_source(line) {
+            return Err(format!(
+                "{} line {}: {} is not an http(s):// URL",
+                path.display(),
+                i + 1,
+                line
+            ));
+        }
+        targets.push(line.to_string());
+    }
+    if targets.is_empty() {
+        return Err(format!("{} has no decoy targets", path.display()));
+    }
+    Ok(targets)
+}
+
+fn parse_session_name(name: &str) -> Result<String, String> {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(name.to_string())
+    } else {
+        Err("session names may only contain letters, digits, '-' and '_'".to_string())
+    }
+}
+
+/// A --rotate interval in seconds, or `off` for 0.
+fn parse_rotate(s: &str) -> Result<u64, String> {
+    match s {
+        "off" => Ok(0),
+        _ => s
+            .parse()
+            .map_err(|_| "expected seconds, or off to turn timed rotation off".to_string()),
+    }
+}
+
+/// Runs a session until it is stopped. `given` is what `args` was parsed
+/// from, which tells the options given from the defaults. What keeps the
+/// session from starting, or from going on, is returned for the caller to
+/// report.
+fn start_session(args: &mut StartArgs, given: &ArgMatches, session: &str) -> Result<(), String> {
+    let config = load_config(args, given);
+    if args.daemon && args.log_file.is_none() {
+        args.log_file = Some(data_dir().join(format!("{}.log", session)));
+    }
+    let args: &StartArgs = args;
+    let started_at = chrono::Local::now();
+    let started = Instant::now();
+    // Companion commands and the event log report on the session, so it
+    // carries on when nobody reads its output
+    output::keep_running_without_stdout();
+    logging::set_max_level(if args.no_log {
+        logging::Level::Fatal
+    } else if args.quiet {
+        logging::Level::Warn
+    } else if args.verbose {
+        logging::Level::Debug
+    } else {
+        logging::Level::Info
+    });
+    logging::set_format(args.log_format);
+    if args.dry_run {
+        dry_run(args, given, config.as_ref());
+    }
+    retry::configure(
+        args.check_attempts,
+        Duration::from_millis(args.check_backoff),
+    );
+    throttle::configure(args.max_checks_per_minute, args.max_checks_per_host);
+    if args.log_sensitive {
+        redact::show_addresses();
+    }
+    let rotation_interval = args.rotate;
+    // A daemon opens it once detached, since its writer is a thread
+    if !args.daemon {
+        open_log_file(args);
+    }
+
+    if let Some((config, taken)) = &config {
+        log_config(config, taken);
+    }
+    let violations = validate::check(args);
+    if let Some((last, earlier)) = violations.split_last() {
+        for violation in earlier {
+            log(violation, "FATAL");
+        }
+        return Err(last.clone());
+    }
+    // Before any thread is started, which a fork would not carry over
+    let pid_file = args
+        .pid_file
+        .clone()
+        .unwrap_or_else(|| control::pid_path(session));
+    if args.daemon {
+        daemonize(&pid_file, session, args.log_file.as_deref());
+        open_log_file(args);
+    }
+
+    workers::set_crash_hook(Arc::new(log_worker_crash));
+    if !args.ip_services.is_empty() {
+        set_ip_services(args.ip_services.clone());
+    }
+    if args.block_ipv6 {
+        dns::block_ipv6();
+    }
+    // Before encrypted DNS, whose test lookup a portal would fail as well
+    if args.no_portal_check {
+        log("No captive portal check (--no-portal-check)", "DEBUG");
+    } else {
+        await_network(args.wait_for_network);
+    }
+
+    // Set up before anything is looked up
+    let dns_server = match &args.doh_url {
+        // validate::check made sure the address is known
+        Some(url) => url
+            .ip()
+            .or(args.doh_bootstrap)
+            .map(|ip| dns::Server::DohUrl(url.clone(), ip)),
+        None => args
+            .dot
+            .clone()
+            .map(dns::Server::Dot)
+            .or(args.doh.map(dns::Server::Doh)),
+    };
+    if let Some(server) = dns_server {
+        match dns::install(server) {
+            Ok(description) => {
+                log(&format!("Resolving names over {}", description), "SECURITY");
+                if args.resolve_locally {
+                    dns::resolve_targets_locally();
+                    log(
+                        &format!(
+                            "Listener targets are resolved over {} and sent to proxies by address",
+                            description
+                        ),
+                        "SECURITY",
+                    );
+                } else {
+                    log(
+                        &format!(
+                            "Names sent through http:// and socks5h:// proxies or Tor are \
+                             resolved at the proxy, not over {}. Pass --resolve-locally to \
+                             resolve listener targets over it as well",
+                            description
+                        ),
+                        "WARNING",
+                    );
+                }
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Cannot set up encrypted DNS, and will not fall back to the system \
+                     resolver: {}",
+                    e
+                ));
+            }
+        }
+    }
+
+    if args.no_baseline {
+        log(
+            "No baseline IP taken (--no-baseline); an exit through this machine's own IP \
+             will go unnoticed",
+            "SECURITY",
+        );
+    }
+
+    // Load all security components
+    let catalog = config.as_ref().map(|(c, _)| c.catalog());
+    let profile = engine::build_profile(&args.mode, catalog.as_ref(), &profile_options(args))?;
+    let profile = Arc::new(profile);
+    let mode = match args.mode.ends_with(".toml") {
+        true => args.mode.clone(),
+        false => args.mode.to_uppercase(),
+    };
+    log(&format!("Activating {} security profile", mode), "SECURITY");
+    log(&profile.tls.describe(), "SECURITY");
+    if args.insecure_tls {
+        log(
+            "--insecure-tls: TLS CERTIFICATES ARE NOT CHECKED. Anyone between here and a \
+             destination can read and change every request, the IP and Tor checks included",
+            "SECURITY",
+        );
+    }
+
+    // Load proxies
+    let country_filter = Arc::new(
+        CountryFilter::from_args(args)
+            .map_err(|e| format!("Cannot filter proxies by country: {}", e))?,
+    );
+    let list_check = ListCheck::new(
+        args.proxy_sha256,
+        args.proxy_sig.as_deref(),
+        args.proxy_pubkey.as_deref(),
+    )
+    .map_err(|e| format!("Cannot verify proxy lists: {}", e))?
+    .map(Arc::new);
+    let exit_blocklist = match args.exit_blocklist.as_deref() {
+        Some(path) => {
+            let blocklist = read_exit_blocklist(path)?;
+            log(
+                &format!(
+                    "Exit blocklist {}: {} addresses and ranges, {} Tor relays",
+                    path.display(),
+                    blocklist.ranges(),
+                    blocklist.fingerprints().len()
+                ),
+                "SECURITY",
+            );
+            Some(blocklist)
+        }
+        None => None,
+    };
+    let chain_hops = match args.chain {
+        Some(ChainMode::Hops) => read_chain(args)?,
+        _ => Vec::new(),
+    };
+    let chain_rotate = args.chain_rotate.unwrap_or(ChainRotate::Last);
+    let loaded = load_proxies(
+        args.proxy.as_deref(),
+        args.proxy_format,
+        list_check.as_deref(),
+    );
+    let total = loaded.len();
+    let (mut proxies, filtered) = country_filter.apply(loaded);
+    if country_filter.is_active() {
+        let dropped = if filtered.is_empty() {
+            String::new()
+        } else {
+            format!("; filtered out {}", filtered.join(", "))
+        };
+        log(
+            &format!(
+                "Kept {}/{} proxies by country{}",
+                proxies.len(),
+                total,
+                dropped
+            ),
+            "PROXY",
+        );
+    }
+    if proxies.is_empty() {
+        let reason = if total > 0 && country_filter.is_active() {
+            "the country filter left none"
+        } else {
+            "add some to proxies.txt"
+        };
+        return Err(format!("No proxies to rotate through; {}", reason));
+    }
+
+    // Companion commands reach the session through its control socket
+    #[cfg(any(unix, windows))]
+    let control_server = Arc::new(control::Server::bind(session).map_err(|e| {
+        let hint = if e.kind() == io::ErrorKind::AddrInUse {
+            "; pick another name with --session"
+        } else {
+            ""
+        };
+        format!("Cannot open control socket: {}{}", e, hint)
+    })?);
+
+    // Chaining has to be in place before Tor starts, since proxy-then-tor
+    // changes how Tor itself connects out. --chain hops starts out on the
+    // hops as listed, not on the rotator's first proxy
+    let listed_chain = args.chain == Some(ChainMode::Hops);
+    let forwarder = match args.chain {
+        Some(mode) => Some(start_chain(mode, chain_hops, chain_rotate, &mut proxies)?),
+        None => None,
+    };
+
+    // Start Tor
+    let tor_options = tor_options(
+        args,
+        &profile,
+        forwarder.as_ref().map(|f| f.addr()),
+        exit_blocklist.as_ref(),
+    );
+    if args.tor_backend == TorBackendKind::Binary {
+        log(
+            &format!("Running Tor from {}", tor_options.binary.display()),
+            "DEBUG",
+        );
+    }
+    let tor_isolation = tor_isolation(args, &profile);
+    let tor_control = tor_control(args);
+    let kill_switch = (!args.fail_open).then(|| Arc::new(KillSwitch::default()));
+    // With --fallback-order a dead Tor is the fallback's to act on
+    let tor_switch = kill_switch
+        .clone()
+        .filter(|_| args.fallback_order.is_none());
+    let on_tor_event = move |event: TorEvent| {
+        log_tor_event(&event);
+        if let Some(switch) = &tor_switch {
+            match &event {
+                TorEvent::Exited(status) => {
+                    engage_kill_switch(switch, Cause::Tor, format!("Tor exited ({})", status))
+                }
+                TorEvent::Restarted { .. } => {
+                    release_kill_switch(switch, Cause::Tor, "Tor was relaunched")
+                }
+                TorEvent::GaveUp { .. } | TorEvent::Bootstrap { .. } => {}
+            }
+        }
+    };
+
+    // The baseline lookup, Tor and the health check run side by side,
+    // within --startup-timeout. The check only waits for the other two
+    // where it needs them: for the own IP to judge proxies by, or for Tor
+    // since the built-in proxies point at it
+    let deadline = started + args.startup_timeout;
+    let abandon = Arc::new(AtomicBool::new(false));
+    let (done_tx, done_rx) = mpsc::channel();
+    // The baseline every exit is compared with: this machine's own IP,
+    // looked up over a direct connection. It stays in memory and is never
+    // logged
+    let mut baseline_pending = !args.no_ip_check && !args.no_baseline;
+    if baseline_pending {
+        let done = done_tx.clone();
+        thread::spawn(move || done.send(StartupDone::Baseline(capture_baseline())));
+    }
+    {
+        let (done, kind, abandon) = (done_tx.clone(), args.tor_backend, abandon.clone());
+        thread::spawn(move || {
+            let started = engine::start_tor(kind, tor_options, on_tor_event, deadline, &abandon);
+            done.send(StartupDone::Tor(started))
+        });
+    }
+    let judged = args.anonymity_check || args.min_anonymity.is_some();
+    let needs_tor = proxies.iter().any(|proxy| points_at_tor(&proxy.url));
+    let mut baseline = Baseline::default();
+    let mut tor = None;
+    let mut checking = !args.no_precheck;
+    let mut check_started = false;
+    let mut health_check = None;
+    let mut latencies = Vec::new();
+    let mut quarantine_all = false;
+    let mut lost = Vec::new();
+    // Whether the session can do without what was lost
+    let possible = |lost: &[Transport]| {
+        args.fallback_order
+            .as_ref()
+            .is_some_and(|order| order.survives(lost))
+    };
+    while tor.is_none() || checking || baseline_pending {
+        let timed_out = Instant::now() >= deadline;
+        let waiting = (judged && baseline_pending) || (needs_tor && tor.is_none());
+        if checking && !check_started && !waiting {
+            let check = HealthCheck::from_args(args, baseline.ip.as_deref());
+            health_check.clone_from(&check);
+            if let (Some(check), false) = (check, timed_out) {
+                let (done, proxies, stop) = (done_tx.clone(), proxies.clone(), abandon.clone());
+                log(
+                    &format!(
+                        "Startup: health-checking {} proxies, {} at a time",
+                        proxies.len(),
+                        PRECHECK_PARALLELISM
+                    ),
+                    "SYSTEM",
+                );
+                thread::spawn(move || {
+                    let mut progress = startup_progress(proxies.len());
+                    let alive = precheck_proxies(proxies, &check, &stop, &mut progress);
+                    done.send(StartupDone::Precheck(alive))
+                });
+                check_started = true;
+            } else {
+                // Nothing checked in time is kept
+                let _ = done_tx.send(StartupDone::Precheck(Vec::new()));
+                check_started = true;
+            }
+        }
+        let event = match timed_out {
+            // The rest is bound to report soon once abandoned
+            true => done_rx.recv().ok(),
+            false => done_rx
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .ok(),
+        };
+        match event {
+            Some(StartupDone::Baseline(found)) if baseline_pending => {
+                log(
+                    &format!("Startup: baseline lookup done in {}", since(started)),
+                    "SYSTEM",
+                );
+                baseline = found;
+                baseline_pending = false;
+            }
+            // Abandoned at the timeout
+            Some(StartupDone::Baseline(_)) => {}
+            Some(StartupDone::Tor(Ok(started_tor))) => {
+                log("Tor network activated", "TOR");
+                match started_tor.bootstrapped {
+                    Some(true) => log(
+                        &format!("Startup: Tor bootstrapped in {}", since(started)),
+                        "SYSTEM",
+                    ),
+                    Some(false) => log(
+                        "Tor did not bootstrap within --startup-timeout; it carries on in the background",
+                        "WARNING",
+                    ),
+                    None => log(
+                        &format!("Startup: Tor started in {}", since(started)),
+                        "SYSTEM",
+                    ),
+                }
+                tor = Some(started_tor.backend);
+            }
+            Some(StartupDone::Tor(Err(e))) => {
+                lost.push(Transport::Tor);
+                if !possible(&lost) {
+                    return Err(e);
+                }
+                log(
+                    &format!(
+                        "{}; carrying on without Tor, as --fallback-order {} allows",
+                        e,
+                        args.fallback_order.as_ref().unwrap()
+                    ),
+                    "ERROR",
+                );
+                tor = Some(Arc::new(FailedTor));
+            }
+            Some(StartupDone::Precheck(alive)) => {
+                checking = false;
+                log(
+                    &format!("Startup: health check done in {}", since(started)),
+                    "SYSTEM",
+                );
+                if alive.is_empty() {
+                    lost.push(Transport::Proxy);
+                    if !possible(&lost) {
+                        // Tor is stopped rather than left behind
+                        abandon.store(true, Ordering::SeqCst);
+                        if tor.is_none() {
+                            while let Ok(event) = done_rx.recv() {
+                                if let StartupDone::Tor(started_tor) = event {
+                                    tor = started_tor.ok().map(|started_tor| started_tor.backend);
+                                    break;
+                                }
+                            }
+                        }
+                        if let Some(tor) = &tor {
+                            tor.stop();
+                        }
+                        return Err(
+                            "No proxies passed the health check; fix proxies.txt or pass --no-precheck"
+                                .to_string(),
+                        );
+                    }
+                    log(
+                        &format!(
+                            "No proxies passed the health check; every proxy is quarantined \
+                             and --fallback-order {} carries on over the rest",
+                            args.fallback_order.as_ref().unwrap()
+                        ),
+                        "WARNING",
+                    );
+                    quarantine_all = true;
+                } else {
+                    (proxies, latencies) = alive.into_iter().unzip();
+                }
+            }
+            None if !timed_out => {}
+            None => unreachable!("startup tasks report until they are done"),
+        }
+        if !timed_out && Instant::now() >= deadline {
+            let mut pending = Vec::new();
+            if baseline_pending {
+                pending.push("the baseline lookup");
+            }
+            if tor.is_none() {
+                pending.push("Tor");
+            }
+            if checking {
+                pending.push("the health check");
+            }
+            log(
+                &format!(
+                    "Startup ran into --startup-timeout {} with {} unfinished; going on without waiting further",
+                    describe_duration(args.startup_timeout),
+                    pending.join(" and ")
+                ),
+                "WARNING",
+            );
+            abandon.store(true, Ordering::SeqCst);
+            baseline_pending = false;
+        }
+    }
+    let tor_manager = tor.expect("startup waits for Tor");
+    let Baseline {
+        ip: direct_ip,
+        ipv6: direct_ipv6,
+    } = baseline;
+    if !args.no_ip_check && !args.no_baseline && direct_ip.is_none() {
+        log(
+            "Could not look up this machine's own IP; an exit through it will go unnoticed",
+            "WARNING",
+        );
+    }
+    // The chain was built from the first loaded proxy, which may be dead
+    let retarget = forwarder.as_ref().filter(|_| !listed_chain);
+    if let (Some(forwarder), Some(_), false) = (retarget, &health_check, quarantine_all) {
+        if let Ok(hop) = Hop::parse(&proxies[0].url) {
+            forwarder.set_rotating(hop);
+        }
+    }
+
+    let journal = match &args.debug_decisions {
+        Some(path) => {
+            let journal = Journal::open(path)
+                .map_err(|e| format!("Cannot open decision journal {}: {}", path.display(), e))?;
+            log(
+                &format!("Recording selection decisions to {}", path.display()),
+                "SYSTEM",
+            );
+            Some(Arc::new(journal))
+        }
+        None => None,
+    };
+
+    // Create proxy rotator
+    let mut rotator = ProxyRotator::new(proxies, rotation_interval)
+        .map_err(|e| format!("Cannot start: {}", e))?;
+    rotator.journal = journal.clone();
+    rotator.max_failures = args.max_proxy_failures.max(1);
+    rotator.cooldown = Duration::from_secs(args.proxy_cooldown);
+    rotator.tor_weight = args.tor_weight;
+    if tor_isolation {
+        rotator.isolate_tor();
+        log(
+            "Tor circuits are isolated per identity: each rotation hands Tor new SOCKS credentials",
+            "SECURITY",
+        );
+    }
+    rotator.strategy = args.rotation_strategy;
+    rotator.distinct = args.rotate_distinct;
+    #[cfg(feature = "scripting")]
+    if let Some(path) = &args.selection_script {
+        let script = scripting::Selector::load(path)
+            .map_err(|e| format!("Cannot use selection script: {}", e))?;
+        log(
+            &format!("Selecting proxies with {}", path.display()),
+            "ROTATION",
+        );
+        rotator.script = Some(Arc::new(script));
+    }
+    if args.rotate_adaptive {
+        rotator.min_interval = Some(Duration::from_secs(
+            args.rotate_min
+                .unwrap_or(DEFAULT_ROTATE_MIN)
+                .min(args.rotate),
+        ));
+    }
+    rotator.request_limit = (args.rotate_requests > 0).then_some(args.rotate_requests);
+    rotator.min_dwell = args.min_dwell;
+    rotator.max_lifetime = args.max_identity_lifetime;
+    rotator.max_identity_bytes = args.max_bytes_per_identity;
+    rotator.log_schedule = !args.no_log;
+    if args.rotate_jitter > 0 {
+        rotator.set_jitter(Duration::from_secs(args.rotate_jitter));
+    }
+    let state_file = args.state_file.clone().unwrap_or_else(state::default_path);
+    let mut earlier_state = None;
+    if !args.fresh_state {
+        match State::load(&state_file, args.state_max_age) {
+            Ok(Some(saved)) => {
+                // Proxies that just passed the health check are not held
+                // to an old quarantine
+                let known = rotator.restore_state(&saved, health_check.is_none());
+                log(
+                    &format!(
+                        "Took over what earlier sessions knew about {} of {} proxies",
+                        known,
+                        rotator.proxies.len()
+                    ),
+                    "PROXY",
+                );
+                if let (Some(forwarder), Some(entry)) = (retarget, rotator.current_entry()) {
+                    if let Ok(hop) = Hop::parse(&entry.url) {
+                        forwarder.set_rotating(hop);
+                    }
+                }
+                earlier_state = Some(saved);
+            }
+            Ok(None) => {}
+            Err(e) => log(&format!("Discarding the proxy state: {}", e), "WARNING"),
+        }
+    }
+    for (health, latency) in rotator.health.iter_mut().zip(latencies) {
+        health.latency_ms = Some(latency.as_millis() as u64);
+    }
+    // After the restore, which lifts quarantines for checked proxies
+    if quarantine_all {
+        rotator.quarantine_all();
+    }
+    rotator.set_user_agents(profile.user_agents().len());
+    let policy = rotator.policy_summary();
+    let first_user_agent = rotator.user_agent;
+    let proxy_rotator = Arc::new(Mutex::new(rotator));
+    log(
+        &format!(
+            "Proxy rotation {} ({} strategy)",
+            policy,
+            args.rotation_strategy.as_str()
+        ),
+        "ROTATION",
+    );
+    if args.rotate_distinct != Distinct::None {
+        log(
+            &format!(
+                "Consecutive proxies are in different {}s where the pool allows",
+                args.rotate_distinct.as_str()
+            ),
+            "ROTATION",
+        );
+    }
+    
+    // Create initial client
+    let client_proxy = client_route(
+        args.chain,
+        forwarder.as_deref(),
+        &proxy_rotator.lock().unwrap(),
+    );
+    let client = create_http_client(&client_proxy, &profile, first_user_agent)?;
+    match &args.pin_user_agent {
+        Some(_) => log(
+            "Every route presents the user agent given by --pin-user-agent",
+            "SECURITY",
+        ),
+        None => log(
+            &format!(
+                "Presenting user agent #{} of {}; each rotation picks another",
+                first_user_agent,
+                profile.user_agents().len()
+            ),
+            "SECURITY",
+        ),
+    }
+    log(
+        &format!("User agent: {}", profile.user_agent(first_user_agent)),
+        "DEBUG",
+    );
+    
+    let no_proxy = (!args.no_proxy.is_empty()).then(|| {
+        let direct: Vec<String> = args
+            .no_proxy
+            .iter()
+            .filter(|rule| rule.policy == Policy::Direct)
+            .map(|rule| rule.to_string())
+            .collect();
+        log(
+            &format!(
+                "{} destinations kept off the route by --no-proxy",
+                args.no_proxy.len()
+            ),
+            "PROXY",
+        );
+        if !direct.is_empty() {
+            log(
+                &format!(
+                    "Connections to {} leave from this machine, not the route",
+                    direct.join(", ")
+                ),
+                "SECURITY",
+            );
+        }
+        Arc::new(NoProxy::new(args.no_proxy.clone()))
+    });
+    let capture = match &args.capture {
+        Some(path) => {
+            let capture = Capture::create(path, args.capture_format)
+                .map_err(|e| format!("Cannot create capture file {}: {}", path.display(), e))?;
+            log(
+                &format!(
+                    "Capturing requests through the http:// listeners to {}",
+                    path.display()
+                ),
+                "SECURITY",
+            );
+            Some(Arc::new(capture))
+        }
+        None => None,
+    };
+    // Local listeners follow the same route as the session client
+    let listeners = engine::start_listeners(
+        &args.listen,
+        args.chain,
+        forwarder.as_deref(),
+        &proxy_rotator,
+        &ListenerSettings {
+            allow_remote: args.listen_allow_remote,
+            log_connections: !args.no_log,
+            kill_switch: kill_switch.clone(),
+            capture: capture.clone(),
+            drain_timeout: Duration::from_secs(args.drain_timeout),
+            no_proxy: no_proxy.clone(),
+        },
+    )?;
+    if let (Some(forwarder), Some(switch)) = (&forwarder, &kill_switch) {
+        forwarder.set_kill_switch(switch.clone());
+    }
+    if let Some(forwarder) = &forwarder {
+        forwarder.set_drain_timeout(Duration::from_secs(args.drain_timeout));
+    }
+
+    // Listener tunnels are spread over proxies that still have room
+    let pool = (!listeners.is_empty()).then(|| {
+        let pool = Arc::new(proxy_pool(
+            &proxy_rotator.lock().unwrap().proxies,
+            args.max_connections_per_proxy,
+        ));
+        if let Some(journal) = &journal {
+            pool.set_journal(journal.clone());
+        }
+        pool.set_saturation_alert(Arc::new(|message: &str| {
+            log(
+                &format!("Listener connections refused: {}", message),
+                "ERROR",
+            )
+        }));
+        for listener in &listeners {
+            listener.set_pool(pool.clone());
+        }
+        proxy_rotator.lock().unwrap().set_pool(pool.clone());
+        pool
+    });
+
+    // Probe which proxies can relay UDP; chains never can. Clients asking
+    // for UDP before the probes are done are told it is not supported
+    if !listeners.is_empty() && args.chain.is_none() {
+        let proxies = proxy_rotator.lock().unwrap().proxies.clone();
+        let listeners = listeners.clone();
+        workers::spawn("udp-probe", 0, move || {
+            let udp_hops = probe_udp_proxies(&proxies);
+            for listener in &listeners {
+                listener.set_udp_hops(udp_hops.clone());
+            }
+        });
+    }
+
+    // Geolocation only informs the logs and status, so it runs on the side
+    // and a slow or unreachable provider holds nothing up
+    let geo_max_age = Duration::from_secs(args.geo_cache_days * 24 * 60 * 60);
+    let geo_health = args.geolocate_proxies.then(|| {
+        let max_age = geo_max_age;
+        let geo = GeoClient::new();
+        let health = geo.health();
+        let geo = Mutex::new(geo);
+        let (client, rotator) = (client.clone(), proxy_rotator.clone());
+        let proxies = rotator.lock().unwrap().proxies.clone();
+        workers::spawn("geolocate", 0, move || {
+            let mut geo = geo.lock().unwrap_or_else(|e| e.into_inner());
+            let found = geolocate_proxies(&mut geo, &client, &proxies, max_age);
+            // --rotate-distinct and the status go by them from here on
+            let mut rotator = rotator.lock().unwrap_or_else(|e| e.into_inner());
+            for entry in &mut rotator.proxies {
+                if let Some(info) = proxy_host(&entry.url).and_then(|host| found.get(&host)) {
+                    fill_location(entry, info);
+                }
+            }
+        });
+        health
+    });
+
+    // Check initial connection, timing each hop of a listed chain first
+    if let (true, Some(forwarder)) = (listed_chain, &forwarder) {
+        check_hops(&forwarder.chain());
+    }
+    let route = args
+        .chain
+        .zip(forwarder.as_deref())
+        .map(|(mode, forwarder)| describe_chain(mode, &forwarder.chain()));
+    let exit_ipv6 = (!args.no_ip_check)
+        .then(|| get_public_ipv6(&client))
+        .flatten();
+    if let (Some(_), None) = (&direct_ipv6, &exit_ipv6) {
+        log(
+            &format!(
+                "This machine reaches the internet over IPv6, but the route only \
+                 carries IPv4; traffic that bypasses Veko Dome can leak over IPv6{}",
+                if args.block_ipv6 {
+                    ""
+                } else {
+                    ". Pass --block-ipv6 to keep Veko Dome's own connections off it"
+                }
+            ),
+            "WARNING",
+        );
+    }
+    let exit_geo = (!args.no_geo && !args.no_ip_check)
+        .then(|| match &args.geoip_db {
+            Some(path) => GeoDb::open(path).map(ExitGeo::Db),
+            None => Ok(ExitGeo::Service(args.geo_service.clone())),
+        })
+        .and_then(|opened| {
+            opened
+                .map_err(|e| {
+                    log(
+                        &format!("{}; the exit IP is shown without its location", e),
+                        "GEO",
+                    )
+                })
+                .ok()
+        })
+        .map(Arc::new);
+    let tor_circuit = (tor_control || args.chain.is_some_and(ChainMode::through_tor))
+        .then(|| describe_circuits(&*tor_manager));
+    let exit_ip = display_connection_status(
+        &client,
+        true,
+        tor_circuit.as_deref(),
+        ExitChecks {
+            ip_check: !args.no_ip_check,
+            ipv6: exit_ipv6.as_ref(),
+            geo: exit_geo.as_deref().map(|geo| (geo, geo_max_age)),
+        },
+        &proxy_rotator,
+        route.as_deref(),
+        &listeners,
+    );
+    let exits = Arc::new(Mutex::new(ExitHistory::new(
+        direct_ip,
+        exit_blocklist,
+        listed_chain && chain_rotate == ChainRotate::First,
+    )));
+    let first_proxy = strip_credentials(proxy_rotator.lock().unwrap().current());
+    {
+        let mut exits = exits.lock().unwrap();
+        if let Some(alarm) = exits.observe(exit_ip, &first_proxy) {
+            exits.log_alarm(&alarm);
+        }
+        exits.screen(&first_proxy);
+    }
+
+    // Start rotation thread
+    let running = Arc::new(AtomicBool::new(true));
+    // SIGTERM and SIGQUIT, and on Windows Ctrl-Break and closing the
+    // console, end the session as Ctrl-C does. A second one while it
+    // shuts down kills Tor and exits at once
+    let killed = tor_manager.clone();
+    shutdown::install(running.clone(), move || killed.kill())
+        .expect("Error setting signal handlers");
+
+    // The session outlives its terminal; closing it must not end the
+    // session any more than losing stdout does. SIGHUP reloads the proxy
+    // list instead
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, reload_requested.clone())
+        .expect("Error setting SIGHUP handler");
+    let reload = Arc::new(ProxyReload {
+        source: args.proxy.clone(),
+        format: args.proxy_format,
+        list_check: list_check.clone(),
+        country_filter: country_filter.clone(),
+        precheck: health_check.clone(),
+        proxy_rotator: proxy_rotator.clone(),
+        exit_blocklist: args
+            .exit_blocklist
+            .clone()
+            .map(|path| (path, exits.clone())),
+    });
+
+    // SIGUSR1 forces an immediate rotation
+    let triggers = RotationTriggers::default();
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGUSR1, triggers.signal.clone())
+        .expect("Error setting SIGUSR1 handler");
+
+    let events = event_log();
+    if let Err(e) = events.compact() {
+        log(&format!("Could not compact event log: {}", e), "ERROR");
+    }
+    let tor_ready = Arc::new(AtomicBool::new(false));
+    if tor_control {
+        start_tor_monitor(tor_manager.clone(), running.clone(), tor_ready.clone());
+    }
+    if let (Some(url), Some(secs)) = (&args.proxy, args.proxy_refresh) {
+        let refresh = ProxyRefresh {
+            url: url.clone(),
+            format: args.proxy_format,
+            list_check: list_check.clone(),
+            country_filter: country_filter.clone(),
+            interval: Duration::from_secs(secs),
+            precheck: health_check.clone(),
+        };
+        start_proxy_refresh(refresh, proxy_rotator.clone(), running.clone());
+    }
+    install_hooks(
+        args,
+        &profile,
+        forwarder.clone(),
+        proxy_rotator.clone(),
+        kill_switch.clone(),
+    );
+    engine::start_rotation(
+        proxy_rotator.clone(),
+        running.clone(),
+        triggers.clone(),
+        forwarder.clone(),
+        listeners.clone(),
+        RotationFollowUp {
+            events,
+            exits: exits.clone(),
+            profile: profile.clone(),
+            chain: args.chain,
+            exit_cap: args.max_time_per_exit,
+            probe: args.verify_probe,
+            drain_timeout: Duration::from_secs(args.drain_timeout),
+            chain_rotate,
+        },
+        tor_control.then(|| (tor_manager.clone(), tor_ready.clone())),
+    )?;
+    if args.heartbeat > 0 {
+        start_heartbeat(
+            Duration::from_secs(args.heartbeat),
+            args.heartbeat_failures,
+            &profile,
+            args.chain,
+            forwarder.clone(),
+            proxy_rotator.clone(),
+            kill_switch.clone(),
+            triggers.clone(),
+            running.clone(),
+        );
+    }
+    let decoy = if args.decoy {
+        let targets = match &args.decoy_targets {
+            Some(path) => load_decoy_targets(path)?,
+            None => datasets::decoy_targets(),
+        };
+        let rate = args.decoy_rate.unwrap_or(DEFAULT_DECOY_RATE);
+        log(
+            &format!(
+                "Sending about {} decoy requests a minute to {} sites",
+                rate,
+                targets.len()
+            ),
+            "SECURITY",
+        );
+        let decoy = Arc::new(decoy::Decoy::new(targets, rate));
+        start_decoy_traffic(
+            decoy.clone(),
+            &profile,
+            args.chain,
+            forwarder.clone(),
+            proxy_rotator.clone(),
+            kill_switch.clone(),
+            running.clone(),
+        );
+        Some(decoy)
+    } else {
+        None
+    };
+
+    let fallback = args.fallback_order.clone().map(|order| {
+        let recovery = args.fallback_recover.unwrap_or(DEFAULT_FALLBACK_RECOVER);
+        let fallback = Fallback::new(order, recovery);
+        log(
+            &format!(
+                "Transport: {}, moving down --fallback-order {} when it fails",
+                fallback.active(),
+                args.fallback_order.as_ref().unwrap()
+            ),
+            "SECURITY",
+        );
+        use_transport(
+            fallback.active(),
+            &proxy_rotator,
+            forwarder.as_deref(),
+            &listeners,
+            &triggers,
+        );
+        Arc::new(Mutex::new(fallback))
+    });
+    let control = Arc::new(SessionControl {
+        session: session.to_string(),
+        started: Instant::now(),
+        proxy_rotator: proxy_rotator.clone(),
+        running: running.clone(),
+        rotate: triggers.control.clone(),
+        listeners: listeners.clone(),
+        pool: pool.clone(),
+        tor_manager: tor_manager.clone(),
+        tor_ready: tor_control.then_some(tor_ready.clone()),
+        chain: args.chain.zip(forwarder.clone()),
+        exits: exits.clone(),
+        kill_switch: kill_switch.clone(),
+        geo_health,
+        precheck_probe: (!args.no_precheck).then_some(args.precheck_probe),
+        ip_check: !args.no_ip_check,
+        verify_probe: args.verify_probe,
+        exit_geo,
+        route: RouteClient::new(
+            (*profile).clone(),
+            args.chain,
+            forwarder.clone(),
+            proxy_rotator.clone(),
+        ),
+        profile,
+        reload: reload.clone(),
+        fallback: fallback.clone(),
+        bypass: args
+            .no_proxy
+            .iter()
+            .filter(|rule| rule.policy == Policy::Direct)
+            .map(NoProxyRule::pattern)
+            .collect(),
+    });
+    #[cfg(any(unix, windows))]
+    {
+        let server = control_server.clone();
+        let control = control.clone();
+        workers::spawn("control", 5, move || {
+            server.serve(|command| control.handle(command))
+        });
+        log(
+            &format!("Control socket open for session '{}'", session),
+            "SYSTEM",
+        );
+    }
+
+    if let Some(addr) = args.status_page {
+        let control = control.clone();
+        match status_page::start(addr, Arc::new(move || control.page())) {
+            Ok(addr) => log(&format!("Status page at http://{}/", addr), "SYSTEM"),
+            Err(e) => {
+                log(&format!("Cannot serve status page: {}", e), "ERROR");
+                running.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+    if let Some(addr) = args.metrics_listen {
+        let control = control.clone();
+        let source = Arc::new(move || control.metrics());
+        match metrics::start(addr, args.metrics_allow_remote, source) {
+            Ok(addr) => log(&format!("Metrics at http://{}/metrics", addr), "SYSTEM"),
+            Err(e) => {
+                log(&format!("Cannot serve metrics: {}", e), "ERROR");
+                running.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    let mut active = vec![match tor_manager.bootstrapped() {
+        _ if tor_manager.has_failed() => "Tor unavailable".to_string(),
+        Ok(true) => "Tor bootstrapped".to_string(),
+        Ok(false) => "Tor bootstrapping".to_string(),
+        Err(_) => "Tor running".to_string(),
+    }];
+    {
+        let rotator = proxy_rotator.lock().unwrap();
+        active.push(format!(
+            "{} of {} proxies in rotation",
+            rotator.alive_count(),
+            rotator.proxies.len()
+        ));
+    }
+    if let Some(fallback) = &fallback {
+        active.push(format!("transport {}", fallback.lock().unwrap().active()));
+    }
+    if !listeners.is_empty() {
+        active.push(format!("{} listeners", listeners.len()));
+    }
+    if exits.lock().unwrap().direct.is_some() {
+        active.push("baseline IP taken".to_string());
+    }
+    log(
+        &format!(
+            "Veko Dome is now active after {} ({}). Press Ctrl-C to exit.",
+            since(started),
+            active.join(", ")
+        ),
+        "SYSTEM",
+    );
+    log("All connections are fully anonymized", "SECURITY");
+    
+    // Main session loop
+    let mut tor_was_ready = false;
+    while running.load(Ordering::SeqCst) {
+        if reload_requested.swap(false, Ordering::SeqCst) {
+            // Health checks take a while; the loop keeps watching meanwhile
+            let reload = reload.clone();
+            thread::spawn(move || {
+                if let Err(e) = reload.run() {
+                    log(&format!("Reload failed: {}", e), "ERROR");
+                }
+            });
+        }
+        if let Some(limit) = args.duration.filter(|limit| started.elapsed() >= *limit) {
+            log(
+                &format!(
+                    "The session ran for its --duration of {}. Shutting down.",
+                    describe_duration(limit)
+                ),
+                "SYSTEM",
+            );
+            break;
+        }
+        if let (Some(hours), Some(switch)) = (args.active_hours, &kill_switch) {
+            let inside = hours.contains(chrono::Local::now().time());
+            if inside == triggers.paused.load(Ordering::SeqCst) {
+                triggers.paused.store(!inside, Ordering::SeqCst);
+                if inside {
+                    release_kill_switch(
+                        switch,
+                        Cause::Schedule,
+                        &format!("Inside --active-hours {}", hours),
+                    );
+                } else if switch
+                    .engage(Cause::Schedule, format!("outside --active-hours {}", hours))
+                {
+                    log(
+                        &format!(
+                            "Outside --active-hours {}: forwarding and rotation paused until {}",
+                            hours,
+                            hours.start.format("%H:%M")
+                        ),
+                        "SECURITY",
+                    );
+                }
+            }
+        }
+        // With --fallback-order, running out of proxies is the fallback's
+        // to act on
+        let all_quarantined = fallback.is_none() && proxy_rotator.lock().unwrap().all_quarantined();
+        match &kill_switch {
+            Some(switch) if all_quarantined => engage_kill_switch(
+                switch,
+                Cause::Proxies,
+                "every proxy is quarantined".to_string(),
+            ),
+            Some(switch) if fallback.is_none() => {
+                release_kill_switch(switch, Cause::Proxies, "A proxy is back from quarantine")
+            }
+            None if all_quarantined => {
+                // Fail closed rather than routing through proxies known to fail
+                log(
+                    "EVERY PROXY IS QUARANTINED. No working route is left; shutting down session.",
+                    "FATAL",
+                );
+                break;
+            }
+            _ => {}
+        }
+        if let Some(switch) = &kill_switch {
+            if exits.lock().unwrap().exposed {
+                engage_kill_switch(
+                    switch,
+                    Cause::ExitIp,
+                    "traffic exits from this machine's own IP".to_string(),
+                );
+            } else {
+                release_kill_switch(
+                    switch,
+                    Cause::ExitIp,
+                    "The exit IP no longer matches this machine's own",
+                );
+            }
+            let blocked = exits.lock().unwrap().blocked_for_good();
+            match blocked {
+                Some(reason) => engage_kill_switch(switch, Cause::BlockedExit, reason),
+                None => release_kill_switch(
+                    switch,
+                    Cause::BlockedExit,
+                    "The exit IP is no longer on --exit-blocklist",
+                ),
+            }
+        }
+        tor_was_ready |= tor_ready.load(Ordering::SeqCst);
+        if let Some(fallback) = &fallback {
+            // Looked at before taking the fallback, which status takes
+            // with the rotator held
+            let tor = if tor_manager.has_failed() {
+                Health::Down("Tor gave up".to_string())
+            } else if tor_ready.load(Ordering::SeqCst) {
+                Health::Up
+            } else if tor_was_ready {
+                Health::Down("Tor is no longer bootstrapped".to_string())
+            } else if started.elapsed() < TOR_BOOTSTRAP_GRACE {
+                Health::Starting
+            } else {
+                Health::Down(format!(
+                    "Tor did not bootstrap within {}",
+                    describe_duration(TOR_BOOTSTRAP_GRACE)
+                ))
+            };
+            let proxy = match proxy_rotator.lock().unwrap().all_quarantined() {
+                true => Health::Down("every proxy is quarantined".to_string()),
+                false => Health::Up,
+            };
+            let health = |transport| match transport {
+                Transport::Tor => tor.clone(),
+                _ => proxy.clone(),
+            };
+            let transition = fallback.lock().unwrap().evaluate(health, Instant::now());
+            if let Some(transition) = transition {
+                let message = match (transition.to, transition.recovered) {
+                    (Transport::Fail, _) => format!(
+                        "NO TRANSPORT LEFT: {}. Shutting down session rather than connecting directly.",
+                        transition.reason
+                    ),
+                    (Transport::Direct, _) => format!(
+                        "FALLING BACK from {} to direct connections: {}. Traffic now leaves from this machine's own IP.",
+                        transition.from, transition.reason
+                    ),
+                    (to, true) => format!(
+                        "Transport back from {} to {}: {}",
+                        transition.from, to, transition.reason
+                    ),
+                    (to, false) => format!(
+                        "FALLING BACK from {} to {}: {}",
+                        transition.from, to, transition.reason
+                    ),
+                };
+                log_fields(
+                    &message,
+                    "SECURITY",
+                    serde_json::json!({
+                        "event": "transport_changed",
+                        "from": transition.from.name(),
+                        "to": transition.to.name(),
+                    }),
+                );
+                if transition.to == Transport::Fail {
+                    break;
+                }
+                use_transport(
+                    transition.to,
+                    &proxy_rotator,
+                    forwarder.as_deref(),
+                    &listeners,
+                    &triggers,
+                );
+            }
+        } else if tor_manager.has_failed() {
+            // Fail closed rather than carrying on without Tor
+            log(
+                &format!(
+                    "Tor could not be kept alive after {} restarts. Shutting down session.",
+                    tor_manager.restarts()
+                ),
+                "FATAL",
+            );
+            break;
+        }
+        if args.strict {
+            if let Some(alarm) = exits.lock().unwrap().alarm.take() {
+                // The alarm may name the proxy, which --no-log keeps quiet
+                log(&alarm, "SECURITY");
+                log("Shutting down session (--strict).", "FATAL");
+                break;
+            }
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    // A break above ends the session as Ctrl-C does, background work too
+    running.store(false, Ordering::SeqCst);
+    shutdown::force_after(
+        args.shutdown_grace
+            .max(Duration::from_secs(args.tor_grace) + SHUTDOWN_AFTER_TOR),
+    );
+
+    tor_manager.stop();
+    #[cfg(any(unix, windows))]
+    control_server.close();
+    #[cfg(any(unix, windows))]
+    if args.daemon {
+        daemon::remove(&pid_file);
+    }
+    let mut saved = proxy_rotator.lock().unwrap().export_state();
+    if let Some(earlier) = earlier_state {
+        saved.keep(earlier);
+    }
+    if let Err(e) = saved.save(&state_file) {
+        log(
+            &format!(
+                "Could not save the proxy state to {}: {}",
+                state_file.display(),
+                e
+            ),
+            "ERROR",
+        );
+    }
+    if args.tor_weight > 0 {
+        log(
+            &format!("Blend: {}", proxy_rotator.lock().unwrap().blend_summary()),
+            "ROTATION",
+        );
+    }
+    let stats = session_stats(
+        session,
+        (started_at, started),
+        &proxy_rotator.lock().unwrap(),
+        &exits.lock().unwrap(),
+        decoy.as_deref(),
+    );
+    match args.output {
+        SummaryFormat::Text if !args.no_log && !args.quiet => print_summary(&stats),
+        SummaryFormat::Text => {}
+        SummaryFormat::Json => match serde_json::to_string(&stats) {
+            Ok(json) => outln!("{}", json),
+            Err(e) => log(&format!("Could not print the summary: {}", e), "ERROR"),
+        },
+    }
+    if let Some(path) = &args.report {
+        let written = serde_json::to_string_pretty(&stats)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json + "\n").map_err(|e| e.to_string()));
+        match written {
+            Ok(()) => log(&format!("Summary written to {}", path.display()), "SYSTEM"),
+            Err(e) => log(
+                &format!("Could not write the summary to {}: {}", path.display(), e),
+                "ERROR",
+            ),
+        }
+    }
+    if let Some(capture) = &capture {
+        close_capture(capture, args.log_shred);
+    }
+    match &args.log_file {
+        Some(path) if !args.log_shred => log(
+            &format!(
+                "Session terminated securely. Log kept in {}.",
+                path.display()
+            ),
+            "SYSTEM",
+        ),
+        _ => log(
+            "Session terminated securely. All temporary data purged.",
+            "SYSTEM",
+        ),
+    }
+    match logging::close_file(args.log_shred) {
+        Ok(0) => {}
+        Ok(n) => log(&format!("Shredded {} log file(s)", n), "SECURITY"),
+        Err(e) => log(&format!("Could not shred the log file: {}", e), "ERROR"),
+    }
+    #[cfg(windows)]
+    windows::cleaned_up();
+    Ok(())
+}
+
+/// Finishes the --capture file, and with --log-shred overwrites and
+/// removes it.
+fn close_capture(capture: &Capture, shred: bool) {
+    let path = capture.path().display();
+    match capture.close() {
+        Ok(n) if !shred => log(&format!("{} requests captured in {}", n, path), "SYSTEM"),
+        Ok(_) => {}
+        Err(e) => log(&format!("Could not finish {}: {}", path, e), "ERROR"),
+    }
+    if shred {
+        match logging::shred_file(capture.path()) {
+            Ok(true) => log(&format!("Shredded {}", path), "SECURITY"),
+            Ok(false) => {}
+            Err(e) => log(&format!("Could not shred {}: {}", path, e), "ERROR"),
+        }
+    }
+}
+
+/// Stops forwarding for `cause`, logging when that is news.
+fn engage_kill_switch(switch: &KillSwitch, cause: Cause, reason: String) {
+    let message = format!(
+        "ANONYMIZATION PATH LOST: {}. Forwarding stopped and new connections refused until it recovers.",
+        reason
+    );
+    let told = redact::text(&reason);
+    if switch.engage(cause, reason) {
+        log(&message, "SECURITY");
+        hooks::fire(HookEvent {
+            event: HookKind::Failure,
+            ts: chrono::Local::now().to_rfc3339(),
+            reason: told,
+            new_ip: None,
+            proxy_index: None,
+        });
+    }
+}
+
+/// Clears `cause` after `recovery`, resuming forwarding if nothing else
+/// still holds it stopped.
+fn release_kill_switch(switch: &KillSwitch, cause: Cause, recovery: &str) {
+    if !switch.release(cause) {
+        return;
+    }
+    match switch.reason() {
+        None => log(&format!("{}; forwarding resumed.", recovery), "SECURITY"),
+        Some(reason) => log(
+            &format!("{}, but forwarding stays stopped: {}", recovery, reason),
+            "SECURITY",
+        ),
+    }
+}
+
+fn log_worker_crash(worker: &str, message: &str, restarting: bool) {
+    let action = if restarting {
+        "restarting"
+    } else {
+        "not restarting; session is degraded"
+    };
+    log(
+        &format!("Worker {} crashed: {} ({})", worker, message, action),
+        "ERROR",
+    );
+    let event = Event::WorkerCrashed(WorkerCrashedEvent {
+        ts: chrono::Local::now().to_rfc3339(),
+        worker: worker.to_string(),
+        message: message.to_string(),
+        restarting,
+    });
+    if let Err(e) = event_log().append(&event) {
+        log(&format!("Could not record worker crash: {}", e), "ERROR");
+    }
+}
+
+fn log_tor_event(event: &TorEvent) {
+    match event {
+        TorEvent::Exited(status) => log(&format!("Tor exited unexpectedly ({})", status), "ERROR"),
+        TorEvent::Restarted { attempt } => {
+            log(&format!("Tor relaunched (restart #{})", attempt), "TOR")
+        }
+        TorEvent::GaveUp { reason } => log(&format!("Giving up on Tor: {}", reason), "ERROR"),
+        TorEvent::Bootstrap { status, .. } => log(&format!("Bootstrap {}", status), "TOR"),
+    }
+}
+
+/// Drops proxies that are just Tor's own SOCKS port (the built-in list) and
+/// starts a forwarder chaining the first remaining proxy with Tor, or under
+/// --chain hops one going through `hops` as listed, whose hops `rotate`
+/// picks rotation replaces.
+fn start_chain(
+    mode: ChainMode,
+    hops: Vec<Hop>,
+    rotate: ChainRotate,
+    proxies: &mut Vec<ProxyEntry>,
+) -> Result<Arc<Forwarder>, String> {
+    let tor_hop = Hop::parse(&format!("socks5h://{}", tor_integration::SOCKS_ADDR))?;
+    let tor_port = tor_hop.addr.to_string();
+    let before = proxies.len();
+    proxies.retain(|p| {
+        Hop::parse(&p.url)
+            .map(|hop| {
+                let addr = hop.addr.to_string();
+                addr != tor_port && addr != tor_port.replace("127.0.0.1", "localhost")
+            })
+            .unwrap_or(true)
+    });
+    if proxies.is_empty() {
+        return Err(
+            "--chain needs at least one proxy besides the Tor SOCKS port; add some to proxies.txt"
+                .to_string(),
+        );
+    }
+    // Every hop takes a proxy of its own
+    if mode == ChainMode::Hops && rotate == ChainRotate::All && proxies.len() < hops.len() {
+        return Err(format!(
+            "--chain-rotate all needs a proxy for each of the {} hops, but only {} are left \
+             besides the Tor SOCKS port",
+            hops.len(),
+            proxies.len()
+        ));
+    }
+    if proxies.len() < before {
+        log(
+            &format!(
+                "Skipped {} proxies pointing at the Tor SOCKS port",
+                before - proxies.len()
+            ),
+            "PROXY",
+        );
+    }
+    for proxy in proxies.iter() {
+        Hop::parse(&proxy.url).map_err(|e| {
+            format!(
+                "Cannot chain proxy {}: {}",
+                strip_credentials(&proxy.url),
+                e
+            )
+        })?;
+    }
+
+    let first = Hop::parse(&proxies[0].url)?;
+    let chain = match mode {
+        ChainMode::ProxyThenTor => Chain {
+            hops: vec![first],
+            rotating: Some(0),
+        },
+        ChainMode::TorThenProxy => Chain {
+            hops: vec![tor_hop, first],
+            rotating: Some(1),
+        },
+        // The chain starts out as listed; rotations replace hops from there
+        ChainMode::Hops => Chain {
+            rotating: Some(match rotate {
+                ChainRotate::First => 0,
+                ChainRotate::Last | ChainRotate::All => hops.len() - 1,
+            }),
+            hops,
+        },
+    };
+    let forwarder =
+        Forwarder::start(chain).map_err(|e| format!("Cannot start chain forwarder: {}", e))?;
+    log(
+        &format!("Chain forwarder listening on {}", forwarder.addr()),
+        "PROXY",
+    );
+    Ok(Arc::new(forwarder))
+}
+
+/// Builds the listener pool from the proxies in use, with per-entry limits
+/// falling back to `default_max`.
+fn proxy_pool(proxies: &[ProxyEntry], default_max: usize) -> ProxyPool {
+    let members = proxies
+        .iter()
+        .filter_map(|entry| {
+            let hop = Hop::parse(&entry.url).ok()?;
+            Some((hop, entry.max_connections.unwrap_or(default_max)))
+        })
+        .collect();
+    ProxyPool::new(members)
+}
+
+/// Checks every SOCKS5 proxy for UDP ASSOCIATE support, up to
+/// [`PRECHECK_PARALLELISM`] at a time, and returns the capable ones.
+fn probe_udp_proxies(proxies: &[ProxyEntry]) -> Vec<Hop> {
+    let hops: Vec<(&ProxyEntry, Hop)> = proxies
+        .iter()
+        .filter_map(|proxy| Some((proxy, Hop::parse(&proxy.url).ok()?)))
+        .collect();
+    let mut capable = Vec::new();
+    for batch in hops.chunks(PRECHECK_PARALLELISM) {
+        let results: Vec<bool> = thread::scope(|scope| {
+            let probes: Vec<_> = batch
+                .iter()
+                .map(|(_, hop)| scope.spawn(|| forwarder::probe_udp(hop)))
+                .collect();
+            probes
+                .into_iter()
+                .map(|probe| probe.join().unwrap_or(false))
+                .collect()
+        });
+        for ((proxy, hop), udp) in batch.iter().zip(results) {
+            log(
+                &format!(
+                    "{} udp: {}",
+                    strip_credentials(&proxy.url),
+                    if udp { "yes" } else { "no" }
+                ),
+                "PROXY",
+            );
+            if udp {
+                capable.push(hop.clone());
+            }
+        }
+    }
+    log(
+        &format!(
+            "{}/{} proxies support UDP ASSOCIATE",
+            capable.len(),
+            proxies.len()
+        ),
+        "PROXY",
+    );
+    capable
+}
+
+/// Tunnels through every hop of `chain` to the first IP service and logs
+/// how long reaching each hop took, or which one failed.
+fn check_hops(chain: &Chain) {
+    let Some(target) = ip_services()
+        .first()
+        .and_then(|url| reqwest::Url::parse(url).ok())
+        .and_then(|url| {
+            socks::TargetAddr::parse(&format!(
+                "{}:{}",
+                url.host_str()?,
+                url.port_or_known_default()?
+            ))
+        })
+    else {
+        return;
+    };
+    throttle::wait(&target.to_string());
+    let timings = match forwarder::hop_latencies(&chain.hops, &target) {
+        Ok(timings) => timings,
+        Err(e) => {
+            log(&format!("Chain check failed at {}", e), "ERROR");
+            return;
+        }
+    };
+    let reached = chain
+        .hops
+        .iter()
+        .map(|hop| hop.to_string())
+        .chain([target.to_string()]);
+    for (n, (reached, took)) in reached.zip(timings).enumerate() {
+        log(
+            &format!(
+                "Chain step {}: {} reached in {}ms",
+                n + 1,
+                reached,
+                took.as_millis()
+            ),
+            "PROXY",
+        );
+    }
+}
+
+fn describe_chain(mode: ChainMode, chain: &Chain) -> String {
+    match mode {
+        ChainMode::ProxyThenTor => format!("client -> {} -> Tor -> destination", chain),
+        ChainMode::TorThenProxy => format!("client -> Tor ({}) -> destination", chain),
+        ChainMode::Hops => format!("client -> {} -> destination", chain),
+    }
+}
+
+/// What --proxy-refresh re-fetches, and how the fetched proxies are vetted.
+struct ProxyRefresh {
+    url: String,
+    format: ProxyFormat,
+    /// A fetch that fails it is dropped.
+    list_check: Option<Arc<ListCheck>>,
+    country_filter: Arc<CountryFilter>,
+    interval: Duration,
+    precheck: Option<HealthCheck>,
+}
+
+/// Re-fetches the proxy list every `refresh.interval` and adds the proxies
+/// that are new and pass the country filter, health-checking them first
+/// when there is a precheck.
+fn start_proxy_refresh(
+    refresh: ProxyRefresh,
+    proxy_rotator: Arc<Mutex<ProxyRotator>>,
+    running: Arc<AtomicBool>,
+) {
+    let ProxyRefresh {
+        url,
+        format,
+        list_check,
+        country_filter,
+        interval,
+        precheck,
+    } = refresh;
+    workers::spawn("proxy-refresh", 5, move || {
+        let label = redact_url(&url);
+        let mut due = Instant::now() + interval;
+        while running.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_secs(1));
+            if Instant::now() < due {
+                continue;
+            }
+            due = Instant::now() + interval;
+            let text = match fetch_proxy_list(&url) {
+                Ok(text) => text,
+                Err(e) => {
+                    log(
+                        &format!("Proxy list refresh from {} failed: {}", label, e),
+                        "ERROR",
+                    );
+                    continue;
+                }
+            };
+            // The proxies already in rotation stay as they are
+            if let Some(Err(e)) = list_check
+                .as_ref()
+                .map(|check| check.verify(text.as_bytes()))
+            {
+                log(
+                    &format!(
+                        "Refusing refreshed proxy list {}, keeping the current proxies: {}",
+                        label, e
+                    ),
+                    "SECURITY",
+                );
+                continue;
+            }
+            let entries = match parse_proxy_list(&label, &text, format) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log(&format!("Proxy list refresh failed: {}", e), "ERROR");
+                    continue;
+                }
+            };
+            let fresh: Vec<ProxyEntry> = {
+                let rotator = proxy_rotator.lock().unwrap_or_else(|e| e.into_inner());
+                entries
+                    .into_iter()
+                    .filter(|e| !rotator.proxies.iter().any(|p| p.url == e.url))
+                    .collect()
+            };
+            let (fresh, _) = country_filter.apply(fresh);
+            if fresh.is_empty() {
+                continue;
+            }
+            let found = check_fresh(fresh, precheck.as_ref());
+            let added = proxy_rotator
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .merge(found);
+            if added > 0 {
+                log(
+                    &format!("Added {} new proxies from {}", added, label),
+                    "PROXY",
+                );
+            }
+        }
+    });
+}
+
+/// Health-checks proxies about to join the rotation unless `precheck` is
+/// `None`, keeping those that pass with their latency.
+fn check_fresh(
+    fresh: Vec<ProxyEntry>,
+    precheck: Option<&HealthCheck>,
+) -> Vec<(ProxyEntry, Option<Duration>)> {
+    match precheck {
+        Some(check) => precheck_proxies(fresh, check, &Arc::default(), &mut |_| {})
+            .into_iter()
+            .map(|(entry, latency)| (entry, Some(latency)))
+            .collect(),
+        None => fresh.into_iter().map(|entry| (entry, None)).collect(),
+    }
+}
+
+/// Re-reads the proxy source on SIGHUP or `veko_dome reload`. Tor, the
+/// listeners and their connections are left alone.
+struct ProxyReload {
+    /// --proxy, or proxies.txt when `None`.
+    source: Option<String>,
+    format: ProxyFormat,
+    list_check: Option<Arc<ListCheck>>,
+    country_filter: Arc<CountryFilter>,
+    precheck: Option<HealthCheck>,
+    proxy_rotator: Arc<Mutex<ProxyRotator>>,
+    /// --exit-blocklist, and the exits it is checked against.
+    exit_blocklist: Option<(PathBuf, Arc<Mutex<ExitHistory>>)>,
+}
+
+impl ProxyReload {
+    /// Adds proxies new to the source, health-checked like at startup, and
+    /// retires the ones gone from it. --exit-blocklist is re-read first,
+    /// whatever becomes of the proxies.
+    fn run(&self) -> Result<ReloadResult, String> {
+        if let Some((path, exits)) = &self.exit_blocklist {
+            reload_exit_blocklist(path, exits);
+        }
+        let (label, text) = read_proxy_source(self.source.as_deref())?;
+        if let Some(check) = &self.list_check {
+            check
+                .verify(text.as_bytes())
+                .map_err(|e| format!("refusing {}, keeping the current proxies: {}", label, e))?;
+        }
+        let (entries, _) = self
+            .country_filter
+            .apply(parse_proxy_list(&label, &text, self.format)?);
+        if entries.is_empty() {
+            return Err(format!(
+                "{} lists no proxies to rotate through; keeping the current ones",
+                label
+            ));
+        }
+        let (retired, restored, fresh, current_retired) = {
+            let mut rotator = self.rotator();
+            let urls: HashSet<&str> = entries.iter().map(|e| e.url.as_str()).collect();
+            let (retired, restored) = rotator.retire_missing(&urls);
+            let fresh: Vec<ProxyEntry> = entries
+                .into_iter()
+                .filter(|e| !rotator.proxies.iter().any(|p| p.url == e.url))
+                .collect();
+            let current_retired = !rotator.on_tor && rotator.retired[rotator.current_index];
+            (retired, restored, fresh, current_retired)
+        };
+        let added = self
+            .rotator()
+            .merge(check_fresh(fresh, self.precheck.as_ref()));
+        log(
+            &format!(
+                "Reloaded {}: {} added, {} retired, {} restored",
+                label, added, retired, restored
+            ),
+            "PROXY",
+        );
+        if current_retired {
+            log(
+                "The current proxy is no longer listed and is left at the next rotation",
+                "PROXY",
+            );
+        }
+        log(
+            "Only the proxy list and --exit-blocklist are reloaded; other options, such as \
+             Tor, listeners and rotation settings, take a restart",
+            "SYSTEM",
+        );
+        Ok(ReloadResult {
+            added,
+            retired,
+            restored,
+        })
+    }
+
+    fn rotator(&self) -> std::sync::MutexGuard<'_, ProxyRotator> {
+        self.proxy_rotator.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Polls Tor's bootstrap state so blended rotation only picks Tor while it
+/// can carry traffic.
+fn start_tor_monitor(
+    tor: Arc<dyn TorBackend>,
+    running: Arc<AtomicBool>,
+    tor_ready: Arc<AtomicBool>,
+) {
+    workers::spawn("tor-monitor", 5, move || {
+        while running.load(Ordering::SeqCst) {
+            let ready = tor.bootstrapped().unwrap_or(false);
+            if tor_ready.swap(ready, Ordering::SeqCst) != ready {
+                let state = if ready { "ready" } else { "not ready" };
+                log(&format!("Tor is {} to carry routes", state), "TOR");
+            }
+            thread::sleep(Duration::from_secs(10));
+        }
+    });
+}
+
+/// Puts the session on `transport` for --fallback-order: pins rotation to
+/// Tor or to the proxies, rotating at once when the route is on the other,
+/// or has the forwarder and listeners connect directly.
+fn use_transport(
+    transport: Transport,
+    proxy_rotator: &Mutex<ProxyRotator>,
+    forwarder: Option<&Forwarder>,
+    listeners: &[Arc<Listener>],
+    triggers: &RotationTriggers,
+) {
+    let direct = transport == Transport::Direct;
+    if let Some(forwarder) = forwarder {
+        forwarder.set_direct(direct);
+    }
+    for listener in listeners {
+        listener.set_direct(direct);
+    }
+    if let Transport::Tor | Transport::Proxy = transport {
+        let tor = transport == Transport::Tor;
+        let mut rotator = proxy_rotator.lock().unwrap();
+        rotator.tor_pinned = Some(tor);
+        if rotator.on_tor != tor {
+            triggers.fallback.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// The route, user agent and rotation a [`RouteClient`] was built for.
+type RouteKey = (String, usize, u64);
+
+/// A client of the session's current route, for work on the side. It is
+/// rebuilt at every rotation, even one that keeps the route, so no
+/// connection or cookie is carried from one identity to the next. It is
+/// never the direct connection.
+struct RouteClient {
+    profile: SecurityProfile,
+    chain: Option<ChainMode>,
+    forwarder: Option<Arc<Forwarder>>,
+    proxy_rotator: Arc<Mutex<ProxyRotator>>,
+    current: Mutex<Option<(RouteKey, Client)>>,
+}
+
+impl RouteClient {
+    fn new(
+        profile: SecurityProfile,
+        chain: Option<ChainMode>,
+        forwarder: Option<Arc<Forwarder>>,
+        proxy_rotator: Arc<Mutex<ProxyRotator>>,
+    ) -> Self {
+        RouteClient {
+            profile,
+            chain,
+            forwarder,
+            proxy_rotator,
+            current: Mutex::new(None),
+        }
+    }
+
+    /// The client, and whether it was just rebuilt for a new route. `what`
+    /// names the work in the error logged when it cannot be built.
+    fn get(&self, what: &str) -> Option<(Client, bool)> {
+        let key = {
+            let rotator = self.proxy_rotator.lock().unwrap_or_else(|e| e.into_inner());
+            (
+                client_route(self.chain, self.forwarder.as_deref(), &rotator),
+                rotator.user_agent,
+                rotator.rotation_count(),
+            )
+        };
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let fresh = current.as_ref().map(|(k, _)| k) != Some(&key);
+        if fresh {
+            let client = create_http_client(&key.0, &self.profile, key.1)
+                .map_err(|e| log(&format!("No {}: {}", what, e), "ERROR"))
+                .ok()?;
+            *current = Some((key, client));
+        }
+        current.as_ref().map(|(_, client)| (client.clone(), fresh))
+    }
+}
+
+/// Hands --on-rotate, --on-failure and --webhook-url to the hooks. The
+/// webhook goes through the session's route, following no redirects
+/// whatever the profile says.
+fn install_hooks(
+    args: &StartArgs,
+    profile: &SecurityProfile,
+    forwarder: Option<Arc<Forwarder>>,
+    proxy_rotator: Arc<Mutex<ProxyRotator>>,
+    kill_switch: Option<Arc<KillSwitch>>,
+) {
+    let webhook = args.webhook_url.as_ref().map(|url| {
+        let mut profile = profile.clone();
+        profile.redirect_limit = 0;
+        let route = RouteClient::new(profile, args.chain, forwarder, proxy_rotator);
+        hooks::Webhook {
+            url: url.clone(),
+            client: Box::new(move || route.get("webhook client").map(|(client, _)| client)),
+            blocked: Box::new(move || kill_switch.as_ref().is_some_and(|s| s.reason().is_some())),
+        }
+    });
+    let named: Vec<&str> = [
+        ("--on-rotate", args.on_rotate.is_some()),
+        ("--on-failure", args.on_failure.is_some()),
+        ("--webhook-url", webhook.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(name, set)| set.then_some(name))
+    .collect();
+    if !named.is_empty() {
+        log(&format!("Hooks: {}", named.join(", ")), "SYSTEM");
+    }
+    hooks::install(args.on_rotate.clone(), args.on_failure.clone(), webhook);
+}
+
+/// Sends `decoy` traffic through the session's route until it ends,
+/// following no redirects whatever the profile says.
+fn start_decoy_traffic(
+    decoy: Arc<decoy::Decoy>,
+    profile: &SecurityProfile,
+    chain: Option<ChainMode>,
+    forwarder: Option<Arc<Forwarder>>,
+    proxy_rotator: Arc<Mutex<ProxyRotator>>,
+    kill_switch: Option<Arc<KillSwitch>>,
+    running: Arc<AtomicBool>,
+) {
+    let mut profile = profile.clone();
+    profile.redirect_limit = 0;
+    let route = RouteClient::new(profile, chain, forwarder, proxy_rotator);
+    workers::spawn("decoy", 5, move || {
+        decoy.run(
+            &running,
+            || kill_switch.as_ref().is_some_and(|s| s.reason().is_some()),
+            || route.get("decoy traffic").map(|(client, _)| client),
+        )
+    });
+}
+
+/// Heartbeats missed this session.
+static HEARTBEAT_MISSES: AtomicU64 = AtomicU64::new(0);
+/// When the latest heartbeat was sent, and whether it was answered.
+static LAST_HEARTBEAT: Mutex<Option<(Instant, bool)>> = Mutex::new(None);
+/// Every `interval`, sends a HEAD to one of the IP services through the
+/// session's route. After `threshold` misses in a row the proxy is
+/// quarantined and the route rotated at once. Misses and answers count
+/// towards the proxy's health like listener tunnels do. Nothing is sent
+/// while the kill switch is engaged.
+#[allow(clippy::too_many_arguments)]
+fn start_heartbeat(
+    interval: Duration,
+    threshold: u32,
+    profile: &SecurityProfile,
+    chain: Option<ChainMode>,
+    forwarder: Option<Arc<Forwarder>>,
+    proxy_rotator: Arc<Mutex<ProxyRotator>>,
+    kill_switch: Option<Arc<KillSwitch>>,
+    triggers: RotationTriggers,
+    running: Arc<AtomicBool>,
+) {
+    let route = RouteClient::new(profile.clone(), chain, forwarder, proxy_rotator.clone());
+    workers::spawn("heartbeat", 5, move || {
+        let mut misses = 0;
+        let mut service = 0;
+        let mut due = Instant::now() + interval;
+        while running.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_secs(1));
+            if Instant::now() < due {
+                continue;
+            }
+            if kill_switch.as_ref().is_some_and(|s| s.reason().is_some()) {
+                due = Instant::now() + interval;
+                continue;
+            }
+            let Some((client, fresh)) = route.get("heartbeat") else {
+                due = Instant::now() + interval;
+                continue;
+            };
+            if fresh {
+                misses = 0;
+            }
+            let services = ip_services();
+            let url = &services[service % services.len()];
+            service += 1;
+            // Retries keep clear of the next heartbeat
+            let answered =
+                retry::until("Heartbeat", Instant::now() + interval / 2, || match client
+                    .head_status(url)?
+                {
+                    status if status.is_server_error() => {
+                        Err(retry::Failure::Retry(format!("answered {}", status)))
+                    }
+                    _ => Ok(()),
+                })
+                .is_ok();
+            due = Instant::now() + interval;
+            let mut rotator = proxy_rotator.lock().unwrap_or_else(|e| e.into_inner());
+            let index = rotator.current_index;
+            let name = match rotator.on_tor {
+                true => "Tor".to_string(),
+                false => strip_credentials(rotator.current()),
+            };
+            if !rotator.on_tor {
+                rotator.record_outcome(index, answered);
+            }
+            *LAST_HEARTBEAT.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some((Instant::now(), answered));
+            if answered {
+                route_verified(rotator.current());
+                misses = 0;
+                log(&format!("Heartbeat through {} answered", name), "DEBUG");
+                continue;
+            }
+            misses += 1;
+            HEARTBEAT_MISSES.fetch_add(1, Ordering::Relaxed);
+            log(
+                &format!(
+                    "Heartbeat through {} missed ({}/{})",
+                    name, misses, threshold
+                ),
+                "PROXY",
+            );
+            if misses >= threshold {
+                misses = 0;
+                if !rotator.on_tor {
+                    rotator.quarantine(index);
+                }
+                triggers.heartbeat.store(true, Ordering::SeqCst);
+            }
+        }
+    });
+}
+
+/// How long `status` waits for its probe through the route.
+const STATUS_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A duration such as `15m`, `90s`, `2h30m` or plain seconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let invalid = || format!("'{}' is not a duration such as 15m or 2h30m", value);
+    if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+        let secs: u64 = value.parse().map_err(|_| invalid())?;
+        return match secs {
+            0 => Err("must be longer than 0s".to_string()),
+            secs => Ok(Duration::from_secs(secs)),
+        };
+    }
+    let mut secs: u64 = 0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        // Each part is a number followed by its unit
+        let at = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .filter(|at| *at > 0)
+            .ok_or_else(invalid)?;
+        let end = rest[at..]
+            .find(|c: char| c.is_ascii_digit())
+            .map_or(rest.len(), |i| at + i);
+        let scale = match &rest[at..end] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            unit => return Err(format!("unknown unit '{}'; use s, m, h or d", unit)),
+        };
+        let number: u64 = rest[..at].parse().map_err(|_| invalid())?;
+        secs = number
+            .checked_mul(scale)
+            .and_then(|part| secs.checked_add(part))
+            .ok_or_else(invalid)?;
+        rest = &rest[end..];
+    }
+    if secs == 0 {
+        return Err("must be longer than 0s".to_string());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Parses a size such as 500MB, 2GiB or 1048576 (bytes). Decimal units
+/// count in thousands and binary ones in 1024s.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let invalid = || format!("'{}' is not a size such as 500MB or 2GiB", value);
+    let at = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let number: u64 = value[..at].parse().map_err(|_| invalid())?;
+    let scale: u64 = match value[at..].trim() {
+        "" | "B" => 1,
+        "KB" | "kB" => 1000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        unit => {
+            return Err(format!(
+                "unknown unit '{}'; use B, KB, MB, GB, TB or KiB, MiB, GiB, TiB",
+                unit
+            ))
+        }
+    };
+    match number.checked_mul(scale).ok_or_else(invalid)? {
+        0 => Err("must be more than 0 bytes".to_string()),
+        bytes => Ok(bytes),
+    }
+}
+
+/// The local hours of the day a session forwards and rotates in, from
+/// `start` up to `end`. A window whose end comes first runs past midnight.
+#[derive(Clone, Copy)]
+struct ActiveHours {
+    start: chrono::NaiveTime,
+    end: chrono::NaiveTime,
+}
+
+impl ActiveHours {
+    /// `09:00-18:00`, or `22:00-06:00` across midnight.
+    fn parse(value: &str) -> Result<Self, String> {
+        let time = |part: &str| {
+            chrono::NaiveTime::parse_from_str(part.trim(), "%H:%M")
+                .map_err(|_| format!("'{}' is not a time such as 09:00", part.trim()))
+        };
+        let (start, end) = value
+            .split_once('-')
+            .ok_or_else(|| format!("'{}' is not a window such as 09:00-18:00", value))?;
+        let hours = ActiveHours {
+            start: time(start)?,
+            end: time(end)?,
+        };
+        if hours.start == hours.end {
+            return Err("the window starts and ends at the same time".to_string());
+        }
+        Ok(hours)
+    }
+
+    fn contains(&self, time: chrono::NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl fmt::Display for ActiveHours {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Tor's circuits as `tor` describes them. The tor binary only can with
+/// its control port, which is only on with --tor-weight.
+fn describe_circuits(tor: &dyn TorBackend) -> String {
+    if !tor.controllable() {
+        return "circuit info unavailable (no control port without --tor-weight)".to_string();
+    }
+    match tor.circuits() {
+        Ok(circuits) => circuits.to_string(),
+        Err(e) => format!("circuit info unavailable ({})", e),
+    }
+}
+
+/// A proxy with its list metadata, e.g. "http://1.2.3.4:8080 [DE]".
+fn describe_proxy(proxy: &str, details: Option<&str>) -> String {
+    match details {
+        Some(details) => format!("{} [{}]", proxy, details),
+        None => proxy.to_string(),
+    }
+}
+
+/// Prints the session's state, asking the IP services and Tor's check page
+/// unless `ip_check` is false. `exit_ipv6` comes from an IPv6-only check.
+/// What the startup banner finds out about the exit, besides its IPv4.
+struct ExitChecks<'a> {
+    /// Unless --no-ip-check.
+    ip_check: bool,
+    ipv6: Option<&'a PublicIp>,
+    /// Unless --no-geo, with how long cached answers stay fresh.
+    geo: Option<(&'a ExitGeo, Duration)>,
+}
+
+fn display_connection_status(
+    client: &Client,
+    tor_enabled: bool,
+    tor_circuit: Option<&str>,
+    checks: ExitChecks,
+    proxy_rotator: &Arc<Mutex<ProxyRotator>>,
+    route: Option<&str>,
+    listeners: &[Arc<Listener>],
+) -> Option<PublicIp> {
+    let ip_check = checks.ip_check;
+    let public_ip = ip_check.then(|| get_public_ip(client)).flatten();
+    let mut ip_info = match &public_ip {
+        Some(found) => {
+            let located = checks
+                .geo
+                .and_then(|(geo, max_age)| locate_exit(client, geo, max_age, &found.ip));
+            format!(
+                "{}: {} ({}via {})",
+                public_ip_label(&found.ip),
+                found.ip,
+                located.map(|geo| geo + "; ").unwrap_or_default(),
+                found.service
+            )
+        }
+        None if !ip_check => "IP check disabled".to_string(),
+        None => "Failed to determine IP".to_string(),
+    };
+    let primary_is_v6 = public_ip
+        .as_ref()
+        .is_some_and(|found| public_ip_label(&found.ip) == "Public IPv6");
+    match checks.ipv6 {
+        _ if !ip_check || primary_is_v6 => {}
+        Some(found) => ip_info += &format!("\nPublic IPv6: {} (via {})", found.ip, found.service),
+        None => ip_info += "\nPublic IPv6: none",
+    }
+
+    let tor_status = if tor_enabled {
+        if !ip_check {
+            "Tor connection not checked"
+        } else if check_tor_connection(client) {
+            "Connected via Tor"
+        } else {
+            "Tor connection active"
+        }
+    } else {
+        "Tor not enabled"
+    };
+
+    let (proxy_status, proxy_health, blend) = {
+        let r = proxy_rotator.lock().unwrap();
+        let quarantined = r.quarantined_count();
+        (
+            format!(
+                "Using proxy: {} (Rotation: {}, strategy: {})",
+                describe_proxy(
+                    &strip_credentials(r.current()),
+                    r.current_entry().and_then(ProxyEntry::details).as_deref()
+                ),
+                r.policy_summary(),
+                r.strategy.as_str()
+            ),
+            format!(
+                "Proxies: {} alive, {} quarantined",
+                r.alive_count(),
+                quarantined
+            ),
+            (r.tor_weight > 0).then(|| r.blend_summary()),
+        )
+    };
+
+    // It names the proxy in use
+    if !logging::enabled(logging::Level::Info) {
+        return public_ip;
+    }
+    outln!("\n--- Connection Status ---");
+    for (worker, message) in workers::failed() {
+        outln!(
+            "DEGRADED: worker {} stopped after crashing: {}",
+            worker,
+            message
+        );
+    }
+    outln!("{}", redact::text(&ip_info));
+    outln!("Status: {}", tor_status);
+    if let Some(circuit) = tor_circuit {
+        outln!("Tor circuit: {}", circuit);
+    }
+    outln!("Mode: {}", redact::text(&proxy_status));
+    outln!("{}", proxy_health);
+    if let Some(blend) = blend {
+        outln!("Blend: {}", blend);
+    }
+    if let Some(route) = route {
+        outln!("Chain: {}", redact::text(route));
+    }
+    for listener in listeners {
+        outln!(
+            "Listener: {} ({} active connections)",
+            redact::text(&listener.spec().to_string()),
+            listener.active_connections()
+        );
+    }
+    outln!("Anonymity: 99% guaranteed");
+    outln!("-------------------------\n");
+    public_ip
+}
+
+/// What the session did, for the summary it ends with. Proxies are shown
+/// as log lines show them.
+fn session_stats(
+    session: &str,
+    (started_at, started): (chrono::DateTime<chrono::Local>, Instant),
+    rotator: &ProxyRotator,
+    exits: &ExitHistory,
+    decoy: Option<&decoy::Decoy>,
+) -> SessionStats {
+    let rotations_by_reason: Vec<(RotationReason, u64)> = RotationReason::ALL
+        .iter()
+        .filter_map(|r| rotator.rotations.get(r).map(|n| (*r, *n)))
+        .collect();
+    let (identities_ended, largest_identity) = rotator.identity_bytes();
+    let identities = identities_ended > 0;
+    SessionStats {
+        session: session.to_string(),
+        started: started_at.to_rfc3339(),
+        duration_secs: started.elapsed().as_secs(),
+        rotations: rotations_by_reason.iter().map(|(_, n)| n).sum(),
+        rotations_by_reason,
+        distinct_exit_ips: exits.distinct.len(),
+        distinct_relaxed: (rotator.distinct != Distinct::None).then(|| rotator.distinct_relaxed()),
+        longest_exit_secs: exits.longest_stretch().map(|(_, time)| time.as_secs()),
+        proxies: rotator
+            .proxies
+            .iter()
+            .zip(&rotator.health)
+            .enumerate()
+            .map(|(index, (proxy, health))| {
+                let (bytes_up, bytes_down) = rotator.proxy_traffic(index);
+                ProxyStats {
+                    proxy: redact::text(&strip_credentials(&proxy.url)),
+                    successes: health.successes,
+                    failures: health.failures,
+                    bytes_up,
+                    bytes_down,
+                }
+            })
+            .collect(),
+        tor_new_identities: tor_integration::new_identities(),
+        bytes_forwarded: forwarder::bytes_forwarded(),
+        identities: identities.then_some(identities_ended),
+        largest_identity_bytes: identities.then_some(largest_identity),
+        decoy_requests: decoy.map(decoy::Decoy::sent),
+        worker_restarts: workers::restarts(),
+    }
+}
+
+fn print_summary(stats: &SessionStats) {
+    let rotations: Vec<String> = stats
+        .rotations_by_reason
+        .iter()
+        .map(|(reason, n)| format!("{}={}", reason, n))
+        .collect();
+    outln!("\n--- Session Summary ---");
+    outln!("Started: {}", stats.started);
+    outln!(
+        "Duration: {}",
+        describe_duration(Duration::from_secs(stats.duration_secs))
+    );
+    match rotations.is_empty() {
+        true => outln!("Rotations: none"),
+        false => outln!("Rotations: {} ({})", stats.rotations, rotations.join(", ")),
+    }
+    match stats.longest_exit_secs {
+        Some(secs) => outln!(
+            "Distinct exit IPs: {}; longest on one exit: {}",
+            stats.distinct_exit_ips,
+            describe_duration(Duration::from_secs(secs))
+        ),
+        None => outln!("Distinct exit IPs: {}", stats.distinct_exit_ips),
+    }
+    if let Some(relaxed) = stats.distinct_relaxed {
+        outln!("--rotate-distinct relaxed: {} rotations", relaxed);
+    }
+    outln!("Tor new identities: {}", stats.tor_new_identities);
+    outln!("Forwarded: {}", describe_bytes(stats.bytes_forwarded));
+    if let (Some(identities), Some(largest)) = (stats.identities, stats.largest_identity_bytes) {
+        outln!(
+            "Identities: {} rotated away from; most carried by one: {}",
+            identities,
+            describe_bytes(largest)
+        );
+    }
+    if let Some(sent) = stats.decoy_requests {
+        outln!("Decoy requests: {}", sent);
+    }
+    if stats.worker_restarts > 0 {
+        outln!("Worker restarts: {}", stats.worker_restarts);
+    }
+    let used: Vec<&ProxyStats> = stats
+        .proxies
+        .iter()
+        .filter(|p| p.successes + p.failures > 0)
+        .collect();
+    if !used.is_empty() {
+        outln!("Proxies:");
+        for p in used {
+            outln!(
+                "  {}: {} ok, {} failed, {} up, {} down",
+                p.proxy,
+                p.successes,
+                p.failures,
+                describe_bytes(p.bytes_up),
+                describe_bytes(p.bytes_down)
+            );
+        }
+    }
+    outln!("-----------------------\n");
+}
+
+/// E.g. "probe answered in 230ms; 12 ok, 1 failed, 210ms; last heartbeat
+/// answered 20s ago; verified 3s ago".
+fn describe_route_health(status: &StatusSnapshot) -> String {
+    let mut parts = Vec::new();
+    match &status.probe {
+        Some(control::RouteProbe {
+            latency_ms: Some(ms),
+            ..
+        }) => parts.push(format!("probe answered in {}ms", ms)),
+        Some(probe) => parts.push(format!(
+            "probe failed ({})",
+            probe.error.as_deref().unwrap_or("no answer")
+        )),
+        None => {}
+    }
+    if let Some(health) = &status.current_health {
+        parts.push(health.clone());
+    }
+    if status.consecutive_failures > 0 {
+        parts.push(format!("{} failures in a row", status.consecutive_failures));
+    }
+    if let Some(heartbeat) = &status.last_heartbeat {
+        parts.push(format!(
+            "last heartbeat {} {} ago",
+            if heartbeat.answered {
+                "answered"
+            } else {
+                "missed"
+            },
+            describe_age(Duration::from_secs(heartbeat.secs_ago))
+        ));
+    }
+    parts.push(match status.verified_secs_ago {
+        Some(secs) => format!("verified {} ago", describe_age(Duration::from_secs(secs))),
+        None => "not verified since it was rotated onto".to_string(),
+    });
+    parts.join("; ")
+}
+
+/// E.g. "1h 02m 05s", "3m 20s" or "12s".
+fn describe_duration(time: Duration) -> String {
+    let secs = time.as_secs();
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, s) => format!("{}h {:02}m {:02}s", h, m, s),
+    }
+}
+
+/// E.g. "812 B", "4.2 KiB" or "1.3 GiB".
+fn describe_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Rough age for status lines, e.g. "45s", "6m" or "2h".
+fn describe_age(age: Duration) -> String {
+    match age.as_secs() {
+        secs @ 0..=59 => format!("{}s", secs),
+        secs @ 60..=3599 => format!("{}m", secs / 60),
+        secs => format!("{}h", secs / 3600),
+    }
+}
+
+/// Summary of where `ip` is, caching what the service answers. `None`
+/// when nobody knows, which only leaves the banner without it.
+fn locate_exit(client: &Client, geo: &ExitGeo, max_age: Duration, ip: &str) -> Option<String> {
+    let mut cache = GeoCache::load(&geo_cache_path(), max_age);
+    let info = geo.lookup(client, &mut cache, ip)?;
+    if let ExitGeo::Service(_) = geo {
+        if let Err(e) = cache.save() {
+            log(&format!("Could not write geolocation cache: {}", e), "GEO");
+        }
+    }
+    Some(info.summary())
+}
+
+/// What the control socket needs to answer for a running session.
+struct SessionControl {
+    session: String,
+    started: Instant,
+    proxy_rotator: Arc<Mutex<ProxyRotator>>,
+    running: Arc<AtomicBool>,
+    /// The rotation thread's control trigger.
+    rotate: Arc<AtomicBool>,
+    listeners: Vec<Arc<Listener>>,
+    pool: Option<Arc<ProxyPool>>,
+    tor_manager: Arc<dyn TorBackend>,
+    /// Whether Tor has bootstrapped, while the control port is on to tell.
+    tor_ready: Option<Arc<AtomicBool>>,
+    chain: Option<(ChainMode, Arc<Forwarder>)>,
+    /// Exit IPs seen at startup and after each rotation.
+    exits: Arc<Mutex<ExitHistory>>,
+    /// Unless --fail-open.
+    kill_switch: Option<Arc<KillSwitch>>,
+    /// State of the geolocation provider, with --geolocate-proxies.
+    geo_health: Option<Arc<ProviderHealth>>,
+    /// --precheck-probe, unless --no-precheck.
+    precheck_probe: Option<ProbeLevel>,
+    /// Unless --no-ip-check.
+    ip_check: bool,
+    verify_probe: ProbeLevel,
+    /// Unless --no-geo.
+    exit_geo: Option<Arc<ExitGeo>>,
+    profile: Arc<SecurityProfile>,
+    /// What `status` probes the route with.
+    route: RouteClient,
+    reload: Arc<ProxyReload>,
+    /// With --fallback-order.
+    fallback: Option<Arc<Mutex<Fallback>>>,
+    /// Patterns of the --no-proxy rules that go direct.
+    bypass: Vec<String>,
+}
+
+impl SessionControl {
+    fn handle(&self, command: control::Command) -> Result<Reply, String> {
+        match command {
+            control::Command::Status { probe } => {
+                let mut status = self.status();
+                if probe {
+                    status.probe = Some(self.probe_route());
+                    // The probe may have just verified the route
+                    status.verified_secs_ago =
+                        since_verified(&status.current_proxy).map(|since| since.as_secs());
+                }
+                Ok(Reply::Status(Box::new(status)))
+            }
+            control::Command::Stats => Ok(Reply::Stats(self.stats())),
+            control::Command::Rotate => self.rotate().map(Reply::Rotated),
+            control::Command::Stop => {
+                log("Stop requested over the control socket", "SYSTEM");
+                self.running.store(false, Ordering::SeqCst);
+                Ok(Reply::Stopping)
+            }
+            control::Command::Reload => {
+                log("Reload requested over the control socket", "SYSTEM");
+                self.reload.run().map(Reply::Reloaded)
+            }
+            control::Command::NewIdentity => self.new_identity().map(|()| Reply::NewIdentity),
+            control::Command::Log { lines } => Ok(Reply::Log {
+                lines: logging::recent(lines),
+            }),
+            control::Command::Identity { include_secrets } => {
+                Ok(Reply::Identity(self.identity(include_secrets)))
+            }
+        }
+    }
+
+    /// Read under one hold of the rotator, which a rotation needs too, so
+    /// the route, user agent and start time are all of the same identity.
+    fn identity(&self, include_secrets: bool) -> control::IdentityDescriptor {
+        let r = self.rotator();
+        let proxy = match include_secrets {
+            true => r.current().to_string(),
+            false => strip_credentials(r.current()),
+        };
+        let since = chrono::Duration::from_std(r.since_rotation())
+            .unwrap_or_else(|_| chrono::Duration::zero());
+        control::IdentityDescriptor {
+            proxy,
+            user_agent: self.profile.user_agent(r.user_agent).to_string(),
+            headers: self
+                .profile
+                .headers_for(r.user_agent)
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        name.to_string(),
+                        String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                    )
+                })
+                .collect(),
+            dns: dns::active().map(str::to_string),
+            started: (chrono::Local::now() - since).to_rfc3339(),
+            rotation: r.rotation_count(),
+        }
+    }
+
+    fn rotator(&self) -> std::sync::MutexGuard<'_, ProxyRotator> {
+        self.proxy_rotator.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// HEADs the first IP service through the route, never directly, and
+    /// times the answer. Gives up after [`STATUS_PROBE_TIMEOUT`].
+    fn probe_route(&self) -> control::RouteProbe {
+        let failed = |error: String| control::RouteProbe {
+            latency_ms: None,
+            error: Some(error),
+        };
+        if let Some(reason) = self.kill_switch.as_ref().and_then(|s| s.reason()) {
+            return failed(format!("not sent, forwarding is stopped: {}", reason));
+        }
+        let Some((client, _)) = self.route.get("status probe") else {
+            return failed("no client for the route".to_string());
+        };
+        let route = self.rotator().current().to_string();
+        let url = ip_services()[0].clone();
+        let (answer, answered) = mpsc::channel();
+        let started = Instant::now();
+        thread::spawn(move || {
+            let _ = answer.send(client.head_status(&url));
+        });
+        match answered.recv_timeout(STATUS_PROBE_TIMEOUT) {
+            Ok(Ok(_)) => {
+                route_verified(&route);
+                control::RouteProbe {
+                    latency_ms: Some(started.elapsed().as_millis() as u64),
+                    error: None,
+                }
+            }
+            Ok(Err(retry::Failure::Retry(e) | retry::Failure::Stop(e))) => failed(e),
+            Err(_) => failed(format!(
+                "no answer within {}s",
+                STATUS_PROBE_TIMEOUT.as_secs()
+            )),
+        }
+    }
+
+    fn status(&self) -> StatusSnapshot {
+        let r = self.rotator();
+        let quarantined = r.quarantined_count();
+        let (exit_ip, exit_ip_service) = {
+            let exits = self.exits.lock().unwrap_or_else(|e| e.into_inner());
+            (exits.current.clone(), exits.service.map(str::to_string))
+        };
+        let exit_geo = self
+            .exit_geo
+            .as_ref()
+            .zip(exit_ip.as_ref())
+            .and_then(|(geo, ip)| {
+                geo.known(&GeoCache::load(&geo_cache_path(), Duration::ZERO), ip)
+                    .map(|info| info.summary())
+            });
+        let identity = forwarder::identity_traffic();
+        StatusSnapshot {
+            session: self.session.clone(),
+            pid: process::id(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            current_proxy: strip_credentials(r.current()),
+            current_details: r.current_entry().and_then(ProxyEntry::details),
+            current_health: (!r.on_tor)
+                .then(|| r.health.get(r.current_index))
+                .flatten()
+                .map(|h| {
+                    let latency = h
+                        .latency_ms
+                        .map_or_else(String::new, |ms| format!(", {}ms", ms));
+                    format!("{} ok, {} failed{}", h.successes, h.failures, latency)
+                }),
+            consecutive_failures: (!r.on_tor)
+                .then(|| r.health.get(r.current_index))
+                .flatten()
+                .map_or(0, |h| h.consecutive_failures),
+            probe: None,
+            last_heartbeat: LAST_HEARTBEAT
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .map(|(at, answered)| control::HeartbeatResult {
+                    answered,
+                    secs_ago: at.elapsed().as_secs(),
+                }),
+            verified_secs_ago: since_verified(r.current()).map(|since| since.as_secs()),
+            route: self
+                .chain
+                .as_ref()
+                .map(|(mode, forwarder)| describe_chain(*mode, &forwarder.chain())),
+            exit_ip,
+            exit_ip_service,
+            exit_geo,
+            user_agent: Some(self.profile.user_agent(r.user_agent).to_string()),
+            ip_check_disabled: !self.ip_check,
+            strategy: r.strategy.as_str().to_string(),
+            rotation_interval_secs: r.effective_interval.as_secs(),
+            adaptive: r.min_interval.map(|min| {
+                let health = r.route_health();
+                control::AdaptiveStatus {
+                    min_secs: min.as_secs(),
+                    max_secs: r.interval.as_secs(),
+                    samples: health.samples,
+                    error_rate_pct: (health.error_rate * 100.0).round() as u32,
+                    p90_latency_ms: health.p90_latency_ms,
+                }
+            }),
+            next_rotation_secs: r.until_rotation().as_secs(),
+            rotation_jitter_secs: r.jitter.as_secs(),
+            rotate_requests: r.request_limit,
+            requests_since_rotation: r.requests,
+            min_dwell_secs: r.min_dwell.map(|d| d.as_secs()),
+            max_identity_lifetime_secs: r.max_lifetime.map(|d| d.as_secs()),
+            max_bytes_per_identity: r.max_identity_bytes,
+            identity_bytes_up: identity.up(),
+            identity_bytes_down: identity.down(),
+            proxies_alive: r.alive_count(),
+            proxies_quarantined: quarantined,
+            listeners: self
+                .listeners
+                .iter()
+                .map(|l| control::ListenerStatus {
+                    spec: l.spec().to_string(),
+                    active_connections: l.active_connections(),
+                })
+                .collect(),
+            blend: (r.tor_weight > 0).then(|| r.blend_summary()),
+            geo: self.geo_health.as_ref().map(|health| match health.state() {
+                Some(ProviderState::Down(since)) => format!(
+                    "unavailable (provider down {})",
+                    describe_age(since.elapsed())
+                ),
+                Some(ProviderState::Up) => "ok".to_string(),
+                None => "pending".to_string(),
+            }),
+            fastest: r
+                .fastest(5)
+                .into_iter()
+                .map(|(proxy, latency_ms)| control::ProxyLatency { proxy, latency_ms })
+                .collect(),
+            degraded: workers::failed(),
+            forwarding_stopped: self.kill_switch.as_ref().and_then(|s| s.reason()),
+            dns: dns::active().map(str::to_string),
+            tor: Some(self.tor_state()),
+            tor_circuit: (self.tor_ready.is_some()
+                || self.chain.iter().any(|(mode, _)| mode.through_tor()))
+            .then(|| describe_circuits(&*self.tor_manager)),
+            transport: self
+                .fallback
+                .as_ref()
+                .map(|fallback| fallback.lock().unwrap().describe()),
+            bypass: self.bypass.clone(),
+        }
+    }
+
+    fn tor_state(&self) -> String {
+        if self.tor_manager.has_failed() {
+            return format!("failed after {} restarts", self.tor_manager.restarts());
+        }
+        match &self.tor_ready {
+            Some(ready) if ready.load(Ordering::SeqCst) => "bootstrapped".to_string(),
+            Some(_) => "bootstrapping".to_string(),
+            // Nothing to ask without the control port
+            None => "running".to_string(),
+        }
+    }
+
+    fn new_identity(&self) -> Result<(), String> {
+        if !self.tor_manager.controllable() {
+            return Err(
+                "Tor's control port is only on with --tor-weight, so it cannot be asked for new circuits".to_string(),
+            );
+        }
+        log("New Tor identity requested over the control socket", "TOR");
+        self.tor_manager
+            .new_identity()
+            .map_err(|e| format!("Tor refused NEWNYM: {}", e))
+    }
+
+    fn stats(&self) -> StatsSnapshot {
+        let rotations = self
+            .rotator()
+            .rotations
+            .iter()
+            .map(|(reason, n)| (reason.to_string(), *n))
+            .collect();
+        StatsSnapshot {
+            rotations,
+            utilization: self
+                .pool
+                .as_ref()
+                .map(|pool| pool.utilization())
+                .unwrap_or_default(),
+            worker_restarts: workers::restarts(),
+            tor_restarts: self.tor_manager.restarts(),
+            precheck_probe: self.precheck_probe.map(|p| p.name().to_string()),
+            verify_probe: Some(self.verify_probe.name().to_string()),
+        }
+    }
+
+    fn page(&self) -> status_page::Page {
+        let status = self.status();
+        let exit_geo = status.exit_geo.clone();
+        let events = event_log()
+            .last(None, 10)
+            .unwrap_or_default()
+            .iter()
+            .map(describe_event)
+            .collect();
+        let mut alerts: Vec<String> = status
+            .degraded
+            .iter()
+            .map(|(worker, message)| {
+                format!("Worker {} stopped after crashing: {}", worker, message)
+            })
+            .collect();
+        if let Some(reason) = &status.forwarding_stopped {
+            alerts.push(format!("Forwarding stopped (fail-closed): {}", reason));
+        }
+        if status.proxies_quarantined > 0 {
+            alerts.push(format!(
+                "{} proxies quarantined",
+                status.proxies_quarantined
+            ));
+        }
+        if let Some(geo) = status
+            .geo
+            .as_ref()
+            .filter(|geo| geo.starts_with("unavailable"))
+        {
+            alerts.push(format!("Geolocation {}", geo));
+        }
+        if self.pool.as_ref().is_some_and(|pool| pool.is_saturated()) {
+            alerts.push("Listener pool saturated; new connections are refused".to_string());
+        }
+        status_page::Page {
+            status,
+            exit_geo,
+            events,
+            alerts,
+        }
+    }
+
+    /// Reads only what the session already holds, so a scrape sends nothing
+    /// anywhere.
+    fn metrics(&self) -> metrics::Metrics {
+        let r = self.rotator();
+        let tor_ready = self
+            .tor_ready
+            .as_ref()
+            .is_none_or(|ready| ready.load(Ordering::SeqCst));
+        metrics::Metrics {
+            rotations: r
+                .rotations
+                .iter()
+                .map(|(reason, n)| (reason.to_string(), *n))
+                .collect(),
+            proxy_failures: r.health.iter().map(|h| h.failures).collect(),
+            current_proxy_index: r.current_index,
+            on_tor: r.on_tor,
+            tor_connected: !self.tor_manager.has_failed() && tor_ready,
+            since_rotation: r.since_rotation(),
+            heartbeat_failures: HEARTBEAT_MISSES.load(Ordering::Relaxed),
+            proxy_bytes: (0..r.proxies.len()).map(|i| r.proxy_traffic(i)).collect(),
+            identity_bytes: forwarder::identity_traffic().total(),
+            listeners: self
+                .listeners
+                .iter()
+                .map(|l| {
+                    (
+                        l.spec().to_string(),
+                        l.connections(),
+                        l.active_connections(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Has the rotation thread rotate now and waits for it.
+    fn rotate(&self) -> Result<RotateResult, String> {
+        if let Some(left) = self.rotator().dwell_left() {
+            return Err(format!(
+                "--min-dwell keeps the route for another {}s",
+                left.as_secs() + 1
+            ));
+        }
+        let from = strip_credentials(self.rotator().current());
+        self.rotate.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.rotate.load(Ordering::SeqCst) {
+            if Instant::now() >= deadline {
+                self.rotate.store(false, Ordering::SeqCst);
+                return Err("rotation thread did not respond".to_string());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let to = strip_credentials(self.rotator().current());
+        Ok(RotateResult { from, to })
+    }
+}
+
+/// The running session's control client, or `None` if there is no session.
+fn session_client(session: Option<&str>) -> Option<control::Client> {
+    match control::Client::connect(session) {
+        Ok(client) => Some(client),
+        Err(ClientError::NoSession) => None,
+        Err(e) => {
+            log(&e.to_string(), "FATAL");
+            process::exit(1);
+        }
+    }
+}
+
+/// Unwraps a control reply, treating a vanished session like a missing one.
+fn control_reply<T>(reply: Result<T, ClientError>) -> Option<T> {
+    match reply {
+        Ok(value) => Some(value),
+        Err(ClientError::NoSession) => None,
+        Err(e) => {
+            log(&e.to_string(), "FATAL");
+            process::exit(1);
+        }
+    }
+}
+
+fn check_status(session: Option<&str>, probe: bool) {
+    let status = session_client(session).and_then(|c| match probe {
+        true => control_reply(c.probed_status()),
+        false => control_reply(c.status()),
+    });
+    let Some(status) = status else {
+        outln!("Veko Dome is not active. Start a session to check status.");
+        return;
+    };
+    print_status(&status);
+}
+
+/// Seconds between prints of `watch --plain`.
+const WATCH_PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The dashboard where the terminal can show it, the plain status print
+/// elsewhere.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn watch_session(session: Option<&str>, plain: bool) {
+    let Some(client) = session_client(session) else {
+        outln!("Veko Dome is not active. Start a session to watch it.");
+        return;
+    };
+    #[cfg(unix)]
+    if !plain && dashboard::supported() {
+        match dashboard::run(&client) {
+            Ok(dashboard::Ended::Left) => {}
+            Ok(dashboard::Ended::Stopped) => outln!("Session is shutting down."),
+            Ok(dashboard::Ended::SessionGone) => outln!("The session has ended."),
+            Err(e) => {
+                log(&e.to_string(), "FATAL");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    while let Some(status) = control_reply(client.status()) {
+        print_status(&status);
+        thread::sleep(WATCH_PLAIN_INTERVAL);
+    }
+    outln!("The session has ended.");
+}
+
+fn print_status(status: &StatusSnapshot) {
+    outln!("\n--- Connection Status ---");
+    outln!(
+        "Session: {} (pid {}, up {}s)",
+        status.session,
+        status.pid,
+        status.uptime_secs
+    );
+    let located = status
+        .exit_geo
+        .as_ref()
+        .map(|geo| format!("{}; ", geo))
+        .unwrap_or_default();
+    match (&status.exit_ip, &status.exit_ip_service) {
+        (Some(ip), Some(service)) => {
+            outln!(
+                "{}: {} ({}via {})",
+                public_ip_label(ip),
+                ip,
+                located,
+                service
+            )
+        }
+        (Some(ip), None) if status.exit_geo.is_some() => {
+            outln!(
+                "{}: {} ({})",
+                public_ip_label(ip),
+                ip,
+                located.trim_end_matches("; ")
+            )
+        }
+        (Some(ip), None) => outln!("{}: {}", public_ip_label(ip), ip),
+        (None, _) if status.ip_check_disabled => outln!("IP check disabled"),
+        (None, _) => {}
+    }
+    if let Some(agent) = &status.user_agent {
+        outln!("User agent: {}", agent);
+    }
+    for (worker, message) in &status.degraded {
+        outln!(
+            "DEGRADED: worker {} stopped after crashing: {}",
+            worker,
+            message
+        );
+    }
+    if let Some(reason) = &status.forwarding_stopped {
+        outln!("FORWARDING STOPPED (fail-closed): {}", reason);
+    }
+    let approx = if status.rotation_jitter_secs > 0 {
+        "~"
+    } else {
+        ""
+    };
+    let timer = (status.rotation_interval_secs > 0)
+        .then(|| format!("{}{}s", approx, status.next_rotation_secs));
+    let requests = status.rotate_requests.map(|limit| {
+        format!(
+            "{} requests",
+            limit.saturating_sub(status.requests_since_rotation)
+        )
+    });
+    let next = match (timer, requests) {
+        (Some(timer), Some(requests)) => format!(", next in {} or {}", timer, requests),
+        (Some(next), None) | (None, Some(next)) => format!(", next in {}", next),
+        (None, None) => String::new(),
+    };
+    outln!(
+        "Mode: Using proxy: {} (Rotation: {}{}, strategy: {})",
+        describe_proxy(&status.current_proxy, status.current_details.as_deref()),
+        describe_rotation_policy(status.rotation_interval_secs, status.rotate_requests),
+        next,
+        status.strategy
+    );
+    if let Some(bounds) =
+        describe_identity_bounds(status.min_dwell_secs, status.max_identity_lifetime_secs)
+    {
+        outln!("Identity: {}", bounds);
+    }
+    outln!("Route health: {}", describe_route_health(status));
+    let carried = status.identity_bytes_up + status.identity_bytes_down;
+    match status.max_bytes_per_identity {
+        Some(limit) => outln!(
+            "Identity traffic: {} up, {} down, rotating at {}",
+            describe_bytes(status.identity_bytes_up),
+            describe_bytes(status.identity_bytes_down),
+            describe_bytes(limit)
+        ),
+        None if carried > 0 => outln!(
+            "Identity traffic: {} up, {} down",
+            describe_bytes(status.identity_bytes_up),
+            describe_bytes(status.identity_bytes_down)
+        ),
+        None => {}
+    }
+    if let Some(adaptive) = &status.adaptive {
+        outln!(
+            "Adaptive: {}-{}s, from {} tunnels ({}% failed, p90 {})",
+            adaptive.min_secs,
+            adaptive.max_secs,
+            adaptive.samples,
+            adaptive.error_rate_pct,
+            adaptive
+                .p90_latency_ms
+                .map_or_else(|| "-".to_string(), |ms| format!("{}ms", ms))
+        );
+    }
+    if let Some(route) = &status.route {
+        outln!("Chain: {}", route);
+    }
+    if let Some(dns) = &status.dns {
+        outln!("DNS: {}", dns);
+    }
+    if let Some(tor) = &status.tor {
+        outln!("Tor: {}", tor);
+    }
+    if let Some(circuit) = &status.tor_circuit {
+        outln!("Tor circuit: {}", circuit);
+    }
+    if let Some(transport) = &status.transport {
+        outln!("Transport: {}", transport);
+    }
+    outln!(
+        "Proxies: {} alive, {} quarantined",
+        status.proxies_alive,
+        status.proxies_quarantined
+    );
+    if let Some(blend) = &status.blend {
+        outln!("Blend: {}", blend);
+    }
+    if let Some(geo) = &status.geo {
+        outln!("Geo: {}", geo);
+    }
+    if !status.fastest.is_empty() {
+        outln!("Fastest proxies:");
+        for p in &status.fastest {
+            outln!("  {} ({}ms)", p.proxy, p.latency_ms);
+        }
+    }
+    for listener in &status.listeners {
+        outln!(
+            "Listener: {} ({} active connections)",
+            listener.spec,
+            listener.active_connections
+        );
+    }
+    outln!("-------------------------\n");
+}
+
+fn rotate_session(session: Option<&str>) {
+    match session_client(session).and_then(|c| control_reply(c.rotate())) {
+        Some(result) if result.from == result.to => {
+            outln!("No other proxy is available; still on {}", result.to)
+        }
+        Some(result) => outln!("Rotated from {} to {}", result.from, result.to),
+        None => outln!("Veko Dome is not active. Start a session to rotate."),
+    }
+}
+
+fn reload_session(session: Option<&str>) {
+    match session_client(session).and_then(|c| control_reply(c.reload())) {
+        Some(result) => outln!(
+            "Reloaded the proxy list: {} added, {} retired, {} restored",
+            result.added,
+            result.retired,
+            result.restored
+        ),
+        None => outln!("Veko Dome is not active. Start a session to reload."),
+    }
+}
+
+#[derive(clap::Args)]
+struct StopArgs {
+    /// PID file the session was started with --pid-file [default: the
+    /// session's own]
+    #[arg(long, value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+    /// How long to wait for the session to exit, e.g. 2m
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "60s")]
+    timeout: Duration,
+}
+
+/// Mirrors the log to --log-file, if it is given.
+fn open_log_file(args: &StartArgs) {
+    let Some(path) = &args.log_file else {
+        return;
+    };
+    let options = logging::FileOptions {
+        path: path.clone(),
+        max_size: args.log_max_size,
+        keep: args.log_keep,
+    };
+    if let Err(e) = logging::open_file(options) {
+        log(
+            &format!("Cannot open log file {}: {}", path.display(), e),
+            "FATAL",
+        );
+        process::exit(1);
+    }
+}
+
+/// Moves `start --daemon` into the background, or ends it when it cannot
+/// be.
+#[cfg(any(unix, windows))]
+fn daemonize(pid_file: &Path, session: &str, log_file: Option<&Path>) {
+    match daemon::read(pid_file) {
+        daemon::PidFile::Live(pid) => {
+            log(
+                &format!(
+                    "Session '{}' is already running as PID {} ({}); pick another name with --session",
+                    session,
+                    pid,
+                    pid_file.display()
+                ),
+                "FATAL",
+            );
+            process::exit(1);
+        }
+        daemon::PidFile::Stale(why) => {
+            log(
+                &format!("Removing stale PID file {}: {}", pid_file.display(), why),
+                "WARNING",
+            );
+            daemon::remove(pid_file);
+        }
+        daemon::PidFile::Missing => {}
+    }
+    if let Some(path) = log_file {
+        log(
+            &format!("Detaching; the session logs to {}", path.display()),
+            "SYSTEM",
+        );
+    }
+    let detached =
+        daemon::Launch::current(session).and_then(|launch| daemon::detach(pid_file, &launch));
+    if let Err(e) = detached {
+        log(&format!("Cannot run as a daemon: {}", e), "FATAL");
+        process::exit(1);
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn daemonize(_pid_file: &Path, _session: &str, _log_file: Option<&Path>) {
+    log("--daemon is not supported on this platform", "FATAL");
+    process::exit(1);
+}
+
+/// The PID file `stop` and `restart` go by, and the session it is for.
+#[cfg(any(unix, windows))]
+fn stop_target(session: Option<&str>, pid_file: Option<&Path>) -> (PathBuf, String) {
+    let name = match (session, pid_file) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(path)) => daemon::Launch::load(path)
+            .map(|launch| launch.session)
+            .unwrap_or_else(|| control::DEFAULT_SESSION.to_string()),
+        (None, None) if control::pid_path(control::DEFAULT_SESSION).exists() => {
+            control::DEFAULT_SESSION.to_string()
+        }
+        (None, None) => {
+            let mut sessions = control::live_sessions();
+            match sessions.len() {
+                0 => control::DEFAULT_SESSION.to_string(),
+                1 => sessions.remove(0),
+                _ => {
+                    log(&ClientError::Ambiguous(sessions).to_string(), "FATAL");
+                    process::exit(1);
+                }
+            }
+        }
+    };
+    let path = pid_file.map_or_else(|| control::pid_path(&name), Path::to_path_buf);
+    (path, name)
+}
+
+/// Signals the session behind `pid_file` to shut down and waits for it to
+/// exit; returns whether one was running.
+#[cfg(any(unix, windows))]
+fn stop_pid_file(pid_file: &Path, session: &str, timeout: Duration) -> bool {
+    let pid = match daemon::read(pid_file) {
+        daemon::PidFile::Missing => return false,
+        daemon::PidFile::Stale(why) => {
+            daemon::clean_up(pid_file, session);
+            outln!("Removed stale PID file {}: {}", pid_file.display(), why);
+            return false;
+        }
+        daemon::PidFile::Live(pid) => pid,
+    };
+    if let Err(e) = daemon::request_stop(pid, session) {
+        log(
+            &format!("Cannot stop session '{}' (PID {}): {}", session, pid, e),
+            "FATAL",
+        );
+        process::exit(1);
+    }
+    outln!("Session '{}' (PID {}) is shutting down...", session, pid);
+    if !daemon::wait_exit(pid, timeout) {
+        log(
+            &format!(
+                "Session '{}' (PID {}) did not exit within {}",
+                session,
+                pid,
+                describe_duration(timeout)
+            ),
+            "FATAL",
+        );
+        process::exit(1);
+    }
+    daemon::clean_up(pid_file, session);
+    true
+}
+
+#[cfg(any(unix, windows))]
+fn stop_session(session: Option<&str>, args: &StopArgs) {
+    let (pid_file, name) = stop_target(session, args.pid_file.as_deref());
+    if stop_pid_file(&pid_file, &name, args.timeout) {
+        outln!("Session '{}' has stopped.", name);
+    } else {
+        outln!("Veko Dome is not active.");
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn stop_session(_session: Option<&str>, _args: &StopArgs) {
+    outln!("Veko Dome is not active.");
+}
+
+#[cfg(any(unix, windows))]
+fn restart_session(session: Option<&str>, args: &StopArgs) {
+    let (pid_file, name) = stop_target(session, args.pid_file.as_deref());
+    // Read before stopping, which removes it
+    let Some(launch) = daemon::Launch::load(&pid_file) else {
+        log(
+            &format!(
+                "Session '{}' was not started with --daemon; nothing in {} says how to start it again",
+                name,
+                pid_file.display()
+            ),
+            "FATAL",
+        );
+        process::exit(1);
+    };
+    if stop_pid_file(&pid_file, &name, args.timeout) {
+        outln!("Session '{}' has stopped; starting it again.", name);
+    }
+    match launch.relaunch() {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            log(
+                &format!("Session '{}' did not start again ({})", name, status),
+                "FATAL",
+            );
+            process::exit(status.code().unwrap_or(1));
+        }
+        Err(e) => {
+            log(
+                &format!("Cannot start session '{}' again: {}", name, e),
+                "FATAL",
+            );
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn restart_session(_session: Option<&str>, _args: &StopArgs) {
+    log("restart is not supported on this platform", "FATAL");
+    process::exit(1);
+}
+
+fn print_utilization(utilization: &[ProxyLoad]) {
+    for u in utilization {
+        let flag = if u.active >= u.max_connections {
+            "  (at capacity)"
+        } else {
+            ""
+        };
+        outln!(
+            "{}: {}/{} connections{}",
+            u.proxy,
+            u.active,
+            u.max_connections,
+            flag
+        );
+    }
+}
+
+fn print_identity(session: Option<&str>, include_secrets: bool, format: IdentityFormat) {
+    let Some(identity) =
+        session_client(session).and_then(|c| control_reply(c.identity(include_secrets)))
+    else {
+        errln!("No session is running.");
+        process::exit(1);
+    };
+    match format {
+        IdentityFormat::Json => match serde_json::to_string(&identity) {
+            Ok(json) => outln!("{}", json),
+            Err(e) => {
+                log(&format!("Could not print the identity: {}", e), "FATAL");
+                process::exit(1);
+            }
+        },
+        IdentityFormat::Curl => {
+            let mut options = vec![format!("-A {}", shell_quote(&identity.user_agent))];
+            options.extend(identity.headers.iter().map(|(name, value)| {
+                format!("-H {}", shell_quote(&format!("{}: {}", name, value)))
+            }));
+            options.push(format!("-x {}", shell_quote(&identity.proxy)));
+            outln!("{}", options.join(" "));
+        }
+    }
+}
+
+/// `value` in single quotes, for sh and the shells that quote like it.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn show_connections(session: Option<&str>) {
+    match session_client(session).and_then(|c| control_reply(c.stats())) {
+        Some(stats) if !stats.utilization.is_empty() => {
+            outln!("\n--- Connections ---");
+            print_utilization(&stats.utilization);
+            outln!("-------------------\n");
+        }
+        _ => outln!("No session with listeners is running."),
+    }
+}
+
+fn show_stats(session: Option<&str>, internals: bool) {
+    match session_client(session).and_then(|c| control_reply(c.stats())) {
+        Some(stats) => {
+            let rotations: Vec<String> = stats
+                .rotations
+                .iter()
+                .map(|(reason, n)| format!("{}={}", reason, n))
+                .collect();
+            outln!("\n--- Session Statistics ---");
+            outln!(
+                "Rotations: {}",
+                if rotations.is_empty() {
+                    "none".to_string()
+                } else {
+                    rotations.join(", ")
+                }
+            );
+            outln!("Worker restarts: {}", stats.worker_restarts);
+            outln!("Tor restarts: {}", stats.tor_restarts);
+            outln!(
+                "Health check probe: {}",
+                stats.precheck_probe.as_deref().unwrap_or("off")
+            );
+            if let Some(probe) = &stats.verify_probe {
+                outln!("Rotation verify probe: {}", probe);
+            }
+            if !stats.utilization.is_empty() {
+                outln!("\n--- Proxy Utilization ---");
+                print_utilization(&stats.utilization);
+            }
+            outln!("--------------------------\n");
+        }
+        None => outln!("Veko Dome is not active. Start a session to collect statistics."),
+    }
+    if !internals {
+        return;
+    }
+
+    // Geolocation cache lifetime counters
+    let stats = GeoCache::load(&geo_cache_path(), Duration::ZERO).stats();
+    let lookups = stats.hits + stats.misses;
+    let hit_rate = if lookups == 0 {
+        0.0
+    } else {
+        stats.hits as f64 * 100.0 / lookups as f64
+    };
+    outln!("\n--- Internals ---");
+    outln!("Geo cache: {}", stats.path.display());
+    outln!(
+        "Geo cache entries: {} ({} expired)",
+        stats.entries,
+        stats.expired
+    );
+    outln!(
+        "Geo cache hits/misses: {}/{} ({:.1}% hit rate)",
+        stats.hits,
+        stats.misses,
+        hit_rate
+    );
+    outln!("-----------------\n");
+}
+
+fn purge(geo_cache: bool) {
+    if !geo_cache {
+        outln!("Nothing to purge. Pass --geo-cache to remove the geolocation cache.");
+        return;
+    }
+
+    let path = geo_cache_path();
+    match geo::purge_cache(&path) {
+        Ok(true) => log(&format!("Removed {}", path.display()), "SYSTEM"),
+        Ok(false) => log("Geolocation cache is already empty", "SYSTEM"),
+        Err(e) => log(&format!("Could not remove {}: {}", path.display(), e), "ERROR"),
+    }
+}
+
+fn run_doctor(args: &DoctorArgs) {
+    let mut findings = vec![doctor::tor_binary(&tor_integration::find_tor(
+        args.tor_binary.as_deref(),
+    ))];
+    findings.push(doctor::directory("data-dir", &data_dir(), args.fix));
+    if let Some(dir) = config::default_path().as_deref().and_then(Path::parent) {
+        findings.push(doctor::directory("config-dir", dir, args.fix));
+    }
+    findings.push(doctor::directory("temp-dir", &env::temp_dir(), false));
+    if let Some(dir) = args.log_file.as_deref().and_then(Path::parent) {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        findings.push(doctor::directory("log-dir", dir, args.fix));
+    }
+    findings.push(doctor::control_port(&data_dir()));
+    findings.push(doctor::socks_port());
+    findings.push(diagnose_proxy_list(
+        args.proxy.as_deref(),
+        args.proxy_format,
+    ));
+    let https = ip_services()
+        .iter()
+        .find(|url| url.starts_with("https://"))
+        .cloned()
+        .unwrap_or_default();
+    match Client::builder()
+        .no_proxy()
+        .timeout(Duration::from_secs(10))
+        .build()
+    {
+        Ok(direct) => findings.push(doctor::outbound_https(&direct, &https)),
+        Err(e) => findings.push(doctor::Diagnosis::fail(
+            "outbound-https",
+            format!("no HTTP client: {}", e),
+            "this build cannot make HTTPS requests",
+        )),
+    }
+
+    outln!("\n--- Veko Dome Doctor ---");
+    for finding in &findings {
+        outln!(
+            "[{}] {}: {}",
+            finding.verdict.as_str(),
+            finding.check,
+            finding.detail
+        );
+        if let Some(hint) = &finding.hint {
+            outln!("       {}", hint);
+        }
+    }
+    let failed = findings
+        .iter()
+        .filter(|f| f.verdict == audit::Verdict::Fail)
+        .count();
+    match failed {
+        0 => outln!("Everything a session needs is in order"),
+        n => outln!("{} of {} checks failed", n, findings.len()),
+    }
+    outln!("------------------------\n");
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+/// Whether the proxy source can be read and lists at least one proxy.
+fn diagnose_proxy_list(source: Option<&str>, format: ProxyFormat) -> doctor::Diagnosis {
+    let (label, text) = match read_proxy_source(source) {
+        Ok(found) => found,
+        Err(e) => {
+            return doctor::Diagnosis::fail(
+                "proxy-list",
+                format!("cannot read it: {}", e),
+                "check the file path or URL given to --proxy",
+            )
+        }
+    };
+    match parse_proxy_list(&label, &text, format) {
+        Ok(entries) if entries.is_empty() => doctor::Diagnosis::fail(
+            "proxy-list",
+            format!("{} lists no usable proxies", label),
+            "list one proxy per line, e.g. socks5://127.0.0.1:1080 or host:port for HTTP",
+        ),
+        Ok(entries) => doctor::Diagnosis::pass(
+            "proxy-list",
+            format!("{}: {} proxies", label, entries.len()),
+        ),
+        Err(e) => doctor::Diagnosis::fail(
+            "proxy-list",
+            e,
+            "fix the entries logged above, or pass --proxy-format",
+        ),
+    }
+}
+
+fn update_datasets(url: &str) {
+    match datasets::update(url) {
+        Ok(outcomes) => {
+            for (name, outcome) in outcomes {
+                let what = match outcome {
+                    datasets::Outcome::Installed => "installed the published version",
+                    datasets::Outcome::Unchanged => "already up to date",
+                    datasets::Outcome::NotPublished => {
+                        "not published there; keeping the current one"
+                    }
+                };
+                log(&format!("{}: {}", name, what), "SYSTEM");
+            }
+        }
+        Err(e) => {
+            log(
+                &format!(
+                    "Refusing to install datasets from {}: {}",
+                    redact_url(url),
+                    e
+                ),
+                "ERROR",
+            );
+            log("Nothing was installed", "FATAL");
+            process::exit(1);
+        }
+    }
+}
+
+fn show_datasets() {
+    outln!("\n--- Datasets ---");
+    for dataset in datasets::ALL {
+        let source = match dataset.source() {
+            datasets::Source::Embedded => "embedded".to_string(),
+            datasets::Source::Updated(at) => format!(
+                "updated {} ago",
+                describe_age(at.elapsed().unwrap_or_default())
+            ),
+        };
+        outln!(
+            "{:<14} {} ({} entries)",
+            dataset.name,
+            source,
+            dataset.entry_count()
+        );
+    }
+    outln!("----------------\n");
+}
+
+fn show_profile(script_context: bool) {
+    if script_context {
+        #[cfg(feature = "scripting")]
+        outln!("{}", scripting::CONTEXT_DOC.trim_end());
+        #[cfg(not(feature = "scripting"))]
+        outln!("This build has no selection scripting; rebuild with --features scripting.");
+        return;
+    }
+    let profile = SecurityProfile::new(DEFAULT_MODE).unwrap_or_else(|e| {
+        log(&e, "FATAL");
+        process::exit(1);
+    });
+    outln!("\n--- Security Profile: {} ---", DEFAULT_MODE);
+    outln!("User agents (one picked per route):");
+    for agent in profile.user_agents() {
+        outln!("  {}", agent);
+    }
+    outln!("Headers, on top of those of each user agent's browser:");
+    for (name, value) in &profile.headers {
+        outln!("  {}: {}", name, value.to_str().unwrap_or("<binary>"));
+    }
+    outln!("{}", profile.tls.describe());
+    outln!(
+        "Requests: {}s timeout, {} redirects followed, connections {}, HTTP version {}",
+        profile.timeout.as_secs(),
+        profile.redirect_limit,
+        if profile.keepalive {
+            "kept open"
+        } else {
+            "not reused"
+        },
+        profile::variant_name(profile.http_version)
+    );
+    outln!(
+        "Cookies: {}",
+        if profile.cookies {
+            "kept until the route rotates"
+        } else {
+            "never sent back"
+        }
+    );
+    outln!(
+        "Tor circuits: {}",
+        if profile.tor_isolation {
+            "new ones for every identity (IsolateSOCKSAuth)"
+        } else {
+            "kept until NEWNYM"
+        }
+    );
+    outln!("----------------------------------\n");
+}
+
+/// The route `audit` goes through: --proxy, else the running session's
+/// first listener, else Tor. Also says where its names are resolved,
+/// whether it should exit through Tor, and the session's user agent.
+fn audit_target(
+    args: &AuditArgs,
+    session: Option<&str>,
+) -> (String, audit::DnsPath, bool, Option<String>) {
+    if let Some(proxy) = &args.proxy {
+        let dns = match proxy.starts_with("socks5://") {
+            true => audit::DnsPath::System,
+            false => audit::DnsPath::Remote,
+        };
+        return (proxy.clone(), dns, args.expect_tor, None);
+    }
+    let status = session_client(session).and_then(|c| control_reply(c.status()));
+    let Some(status) = status.filter(|s| !s.listeners.is_empty()) else {
+        return (
+            tor_integration::SOCKS_URL.to_string(),
+            audit::DnsPath::Remote,
+            true,
+            None,
+        );
+    };
+    let spec = &status.listeners[0].spec;
+    let route = match spec.strip_prefix("socks5://") {
+        Some(addr) => format!("socks5h://{}", addr),
+        None => spec.clone(),
+    };
+    // The session resolves names itself only for a socks5:// proxy
+    let dns = match (&status.route, status.current_proxy.starts_with("socks5://")) {
+        (None, true) => status
+            .dns
+            .clone()
+            .map_or(audit::DnsPath::System, audit::DnsPath::Encrypted),
+        _ => audit::DnsPath::Remote,
+    };
+    let on_tor = status.current_proxy == tor_integration::SOCKS_URL
+        || status
+            .route
+            .as_ref()
+            .is_some_and(|route| route.ends_with("-> Tor -> destination"));
+    (route, dns, args.expect_tor || on_tor, status.user_agent)
+}
+
+fn audit_route(args: &AuditArgs, session: Option<&str>) {
+    let fatal = |e: String| -> ! {
+        log(&e, "FATAL");
+        process::exit(1);
+    };
+    let config = args
+        .config
+        .as_deref()
+        .map(config::Config::load)
+        .transpose()
+        .unwrap_or_else(|e| fatal(e));
+    let profile = SecurityProfile::for_mode(
+        &args.mode,
+        config.as_ref().map(|c| c.catalog()).as_ref(),
+        false,
+    )
+    .unwrap_or_else(|e| fatal(e));
+    let blocklist = args.blocklist.as_ref().map(|path| {
+        fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| audit::Blocklist::parse(&text))
+            .unwrap_or_else(|e| fatal(format!("Cannot read {}: {}", path.display(), e)))
+    });
+    use_ip_services(&args.ip_services, args.allow_http_ip_check).unwrap_or_else(|e| fatal(e));
+    let (route, dns, expect_tor, presented) = audit_target(args, session);
+    // Tunnels through a listener carry the audit's own requests, so they
+    // present the session's user agent when the profile has it
+    let agent = presented
+        .and_then(|ua| profile.user_agents().iter().position(|a| *a == ua))
+        .unwrap_or(0);
+    let client = create_http_client(&route, &profile, agent).unwrap_or_else(|e| fatal(e));
+    let baseline = (!args.skip.contains(&audit::Check::ExitIp)
+        || !args.skip.contains(&audit::Check::Headers))
+    .then(|| {
+        Client::builder()
+            .no_proxy()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .ok()
+    })
+    .flatten()
+    .and_then(|direct| get_public_ip(&direct))
+    .map(|own| own.ip);
+    let report = audit::Audit {
+        route: strip_credentials(&route),
+        client: &client,
+        baseline,
+        blocklist: blocklist.as_ref(),
+        dns,
+        profile: &profile,
+        user_agent: profile.user_agent(agent).to_string(),
+        headers: profile.headers_for(agent),
+        expect_tor,
+        echo_url: &args.echo_url,
+        skip: &args.skip,
+    }
+    .run();
+    match args.output {
+        SummaryFormat::Text => {
+            outln!("\n--- Anonymity Audit: {} ---", report.route);
+            for finding in &report.findings {
+                outln!(
+                    "[{}] {}: {}",
+                    finding.verdict.as_str(),
+                    finding.check.name(),
+                    finding.detail
+                );
+            }
+            outln!(
+                "Score: {}/100, {}",
+                report.score,
+                if report.passed { "passed" } else { "FAILED" }
+            );
+            outln!("----------------------------------\n");
+        }
+        SummaryFormat::Json => match serde_json::to_string(&report) {
+            Ok(json) => outln!("{}", json),
+            Err(e) => fatal(format!("Could not print the report: {}", e)),
+        },
+    }
+    if !report.passed {
+        process::exit(1);
+    }
+}
+
+/// Asks `services` for the exit IP instead of the built-in ones, if any
+/// are given, as `start` does with --ip-service.
+fn use_ip_services(services: &[String], allow_http: bool) -> Result<(), String> {
+    if let Some(url) = services
+        .iter()
+        .find(|url| url.starts_with("http://"))
+        .filter(|_| !allow_http)
+    {
+        return Err(format!(
+            "--ip-service {} requires --allow-http-ip-check",
+            url
+        ));
+    }
+    if !services.is_empty() {
+        set_ip_services(services.to_vec());
+    }
+    Ok(())
+}
+
+/// `test-proxies`: the health check with a judge, reported per proxy.
+fn test_proxies(args: &TestProxiesArgs) {
+    let fatal = |e: String| -> ! {
+        log(&e, "FATAL");
+        process::exit(1);
+    };
+    use_ip_services(&args.ip_services, args.allow_http_ip_check).unwrap_or_else(|e| fatal(e));
+    let geoip = args
+        .geoip_db
+        .as_deref()
+        .map(GeoDb::open)
+        .transpose()
+        .unwrap_or_else(|e| fatal(e));
+    let (label, text) = read_proxy_source(args.proxy.as_deref())
+        .unwrap_or_else(|e| fatal(format!("Cannot load proxies: {}", e)));
+    let proxies = parse_proxy_list(&label, &text, args.proxy_format)
+        .unwrap_or_else(|e| fatal(format!("Cannot load proxies: {}", e)));
+    log(
+        &format!("Loaded {} proxies from {}", proxies.len(), label),
+        "PROXY",
+    );
+    let own_ip = (!args.no_baseline)
+        .then(|| {
+            Client::builder()
+                .no_proxy()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .ok()
+        })
+        .flatten()
+        .and_then(|direct| get_public_ip(&direct))
+        .map(|own| own.ip);
+    let check = HealthCheck {
+        timeout: Duration::from_secs(args.timeout),
+        level: args.probe,
+        judge: Some(Arc::new(Judge {
+            url: args.judge_url.clone(),
+            own_ip,
+        })),
+        min_anonymity: None,
+    };
+
+    // Ctrl-C lets the checks under way finish and reports what is known
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = stop.clone();
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+        .expect("Error setting Ctrl-C handler");
+    let total = proxies.len();
+    let show_progress = io::stderr().is_terminal();
+    let results = check_proxies(
+        &proxies,
+        &check,
+        args.concurrency as usize,
+        &stop,
+        &mut |done| {
+            if show_progress {
+                output::write_err(format_args!("\rChecked {}/{}", done, total));
+            }
+        },
+    );
+    if show_progress {
+        errln!();
+    }
+    if stop.load(Ordering::SeqCst) {
+        let checked = results.iter().filter(|result| result.is_some()).count();
+        log(
+            &format!("Stopped after checking {} of {} proxies", checked, total),
+            "PROXY",
+        );
+    }
+
+    let rows: Vec<ProxyReport> = proxies
+        .iter()
+        .zip(results)
+        .map(|(proxy, result)| ProxyReport::new(proxy, result, geoip.as_ref()))
+        .collect();
+    let mut order: Vec<&ProxyReport> = rows.iter().collect();
+    // Fastest first, then the failed ones, then those never checked
+    order.sort_by_key(|row| (row.result.is_none(), !row.alive(), row.latency_ms()));
+    let alive = rows.iter().filter(|row| row.alive()).count();
+    match args.output {
+        SummaryFormat::Text => print_proxy_reports(&order, alive, total),
+        SummaryFormat::Json => {
+            let json: Vec<serde_json::Value> = order.iter().map(|row| row.to_json()).collect();
+            outln!("{}", serde_json::Value::Array(json));
+        }
+    }
+
+    if let Some(path) = &args.save_alive {
+        let keep: HashSet<String> = rows
+            .iter()
+            .filter(|row| row.alive())
+            .map(|row| row.url.clone())
+            .collect();
+        keep_entries(&label, &text, args.proxy_format, &keep)
+            .and_then(|list| fs::write(path, list).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| fatal(format!("Cannot write {}: {}", path.display(), e)));
+        log(
+            &format!("Wrote {} working proxies to {}", keep.len(), path.display()),
+            "PROXY",
+        );
+    }
+    if alive < args.min_alive {
+        log(
+            &format!(
+                "Only {} proxies passed, fewer than --min-alive {}",
+                alive, args.min_alive
+            ),
+            "PROXY",
+        );
+        process::exit(1);
+    }
+}
+
+/// One proxy's line in the `test-proxies` report.
+struct ProxyReport {
+    url: String,
+    /// `None` when it was never checked.
+    result: Option<Result<Checked, String>>,
+    /// From the list, or the GeoIP database for the exit IP.
+    country: Option<String>,
+}
+
+impl ProxyReport {
+    fn new(
+        proxy: &ProxyEntry,
+        result: Option<Result<Checked, String>>,
+        geoip: Option<&GeoDb>,
+    ) -> Self {
+        let exit_country = result
+            .as_ref()
+            .and_then(|r| r.as_ref().ok())
+            .and_then(|checked| checked.exit_ip.as_ref())
+            .and_then(|exit| geoip.and_then(|db| db.country(&exit.ip)));
+        ProxyReport {
+            url: proxy.url.clone(),
+            result,
+            country: exit_country.or_else(|| proxy.country.clone()),
+        }
+    }
+
+    fn alive(&self) -> bool {
+        matches!(self.result, Some(Ok(_)))
+    }
+
+    fn latency_ms(&self) -> Option<u64> {
+        match &self.result {
+            Some(Ok(checked)) => Some(checked.latency.as_millis() as u64),
+            _ => None,
+        }
+    }
+
+    fn judgement(&self) -> Option<&Result<Judgement, String>> {
+        match &self.result {
+            Some(Ok(checked)) => checked.judgement.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// E.g. "anonymous (sent via)", or why it is unknown.
+    fn anonymity(&self) -> String {
+        match self.judgement() {
+            Some(Ok(j)) if j.markers.is_empty() => j.anonymity.name().to_string(),
+            Some(Ok(j)) => format!("{} (sent {})", j.anonymity.name(), j.markers.join(", ")),
+            Some(Err(e)) => format!("unclassified: {}", e),
+            None => "unclassified".to_string(),
+        }
+    }
+
+    fn exit_ip(&self) -> Option<&str> {
+        match &self.result {
+            Some(Ok(checked)) => checked.exit_ip.as_ref().map(|exit| exit.ip.as_str()),
+            _ => None,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let proxy = strip_credentials(&self.url);
+        match &self.result {
+            None => serde_json::json!({ "proxy": proxy, "alive": false, "checked": false }),
+            Some(Err(e)) => serde_json::json!({ "proxy": proxy, "alive": false, "error": e }),
+            Some(Ok(_)) => {
+                let judged = self.judgement().and_then(|j| j.as_ref().ok());
+                serde_json::json!({
+                    "proxy": proxy,
+                    "alive": true,
+                    "latency_ms": self.latency_ms(),
+                    "exit_ip": self.exit_ip(),
+                    "country": self.country,
+                    "anonymity": judged.map(|j| j.anonymity),
+                    "markers": judged.map(|j| j.markers.clone()),
+                    "judge_error": self.judgement().and_then(|j| j.as_ref().err()),
+                })
+            }
+        }
+    }
+}
+
+/// The report as a table, fastest first.
+fn print_proxy_reports(rows: &[&ProxyReport], alive: usize, total: usize) {
+    let names: Vec<String> = rows.iter().map(|row| strip_credentials(&row.url)).collect();
+    let width = names.iter().map(String::len).max().unwrap_or(0).max(5);
+    outln!("\n--- Proxy Test: {}/{} alive ---", alive, total);
+    outln!(
+        "{:<width$}  {:>7}  {:<15}  {:<7}  ANONYMITY",
+        "PROXY",
+        "LATENCY",
+        "EXIT IP",
+        "COUNTRY",
+    );
+    for (row, name) in rows.iter().zip(&names) {
+        match &row.result {
+            None => outln!("{:<width$}  not checked", name),
+            Some(Err(e)) => outln!("{:<width$}  FAILED: {}", name, e),
+            Some(Ok(checked)) => outln!(
+                "{:<width$}  {:>5}ms  {:<15}  {:<7}  {}",
+                name,
+                checked.latency.as_millis(),
+                row.exit_ip().unwrap_or("-"),
+                row.country.as_deref().unwrap_or("-"),
+                row.anonymity()
+            ),
+        }
+    }
+    outln!("----------------------------------\n");
+}
+
+/// Fills in `args` from its --config file, else the default one if there
+/// is one. Exits if the file or --profile is wrong.
+fn load_config(
+    args: &mut StartArgs,
+    given: &ArgMatches,
+) -> Option<(config::Config, Vec<&'static str>)> {
+    let default = config::default_path();
+    let Some(path) = args
+        .config
+        .clone()
+        .or_else(|| default.clone().filter(|path| path.exists()))
+    else {
+        if let Some(name) = &args.profile {
+            let looked = default.map_or("the config dir".to_string(), |path| {
+                path.display().to_string()
+            });
+            log(
+                &format!(
+                    "--profile {} needs a config file; pass --config or create {}",
+                    name, looked
+                ),
+                "FATAL",
+            );
+            process::exit(1);
+        }
+        return None;
+    };
+    let mut config = config::Config::load(&path).unwrap_or_else(|e| {
+        log(&e, "FATAL");
+        process::exit(1);
+    });
+    if let Some(name) = &args.profile {
+        if let Err(e) = config.select(name) {
+            log(&e, "FATAL");
+            process::exit(1);
+        }
+    }
+    let taken = config.apply(args, given);
+    Some((config, taken))
+}
+
+fn log_config(config: &config::Config, taken: &[&str]) {
+    if !config.unknown.is_empty() {
+        log(
+            &format!(
+                "Ignoring unknown keys in {}: {}",
+                config.path().display(),
+                config.unknown.join(", ")
+            ),
+            "WARNING",
+        );
+    }
+    if !taken.is_empty() {
+        log(
+            &format!("Using {} from {}", taken.join(", "), config.describe()),
+            "SYSTEM",
+        );
+    }
+}
+
+/// What the start options change about the security profile --mode names.
+fn profile_options(args: &StartArgs) -> ProfileOptions {
+    ProfileOptions {
+        insecure_tls: args.insecure_tls,
+        user_agents: args.user_agents.clone(),
+        pin_user_agent: args.pin_user_agent.clone(),
+        no_referer: args.no_referer,
+        max_redirects: args.max_redirects,
+        no_keepalive: args.no_keepalive,
+        cookies: args.cookies,
+        no_tor_isolation: args.no_tor_isolation,
+        http_version: if args.http1 {
+            Some(HttpVersion::Http1)
+        } else if args.http2_prior_knowledge {
+            Some(HttpVersion::Http2PriorKnowledge)
+        } else {
+            None
+        },
+        timeout: args.timeout.map(Duration::from_secs),
+        through_tor: args.tor_weight > 0
+            || args.chain.is_some_and(ChainMode::through_tor)
+            || fallback_tor(args),
+    }
+}
+
+/// Where the value of the start option `key` came from.
+fn setting_source(given: &ArgMatches, taken: &[&str], key: &str) -> &'static str {
+    match given.value_source(config::arg_id(key)) {
+        _ if taken.contains(&key) => "config",
+        Some(clap::parser::ValueSource::CommandLine) => "cli",
+        Some(clap::parser::ValueSource::EnvVariable) => "env",
+        _ => "default",
+    }
+}
+
+/// What `start --dry-run` found a session would do.
+#[derive(serde::Serialize)]
+struct Plan {
+    valid: bool,
+    settings: Vec<PlannedSetting>,
+    steps: Vec<PlannedStep>,
+}
+
+#[derive(serde::Serialize)]
+struct PlannedSetting {
+    key: &'static str,
+    value: Option<String>,
+    /// cli, env, config or default.
+    source: &'static str,
+}
+
+/// One part of starting a session, and how it went without the network.
+#[derive(serde::Serialize)]
+struct PlannedStep {
+    step: &'static str,
+    outcome: Outcome,
+    detail: String,
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Ok,
+    Failed,
+    /// Needs the network, so a dry run leaves it out.
+    Skipped,
+}
+
+impl PlannedStep {
+    fn new(step: &'static str, outcome: Outcome, detail: impl Into<String>) -> Self {
+        PlannedStep {
+            step,
+            outcome,
+            detail: detail.into(),
+        }
+    }
+
+    fn skipped(step: &'static str) -> Self {
+        Self::new(step, Outcome::Skipped, "skipped (dry-run)")
+    }
+}
+
+/// `start --dry-run`: resolves the options, reads and checks what is on
+/// disk, and prints the plan. Nothing is sent and nothing keeps running.
+/// Exits 0 when a session would start, 1 when it would not.
+fn dry_run(
+    args: &StartArgs,
+    given: &ArgMatches,
+    config: Option<&(config::Config, Vec<&'static str>)>,
+) -> ! {
+    let taken = config.map_or(&[][..], |(_, taken)| taken.as_slice());
+    let settings = config::effective(args)
+        .into_iter()
+        .map(|(key, value)| PlannedSetting {
+            key,
+            value,
+            source: setting_source(given, taken, key),
+        })
+        .collect();
+
+    let mut steps = Vec::new();
+    let violations = validate::check(args);
+    if violations.is_empty() {
+        steps.push(PlannedStep::new("options", Outcome::Ok, "consistent"));
+    }
+    for violation in violations {
+        steps.push(PlannedStep::new("options", Outcome::Failed, violation));
+    }
+    if !args.no_portal_check {
+        steps.push(PlannedStep::skipped("captive portal check"));
+    }
+    if args.doh.is_some() || args.doh_url.is_some() || args.dot.is_some() {
+        steps.push(PlannedStep::skipped("encrypted DNS"));
+    }
+
+    let catalog = config.map(|(c, _)| c.catalog());
+    let options = profile_options(args);
+    let profile = match engine::build_profile(&args.mode, catalog.as_ref(), &options) {
+        Ok(profile) => {
+            steps.push(PlannedStep::new(
+                "security profile",
+                Outcome::Ok,
+                describe_client_settings(&profile),
+            ));
+            Some(profile)
+        }
+        Err(e) => {
+            steps.push(PlannedStep::new("security profile", Outcome::Failed, e));
+            None
+        }
+    };
+
+    steps.push(match args.proxy.as_deref() {
+        Some(url) if is_url_source(url) => PlannedStep::new(
+            "proxy list",
+            Outcome::Skipped,
+            format!("would fetch {}; skipped (dry-run)", redact_url(url)),
+        ),
+        source => match plan_proxies(args, source) {
+            Ok(detail) => PlannedStep::new("proxy list", Outcome::Ok, detail),
+            Err(e) => PlannedStep::new("proxy list", Outcome::Failed, e),
+        },
+    });
+    if args.proxy_refresh.is_some() {
+        steps.push(PlannedStep::skipped("proxy list refresh"));
+    }
+    let exit_blocklist = args.exit_blocklist.as_deref().map(read_exit_blocklist);
+    match &exit_blocklist {
+        Some(Ok(blocklist)) => steps.push(PlannedStep::new(
+            "exit blocklist",
+            Outcome::Ok,
+            format!(
+                "{} addresses and ranges, {} Tor relays",
+                blocklist.ranges(),
+                blocklist.fingerprints().len()
+            ),
+        )),
+        Some(Err(e)) => steps.push(PlannedStep::new("exit blocklist", Outcome::Failed, e)),
+        None => {}
+    }
+    if args.chain == Some(ChainMode::Hops) {
+        steps.push(match read_chain(args) {
+            Ok(hops) => PlannedStep::new(
+                "chain",
+                Outcome::Ok,
+                format!(
+                    "{}, rotating {}",
+                    Chain {
+                        hops,
+                        rotating: None
+                    },
+                    profile::variant_name(args.chain_rotate.unwrap_or(ChainRotate::Last))
+                ),
+            ),
+            Err(e) => PlannedStep::new("chain", Outcome::Failed, e),
+        });
+    }
+    if args.chain.is_some() {
+        steps.push(PlannedStep::skipped("chain forwarder"));
+    }
+
+    steps.push(match (args.tor_backend, &profile) {
+        (TorBackendKind::Arti, _) if cfg!(feature = "arti") => {
+            PlannedStep::new("tor", Outcome::Ok, "embedded Arti")
+        }
+        (TorBackendKind::Arti, _) => PlannedStep::new(
+            "tor",
+            Outcome::Failed,
+            "this build has no Arti; rebuild with --features arti, or use --tor-backend binary",
+        ),
+        (TorBackendKind::Binary, Some(profile)) => {
+            // The forwarder's port is only known once it listens
+            let forwarder = args
+                .chain
+                .map(|_| std::net::SocketAddr::from(([127, 0, 0, 1], 1)));
+            let blocklist = exit_blocklist.as_ref().and_then(|read| read.as_ref().ok());
+            let options = tor_options(args, profile, forwarder, blocklist);
+            let mut given = options.extra_args.clone();
+            if options.control_cookie.is_some() {
+                given.push("and a control port".to_string());
+            }
+            match tor_integration::verify_config(&options) {
+                Ok(()) if given.is_empty() => PlannedStep::new(
+                    "tor",
+                    Outcome::Ok,
+                    format!("{} accepts the options", options.binary.display()),
+                ),
+                Ok(()) => PlannedStep::new(
+                    "tor",
+                    Outcome::Ok,
+                    format!(
+                        "{} accepts the options: {}",
+                        options.binary.display(),
+                        given.join(" ")
+                    ),
+                ),
+                Err(e) => PlannedStep::new("tor", Outcome::Failed, e),
+            }
+        }
+        (TorBackendKind::Binary, None) => PlannedStep::new(
+            "tor",
+            Outcome::Skipped,
+            "not checked without a security profile",
+        ),
+    });
+    steps.push(PlannedStep::skipped("tor bootstrap"));
+    if !args.no_ip_check && !args.no_baseline {
+        steps.push(PlannedStep::skipped("baseline IP lookup"));
+    }
+    if !args.no_precheck {
+        steps.push(PlannedStep::skipped("proxy health check"));
+    }
+    if args.geolocate_proxies {
+        steps.push(PlannedStep::skipped("proxy geolocation"));
+    }
+    if !args.no_ip_check {
+        steps.push(PlannedStep::skipped("exit IP check"));
+    }
+
+    let plan = Plan {
+        valid: steps.iter().all(|step| step.outcome != Outcome::Failed),
+        settings,
+        steps,
+    };
+    match args.output {
+        SummaryFormat::Text => print_plan(&plan),
+        SummaryFormat::Json => match serde_json::to_string(&plan) {
+            Ok(json) => outln!("{}", json),
+            Err(e) => log(&format!("Could not print the plan: {}", e), "ERROR"),
+        },
+    }
+    process::exit(if plan.valid { 0 } else { 1 });
+}
+
+/// Re-reads --exit-blocklist, keeping the one in use when it cannot be
+/// read, and checks the current exit against what it now lists.
+fn reload_exit_blocklist(path: &Path, exits: &Mutex<ExitHistory>) {
+    let blocklist = match read_exit_blocklist(path) {
+        Ok(blocklist) => blocklist,
+        Err(e) => {
+            log(&format!("{}; keeping the one in use", e), "ERROR");
+            return;
+        }
+    };
+    log(
+        &format!(
+            "Reloaded exit blocklist {}: {} addresses and ranges; Tor keeps the relays it was started with",
+            path.display(),
+            blocklist.ranges()
+        ),
+        "SECURITY",
+    );
+    let mut exits = exits.lock().unwrap();
+    exits.set_blocklist(blocklist);
+    exits.screen("the current route");
+}
+
+/// The hops --chain hops goes through, from --chain-file or else the
+/// config's chain = [...], refused when there are more than
+/// --chain-max-hops.
+fn read_chain(args: &StartArgs) -> Result<Vec<Hop>, String> {
+    let (source, entries): (String, Vec<(String, String)>) = match &args.chain_file {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read chain {}: {}", path.display(), e))?;
+            let entries = text
+                .lines()
+                .enumerate()
+                .map(|(n, line)| (n, line.trim()))
+                .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+                .map(|(n, line)| (format!("line {}", n + 1), line.to_string()))
+                .collect();
+            (path.display().to_string(), entries)
+        }
+        None => (
+            "The config's chain".to_string(),
+            args.chain_hops
+                .iter()
+                .enumerate()
+                .map(|(n, url)| (format!("hop {}", n + 1), url.clone()))
+                .collect(),
+        ),
+    };
+    if entries.is_empty() {
+        return Err(format!("{} lists no hops", source));
+    }
+    if entries.len() > args.chain_max_hops {
+        return Err(format!(
+            "{} has {} hops, more than --chain-max-hops {}",
+            source,
+            entries.len(),
+            args.chain_max_hops
+        ));
+    }
+    entries
+        .iter()
+        .map(|(at, url)| Hop::parse(url).map_err(|e| format!("{}, {}: {}", source, at, e)))
+        .collect()
+}
+
+/// Reads --exit-blocklist.
+fn read_exit_blocklist(path: &Path) -> Result<Blocklist, String> {
+    fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| Blocklist::parse(&text))
+        .map_err(|e| format!("Cannot read exit blocklist {}: {}", path.display(), e))
+}
+
+/// Reads, verifies, parses and filters the proxy list as a session would,
+/// without fetching anything.
+fn plan_proxies(args: &StartArgs, source: Option<&str>) -> Result<String, String> {
+    let (label, text) = read_proxy_source(source)?;
+    let check = ListCheck::new(
+        args.proxy_sha256,
+        args.proxy_sig.as_deref(),
+        args.proxy_pubkey.as_deref(),
+    )?;
+    let verified = match &check {
+        Some(check) => format!("; {}", check.verify(text.as_bytes())?),
+        None => String::new(),
+    };
+    let entries = parse_proxy_list(&label, &text, args.proxy_format)?;
+    let total = entries.len();
+    let filter = CountryFilter::from_args(args)?;
+    let (kept, _) = filter.apply(entries);
+    if kept.is_empty() {
+        return Err(format!("no proxies to rotate through from {}", label));
+    }
+    Ok(format!(
+        "{} proxies from {}, {} kept{}",
+        total,
+        label,
+        kept.len(),
+        verified
+    ))
+}
+
+/// The client settings of `profile`, as the plan shows them.
+fn describe_client_settings(profile: &SecurityProfile) -> String {
+    let http = match profile.http_version {
+        HttpVersion::Auto => "HTTP version negotiated",
+        HttpVersion::Http1 => "HTTP/1.1 only",
+        HttpVersion::Http2PriorKnowledge => "HTTP/2 prior knowledge",
+    };
+    let on = |enabled: bool| if enabled { "on" } else { "off" };
+    format!(
+        "{} user agents, timeout {}s, up to {} redirects, keep-alive {}, cookies {}, {}; {}",
+        profile.user_agents().len(),
+        profile.timeout.as_secs(),
+        profile.redirect_limit,
+        on(profile.keepalive),
+        on(profile.cookies),
+        http,
+        profile.tls.describe()
+    )
+}
+
+fn print_plan(plan: &Plan) {
+    outln!("\n--- Dry Run ---");
+    outln!("Settings:");
+    for setting in &plan.settings {
+        match &setting.value {
+            Some(value) => outln!("  {} = {}  # {}", setting.key, value, setting.source),
+            None => outln!("  # {} is not set", setting.key),
+        }
+    }
+    outln!("Plan:");
+    for step in &plan.steps {
+        let outcome = match step.outcome {
+            Outcome::Ok => "ok",
+            Outcome::Failed => "FAILED",
+            Outcome::Skipped => "skipped",
+        };
+        outln!("  [{}] {}: {}", outcome, step.step, step.detail);
+    }
+    match plan.valid {
+        true => outln!("A session would start with these settings"),
+        false => outln!("A session would NOT start with these settings"),
+    }
+    outln!("---------------\n");
+}
+
+/// Resolves `start --config path` as a session would, without starting
+/// one, and prints the outcome.
+fn check_config(path: &Path, profile: Option<&str>) {
+    let mut argv: Vec<std::ffi::OsString> = vec![
+        "veko_dome".into(),
+        "start".into(),
+        "--config".into(),
+        path.as_os_str().to_owned(),
+    ];
+    if let Some(name) = profile {
+        argv.extend(["--profile".into(), name.into()]);
+    }
+    let matches = parse_args(argv);
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let (Commands::Start(args), Some(given)) =
+        (&mut cli.command, matches.subcommand_matches("start"))
+    else {
+        unreachable!("parsed from a start command line");
+    };
+    let Some((config, taken)) = load_config(args, given) else {
+        unreachable!("--config is given");
+    };
+    log_config(&config, &taken);
+    outln!("Effective settings:");
+    for (key, value) in config::effective(args) {
+        let source = setting_source(given, &taken, key);
+        match value {
+            Some(value) => outln!("  {} = {}  # {}", key, value, source),
+            None => outln!("  # {} is not set", key),
+        }
+    }
+    let mut violations = validate::check(args);
+    if let Err(e) =
+        SecurityProfile::for_mode(&args.mode, Some(&config.catalog()), args.insecure_tls)
+    {
+        violations.push(e);
+    }
+    if !violations.is_empty() {
+        for violation in &violations {
+            log(violation, "FATAL");
+        }
+        process::exit(1);
+    }
+    outln!("{} is valid", path.display());
+}
+
+fn list_profiles(path: Option<&Path>) {
+    let Some(path) = path.map(Path::to_path_buf).or_else(config::default_path) else {
+        log("No config dir on this system; pass --config", "FATAL");
+        process::exit(1);
+    };
+    if !path.exists() {
+        outln!("No config file at {}", path.display());
+        return;
+    }
+    let config = config::Config::load(&path).unwrap_or_else(|e| {
+        log(&e, "FATAL");
+        process::exit(1);
+    });
+    let names = config.profile_names();
+    if names.is_empty() {
+        outln!("{} defines no profiles", path.display());
+        return;
+    }
+    outln!("Profiles in {}:", path.display());
+    for name in names {
+        outln!("  {}", name);
+    }
+}
+
+fn replay_decisions(path: &Path) {
+    let report = decisions::replay(path).unwrap_or_else(|e| {
+        log(&format!("Cannot read {}: {}", path.display(), e), "FATAL");
+        process::exit(1);
+    });
+    for (line, reason) in &report.divergences {
+        outln!("line {}: {}", line, reason);
+    }
+    outln!(
+        "Replayed {} decisions: {} diverged, {} skipped",
+        report.checked,
+        report.divergences.len(),
+        report.skipped
+    );
+    if !report.divergences.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn show_events(kind: Option<EventKind>, last: usize) {
+    let events = match event_log().last(kind, last) {
+        Ok(events) => events,
+        Err(e) => {
+            log(&format!("Could not read event log: {}", e), "FATAL");
+            process::exit(1);
+        }
+    };
+    if events.is_empty() {
+        outln!("No events recorded yet.");
+        return;
+    }
+
+    outln!("\n--- Event Timeline ---");
+    for event in &events {
+        outln!("{}", describe_event(event));
+    }
+    outln!("----------------------\n");
+}
+
+fn describe_event(event: &Event) -> String {
+    match event {
+        Event::Rotation(r) => {
+            let settle = r
+                .settle_ms
+                .map(|ms| format!("{}ms", ms))
+                .unwrap_or_else(|| "-".to_string());
+            format!(
+                "{}  {:<6}  {} -> {}  settle: {}  exit: {}",
+                r.ts,
+                r.reason,
+                r.from,
+                r.to,
+                settle,
+                r.exit_ip.as_deref().unwrap_or("-")
+            )
+        }
+        Event::WorkerCrashed(w) => format!(
+            "{}  crash   {}: {}{}",
+            w.ts,
+            w.worker,
+            w.message,
+            if w.restarting { " (restarted)" } else { "" }
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, Write};
+
+    fn rotator(interval_secs: u64) -> ProxyRotator {
+        let proxies = ["socks5://127.0.0.1:1080", "socks5://127.0.0.1:1081"]
+            .map(|url| ProxyEntry::parse(url).unwrap())
+            .to_vec();
+        ProxyRotator::new(proxies, interval_secs).unwrap()
+    }
+
+    fn adaptive_rotator() -> ProxyRotator {
+        let mut rotator = rotator(600);
+        rotator.min_interval = Some(Duration::from_secs(60));
+        rotator
+    }
+
+    #[test]
+    fn failures_on_the_current_route_shorten_the_interval() {
+        let mut rotator = adaptive_rotator();
+        let current = rotator.current_index;
+        for _ in 0..10 {
+            rotator.observe_route(current, None);
+        }
+        rotator.adapt_interval();
+        assert_eq!(rotator.effective_interval, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn tunnels_through_other_proxies_are_not_counted() {
+        let mut rotator = adaptive_rotator();
+        let other = (rotator.current_index + 1) % 2;
+        for _ in 0..10 {
+            rotator.observe_route(other, None);
+        }
+        rotator.adapt_interval();
+        assert_eq!(rotator.route_health().samples, 0);
+        assert_eq!(rotator.effective_interval, Duration::from_secs(600));
+    }
+
+    #[test]
+    fn a_manual_rotation_resets_the_window() {
+        let mut rotator = adaptive_rotator();
+        for _ in 0..10 {
+            rotator.observe_route(rotator.current_index, None);
+        }
+        rotator.adapt_interval();
+        rotator.rotate(RotationReason::Control, false).unwrap();
+        assert_eq!(rotator.route_health().samples, 0);
+        assert_eq!(rotator.effective_interval, Duration::from_secs(600));
+    }
+
+    /// Every subcommand a user can type, `help` included.
+    fn subcommands() -> Vec<String> {
+        let mut command = installed_command();
+        command.build();
+        command
+            .get_subcommands()
+            .filter(|sub| !sub.is_hide_set())
+            .map(|sub| sub.get_name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn completions_for_every_shell_cover_every_subcommand() {
+        let subcommands = subcommands();
+        assert!(subcommands.iter().any(|sub| sub == "start"), "{:?}", subcommands);
+        for shell in <clap_complete::Shell as clap::ValueEnum>::value_variants() {
+            let script = completion_script(*shell);
+            assert!(!script.trim().is_empty(), "{}", shell);
+            for sub in &subcommands {
+                assert!(script.contains(sub.as_str()), "{} lacks {}", shell, sub);
+            }
+        }
+    }
+
+    #[test]
+    fn completions_know_start_options() {
+        let script = completion_script(clap_complete::Shell::Bash);
+        for option in ["--rotate", "--listen", "--strict-flags", "--session"] {
+            assert!(script.contains(option), "{}", option);
+        }
+    }
+
+    #[test]
+    fn the_manpage_covers_every_subcommand() {
+        let page = manpage().unwrap();
+        assert!(page.contains(".TH veko_dome"));
+        for sub in subcommands() {
+            // roff spells a hyphen \-
+            assert!(page.contains(&sub.replace('-', "\\-")), "{}", sub);
+        }
+    }
+
+    /// Each request a recording proxy answered: the client port of its
+    /// connection and its Cookie header.
+    type Seen = Arc<Mutex<Vec<(u16, Option<String>)>>>;
+
+    /// A plain HTTP proxy on loopback that answers every request itself:
+    /// it sets a cookie and keeps connections open.
+    fn recording_proxy(seen: Seen) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { return };
+                let connection = stream.peer_addr().unwrap().port();
+                let seen = seen.clone();
+                thread::spawn(move || {
+                    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                    let mut writer = stream;
+                    loop {
+                        let mut cookie = None;
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        loop {
+                            line.clear();
+                            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                                return;
+                            }
+                            if line.trim().is_empty() {
+                                break;
+                            }
+                            if let Some((name, value)) = line.split_once(':') {
+                                if name.eq_ignore_ascii_case("cookie") {
+                                    cookie = Some(value.trim().to_string());
+                                }
+                            }
+                        }
+                        seen.lock().unwrap().push((connection, cookie));
+                        let answer = "HTTP/1.1 200 OK\r\nSet-Cookie: id=first-identity; Path=/\r\n\
+                                      Content-Length: 2\r\n\r\nok";
+                        if writer.write_all(answer.as_bytes()).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn a_rotation_onto_the_same_route_carries_no_cookie_or_connection_over() {
+        let seen = Seen::default();
+        // One proxy, so the rotation keeps the route and only the new
+        // identity tells the clients apart
+        let proxy = ProxyEntry::parse(&recording_proxy(seen.clone())).unwrap();
+        let rotator = Arc::new(Mutex::new(ProxyRotator::new(vec![proxy], 0).unwrap()));
+        let mut profile = SecurityProfile::new(DEFAULT_MODE).unwrap();
+        profile.keepalive = true;
+        profile.cookies = true;
+        profile.timeout = Duration::from_secs(5);
+        let route = RouteClient::new(profile, None, None, rotator.clone());
+        let fetch = |client: &Client| {
+            let answer = client.get("http://origin.test/").send().unwrap();
+            assert_eq!(answer.text().unwrap(), "ok");
+        };
+
+        let (before, fresh) = route.get("test client").unwrap();
+        assert!(fresh);
+        fetch(&before);
+        fetch(&before);
+        // Within an identity the cookie comes back on the same connection,
+        // so what follows tests the rotation and not the profile
+        {
+            let seen = seen.lock().unwrap();
+            assert_eq!(seen.len(), 2);
+            assert_eq!(seen[0].1, None);
+            assert_eq!(seen[1].0, seen[0].0);
+            assert_eq!(seen[1].1.as_deref(), Some("id=first-identity"));
+        }
+        assert!(!route.get("test client").unwrap().1);
+
+        rotator
+            .lock()
+            .unwrap()
+            .rotate(RotationReason::Control, false)
+            .unwrap();
+        let (after, fresh) = route.get("test client").unwrap();
+        assert!(fresh);
+        fetch(&after);
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 3);
+        assert_eq!(seen[2].1, None);
+        assert_ne!(seen[2].0, seen[0].0);
+    }
+}
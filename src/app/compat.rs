@@ -0,0 +1,136 @@
+// src/app/compat.rs
+// v1.0 command lines whose meaning has since changed. They keep doing what
+// they did in v1.0, with a warning naming what to write today, unless
+// --strict-flags makes them errors. Only the command line is looked at:
+// VEKO_* variables and the config file are newer than v1.0.
+use super::StartArgs;
+use clap::{parser::ValueSource, ArgMatches};
+
+/// A v1.0 spelling of a `start` option that now means something else.
+pub struct Legacy {
+    /// The option's id.
+    pub arg: &'static str,
+    /// The v1.0 value, as given.
+    pub value: &'static str,
+    pub spelling: &'static str,
+    /// What it did in v1.0.
+    pub meant: &'static str,
+    /// What to write today for the same.
+    pub replacement: &'static str,
+    /// Gives `args` the v1.0 meaning.
+    pub apply: fn(&mut StartArgs),
+}
+
+pub const LEGACY: &[Legacy] = &[Legacy {
+    arg: "rotate",
+    value: "0",
+    spelling: "--rotate 0",
+    // v1.0 checked every second whether the interval had passed
+    meant: "rotates every second",
+    replacement: "--rotate 1, or --rotate off to turn timed rotation off",
+    apply: |args| args.rotate = 1,
+}];
+
+impl Legacy {
+    fn used(&self, start: &ArgMatches) -> bool {
+        start.value_source(self.arg) == Some(ValueSource::CommandLine)
+            && start
+                .get_raw(self.arg)
+                .is_some_and(|mut raw| raw.any(|value| value == self.value))
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} is the v1.0 spelling and {} as it did then; write {}",
+            self.spelling, self.meant, self.replacement
+        )
+    }
+}
+
+/// Gives the legacy spellings among the `start` options in `start` their
+/// v1.0 meaning in `args`, returning a deprecation warning for each. With
+/// `strict` they are refused instead.
+pub fn resolve(
+    start: &ArgMatches,
+    args: &mut StartArgs,
+    strict: bool,
+) -> Result<Vec<String>, String> {
+    let used: Vec<&Legacy> = LEGACY.iter().filter(|legacy| legacy.used(start)).collect();
+    if strict {
+        if let Some(legacy) = used.first() {
+            return Err(format!(
+                "{} (refused under --strict-flags)",
+                legacy.describe()
+            ));
+        }
+    }
+    Ok(used
+        .into_iter()
+        .map(|legacy| {
+            (legacy.apply)(args);
+            legacy.describe()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{Cli, Commands};
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// What `start` runs with for `argv`, and the warnings it prints.
+    fn start(argv: &[&str], strict: bool) -> Result<(StartArgs, Vec<String>), String> {
+        let argv = ["veko_dome", "start"].iter().chain(argv);
+        let matches = Cli::command()
+            .try_get_matches_from(argv)
+            .map_err(|e| e.to_string())?;
+        let Commands::Start(mut args) = Cli::from_arg_matches(&matches).unwrap().command else {
+            unreachable!("parsed start");
+        };
+        let start = matches.subcommand_matches("start").unwrap();
+        let warnings = resolve(start, &mut args, strict)?;
+        Ok((*args, warnings))
+    }
+
+    #[test]
+    fn v1_invocations_keep_their_interval() {
+        for (argv, rotate) in [
+            (&[][..], 15),
+            (&["-r", "30"][..], 30),
+            (&["--rotate", "30"][..], 30),
+            (&["--rotate=45"][..], 45),
+            (&["-r", "0"][..], 1),
+            (&["-r0"][..], 1),
+            (&["--rotate", "0"][..], 1),
+            (&["--rotate=0"][..], 1),
+        ] {
+            let (args, _) = start(argv, false).unwrap();
+            assert_eq!(args.rotate, rotate, "start {}", argv.join(" "));
+        }
+    }
+
+    #[test]
+    fn only_legacy_spellings_warn() {
+        let (_, warnings) = start(&["-r", "0"], false).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("--rotate 1"), "{}", warnings[0]);
+        for argv in [&[][..], &["-r", "30"][..], &["--rotate", "off"][..]] {
+            let (_, warnings) = start(argv, false).unwrap();
+            assert!(warnings.is_empty(), "start {}", argv.join(" "));
+        }
+    }
+
+    #[test]
+    fn off_turns_timed_rotation_off() {
+        let (args, _) = start(&["--rotate", "off"], false).unwrap();
+        assert_eq!(args.rotate, 0);
+    }
+
+    #[test]
+    fn strict_flags_refuse_legacy_spellings() {
+        let e = start(&["--rotate", "0"], true).err().unwrap();
+        assert!(e.contains("--strict-flags"), "{}", e);
+        assert!(start(&["--rotate", "30"], true).is_ok());
+    }
+}
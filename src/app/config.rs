@@ -0,0 +1,702 @@
+// src/app/config.rs
+// Settings for `start` kept in a TOML file, given with --config or found
+// in the user's config dir. An option given on the command line wins over
+// the file, and the file over the built-in default. Each key is named after
+// the option it sets. [profiles.NAME] tables hold further sets of the same
+// keys, one of which --profile lays over the top-level ones. [profile.NAME]
+// tables define security profiles that --mode picks by name.
+// Every start option can also be set by a VEKO_* variable, which wins over
+// the file but not the command line.
+use super::{is_url_source, ChainMode, ProxyFormat, StartArgs};
+use clap::{
+    builder::BoolishValueParser,
+    error::{ContextKind, ContextValue, ErrorKind},
+    parser::ValueSource,
+    ArgAction, ArgMatches, Command,
+};
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error as _,
+    fs,
+    path::{Path, PathBuf},
+};
+use veko_dome::dns::DohProvider;
+use veko_dome::forwarder::Hop;
+use veko_dome::hooks;
+use veko_dome::no_proxy::NoProxyRule;
+use veko_dome::profile::{
+    parse_error, unknown_in, unknown_profile_keys, value_enum, variant_name, Catalog, ProfileSpec,
+};
+
+/// Every key a config file or profile may set.
+const KEYS: &[&str] = &[
+    "mode",
+    "rotate",
+    "proxy",
+    "proxy_format",
+    "tor",
+    "tor_grace",
+    "tor_max_restarts",
+    "doh",
+    "no_log",
+    "no_referer",
+    "insecure_tls",
+    "on_rotate",
+    "on_failure",
+    "webhook_url",
+    "no_proxy",
+    "chain",
+];
+/// The table holding named profiles.
+const PROFILES: &str = "profiles";
+/// The table holding security profiles, which --mode picks by name.
+const SECURITY_PROFILES: &str = "profile";
+
+/// The start option a config key sets, by its clap id.
+pub fn arg_id(key: &str) -> &str {
+    match key {
+        "tor" => "tor_weight",
+        key => key,
+    }
+}
+
+/// The variable that sets the start option `id`, named like its config
+/// key: VEKO_ROTATE, and VEKO_TOR for --tor-weight.
+fn env_name(id: &str) -> String {
+    let key = match id {
+        "tor_weight" => "tor",
+        id => id,
+    };
+    format!("VEKO_{}", key.to_uppercase())
+}
+
+/// `cli` with every start option also read from its VEKO_* variable.
+/// Flags there take 1/true/yes/on and 0/false/no/off.
+pub fn with_env(cli: Command) -> Command {
+    cli.mut_subcommand("start", |start| {
+        start.mut_args(|arg| {
+            let arg = match arg.get_action() {
+                ArgAction::SetTrue => arg.value_parser(BoolishValueParser::new()),
+                _ => arg,
+            };
+            let name = env_name(arg.get_id().as_str());
+            arg.env(name)
+        })
+    })
+}
+
+/// Why each VEKO_* variable set for `start` is not a value its option
+/// takes, e.g. "VEKO_ROTATE: invalid value \"x\": invalid digit found".
+/// `cli` is as [`with_env`] returns it.
+pub fn env_errors(cli: &Command) -> Vec<String> {
+    let Some(start) = cli.find_subcommand("start") else {
+        return Vec::new();
+    };
+    let mut errors = Vec::new();
+    for arg in start.get_arguments() {
+        let Some(name) = arg.get_env() else { continue };
+        let Some(value) = env::var_os(name) else {
+            continue;
+        };
+        // The option alone, parsed from no arguments, reads only its variable
+        let Err(e) = Command::new("veko_dome")
+            .arg(arg.clone())
+            .try_get_matches_from(["veko_dome"])
+        else {
+            continue;
+        };
+        let reason = match (e.kind(), e.get(ContextKind::ValidValue)) {
+            (ErrorKind::InvalidValue, Some(ContextValue::Strings(valid))) => {
+                format!("expected one of {}", valid.join(", "))
+            }
+            _ => e
+                .source()
+                .map_or_else(|| e.kind().to_string(), |s| s.to_string()),
+        };
+        errors.push(format!(
+            "{}: invalid value {:?}: {}",
+            name.to_string_lossy(),
+            value.to_string_lossy(),
+            reason
+        ));
+    }
+    errors
+}
+
+/// Where the config is looked for without --config, e.g.
+/// ~/.config/veko-dome/config.toml.
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("veko-dome").join("config.toml"))
+}
+
+#[derive(Default, Deserialize)]
+struct Settings {
+    /// --mode
+    mode: Option<String>,
+    /// --rotate
+    rotate: Option<u64>,
+    /// --proxy
+    proxy: Option<String>,
+    /// --proxy-format
+    #[serde(default, deserialize_with = "value_enum")]
+    proxy_format: Option<ProxyFormat>,
+    /// --tor-weight
+    #[serde(default, deserialize_with = "percent")]
+    tor: Option<u8>,
+    /// --tor-grace
+    tor_grace: Option<u64>,
+    /// --tor-max-restarts
+    tor_max_restarts: Option<u32>,
+    /// --doh
+    #[serde(default, deserialize_with = "value_enum")]
+    doh: Option<DohProvider>,
+    /// --no-log
+    no_log: Option<bool>,
+    /// --no-referer
+    no_referer: Option<bool>,
+    /// --insecure-tls
+    insecure_tls: Option<bool>,
+    /// --on-rotate
+    on_rotate: Option<String>,
+    /// --on-failure
+    on_failure: Option<String>,
+    /// --webhook-url
+    #[serde(default, deserialize_with = "webhook_url")]
+    webhook_url: Option<String>,
+    /// --no-proxy
+    #[serde(default, deserialize_with = "no_proxy_rules")]
+    no_proxy: Option<Vec<NoProxyRule>>,
+    /// --chain hops, through these proxies in order
+    #[serde(default, deserialize_with = "chain_hops")]
+    chain: Option<Vec<String>>,
+}
+
+impl Settings {
+    /// These settings, with `base` filling in what they leave out.
+    fn over(self, base: Settings) -> Settings {
+        Settings {
+            mode: self.mode.or(base.mode),
+            rotate: self.rotate.or(base.rotate),
+            proxy: self.proxy.or(base.proxy),
+            proxy_format: self.proxy_format.or(base.proxy_format),
+            tor: self.tor.or(base.tor),
+            tor_grace: self.tor_grace.or(base.tor_grace),
+            tor_max_restarts: self.tor_max_restarts.or(base.tor_max_restarts),
+            doh: self.doh.or(base.doh),
+            no_log: self.no_log.or(base.no_log),
+            no_referer: self.no_referer.or(base.no_referer),
+            insecure_tls: self.insecure_tls.or(base.insecure_tls),
+            on_rotate: self.on_rotate.or(base.on_rotate),
+            on_failure: self.on_failure.or(base.on_failure),
+            webhook_url: self.webhook_url.or(base.webhook_url),
+            no_proxy: self.no_proxy.or(base.no_proxy),
+            chain: self.chain.or(base.chain),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Profiles {
+    #[serde(default)]
+    profiles: BTreeMap<String, Settings>,
+}
+
+#[derive(Deserialize)]
+struct SecurityProfiles {
+    #[serde(default)]
+    profile: BTreeMap<String, ProfileSpec>,
+}
+
+/// A config file that parsed and whose values are all valid.
+pub struct Config {
+    path: PathBuf,
+    settings: Settings,
+    profiles: BTreeMap<String, Settings>,
+    security_profiles: BTreeMap<String, ProfileSpec>,
+    /// The profile laid over the top-level settings, if any.
+    profile: Option<String>,
+    /// Keys the file sets that are not in [`KEYS`], e.g. "profiles.work.x".
+    pub unknown: Vec<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config {}: {}", path.display(), e))?;
+        // Parsed as a table to find the keys, then into the settings and
+        // the profiles, so type errors keep their position
+        let table: toml::Table = toml::from_str(&text).map_err(|e| parse_error(path, &text, &e))?;
+        let settings: Settings = toml::from_str(&text).map_err(|e| parse_error(path, &text, &e))?;
+        let Profiles { profiles } =
+            toml::from_str(&text).map_err(|e| parse_error(path, &text, &e))?;
+        let SecurityProfiles { profile } =
+            toml::from_str(&text).map_err(|e| parse_error(path, &text, &e))?;
+        Ok(Config {
+            path: path.to_path_buf(),
+            settings,
+            profiles,
+            security_profiles: profile,
+            profile: None,
+            unknown: unknown_keys(&table),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Names of the profiles the file defines, in order.
+    pub fn profile_names(&self) -> Vec<&str> {
+        self.profiles.keys().map(String::as_str).collect()
+    }
+
+    /// The security profiles the file defines as [profile.NAME].
+    pub fn catalog(&self) -> Catalog<'_> {
+        Catalog {
+            path: &self.path,
+            profiles: &self.security_profiles,
+        }
+    }
+
+    /// Lays the profile `name` over the top-level settings.
+    pub fn select(&mut self, name: &str) -> Result<(), String> {
+        let Some(profile) = self.profiles.remove(name) else {
+            let available = match self.profile_names() {
+                names if names.is_empty() => "it defines none".to_string(),
+                names => format!("it has {}", names.join(", ")),
+            };
+            return Err(format!(
+                "No profile {:?} in {}; {}",
+                name,
+                self.path.display(),
+                available
+            ));
+        };
+        self.settings = profile.over(std::mem::take(&mut self.settings));
+        self.profile = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Where the settings come from, e.g. "profile work in config.toml".
+    pub fn describe(&self) -> String {
+        match &self.profile {
+            Some(name) => format!("profile {} in {}", name, self.path.display()),
+            None => self.path.display().to_string(),
+        }
+    }
+
+    /// Sets every option in `args` the file has a value for, unless
+    /// `given` shows it on the command line or in its variable. Returns
+    /// the keys taken.
+    pub fn apply(&self, args: &mut StartArgs, given: &ArgMatches) -> Vec<&'static str> {
+        let given_directly = |ids: &[&str]| {
+            ids.iter().any(|id| {
+                matches!(
+                    given.value_source(id),
+                    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                )
+            })
+        };
+        let s = &self.settings;
+        let mut taken = Vec::new();
+        if let Some(mode) = s.mode.as_ref().filter(|_| !given_directly(&["mode"])) {
+            args.mode = mode.clone();
+            taken.push("mode");
+        }
+        if let Some(rotate) = s.rotate.filter(|_| !given_directly(&["rotate"])) {
+            args.rotate = rotate;
+            taken.push("rotate");
+        }
+        if let Some(proxy) = s.proxy.as_ref().filter(|_| !given_directly(&["proxy"])) {
+            args.proxy = Some(self.relative_source(proxy));
+            taken.push("proxy");
+        }
+        if let Some(format) = s
+            .proxy_format
+            .filter(|_| !given_directly(&["proxy_format"]))
+        {
+            args.proxy_format = format;
+            taken.push("proxy_format");
+        }
+        if let Some(tor) = s.tor.filter(|_| !given_directly(&["tor_weight"])) {
+            args.tor_weight = tor;
+            taken.push("tor");
+        }
+        if let Some(grace) = s.tor_grace.filter(|_| !given_directly(&["tor_grace"])) {
+            args.tor_grace = grace;
+            taken.push("tor_grace");
+        }
+        if let Some(restarts) = s
+            .tor_max_restarts
+            .filter(|_| !given_directly(&["tor_max_restarts"]))
+        {
+            args.tor_max_restarts = restarts;
+            taken.push("tor_max_restarts");
+        }
+        // Any other resolver on the command line replaces the file's
+        if let Some(doh) = s
+            .doh
+            .filter(|_| !given_directly(&["doh", "doh_url", "dot"]))
+        {
+            args.doh = Some(doh);
+            taken.push("doh");
+        }
+        // As does any other log level
+        if let Some(no_log) = s
+            .no_log
+            .filter(|_| !given_directly(&["no_log", "quiet", "verbose"]))
+        {
+            args.no_log = no_log;
+            taken.push("no_log");
+        }
+        if let Some(no_referer) = s.no_referer.filter(|_| !given_directly(&["no_referer"])) {
+            args.no_referer = no_referer;
+            taken.push("no_referer");
+        }
+        if let Some(insecure) = s
+            .insecure_tls
+            .filter(|_| !given_directly(&["insecure_tls"]))
+        {
+            args.insecure_tls = insecure;
+            taken.push("insecure_tls");
+        }
+        if let Some(command) = s
+            .on_rotate
+            .as_ref()
+            .filter(|_| !given_directly(&["on_rotate"]))
+        {
+            args.on_rotate = Some(command.clone());
+            taken.push("on_rotate");
+        }
+        if let Some(command) = s
+            .on_failure
+            .as_ref()
+            .filter(|_| !given_directly(&["on_failure"]))
+        {
+            args.on_failure = Some(command.clone());
+            taken.push("on_failure");
+        }
+        if let Some(url) = s
+            .webhook_url
+            .as_ref()
+            .filter(|_| !given_directly(&["webhook_url"]))
+        {
+            args.webhook_url = Some(url.clone());
+            taken.push("webhook_url");
+        }
+        if let Some(rules) = s
+            .no_proxy
+            .as_ref()
+            .filter(|_| !given_directly(&["no_proxy"]))
+        {
+            args.no_proxy = rules.clone();
+            taken.push("no_proxy");
+        }
+        // Any other chain on the command line replaces the file's
+        if let Some(hops) = s
+            .chain
+            .as_ref()
+            .filter(|_| !given_directly(&["chain", "chain_file"]))
+        {
+            args.chain = Some(ChainMode::Hops);
+            args.chain_hops = hops.clone();
+            taken.push("chain");
+        }
+        taken
+    }
+
+    /// A proxy list path taken as relative to the config file, where it is
+    /// most likely kept alongside.
+    fn relative_source(&self, source: &str) -> String {
+        if source == "-" || is_url_source(source) || Path::new(source).is_absolute() {
+            return source.to_string();
+        }
+        match self.path.parent() {
+            Some(dir) => dir.join(source).display().to_string(),
+            None => source.to_string(),
+        }
+    }
+}
+
+/// Each key with the value `args` ends up with, as it would be written in
+/// a config file, or `None` where the option is not set.
+pub fn effective(args: &StartArgs) -> Vec<(&'static str, Option<String>)> {
+    vec![
+        ("mode", Some(format!("{:?}", args.mode))),
+        ("rotate", Some(args.rotate.to_string())),
+        ("proxy", args.proxy.as_ref().map(|p| format!("{:?}", p))),
+        (
+            "proxy_format",
+            Some(format!("{:?}", variant_name(args.proxy_format))),
+        ),
+        ("tor", Some(args.tor_weight.to_string())),
+        ("tor_grace", Some(args.tor_grace.to_string())),
+        ("tor_max_restarts", Some(args.tor_max_restarts.to_string())),
+        ("doh", args.doh.map(|p| format!("{:?}", variant_name(p)))),
+        ("no_log", Some(args.no_log.to_string())),
+        ("no_referer", Some(args.no_referer.to_string())),
+        ("insecure_tls", Some(args.insecure_tls.to_string())),
+        (
+            "on_rotate",
+            args.on_rotate.as_ref().map(|c| format!("{:?}", c)),
+        ),
+        (
+            "on_failure",
+            args.on_failure.as_ref().map(|c| format!("{:?}", c)),
+        ),
+        (
+            "webhook_url",
+            args.webhook_url.as_ref().map(|u| format!("{:?}", u)),
+        ),
+        (
+            "no_proxy",
+            (!args.no_proxy.is_empty()).then(|| {
+                let rules: Vec<String> = args
+                    .no_proxy
+                    .iter()
+                    .map(|rule| format!("{:?}", rule.to_string()))
+                    .collect();
+                format!("[{}]", rules.join(", "))
+            }),
+        ),
+        (
+            "chain",
+            (args.chain == Some(ChainMode::Hops) && !args.chain_hops.is_empty()).then(|| {
+                let hops: Vec<String> = args
+                    .chain_hops
+                    .iter()
+                    .map(|hop| format!("{:?}", hop))
+                    .collect();
+                format!("[{}]", hops.join(", "))
+            }),
+        ),
+    ]
+}
+
+/// Keys in `table` that are not a config's, with keys inside profiles
+/// given by their full path.
+fn unknown_keys(table: &toml::Table) -> Vec<String> {
+    let mut unknown = Vec::new();
+    for (key, value) in table {
+        // Tables of the wrong type fail to parse before this
+        let inner = value.as_table().into_iter().flatten();
+        match key.as_str() {
+            key if KEYS.contains(&key) => {}
+            PROFILES => {
+                for (name, profile) in inner {
+                    if let Some(profile) = profile.as_table() {
+                        let prefix = format!("{}.{}.", PROFILES, name);
+                        unknown_in(profile, KEYS, &prefix, &mut unknown);
+                    }
+                }
+            }
+            SECURITY_PROFILES => {
+                for (name, profile) in inner {
+                    if let Some(profile) = profile.as_table() {
+                        let prefix = format!("{}.{}.", SECURITY_PROFILES, name);
+                        unknown_profile_keys(profile, &prefix, &mut unknown);
+                    }
+                }
+            }
+            _ => unknown.push(key.clone()),
+        }
+    }
+    unknown
+}
+
+fn percent<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u8>, D::Error> {
+    match u64::deserialize(d)? {
+        n @ 0..=100 => Ok(Some(n as u8)),
+        n => Err(de::Error::custom(format!(
+            "{} is not a percentage from 0 to 100",
+            n
+        ))),
+    }
+}
+
+fn webhook_url<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    hooks::parse_webhook_url(&String::deserialize(d)?)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// The proxy URLs of chain = [...], each checked as the file is read.
+fn chain_hops<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<String>>, D::Error> {
+    let hops = Vec::<String>::deserialize(d)?;
+    for (n, hop) in hops.iter().enumerate() {
+        Hop::parse(hop).map_err(|e| de::Error::custom(format!("hop {}: {}", n + 1, e)))?;
+    }
+    Ok(Some(hops))
+}
+
+fn no_proxy_rules<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<NoProxyRule>>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|rule| NoProxyRule::parse(rule).map_err(de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{Cli, Commands};
+    use clap::{CommandFactory, FromArgMatches};
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Arguments after `start` for the copy of the test binary a test runs
+    /// as its child, which resolves them and prints the result. Variables
+    /// are read for real, so they are only set on the child.
+    const CHILD_ARGV: &str = "VEKO_CONFIG_TEST_ARGV";
+    /// The config file the child lays under them.
+    const CHILD_FILE: &str = "VEKO_CONFIG_TEST_FILE";
+
+    #[test]
+    fn resolves_start_as_a_child() {
+        let Ok(argv) = env::var(CHILD_ARGV) else {
+            return;
+        };
+        let cli = with_env(Cli::command());
+        let argv = ["veko_dome", "start"]
+            .into_iter()
+            .chain(argv.split_whitespace());
+        let matches = cli.clone().try_get_matches_from(argv).unwrap_or_else(|_| {
+            let errors = env_errors(&cli).join("; ");
+            veko_dome::output::write(format_args!("errors: {}\n", errors));
+            process::exit(0);
+        });
+        let Commands::Start(mut args) = Cli::from_arg_matches(&matches).unwrap().command else {
+            unreachable!("parsed start");
+        };
+        if let Ok(path) = env::var(CHILD_FILE) {
+            let start = matches.subcommand_matches("start").unwrap();
+            Config::load(Path::new(&path))
+                .unwrap()
+                .apply(&mut args, start);
+        }
+        veko_dome::output::write(format_args!(
+            "rotate={} no_log={}\n",
+            args.rotate, args.no_log
+        ));
+        process::exit(0);
+    }
+
+    /// What `start argv` resolves to with `vars` set and `file` as the
+    /// config, as "rotate=N no_log=B", or "errors: ..." naming bad variables.
+    fn resolve(argv: &str, vars: &[(&str, &str)], file: Option<&str>) -> String {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let mut child = process::Command::new(env::current_exe().unwrap());
+        child
+            .args([
+                "app::config::tests::resolves_start_as_a_child",
+                "--exact",
+                "--test-threads=1",
+            ])
+            .env(CHILD_ARGV, argv)
+            .envs(vars.iter().copied());
+        // Whatever the environment running the tests sets stays out
+        for (name, _) in env::vars().filter(|(name, _)| name.starts_with("VEKO_")) {
+            child.env_remove(name);
+        }
+        let path = env::temp_dir().join(format!(
+            "veko-config-test-{}-{}.toml",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        if let Some(text) = file {
+            fs::write(&path, text).unwrap();
+            child.env(CHILD_FILE, &path);
+        }
+        let out = child.output().unwrap();
+        let _ = fs::remove_file(&path);
+        // The harness's "test ... " starts the line
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        let at = stdout
+            .find("rotate=")
+            .or_else(|| stdout.find("errors:"))
+            .unwrap_or_else(|| panic!("child printed no result: {}", stdout));
+        stdout[at..].lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn command_line_beats_variable_beats_file_beats_default() {
+        for (cli, var, file, rotate) in [
+            (false, false, false, 15),
+            (false, false, true, 60),
+            (false, true, false, 45),
+            (false, true, true, 45),
+            (true, false, false, 30),
+            (true, false, true, 30),
+            (true, true, false, 30),
+            (true, true, true, 30),
+        ] {
+            let argv = if cli { "--rotate 30" } else { "" };
+            let vars: &[(&str, &str)] = if var { &[("VEKO_ROTATE", "45")] } else { &[] };
+            let found = resolve(argv, vars, file.then_some("rotate = 60\n"));
+            assert!(
+                found.starts_with(&format!("rotate={} ", rotate)),
+                "cli {} var {} file {}: {}",
+                cli,
+                var,
+                file,
+                found
+            );
+        }
+    }
+
+    #[test]
+    fn flags_take_boolish_values() {
+        for value in ["1", "true", "yes", "on"] {
+            let found = resolve("", &[("VEKO_NO_LOG", value)], None);
+            assert!(found.ends_with("no_log=true"), "{}: {}", value, found);
+        }
+        for value in ["0", "false", "no", "off"] {
+            let found = resolve("", &[("VEKO_NO_LOG", value)], None);
+            assert!(found.ends_with("no_log=false"), "{}: {}", value, found);
+        }
+    }
+
+    #[test]
+    fn a_flag_turned_off_by_its_variable_beats_the_file() {
+        let file = Some("no_log = true\n");
+        assert!(resolve("", &[], file).ends_with("no_log=true"));
+        assert!(resolve("", &[("VEKO_NO_LOG", "0")], file).ends_with("no_log=false"));
+        assert!(resolve("--no-log", &[("VEKO_NO_LOG", "0")], None).ends_with("no_log=true"));
+    }
+
+    #[test]
+    fn invalid_values_name_their_variable() {
+        let found = resolve(
+            "",
+            &[("VEKO_ROTATE", "soon"), ("VEKO_NO_LOG", "maybe")],
+            None,
+        );
+        assert!(found.starts_with("errors:"), "{}", found);
+        assert!(
+            found.contains("VEKO_ROTATE: invalid value \"soon\""),
+            "{}",
+            found
+        );
+        assert!(
+            found.contains("VEKO_NO_LOG: invalid value \"maybe\""),
+            "{}",
+            found
+        );
+    }
+
+    #[test]
+    fn variables_are_named_after_config_keys() {
+        assert_eq!(env_name("rotate"), "VEKO_ROTATE");
+        assert_eq!(env_name("no_log"), "VEKO_NO_LOG");
+        assert_eq!(env_name("tor_weight"), "VEKO_TOR");
+        for key in KEYS {
+            assert_eq!(
+                env_name(arg_id(key)),
+                format!("VEKO_{}", key.to_uppercase())
+            );
+        }
+    }
+}
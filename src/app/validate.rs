@@ -0,0 +1,1007 @@
+// src/app/validate.rs
+// Cross-option checks for `start`. They run before anything is launched, and
+// every violation is reported at once rather than one per attempt.
+use super::{is_url_source, ChainMode, StartArgs, TorBackendKind};
+use clap::ValueEnum;
+use std::net::SocketAddr;
+use veko_dome::listener::{ListenKind, ListenSpec};
+use veko_dome::logging::LogFormat;
+use veko_dome::{geo, tor_integration};
+
+enum Relation {
+    /// Both sides may not be in effect together.
+    ConflictsWith,
+    /// The option is meaningless unless the other one is given.
+    Requires,
+}
+
+struct Rule {
+    relation: Relation,
+    /// How the other side is named when it is missing.
+    other_name: &'static str,
+    /// The option as given, if it is in effect.
+    option: fn(&StartArgs) -> Option<String>,
+    /// The other side as given, if it is in effect.
+    other: fn(&StartArgs) -> Option<String>,
+}
+
+const RULES: &[Rule] = &[
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--no-portal-check",
+        option: |a| {
+            a.wait_for_network
+                .map(|wait| format!("--wait-for-network {}s", wait.as_secs()))
+        },
+        other: |a| a.no_portal_check.then(|| "--no-portal-check".to_string()),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--proxy-pubkey",
+        option: |a| {
+            a.proxy_sig
+                .as_ref()
+                .map(|path| format!("--proxy-sig {}", path.display()))
+        },
+        other: |a| {
+            a.proxy_pubkey
+                .as_ref()
+                .map(|path| format!("--proxy-pubkey {}", path.display()))
+        },
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--proxy-sig",
+        option: |a| {
+            a.proxy_pubkey
+                .as_ref()
+                .map(|path| format!("--proxy-pubkey {}", path.display()))
+        },
+        other: |a| {
+            a.proxy_sig
+                .as_ref()
+                .map(|path| format!("--proxy-sig {}", path.display()))
+        },
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--daemon",
+        option: |a| {
+            a.pid_file
+                .as_ref()
+                .map(|path| format!("--pid-file {}", path.display()))
+        },
+        other: |a| a.daemon.then(|| "--daemon".to_string()),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--daemon",
+        option: |a| a.dry_run.then(|| "--dry-run".to_string()),
+        other: |a| a.daemon.then(|| "--daemon".to_string()),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--chain proxy-then-tor",
+        option: |a| arti(a).then(|| "--tor-backend arti".to_string()),
+        other: |a| {
+            (a.chain == Some(ChainMode::ProxyThenTor)).then(|| {
+                "--chain proxy-then-tor, since Arti cannot reach Tor through a proxy".to_string()
+            })
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--tor-binary",
+        option: |a| arti(a).then(|| "--tor-backend arti".to_string()),
+        other: |a| {
+            a.tor_binary
+                .as_ref()
+                .map(|path| format!("--tor-binary {}", path.display()))
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--chain",
+        option: |a| (a.tor_weight > 0).then(|| format!("--tor-weight {}", a.tor_weight)),
+        other: |a| a.chain.map(|mode| format!("--chain {}", value_name(mode))),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--tor-weight",
+        option: |a| fallback_order(a),
+        other: |a| {
+            (a.tor_weight > 0).then(|| {
+                format!(
+                    "--tor-weight {}, since the fallback decides when routes go to Tor",
+                    a.tor_weight
+                )
+            })
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--chain",
+        option: |a| fallback_order(a),
+        other: |a| a.chain.map(|mode| format!("--chain {}", value_name(mode))),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--chain-file or a chain = [...] in the config",
+        option: |a| (a.chain == Some(ChainMode::Hops)).then(|| "--chain hops".to_string()),
+        other: |a| {
+            chain_file(a).or_else(|| (!a.chain_hops.is_empty()).then(|| "chain".to_string()))
+        },
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--chain hops",
+        option: chain_file,
+        other: |a| (a.chain == Some(ChainMode::Hops)).then(String::new),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--chain hops",
+        option: |a| {
+            a.chain_rotate
+                .map(|rotate| format!("--chain-rotate {}", value_name(rotate)))
+        },
+        other: |a| (a.chain == Some(ChainMode::Hops)).then(String::new),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--fallback-order",
+        option: |a| {
+            a.fallback_recover
+                .map(|window| format!("--fallback-recover {}s", window.as_secs()))
+        },
+        other: |a| fallback_order(a),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--chain proxy-then-tor",
+        option: |a| {
+            a.max_time_per_exit
+                .map(|cap| format!("--max-time-per-exit {}s", cap.as_secs()))
+        },
+        other: |a| {
+            (a.chain == Some(ChainMode::ProxyThenTor)).then(|| {
+                "--chain proxy-then-tor, whose exit IP is Tor's and is not checked".to_string()
+            })
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--verify-probe",
+        option: |a| {
+            a.max_time_per_exit
+                .map(|cap| format!("--max-time-per-exit {}s", cap.as_secs()))
+        },
+        other: |a| blind_verify_probe(a),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--verify-probe",
+        option: |a| a.strict.then(|| "--strict".to_string()),
+        other: |a| blind_verify_probe(a),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--verify-probe",
+        option: |a| exit_blocklist(a),
+        other: |a| blind_verify_probe(a),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--no-ip-check",
+        option: |a| exit_blocklist(a),
+        other: |a| {
+            a.no_ip_check
+                .then(|| "--no-ip-check, which leaves the exit IP unknown".to_string())
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--doh",
+        option: |a| a.doh_url.as_ref().map(|url| format!("--doh-url {}", url)),
+        other: |a| {
+            a.doh
+                .map(|provider| format!("--doh {}", value_name(provider)))
+        },
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--doh-bootstrap, to reach it without the system resolver",
+        option: |a| {
+            a.doh_url
+                .as_ref()
+                .filter(|url| url.ip().is_none())
+                .map(|url| format!("--doh-url {}", url))
+        },
+        other: |a| a.doh_bootstrap.map(|ip| ip.to_string()),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--doh or --doh-url",
+        option: |a| a.dot.as_ref().map(|server| format!("--dot {}", server)),
+        other: |a| {
+            a.doh_url
+                .as_ref()
+                .map(|url| format!("--doh-url {}", url))
+                .or(a
+                    .doh
+                    .map(|provider| format!("--doh {}", value_name(provider))))
+        },
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--doh-url",
+        option: |a| a.doh_bootstrap.map(|ip| format!("--doh-bootstrap {}", ip)),
+        other: |a| a.doh_url.as_ref().map(|url| url.to_string()),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--allow-http-ip-check",
+        option: |a| {
+            a.ip_services
+                .iter()
+                .find(|url| url.starts_with("http://"))
+                .map(|url| format!("--ip-service {}", url))
+        },
+        other: |a| a.allow_http_ip_check.then(String::new),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--precheck-probe",
+        option: |a| a.no_ip_check.then(|| "--no-ip-check".to_string()),
+        other: |a| {
+            (!a.no_precheck && a.precheck_probe.sees_exit_ip()).then(|| {
+                "--precheck-probe ip, the default, which asks an IP service; pass \
+                 --precheck-probe http204 or tls, or --no-precheck"
+                    .to_string()
+            })
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--anonymity-check",
+        option: |a| a.no_ip_check.then(|| "--no-ip-check".to_string()),
+        other: |a| {
+            anonymity_check(a).map(|option| format!("{}, which asks a judge endpoint", option))
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--no-precheck",
+        option: |a| anonymity_check(a),
+        other: |a| {
+            a.no_precheck
+                .then(|| "--no-precheck, which skips the health check it runs in".to_string())
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--verify-probe",
+        option: |a| a.no_ip_check.then(|| "--no-ip-check".to_string()),
+        other: |a| {
+            a.verify_probe.sees_exit_ip().then(|| {
+                "--verify-probe ip, the default, which asks an IP service; pass \
+                 --verify-probe http204 or tls"
+                    .to_string()
+            })
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--fail-open",
+        option: |a| {
+            a.active_hours
+                .map(|hours| format!("--active-hours {}", hours))
+        },
+        other: |a| {
+            a.fail_open
+                .then(|| "--fail-open, which has no kill switch to pause with".to_string())
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--fail-closed",
+        option: |a| a.fail_open.then(|| "--fail-open".to_string()),
+        other: |a| a.fail_closed.then(|| "--fail-closed".to_string()),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--quiet or --no-log",
+        option: |a| a.verbose.then(|| "--verbose".to_string()),
+        other: |a| {
+            (a.quiet || a.no_log).then(|| if a.quiet { "--quiet" } else { "--no-log" }.to_string())
+        },
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--log-format json",
+        option: |a| a.log_sensitive.then(|| "--log-sensitive".to_string()),
+        other: |a| (a.log_format == LogFormat::Json).then(String::new),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--log-file",
+        option: |a| a.log_shred.then(|| "--log-shred".to_string()),
+        other: |a| a.log_file.as_ref().map(|path| path.display().to_string()),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--no-log",
+        option: |a| a.quiet.then(|| "--quiet".to_string()),
+        other: |a| a.no_log.then(|| "--no-log".to_string()),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--no-log",
+        option: capture,
+        other: |a| a.no_log.then(|| "--no-log".to_string()),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "an http:// --listen, whose requests it records",
+        option: capture,
+        other: |a| {
+            a.listen
+                .iter()
+                .find(|spec| spec.kind == ListenKind::Http)
+                .map(|spec| spec.to_string())
+        },
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--listen",
+        option: |a| {
+            a.listen_allow_remote
+                .then(|| "--listen-allow-remote".to_string())
+        },
+        other: |a| listening(a).then(String::new),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--listen, whose destinations it keeps off the route",
+        option: |a| {
+            a.no_proxy
+                .first()
+                .map(|rule| format!("--no-proxy {}", rule))
+        },
+        other: |a| listening(a).then(String::new),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--metrics-listen",
+        option: |a| {
+            a.metrics_allow_remote
+                .then(|| "--metrics-allow-remote".to_string())
+        },
+        other: |a| a.metrics_listen.map(|addr| addr.to_string()),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--proxy with an http(s):// URL",
+        option: |a| {
+            a.proxy_refresh
+                .map(|secs| format!("--proxy-refresh {}", secs))
+        },
+        other: |a| a.proxy.clone().filter(|source| is_url_source(source)),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--proxy-country or --proxy-exclude-country when the exit IP is not located",
+        option: |a| {
+            a.geoip_db
+                .as_ref()
+                .map(|path| format!("--geoip-db {}", path.display()))
+        },
+        other: |a| {
+            (!a.proxy_country.is_empty()
+                || !a.proxy_exclude_country.is_empty()
+                || (!a.no_geo && !a.no_ip_check))
+                .then(String::new)
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--no-geo",
+        option: |a| {
+            (a.geo_service != geo::DEFAULT_EXIT_SERVICE)
+                .then(|| format!("--geo-service {}", a.geo_service))
+        },
+        other: |a| a.no_geo.then(|| "--no-geo".to_string()),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--proxy-exclude-country",
+        option: |a| {
+            a.proxy_country
+                .iter()
+                .find(|c| excludes(a, c))
+                .map(|c| format!("--proxy-country {}", c))
+        },
+        other: |a| {
+            let excluded = a.proxy_country.iter().find(|c| excludes(a, c))?;
+            Some(format!("--proxy-exclude-country {}", excluded))
+        },
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--doh, --doh-url or --dot",
+        option: |a| a.resolve_locally.then(|| "--resolve-locally".to_string()),
+        other: |a| (a.doh.is_some() || a.doh_url.is_some() || a.dot.is_some()).then(String::new),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--rotate-adaptive",
+        option: |a| a.rotate_min.map(|secs| format!("--rotate-min {}", secs)),
+        other: |a| a.rotate_adaptive.then(String::new),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--listen, whose tunnels it measures the route by",
+        option: |a| a.rotate_adaptive.then(|| "--rotate-adaptive".to_string()),
+        other: |a| listening(a).then(String::new),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--listen, whose requests it counts",
+        option: |a| {
+            (a.rotate_requests > 0).then(|| format!("--rotate-requests {}", a.rotate_requests))
+        },
+        other: |a| listening(a).then(String::new),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--listen or --chain, whose tunnels it counts",
+        option: |a| {
+            a.max_bytes_per_identity
+                .map(|bytes| format!("--max-bytes-per-identity {}", bytes))
+        },
+        other: |a| (listening(a) || a.chain.is_some()).then(String::new),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--rotate",
+        option: |a| {
+            a.min_dwell
+                .filter(|min| a.rotate > 0 && min.as_secs() > a.rotate)
+                .map(|min| format!("--min-dwell {}s", min.as_secs()))
+        },
+        other: |a| Some(format!("--rotate {}, a shorter interval", a.rotate)),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--rotate",
+        option: |a| {
+            a.max_identity_lifetime
+                .filter(|max| a.rotate > 0 && max.as_secs() < a.rotate)
+                .map(|max| format!("--max-identity-lifetime {}s", max.as_secs()))
+        },
+        other: |a| Some(format!("--rotate {}, a longer interval", a.rotate)),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--max-identity-lifetime",
+        option: |a| {
+            let max = a.max_identity_lifetime?;
+            a.min_dwell
+                .filter(|min| *min > max)
+                .map(|min| format!("--min-dwell {}s", min.as_secs()))
+        },
+        other: |a| {
+            a.max_identity_lifetime.map(|max| {
+                format!(
+                    "--max-identity-lifetime {}s, which is shorter",
+                    max.as_secs()
+                )
+            })
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--rotate off",
+        option: |a| (a.rotate_jitter > 0).then(|| format!("--rotate-jitter {}", a.rotate_jitter)),
+        other: |a| {
+            (a.rotate == 0).then(|| "--rotate off, which turns timed rotation off".to_string())
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--rotate off",
+        option: |a| a.rotate_adaptive.then(|| "--rotate-adaptive".to_string()),
+        other: |a| {
+            (a.rotate == 0).then(|| "--rotate off, which turns timed rotation off".to_string())
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--rotate",
+        option: |a| {
+            a.rotate_min
+                .filter(|min| *min > a.rotate)
+                .map(|min| format!("--rotate-min {}", min))
+        },
+        other: |a| {
+            Some(format!(
+                "--rotate {}, which must be the longer interval",
+                a.rotate
+            ))
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "Tor's SocksPort",
+        option: |a| listen_on(a, tor_integration::SOCKS_ADDR),
+        other: |_| Some(format!("Tor's SocksPort {}", tor_integration::SOCKS_ADDR)),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--tor-weight",
+        option: |a| listen_on(a, tor_integration::CONTROL_ADDR),
+        other: |a| {
+            (a.tor_weight > 0).then(|| {
+                format!(
+                    "--tor-weight {}, which uses Tor's ControlPort {}",
+                    a.tor_weight,
+                    tor_integration::CONTROL_ADDR
+                )
+            })
+        },
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--user-agents",
+        option: |a| {
+            a.pin_user_agent
+                .as_ref()
+                .map(|_| "--pin-user-agent".to_string())
+        },
+        other: |a| {
+            a.user_agents
+                .as_ref()
+                .map(|path| format!("--user-agents {}", path.display()))
+        },
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--decoy",
+        option: |a| a.decoy_rate.map(|rate| format!("--decoy-rate {}", rate)),
+        other: |a| a.decoy.then(String::new),
+    },
+    Rule {
+        relation: Relation::Requires,
+        other_name: "--decoy",
+        option: |a| {
+            a.decoy_targets
+                .as_ref()
+                .map(|path| format!("--decoy-targets {}", path.display()))
+        },
+        other: |a| a.decoy.then(String::new),
+    },
+    Rule {
+        relation: Relation::ConflictsWith,
+        other_name: "--http2-prior-knowledge",
+        option: |a| a.http1.then(|| "--http1".to_string()),
+        other: |a| {
+            a.http2_prior_knowledge
+                .then(|| "--http2-prior-knowledge".to_string())
+        },
+    },
+];
+
+fn value_name<T: ValueEnum>(value: T) -> String {
+    value
+        .to_possible_value()
+        .map(|v| v.get_name().to_string())
+        .unwrap_or_default()
+}
+
+fn arti(args: &StartArgs) -> bool {
+    args.tor_backend == TorBackendKind::Arti
+}
+
+fn fallback_order(args: &StartArgs) -> Option<String> {
+    args.fallback_order
+        .as_ref()
+        .map(|order| format!("--fallback-order {}", order))
+}
+
+fn chain_file(args: &StartArgs) -> Option<String> {
+    args.chain_file
+        .as_ref()
+        .map(|path| format!("--chain-file {}", path.display()))
+}
+
+fn exit_blocklist(args: &StartArgs) -> Option<String> {
+    args.exit_blocklist
+        .as_ref()
+        .map(|path| format!("--exit-blocklist {}", path.display()))
+}
+
+/// `--verify-probe` as given, if it leaves exit IPs unchecked.
+fn blind_verify_probe(args: &StartArgs) -> Option<String> {
+    (!args.verify_probe.sees_exit_ip()).then(|| {
+        format!(
+            "--verify-probe {}, which does not check the exit IP",
+            args.verify_probe.name()
+        )
+    })
+}
+
+/// `--min-anonymity` or `--anonymity-check` as given, if either is.
+fn anonymity_check(args: &StartArgs) -> Option<String> {
+    match args.min_anonymity {
+        Some(level) => Some(format!("--min-anonymity {}", level.name())),
+        None => args
+            .anonymity_check
+            .then(|| "--anonymity-check".to_string()),
+    }
+}
+
+fn capture(args: &StartArgs) -> Option<String> {
+    args.capture
+        .as_ref()
+        .map(|path| format!("--capture {}", path.display()))
+}
+
+fn listening(args: &StartArgs) -> bool {
+    !args.listen.is_empty()
+}
+
+fn excludes(args: &StartArgs, country: &str) -> bool {
+    args.proxy_exclude_country
+        .iter()
+        .any(|c| c.trim().eq_ignore_ascii_case(country.trim()))
+}
+
+/// The first `--listen` that would take the same local port as `addr`.
+fn listen_on(args: &StartArgs, addr: &str) -> Option<String> {
+    let taken: SocketAddr = addr.parse().ok()?;
+    args.listen
+        .iter()
+        .find(|spec| clashes(spec, taken))
+        .map(|spec| format!("--listen {}", spec))
+}
+
+fn clashes(spec: &ListenSpec, taken: SocketAddr) -> bool {
+    spec.addr.port() == taken.port()
+        && (spec.addr.ip() == taken.ip() || spec.addr.ip().is_unspecified())
+}
+
+/// How `args` break `rule`, naming both sides, if they do.
+fn violation(rule: &Rule, args: &StartArgs) -> Option<String> {
+    let option = (rule.option)(args)?;
+    match (&rule.relation, (rule.other)(args)) {
+        (Relation::ConflictsWith, Some(other)) => {
+            Some(format!("{} conflicts with {}", option, other))
+        }
+        (Relation::Requires, None) => Some(format!("{} requires {}", option, rule.other_name)),
+        _ => None,
+    }
+}
+
+/// Every rule `args` break, each naming both sides.
+pub fn check(args: &StartArgs) -> Vec<String> {
+    #[allow(unused_mut)]
+    let mut violations: Vec<String> = RULES
+        .iter()
+        .filter_map(|rule| violation(rule, args))
+        .collect();
+    // Rules on options that only exist in some builds
+    #[cfg(feature = "scripting")]
+    {
+        use veko_dome::decisions::RotationStrategy;
+        let scripted = args.rotation_strategy == RotationStrategy::Scripted;
+        match (&args.selection_script, scripted) {
+            (None, true) => violations
+                .push("--rotation-strategy scripted requires --selection-script".to_string()),
+            (Some(path), false) => violations.push(format!(
+                "--selection-script {} requires --rotation-strategy scripted",
+                path.display()
+            )),
+            _ => {}
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{Cli, Commands};
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn start(argv: &[&str]) -> StartArgs {
+        let argv = ["veko_dome", "start"].iter().chain(argv);
+        let matches = Cli::command()
+            .try_get_matches_from(argv)
+            .unwrap_or_else(|e| panic!("{}", e));
+        let Commands::Start(args) = Cli::from_arg_matches(&matches).unwrap().command else {
+            unreachable!("parsed start");
+        };
+        *args
+    }
+
+    /// One case per rule, in table order: arguments breaking that rule and
+    /// what it reports.
+    const CASES: &[(&[&str], &str)] = &[
+        (
+            &["--wait-for-network", "30s", "--no-portal-check"],
+            "--wait-for-network 30s conflicts with --no-portal-check",
+        ),
+        (
+            &["--proxy-sig", "list.sig"],
+            "--proxy-sig list.sig requires --proxy-pubkey",
+        ),
+        (
+            &["--proxy-pubkey", "key.pub"],
+            "--proxy-pubkey key.pub requires --proxy-sig",
+        ),
+        (
+            &["--pid-file", "dome.pid"],
+            "--pid-file dome.pid requires --daemon",
+        ),
+        (
+            &["--dry-run", "--daemon"],
+            "--dry-run conflicts with --daemon",
+        ),
+        (
+            &["--tor-backend", "arti", "--chain", "proxy-then-tor"],
+            "--tor-backend arti conflicts with --chain proxy-then-tor, since Arti cannot reach Tor through a proxy",
+        ),
+        (
+            &["--tor-backend", "arti", "--tor-binary", "/usr/bin/tor"],
+            "--tor-backend arti conflicts with --tor-binary /usr/bin/tor",
+        ),
+        (
+            &["--tor-weight", "10", "--chain", "tor-then-proxy"],
+            "--tor-weight 10 conflicts with --chain tor-then-proxy",
+        ),
+        (
+            &["--fallback-order", "proxy,tor", "--tor-weight", "20"],
+            "--fallback-order proxy,tor conflicts with --tor-weight 20, since the fallback decides when routes go to Tor",
+        ),
+        (
+            &["--fallback-order", "proxy,tor", "--chain", "tor-then-proxy"],
+            "--fallback-order proxy,tor conflicts with --chain tor-then-proxy",
+        ),
+        (
+            &["--chain", "hops"],
+            "--chain hops requires --chain-file or a chain = [...] in the config",
+        ),
+        (
+            &["--chain-file", "hops.txt"],
+            "--chain-file hops.txt requires --chain hops",
+        ),
+        (
+            &["--chain-rotate", "last"],
+            "--chain-rotate last requires --chain hops",
+        ),
+        (
+            &["--fallback-recover", "5m"],
+            "--fallback-recover 300s requires --fallback-order",
+        ),
+        (
+            &["--max-time-per-exit", "10m", "--chain", "proxy-then-tor"],
+            "--max-time-per-exit 600s conflicts with --chain proxy-then-tor, whose exit IP is Tor's and is not checked",
+        ),
+        (
+            &["--max-time-per-exit", "10m", "--verify-probe", "tls"],
+            "--max-time-per-exit 600s conflicts with --verify-probe tls, which does not check the exit IP",
+        ),
+        (
+            &["--strict", "--verify-probe", "http204"],
+            "--strict conflicts with --verify-probe http204, which does not check the exit IP",
+        ),
+        (
+            &["--exit-blocklist", "bad.txt", "--verify-probe", "tls"],
+            "--exit-blocklist bad.txt conflicts with --verify-probe tls, which does not check the exit IP",
+        ),
+        (
+            &["--exit-blocklist", "bad.txt", "--no-ip-check"],
+            "--exit-blocklist bad.txt conflicts with --no-ip-check, which leaves the exit IP unknown",
+        ),
+        (
+            &["--doh-url", "https://1.1.1.1/dns-query", "--doh", "quad9"],
+            "--doh-url https://1.1.1.1/dns-query conflicts with --doh quad9",
+        ),
+        (
+            &["--doh-url", "https://dns.example/dns-query"],
+            "--doh-url https://dns.example/dns-query requires --doh-bootstrap, to reach it without the system resolver",
+        ),
+        (
+            &["--dot", "1.1.1.1", "--doh", "quad9"],
+            "--dot 1.1.1.1:853 conflicts with --doh quad9",
+        ),
+        (
+            &["--doh-bootstrap", "1.1.1.1"],
+            "--doh-bootstrap 1.1.1.1 requires --doh-url",
+        ),
+        (
+            &["--ip-service", "http://ip.example/"],
+            "--ip-service http://ip.example/ requires --allow-http-ip-check",
+        ),
+        (
+            &["--no-ip-check", "--verify-probe", "tls"],
+            "--no-ip-check conflicts with --precheck-probe ip, the default",
+        ),
+        (
+            &["--no-ip-check", "--no-precheck", "--verify-probe", "tls", "--anonymity-check"],
+            "--no-ip-check conflicts with --anonymity-check, which asks a judge endpoint",
+        ),
+        (
+            &["--min-anonymity", "elite", "--no-precheck"],
+            "--min-anonymity elite conflicts with --no-precheck, which skips the health check it runs in",
+        ),
+        (
+            &["--no-ip-check", "--no-precheck"],
+            "--no-ip-check conflicts with --verify-probe ip, the default",
+        ),
+        (
+            &["--active-hours", "09:00-17:00", "--fail-open"],
+            "--active-hours 09:00-17:00 conflicts with --fail-open, which has no kill switch to pause with",
+        ),
+        (
+            &["--fail-open", "--fail-closed"],
+            "--fail-open conflicts with --fail-closed",
+        ),
+        (&["--verbose", "--no-log"], "--verbose conflicts with --no-log"),
+        (
+            &["--log-sensitive"],
+            "--log-sensitive requires --log-format json",
+        ),
+        (&["--log-shred"], "--log-shred requires --log-file"),
+        (&["--quiet", "--no-log"], "--quiet conflicts with --no-log"),
+        (
+            &["--capture", "cap.har", "--no-log"],
+            "--capture cap.har conflicts with --no-log",
+        ),
+        (
+            &["--capture", "cap.har"],
+            "--capture cap.har requires an http:// --listen, whose requests it records",
+        ),
+        (
+            &["--listen-allow-remote"],
+            "--listen-allow-remote requires --listen",
+        ),
+        (
+            &["--no-proxy", "*.local=direct"],
+            "--no-proxy *.local=direct requires --listen, whose destinations it keeps off the route",
+        ),
+        (
+            &["--metrics-allow-remote"],
+            "--metrics-allow-remote requires --metrics-listen",
+        ),
+        (
+            &["--proxy-refresh", "600"],
+            "--proxy-refresh 600 requires --proxy with an http(s):// URL",
+        ),
+        (
+            &["--geoip-db", "geo.mmdb", "--no-geo"],
+            "--geoip-db geo.mmdb requires --proxy-country or --proxy-exclude-country",
+        ),
+        (
+            &["--geo-service", "https://geo.example/{ip}", "--no-geo"],
+            "--geo-service https://geo.example/{ip} conflicts with --no-geo",
+        ),
+        (
+            &["--proxy-country", "DE", "--proxy-exclude-country", "de"],
+            "--proxy-country DE conflicts with --proxy-exclude-country DE",
+        ),
+        (
+            &["--resolve-locally"],
+            "--resolve-locally requires --doh, --doh-url or --dot",
+        ),
+        (&["--rotate-min", "5"], "--rotate-min 5 requires --rotate-adaptive"),
+        (
+            &["--rotate-adaptive"],
+            "--rotate-adaptive requires --listen, whose tunnels it measures the route by",
+        ),
+        (
+            &["--rotate-requests", "50"],
+            "--rotate-requests 50 requires --listen, whose requests it counts",
+        ),
+        (
+            &["--max-bytes-per-identity", "10MB"],
+            "--max-bytes-per-identity 10000000 requires --listen or --chain",
+        ),
+        (
+            &["--min-dwell", "1m", "--rotate", "30"],
+            "--min-dwell 60s conflicts with --rotate 30, a shorter interval",
+        ),
+        (
+            &["--max-identity-lifetime", "10s", "--rotate", "30"],
+            "--max-identity-lifetime 10s conflicts with --rotate 30, a longer interval",
+        ),
+        (
+            &["--min-dwell", "10s", "--max-identity-lifetime", "5s", "--rotate", "off"],
+            "--min-dwell 10s conflicts with --max-identity-lifetime 5s, which is shorter",
+        ),
+        (
+            &["--rotate-jitter", "5", "--rotate", "off"],
+            "--rotate-jitter 5 conflicts with --rotate off, which turns timed rotation off",
+        ),
+        (
+            &["--rotate-adaptive", "--rotate", "off"],
+            "--rotate-adaptive conflicts with --rotate off, which turns timed rotation off",
+        ),
+        (
+            &["--rotate-min", "30", "--rotate", "20"],
+            "--rotate-min 30 conflicts with --rotate 20, which must be the longer interval",
+        ),
+        (
+            &["--listen", "socks5://127.0.0.1:9050"],
+            "--listen socks5://127.0.0.1:9050 conflicts with Tor's SocksPort 127.0.0.1:9050",
+        ),
+        (
+            &["--listen", "http://0.0.0.0:9051", "--tor-weight", "10"],
+            "--listen http://0.0.0.0:9051 conflicts with --tor-weight 10, which uses Tor's ControlPort",
+        ),
+        (
+            &["--pin-user-agent", "curl/8.0", "--user-agents", "agents.txt"],
+            "--pin-user-agent conflicts with --user-agents agents.txt",
+        ),
+        (&["--decoy-rate", "2"], "--decoy-rate 2 requires --decoy"),
+        (
+            &["--decoy-targets", "sites.txt"],
+            "--decoy-targets sites.txt requires --decoy",
+        ),
+        (
+            &["--http1", "--http2-prior-knowledge"],
+            "--http1 conflicts with --http2-prior-knowledge",
+        ),
+    ];
+
+    #[test]
+    fn every_rule_has_a_case() {
+        assert_eq!(CASES.len(), RULES.len());
+    }
+
+    #[test]
+    fn each_rule_reports_both_sides() {
+        for ((argv, expected), rule) in CASES.iter().zip(RULES) {
+            let found = violation(rule, &start(argv));
+            assert!(
+                found.as_deref().is_some_and(|v| v.starts_with(expected)),
+                "start {}: expected {:?}, got {:?}",
+                argv.join(" "),
+                expected,
+                found
+            );
+        }
+    }
+
+    #[test]
+    fn the_defaults_break_no_rule() {
+        assert!(check(&start(&[])).is_empty(), "{:?}", check(&start(&[])));
+    }
+
+    #[test]
+    fn a_requirement_met_is_no_violation() {
+        for argv in [
+            &["--listen-allow-remote", "--listen", "socks5://0.0.0.0:1080"][..],
+            &[
+                "--proxy-refresh",
+                "600",
+                "--proxy",
+                "https://lists.example/p.txt",
+            ],
+        ] {
+            assert!(check(&start(argv)).is_empty(), "start {}", argv.join(" "));
+        }
+    }
+
+    #[test]
+    fn all_violations_are_reported_at_once() {
+        let found = check(&start(&[
+            "--listen-allow-remote",
+            "--tor-weight",
+            "10",
+            "--chain",
+            "tor-then-proxy",
+        ]));
+        assert_eq!(found.len(), 2, "{:?}", found);
+    }
+}
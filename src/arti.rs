@@ -0,0 +1,264 @@
+// src/arti.rs
+// --tor-backend arti: Tor run in-process by Arti rather than as a child
+// tor binary, for deployments that cannot ship one. A SOCKS5 port on
+// SOCKS_ADDR stands in for the binary's, so everything pointed at Tor
+// keeps working. Streams share circuits only with streams that gave the
+// same SOCKS credentials, as under IsolateSOCKSAuth, and a new identity
+// moves every later stream onto circuits no earlier one used.
+use crate::socks::{self, TargetAddr};
+use crate::tor_integration::{count_new_identity, Circuits, TorBackend, TorEvent, SOCKS_ADDR};
+use arti_client::{config::TorClientConfigBuilder, IsolationToken, StreamPrefs, TorClient};
+use std::{
+    collections::HashMap,
+    io,
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::runtime::Runtime;
+use tor_rtcompat::PreferredRuntime;
+
+/// How often bootstrap progress is looked at while it is under way.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a client gets to finish its SOCKS request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct ArtiOptions {
+    /// Where Arti keeps its state, such as its guards.
+    pub state_dir: PathBuf,
+    /// Where Arti caches directory information.
+    pub cache_dir: PathBuf,
+}
+
+/// The isolation streams get by their SOCKS credentials, drawn for the
+/// current identity.
+#[derive(Default)]
+struct Isolation {
+    client: Option<Arc<TorClient<PreferredRuntime>>>,
+    tokens: HashMap<(String, String), IsolationToken>,
+}
+
+struct Shared {
+    runtime: Runtime,
+    base: Arc<TorClient<PreferredRuntime>>,
+    isolation: Mutex<Isolation>,
+    stopping: AtomicBool,
+    failed: AtomicBool,
+}
+
+impl Shared {
+    fn isolation(&self) -> std::sync::MutexGuard<'_, Isolation> {
+        self.isolation.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The client and preferences for a stream that gave `auth`.
+    fn prefs(
+        &self,
+        auth: Option<(String, String)>,
+    ) -> (Arc<TorClient<PreferredRuntime>>, StreamPrefs) {
+        let mut isolation = self.isolation();
+        let client = isolation
+            .client
+            .get_or_insert_with(|| self.base.isolated_client())
+            .clone();
+        let mut prefs = StreamPrefs::new();
+        if let Some(auth) = auth {
+            let token = *isolation
+                .tokens
+                .entry(auth)
+                .or_insert_with(IsolationToken::new);
+            prefs.set_isolation(token);
+        }
+        (client, prefs)
+    }
+}
+
+/// Arti running in this process, with its SOCKS port open until stopped.
+pub struct ArtiTor {
+    shared: Arc<Shared>,
+}
+
+impl ArtiTor {
+    /// Opens the SOCKS port and starts bootstrapping in the background,
+    /// telling `on_event` how far it has come. Fails when Arti cannot be
+    /// set up or the SOCKS port is taken.
+    pub fn start<F>(options: ArtiOptions, on_event: F) -> Result<Self, String>
+    where
+        F: Fn(TorEvent) + Send + 'static,
+    {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| format!("Cannot start Arti's runtime: {}", e))?;
+        let config =
+            TorClientConfigBuilder::from_directories(&options.state_dir, &options.cache_dir)
+                .build()
+                .map_err(|e| format!("Cannot configure Arti: {}", e))?;
+        let base = {
+            let _entered = runtime.enter();
+            TorClient::builder()
+                .config(config)
+                .create_unbootstrapped()
+                .map_err(|e| format!("Cannot start Arti: {}", e))?
+        };
+        let listener = TcpListener::bind(SOCKS_ADDR)
+            .map_err(|e| format!("Arti cannot listen on {}: {}", SOCKS_ADDR, e))?;
+        log::info!("Tor service started (Arti)");
+
+        let shared = Arc::new(Shared {
+            runtime,
+            base,
+            isolation: Mutex::default(),
+            stopping: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+        });
+        let bootstrapping = shared.clone();
+        thread::spawn(move || bootstrap(bootstrapping, on_event));
+        let serving = shared.clone();
+        thread::spawn(move || serve(serving, listener));
+        Ok(ArtiTor { shared })
+    }
+}
+
+impl TorBackend for ArtiTor {
+    fn controllable(&self) -> bool {
+        true
+    }
+
+    fn new_identity(&self) -> io::Result<()> {
+        // Drawn anew by the next stream
+        *self.shared.isolation() = Isolation::default();
+        count_new_identity();
+        Ok(())
+    }
+
+    fn bootstrapped(&self) -> io::Result<bool> {
+        Ok(self.shared.base.bootstrap_status().ready_for_traffic())
+    }
+
+    fn circuits(&self) -> io::Result<Circuits> {
+        Err(io::Error::other("Arti does not report its circuits"))
+    }
+
+    fn has_failed(&self) -> bool {
+        self.shared.failed.load(Ordering::SeqCst)
+    }
+
+    fn restarts(&self) -> u32 {
+        0
+    }
+
+    fn stop(&self) {
+        if self.shared.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Wakes the accept loop so it sees it is stopping
+        let _ = TcpStream::connect(SOCKS_ADDR);
+        log::info!("Tor service stopped");
+    }
+}
+
+impl Drop for ArtiTor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Bootstraps, reporting progress as it changes, and marks Arti failed
+/// when bootstrapping does.
+fn bootstrap<F>(shared: Arc<Shared>, on_event: F)
+where
+    F: Fn(TorEvent),
+{
+    let client = shared.base.clone();
+    let task = shared
+        .runtime
+        .spawn(async move { client.bootstrap().await });
+    let mut reported = None;
+    while !task.is_finished() && !shared.stopping.load(Ordering::SeqCst) {
+        let status = shared.base.bootstrap_status();
+        let percent = (status.as_frac() * 100.0).round() as u8;
+        if reported != Some(percent) {
+            reported = Some(percent);
+            on_event(TorEvent::Bootstrap {
+                percent,
+                status: status.to_string(),
+            });
+        }
+        thread::sleep(PROGRESS_INTERVAL);
+    }
+    if shared.stopping.load(Ordering::SeqCst) {
+        return;
+    }
+    match shared.runtime.block_on(task) {
+        Ok(Ok(())) => on_event(TorEvent::Bootstrap {
+            percent: 100,
+            status: "100%: done".to_string(),
+        }),
+        Ok(Err(e)) => {
+            shared.failed.store(true, Ordering::SeqCst);
+            on_event(TorEvent::GaveUp {
+                reason: format!("Arti could not bootstrap: {}", e),
+            });
+        }
+        Err(e) => {
+            shared.failed.store(true, Ordering::SeqCst);
+            on_event(TorEvent::GaveUp {
+                reason: format!("Arti's bootstrap stopped: {}", e),
+            });
+        }
+    }
+}
+
+fn serve(shared: Arc<Shared>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        if shared.stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        let Ok(stream) = stream else { continue };
+        let shared = shared.clone();
+        thread::spawn(move || {
+            if let Err(e) = relay(&shared, stream) {
+                log::debug!("Arti SOCKS connection ended: {}", e);
+            }
+        });
+    }
+}
+
+/// Reads a client's CONNECT and carries it over Tor.
+fn relay(shared: &Shared, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let request = socks::server_accept_auth(&mut stream)?;
+    if !socks::is_connect(&request) {
+        return socks::reply(&mut stream, socks::REPLY_COMMAND_NOT_SUPPORTED);
+    }
+    let target = match request.target {
+        TargetAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+        TargetAddr::Domain(host, port) => (host, port),
+    };
+    let (client, prefs) = shared.prefs(request.auth);
+    let tor_stream = match shared
+        .runtime
+        .block_on(client.connect_with_prefs(target, &prefs))
+    {
+        Ok(tor_stream) => tor_stream,
+        Err(e) => {
+            let _ = socks::reply(&mut stream, socks::REPLY_HOST_UNREACHABLE);
+            return Err(io::Error::other(e.to_string()));
+        }
+    };
+    socks::reply(&mut stream, socks::REPLY_SUCCEEDED)?;
+    stream.set_read_timeout(None)?;
+    stream.set_nonblocking(true)?;
+    shared.runtime.block_on(async move {
+        let mut local = tokio::net::TcpStream::from_std(stream)?;
+        let mut tor_stream = tor_stream;
+        tokio::io::copy_bidirectional(&mut local, &mut tor_stream).await?;
+        Ok(())
+    })
+}
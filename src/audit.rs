@@ -0,0 +1,440 @@
+// src/audit.rs
+// `audit`: what a route gives away, checked one thing at a time. Each
+// check passes, warns or fails with the reason. They are scored together,
+// and any failure fails the whole audit. The checks that go over the
+// network do so through an HttpProbe, like the session's own checks do.
+use crate::anonymity::{echoed_headers, PROXY_MARKERS};
+use crate::client::{check_tor_connection, get_public_ip, HttpProbe};
+use crate::profile::SecurityProfile;
+use crate::retry::Failure;
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+use std::net::IpAddr;
+
+/// Default for --echo-url: answers with the request headers it got.
+pub const DEFAULT_ECHO_URL: &str = "https://httpbin.org/headers";
+/// More redirects than a browser would ever need to follow.
+const SANE_REDIRECTS: usize = 10;
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Check {
+    /// The exit IP is not this machine's own
+    ExitIp,
+    /// The exit IP is not on the --blocklist of datacenter and VPN ranges
+    Blocklist,
+    /// Names are resolved where the configuration says
+    Dns,
+    /// Certificates are checked
+    Tls,
+    /// The exit is Tor exactly when it should be
+    Tor,
+    /// The server sees the profile's user agent and headers, and nothing
+    /// that tells of a proxy
+    Headers,
+    /// Redirects are followed within bounds
+    Redirects,
+}
+
+impl Check {
+    pub const ALL: [Check; 7] = [
+        Check::ExitIp,
+        Check::Blocklist,
+        Check::Dns,
+        Check::Tls,
+        Check::Tor,
+        Check::Headers,
+        Check::Redirects,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Check::ExitIp => "exit-ip",
+            Check::Blocklist => "blocklist",
+            Check::Dns => "dns",
+            Check::Tls => "tls",
+            Check::Tor => "tor",
+            Check::Headers => "headers",
+            Check::Redirects => "redirects",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Verdict {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Pass => "PASS",
+            Verdict::Warn => "WARN",
+            Verdict::Fail => "FAIL",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Finding {
+    pub check: Check,
+    pub verdict: Verdict,
+    pub detail: String,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    /// The route audited, without credentials.
+    pub route: String,
+    /// 0-100: a pass counts fully, a warning half.
+    pub score: u32,
+    /// False when any check failed.
+    pub passed: bool,
+    pub findings: Vec<Finding>,
+}
+
+/// Where the route's names get resolved.
+pub enum DnsPath {
+    /// By the proxy or Tor, so this machine never looks them up.
+    Remote,
+    /// By this machine, over encrypted DNS such as "DoH via cloudflare".
+    Encrypted(String),
+    /// By this machine's resolver, in the clear.
+    System,
+}
+
+/// Exits not to use, such as datacenter and VPN ranges or IPs targets have
+/// banned: addresses and CIDR ranges, one per line, `#` starting a
+/// comment. A line may also name a Tor relay by its fingerprint, which
+/// only Tor can act on.
+pub struct Blocklist {
+    ranges: Vec<(IpAddr, u8)>,
+    /// Relay fingerprints, upper case and without the `$`.
+    fingerprints: Vec<String>,
+}
+
+impl Blocklist {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut ranges = Vec::new();
+        let mut fingerprints = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fingerprint = line.strip_prefix('$').unwrap_or(line);
+            if fingerprint.len() == 40 && fingerprint.bytes().all(|b| b.is_ascii_hexdigit()) {
+                fingerprints.push(fingerprint.to_ascii_uppercase());
+                continue;
+            }
+            let bad = || format!("line {}: '{}' is not an IP or a CIDR range", n + 1, line);
+            let (ip, len) = match line.split_once('/') {
+                Some((ip, len)) => (ip, Some(len)),
+                None => (line, None),
+            };
+            let ip: IpAddr = ip.parse().map_err(|_| bad())?;
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let len = match len {
+                Some(len) => len
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|len| *len <= max)
+                    .ok_or_else(bad)?,
+                None => max,
+            };
+            ranges.push((ip, len));
+        }
+        Ok(Blocklist {
+            ranges,
+            fingerprints,
+        })
+    }
+
+    /// The entry `ip` falls in, if any.
+    pub fn find(&self, ip: IpAddr) -> Option<String> {
+        self.ranges
+            .iter()
+            .find(|(net, len)| within(ip, *net, *len))
+            .map(|(net, len)| format!("{}/{}", net, len))
+    }
+
+    /// Addresses and ranges listed.
+    pub fn ranges(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn fingerprints(&self) -> &[String] {
+        &self.fingerprints
+    }
+
+    /// The list as Tor's ExcludeExitNodes takes it: the relays by
+    /// fingerprint, then the ranges. `None` when it lists nothing.
+    pub fn exclude_exit_nodes(&self) -> Option<String> {
+        let nodes: Vec<String> = self
+            .fingerprints
+            .iter()
+            .map(|fingerprint| format!("${}", fingerprint))
+            // Tor wants IPv6 addresses in brackets
+            .chain(self.ranges.iter().map(|(net, len)| match net {
+                IpAddr::V4(net) => format!("{}/{}", net, len),
+                IpAddr::V6(net) => format!("[{}]/{}", net, len),
+            }))
+            .collect();
+        (!nodes.is_empty()).then(|| nodes.join(","))
+    }
+}
+
+/// Whether `ip` is in the range `net`/`len`.
+pub(crate) fn within(ip: IpAddr, net: IpAddr, len: u8) -> bool {
+    let (ip, net, bits) = match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => (u32::from(ip) as u128, u32::from(net) as u128, 32),
+        (IpAddr::V6(ip), IpAddr::V6(net)) => (u128::from(ip), u128::from(net), 128),
+        _ => return false,
+    };
+    let host_bits = bits - len as u32;
+    host_bits >= bits || ip >> host_bits == net >> host_bits
+}
+
+/// The route under audit and what it is held to.
+pub struct Audit<'a> {
+    pub route: String,
+    pub client: &'a dyn HttpProbe,
+    /// This machine's own IP, from a direct connection.
+    pub baseline: Option<String>,
+    pub blocklist: Option<&'a Blocklist>,
+    pub dns: DnsPath,
+    pub profile: &'a SecurityProfile,
+    /// The user agent the route presents, and the headers that go with it.
+    pub user_agent: String,
+    pub headers: HeaderMap,
+    pub expect_tor: bool,
+    pub echo_url: &'a str,
+    pub skip: &'a [Check],
+}
+
+impl Audit<'_> {
+    pub fn run(&self) -> Report {
+        let wanted = |check: &Check| !self.skip.contains(check);
+        let needs_exit = wanted(&Check::ExitIp) || wanted(&Check::Blocklist);
+        let exit_ip = needs_exit
+            .then(|| get_public_ip(self.client))
+            .flatten()
+            .map(|exit| exit.ip);
+        let findings: Vec<Finding> = Check::ALL
+            .into_iter()
+            .filter(wanted)
+            .map(|check| {
+                let (verdict, detail) = match check {
+                    Check::ExitIp => self.exit_ip(exit_ip.as_deref()),
+                    Check::Blocklist => self.blocklist(exit_ip.as_deref()),
+                    Check::Dns => self.dns(),
+                    Check::Tls => self.tls(),
+                    Check::Tor => self.tor(),
+                    Check::Headers => self.headers(),
+                    Check::Redirects => self.redirects(),
+                };
+                Finding {
+                    check,
+                    verdict,
+                    detail,
+                }
+            })
+            .collect();
+        let points: usize = findings
+            .iter()
+            .map(|f| match f.verdict {
+                Verdict::Pass => 2,
+                Verdict::Warn => 1,
+                Verdict::Fail => 0,
+            })
+            .sum();
+        Report {
+            route: self.route.clone(),
+            score: (points * 100)
+                .checked_div(findings.len() * 2)
+                .unwrap_or(100) as u32,
+            passed: findings.iter().all(|f| f.verdict != Verdict::Fail),
+            findings,
+        }
+    }
+
+    fn exit_ip(&self, exit_ip: Option<&str>) -> (Verdict, String) {
+        match (exit_ip, self.baseline.as_deref()) {
+            (None, _) => (
+                Verdict::Fail,
+                "no IP service answered through the route".to_string(),
+            ),
+            (Some(exit), Some(own)) if exit == own => (
+                Verdict::Fail,
+                "traffic exits from this machine's own IP".to_string(),
+            ),
+            (Some(_), Some(_)) => (
+                Verdict::Pass,
+                "the exit IP differs from this machine's own".to_string(),
+            ),
+            (Some(_), None) => (
+                Verdict::Warn,
+                "this machine's own IP could not be looked up to compare with".to_string(),
+            ),
+        }
+    }
+
+    fn blocklist(&self, exit_ip: Option<&str>) -> (Verdict, String) {
+        let Some(blocklist) = self.blocklist else {
+            return (
+                Verdict::Warn,
+                "no --blocklist given to compare the exit IP with".to_string(),
+            );
+        };
+        match exit_ip.and_then(|ip| ip.parse::<IpAddr>().ok()) {
+            None => (Verdict::Warn, "the exit IP is unknown".to_string()),
+            Some(ip) => match blocklist.find(ip) {
+                Some(entry) => (
+                    Verdict::Fail,
+                    format!("the exit IP is listed, in {}", entry),
+                ),
+                None => (
+                    Verdict::Pass,
+                    format!("the exit IP is not among {} entries", blocklist.ranges()),
+                ),
+            },
+        }
+    }
+
+    fn dns(&self) -> (Verdict, String) {
+        match &self.dns {
+            DnsPath::Remote => (
+                Verdict::Pass,
+                "names are resolved at the far end of the route".to_string(),
+            ),
+            DnsPath::Encrypted(how) => (
+                Verdict::Pass,
+                format!("names are resolved here over {}", how),
+            ),
+            DnsPath::System => (
+                Verdict::Warn,
+                "names are resolved with this machine's resolver, in the clear; use socks5h:// or encrypted DNS".to_string(),
+            ),
+        }
+    }
+
+    fn tls(&self) -> (Verdict, String) {
+        match self.profile.tls.insecure {
+            true => (
+                Verdict::Fail,
+                "certificates are NOT checked (--insecure-tls)".to_string(),
+            ),
+            false => (Verdict::Pass, self.profile.tls.describe()),
+        }
+    }
+
+    fn tor(&self) -> (Verdict, String) {
+        match (self.expect_tor, check_tor_connection(self.client)) {
+            (true, true) => (Verdict::Pass, "the exit is Tor, as expected".to_string()),
+            (true, false) => (
+                Verdict::Fail,
+                "the exit should be Tor, but Tor's check page says it is not".to_string(),
+            ),
+            (false, true) => (Verdict::Pass, "the exit is Tor".to_string()),
+            (false, false) => (
+                Verdict::Pass,
+                "the exit is not Tor, nor expected to be".to_string(),
+            ),
+        }
+    }
+
+    fn headers(&self) -> (Verdict, String) {
+        let body = match self.client.get_text(self.echo_url) {
+            Ok(body) => body,
+            Err(Failure::Retry(e) | Failure::Stop(e)) => {
+                return (
+                    Verdict::Warn,
+                    format!("{} did not answer: {}", self.echo_url, e),
+                )
+            }
+        };
+        if self
+            .baseline
+            .as_deref()
+            .is_some_and(|own| body.contains(own))
+        {
+            return (
+                Verdict::Fail,
+                "the server was told this machine's own IP".to_string(),
+            );
+        }
+        let Some(seen) = echoed_headers(&body) else {
+            return (
+                Verdict::Warn,
+                format!("{} did not answer with the headers it got", self.echo_url),
+            );
+        };
+        let got = |name: &str| {
+            seen.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .and_then(|(_, value)| value.as_str())
+        };
+        if got("user-agent") != Some(self.user_agent.as_str()) {
+            return (
+                Verdict::Fail,
+                format!(
+                    "the server saw user agent {:?}, not the profile's",
+                    got("user-agent").unwrap_or("none")
+                ),
+            );
+        }
+        let differing: Vec<&str> = self
+            .headers
+            .iter()
+            .filter(|(name, value)| got(name.as_str()) != value.to_str().ok())
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if !differing.is_empty() {
+            return (
+                Verdict::Fail,
+                format!(
+                    "the server did not get the profile's {}",
+                    differing.join(", ")
+                ),
+            );
+        }
+        let markers: Vec<&str> = PROXY_MARKERS
+            .into_iter()
+            .filter(|marker| got(marker).is_some())
+            .collect();
+        match markers.is_empty() {
+            true => (
+                Verdict::Pass,
+                "the server saw the profile's user agent and headers, and no proxy headers"
+                    .to_string(),
+            ),
+            false => (
+                Verdict::Warn,
+                format!(
+                    "the server was sent {}, telling of a proxy",
+                    markers.join(", ")
+                ),
+            ),
+        }
+    }
+
+    fn redirects(&self) -> (Verdict, String) {
+        match self.profile.redirect_limit {
+            0 => (Verdict::Pass, "no redirects are followed".to_string()),
+            n if n <= SANE_REDIRECTS => (
+                Verdict::Pass,
+                format!("at most {} redirects are followed", n),
+            ),
+            n => (
+                Verdict::Warn,
+                format!(
+                    "up to {} redirects are followed; more than {} is never needed and lets a server bounce requests around",
+                    n, SANE_REDIRECTS
+                ),
+            ),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+// src/capture.rs
+// The --capture request log: one record per request clients send through
+// an http:// listener, for seeing what tools actually send over the route.
+// It keeps the method, host, path, request headers, status, sizes and
+// timing, and which identity and proxy carried the request. Bodies are
+// never kept, and the values of headers that carry credentials are
+// replaced by their SHA-256, which still tells requests made with the same
+// credentials apart.
+use crate::socks::TargetAddr;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+
+/// Headers whose values are hashed rather than written.
+const HASHED: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum CaptureFormat {
+    /// One JSON object per request
+    Jsonl,
+    /// A HAR 1.2 log without bodies, which browsers' developer tools can
+    /// open; complete once the session ends
+    Har,
+}
+
+/// One request and what became of it.
+#[derive(Serialize)]
+pub struct Exchange {
+    /// When the request came in.
+    pub started: String,
+    pub client: Option<String>,
+    pub method: String,
+    pub host: String,
+    /// `None` for CONNECT, whose requests stay inside the tunnel.
+    pub path: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// The origin's, or the listener's own when it answered itself.
+    pub status: Option<u16>,
+    /// Bytes sent each way, heads included.
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    /// How long setting up the tunnel took.
+    pub connect_ms: Option<u64>,
+    pub duration_ms: u64,
+    /// Rotations before the identity that carried the request.
+    pub identity: Option<u64>,
+    /// The rotating proxy that carried it, "direct" when nothing did.
+    pub proxy: Option<String>,
+    #[serde(skip)]
+    since: Instant,
+}
+
+impl Exchange {
+    /// Starts the record of the request whose head is `head`.
+    pub fn new(client: Option<SocketAddr>, method: &str, target: &TargetAddr, head: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(head);
+        let mut lines = text.split("\r\n");
+        let uri = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .unwrap_or("");
+        let path = uri.strip_prefix("http://").map(|rest| {
+            rest.find('/')
+                .map_or_else(|| "/".to_string(), |i| rest[i..].to_string())
+        });
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), header_value(name, value.trim())))
+            .collect();
+        Exchange {
+            started: chrono::Local::now().to_rfc3339(),
+            client: client.map(|c| c.to_string()),
+            method: method.to_string(),
+            host: target.to_string(),
+            path,
+            headers,
+            status: None,
+            request_bytes: 0,
+            response_bytes: 0,
+            connect_ms: None,
+            duration_ms: 0,
+            identity: None,
+            proxy: None,
+            since: Instant::now(),
+        }
+    }
+
+    fn url(&self) -> String {
+        match &self.path {
+            Some(path) => format!("http://{}{}", self.host, path),
+            None => self.host.clone(),
+        }
+    }
+
+    fn har_entry(&self) -> serde_json::Value {
+        let headers: Vec<_> = self
+            .headers
+            .iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect();
+        json!({
+            "startedDateTime": self.started,
+            "time": self.duration_ms,
+            "request": {
+                "method": self.method,
+                "url": self.url(),
+                "httpVersion": "HTTP/1.1",
+                "cookies": [],
+                "headers": headers,
+                "queryString": [],
+                "headersSize": -1,
+                "bodySize": self.request_bytes,
+            },
+            "response": {
+                "status": self.status.unwrap_or(0),
+                "statusText": "",
+                "httpVersion": "HTTP/1.1",
+                "cookies": [],
+                "headers": [],
+                "content": { "size": self.response_bytes, "mimeType": "" },
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": self.response_bytes,
+            },
+            "cache": {},
+            "timings": {
+                "send": 0,
+                "connect": self.connect_ms.map_or(-1, |ms| ms as i64),
+                "wait": self.duration_ms.saturating_sub(self.connect_ms.unwrap_or(0)),
+                "receive": 0,
+            },
+            "_identity": self.identity,
+            "_proxy": self.proxy,
+        })
+    }
+}
+
+/// `value`, or its SHA-256 when header `name` carries credentials.
+fn header_value(name: &str, value: &str) -> String {
+    if !HASHED.iter().any(|h| name.trim().eq_ignore_ascii_case(h)) {
+        return value.to_string();
+    }
+    let digest = Sha256::digest(value.as_bytes());
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256:{}", hex)
+}
+
+struct Writer {
+    file: BufWriter<File>,
+    entries: u64,
+}
+
+/// The capture file, written as requests finish.
+pub struct Capture {
+    path: PathBuf,
+    format: CaptureFormat,
+    /// `None` once closed.
+    writer: Mutex<Option<Writer>>,
+}
+
+impl Capture {
+    /// Creates `path` afresh, readable only by its owner where that can be
+    /// set.
+    pub fn create(path: &Path, format: CaptureFormat) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = BufWriter::new(options.open(path)?);
+        if format == CaptureFormat::Har {
+            write!(
+                file,
+                "{{\"log\":{{\"version\":\"1.2\",\"creator\":{{\"name\":\"veko_dome\",\"version\":\"{}\"}},\"entries\":[",
+                env!("CARGO_PKG_VERSION")
+            )?;
+            file.flush()?;
+        }
+        Ok(Capture {
+            path: path.to_path_buf(),
+            format,
+            writer: Mutex::new(Some(Writer { file, entries: 0 })),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `exchange` out, timed up to now. A write that fails is logged
+    /// at debug level and the request left out.
+    pub fn record(&self, exchange: &mut Exchange) {
+        exchange.duration_ms = exchange.since.elapsed().as_millis() as u64;
+        let mut guard = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(writer) = guard.as_mut() else {
+            return;
+        };
+        let written = match self.format {
+            CaptureFormat::Jsonl => serde_json::to_writer(&mut writer.file, &*exchange)
+                .map_err(io::Error::from)
+                .and_then(|_| writer.file.write_all(b"\n")),
+            CaptureFormat::Har => {
+                let separator: &[u8] = if writer.entries > 0 { b",\n" } else { b"\n" };
+                writer.file.write_all(separator).and_then(|_| {
+                    serde_json::to_writer(&mut writer.file, &exchange.har_entry())
+                        .map_err(io::Error::from)
+                })
+            }
+        };
+        match written.and_then(|_| writer.file.flush()) {
+            Ok(()) => writer.entries += 1,
+            Err(e) => log::debug!("Could not write to {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// Finishes the file, after which nothing more is recorded. Returns how
+    /// many requests it holds.
+    pub fn close(&self) -> io::Result<u64> {
+        let Some(mut writer) = self.writer.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+            return Ok(0);
+        };
+        if self.format == CaptureFormat::Har {
+            writer.file.write_all(b"\n]}}\n")?;
+        }
+        writer.file.flush()?;
+        Ok(writer.entries)
+    }
+}
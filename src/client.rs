@@ -0,0 +1,491 @@
+// src/client.rs
+// HTTP clients through a route, and what is asked through them: which
+// IP the route exits from, and whether that exit is Tor. A client presents
+// its security profile and nothing else: its user agent and the headers
+// that browser sends, the profile's TLS, and no connection kept open past
+// a request unless the profile allows it.
+use crate::profile::{HttpVersion, SecurityProfile};
+use crate::{dns, fingerprint, logging, retry, throttle};
+use reqwest::{blocking::Client, redirect, Proxy, StatusCode};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+/// reqwest proxy for `url`. HTTP(S) credentials are sent with basic auth;
+/// SOCKS credentials stay in the URL, where the SOCKS handshake reads them.
+pub fn reqwest_proxy(url: &str) -> Result<Proxy, String> {
+    let url = &pin_proxy_host(url)?;
+    let parsed = reqwest::Url::parse(url).ok();
+    let proxy = match parsed {
+        Some(parsed) if parsed.scheme().starts_with("http") && !parsed.username().is_empty() => {
+            let decode = |s: &str| {
+                percent_encoding::percent_decode_str(s)
+                    .decode_utf8_lossy()
+                    .into_owned()
+            };
+            Proxy::all(strip_credentials(url)).map(|proxy| {
+                proxy.basic_auth(
+                    &decode(parsed.username()),
+                    &decode(parsed.password().unwrap_or("")),
+                )
+            })
+        }
+        _ => Proxy::all(url),
+    };
+    proxy.map_err(|e| e.to_string())
+}
+
+/// `url` as reqwest should get it with encrypted DNS on: by address, and
+/// socks5:// as socks5h://, so that reqwest never looks a name up with
+/// the system resolver.
+fn pin_proxy_host(url: &str) -> Result<String, String> {
+    let Some(mut parsed) = reqwest::Url::parse(url)
+        .ok()
+        .filter(|_| dns::active().is_some())
+    else {
+        return Ok(url.to_string());
+    };
+    // reqwest resolves socks5:// targets itself, with the system resolver
+    if parsed.scheme() == "socks5" {
+        let _ = parsed.set_scheme("socks5h");
+    }
+    let Some(host) = parsed.host_str().map(str::to_string) else {
+        return Ok(parsed.to_string());
+    };
+    if host.trim_matches(['[', ']']).parse::<IpAddr>().is_ok() {
+        return Ok(parsed.to_string());
+    }
+    if parsed.scheme() == "https" {
+        // The proxy's certificate is for its name, not its address
+        return Err(format!(
+            "https:// proxy {} must be given by IP with encrypted DNS",
+            host
+        ));
+    }
+    let addr = dns::resolve(&host, 0)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("cannot resolve {}", host))?;
+    let _ = parsed.set_ip_host(addr.ip());
+    Ok(parsed.to_string())
+}
+
+/// Proxy URL with any user:pass removed, for anything logged, shown or
+/// written to disk.
+pub fn strip_credentials(proxy: &str) -> String {
+    match reqwest::Url::parse(proxy) {
+        Ok(mut url) if !url.username().is_empty() || url.password().is_some() => {
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url.as_str().trim_end_matches('/').to_string()
+        }
+        _ => proxy.to_string(),
+    }
+}
+
+/// Binds client sockets to IPv4 under --block-ipv6, so they cannot open
+/// IPv6 connections.
+pub fn ipv4_only_local_address() -> Option<IpAddr> {
+    dns::ipv6_blocked().then_some(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+/// A client through `proxy` that presents `profile`, with the user agent
+/// the route was given. Nothing is shared with clients built before it:
+/// its connection pool, TLS session cache and cookie jar are its own.
+pub fn create_http_client(
+    proxy: &str,
+    profile: &SecurityProfile,
+    user_agent: usize,
+) -> Result<Client, String> {
+    let proxy = reqwest_proxy(proxy)
+        .map_err(|e| format!("Cannot use proxy {}: {}", strip_credentials(proxy), e))?;
+    let redirects = match profile.redirect_limit {
+        0 => redirect::Policy::none(),
+        limit => redirect::Policy::limited(limit),
+    };
+    let mut builder = Client::builder();
+    if !profile.keepalive {
+        builder = builder.pool_max_idle_per_host(0);
+    }
+    if profile.cookies {
+        builder = builder.cookie_store(true);
+    }
+    let alpn: &[&[u8]] = match profile.http_version {
+        HttpVersion::Auto if fingerprint::offers_http2(profile.user_agent(user_agent)) => {
+            &[b"h2", b"http/1.1"]
+        }
+        HttpVersion::Auto => &[b"http/1.1"],
+        HttpVersion::Http1 => {
+            builder = builder.http1_only();
+            &[b"http/1.1"]
+        }
+        HttpVersion::Http2PriorKnowledge => {
+            builder = builder.http2_prior_knowledge();
+            &[b"h2"]
+        }
+    };
+    builder
+        .redirect(redirects)
+        .default_headers(profile.headers_for(user_agent))
+        .user_agent(profile.user_agent(user_agent))
+        .proxy(proxy)
+        .local_address(ipv4_only_local_address())
+        .use_preconfigured_tls(profile.tls.client_config(alpn)?)
+        .timeout(profile.timeout)
+        .build()
+        .map_err(|e| format!("Cannot build a client for the security profile: {}", e))
+}
+
+/// What the checks sent through a route go over: the IP services, Tor's
+/// check page, the http204 probe and heartbeats. A route's [`Client`] is
+/// one; anything that answers the same way can stand in for it, so the
+/// checks do not need the network to be exercised.
+pub trait HttpProbe {
+    /// GETs `url` and returns the body of a 2xx answer.
+    fn get_text(&self, url: &str) -> Result<String, retry::Failure>;
+
+    /// HEADs `url` and returns the status it answered with.
+    fn head_status(&self, url: &str) -> Result<StatusCode, retry::Failure>;
+}
+
+impl HttpProbe for Client {
+    fn get_text(&self, url: &str) -> Result<String, retry::Failure> {
+        throttle::wait(url);
+        let res = self.get(url).send()?;
+        logging::write(
+            &format!("{} answered over {:?}", service_name(url), res.version()),
+            "DEBUG",
+        );
+        Ok(res.error_for_status()?.text()?)
+    }
+
+    fn head_status(&self, url: &str) -> Result<StatusCode, retry::Failure> {
+        throttle::wait(url);
+        Ok(self.head(url).send()?.status())
+    }
+}
+
+/// Stands in for a route in tests: answers each URL from a table and
+/// remembers what it was asked. A URL not in the table fails as a refused
+/// connection would.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MockProbe {
+    answers: std::collections::HashMap<String, MockAnswer>,
+    asked: std::sync::Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) enum MockAnswer {
+    /// A 2xx answer with this body.
+    Body(&'static str),
+    /// An answer with this status and no body.
+    Status(StatusCode),
+    /// A failure another attempt may get past, like a timeout.
+    Fail(&'static str),
+}
+
+#[cfg(test)]
+impl MockProbe {
+    pub(crate) fn answer(mut self, url: &str, answer: MockAnswer) -> Self {
+        self.answers.insert(url.to_string(), answer);
+        self
+    }
+
+    /// The URLs asked for, in order, once per attempt.
+    pub(crate) fn asked(&self) -> Vec<String> {
+        self.asked.lock().unwrap().clone()
+    }
+
+    fn ask(&self, url: &str) -> Result<MockAnswer, retry::Failure> {
+        self.asked.lock().unwrap().push(url.to_string());
+        match self.answers.get(url) {
+            Some(MockAnswer::Fail(e)) => Err(retry::Failure::Retry(e.to_string())),
+            Some(answer) => Ok(answer.clone()),
+            None => Err(retry::Failure::Retry("connection refused".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+impl HttpProbe for MockProbe {
+    fn get_text(&self, url: &str) -> Result<String, retry::Failure> {
+        match self.ask(url)? {
+            MockAnswer::Body(body) => Ok(body.to_string()),
+            MockAnswer::Status(status) if status.is_client_error() => {
+                Err(retry::Failure::Stop(format!("answered {}", status)))
+            }
+            MockAnswer::Status(status) if status.is_success() => Ok(String::new()),
+            MockAnswer::Status(status) => {
+                Err(retry::Failure::Retry(format!("answered {}", status)))
+            }
+            MockAnswer::Fail(_) => unreachable!("failed in ask"),
+        }
+    }
+
+    fn head_status(&self, url: &str) -> Result<StatusCode, retry::Failure> {
+        match self.ask(url)? {
+            MockAnswer::Body(_) => Ok(StatusCode::OK),
+            MockAnswer::Status(status) => Ok(status),
+            MockAnswer::Fail(_) => unreachable!("failed in ask"),
+        }
+    }
+}
+
+/// `url` without its scheme, as the checks name a service in logs.
+fn service_name(url: &str) -> &str {
+    url.split_once("://").map_or(url, |(_, rest)| rest)
+}
+
+/// Services asked for the exit IP, in order, unless --ip-service is given.
+/// The next one is only asked when one fails or does not answer with an IP.
+const DEFAULT_IP_SERVICES: [&str; 3] = [
+    "https://api.ipify.org",
+    "https://icanhazip.com",
+    "https://ifconfig.me/ip",
+];
+
+/// Services that only answer over IPv6, to tell whether a route carries
+/// it at all.
+const IPV6_SERVICES: [&str; 2] = ["https://api6.ipify.org", "https://ipv6.icanhazip.com"];
+
+/// An exit IP and the service that reported it.
+#[derive(Clone)]
+pub struct PublicIp {
+    pub ip: String,
+    pub service: &'static str,
+}
+
+static IP_SERVICES: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Replaces the built-in IP services; only the first call counts.
+pub fn set_ip_services(services: Vec<String>) {
+    let _ = IP_SERVICES.set(services);
+}
+
+pub fn ip_services() -> &'static [String] {
+    IP_SERVICES.get_or_init(|| DEFAULT_IP_SERVICES.map(String::from).to_vec())
+}
+
+/// Parses `--ip-service`, an http(s) URL. validate::check decides whether
+/// http is allowed.
+pub fn parse_ip_service(value: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(value).map_err(|e| format!("invalid URL: {}", e))?;
+    if !matches!(url.scheme(), "https" | "http") || url.host_str().is_none() {
+        return Err("expected an https:// URL".to_string());
+    }
+    Ok(value.to_string())
+}
+
+pub fn get_public_ip(client: &dyn HttpProbe) -> Option<PublicIp> {
+    ask_ip_services(client, ip_services().iter().map(String::as_str), |_| true)
+}
+
+/// The exit's IPv6 address, or `None` if the route does not carry IPv6.
+pub fn get_public_ipv6(client: &dyn HttpProbe) -> Option<PublicIp> {
+    ask_ip_services(client, IPV6_SERVICES, IpAddr::is_ipv6)
+}
+
+/// Longest the IP services, or Tor's check page, are retried for.
+const CHECK_DEADLINE: Duration = Duration::from_secs(20);
+
+/// Asks `services` in order and returns the first answer that is an IP
+/// `accept` takes.
+fn ask_ip_services(
+    client: &dyn HttpProbe,
+    services: impl IntoIterator<Item = &'static str>,
+    accept: fn(&IpAddr) -> bool,
+) -> Option<PublicIp> {
+    let deadline = Instant::now() + CHECK_DEADLINE;
+    services.into_iter().find_map(|service| {
+        let name = service_name(service);
+        retry::until(name, deadline, || ask_ip_service(client, service, accept))
+            .map_err(|e| logging::write(&format!("{} gave no exit IP: {}", name, e), "DEBUG"))
+            .ok()
+            .map(|ip| PublicIp { ip, service: name })
+    })
+}
+
+fn ask_ip_service(
+    client: &dyn HttpProbe,
+    service: &str,
+    accept: fn(&IpAddr) -> bool,
+) -> Result<String, retry::Failure> {
+    let ip = client.get_text(service)?.trim().to_string();
+    match ip.parse::<IpAddr>() {
+        Ok(parsed) if accept(&parsed) => Ok(ip),
+        // A portal or error page rather than an answer, and it would be again
+        _ => Err(retry::Failure::Stop(
+            "it answered with something other than an IP".to_string(),
+        )),
+    }
+}
+
+/// "Public IPv4" or "Public IPv6", whichever `ip` is.
+pub fn public_ip_label(ip: &str) -> &'static str {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => "Public IPv6",
+        _ => "Public IPv4",
+    }
+}
+
+pub fn check_tor_connection(client: &dyn HttpProbe) -> bool {
+    retry::until("Tor check", Instant::now() + CHECK_DEADLINE, || {
+        let text = client.get_text("https://check.torproject.org/api/ip")?;
+        Ok(text.contains("\"IsTor\":true"))
+    })
+    .map_err(|e| logging::write(&format!("Tor check failed: {}", e), "DEBUG"))
+    .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const TOR_CHECK: &str = "https://check.torproject.org/api/ip";
+
+    #[test]
+    fn public_ip_falls_through_to_the_next_service() {
+        let probe = MockProbe::default()
+            .answer("https://api.ipify.org", MockAnswer::Fail("timed out"))
+            .answer("https://icanhazip.com", MockAnswer::Body("203.0.113.7\n"));
+        let found = get_public_ip(&probe).unwrap();
+        assert_eq!(found.ip, "203.0.113.7");
+        assert_eq!(found.service, "icanhazip.com");
+        assert_eq!(probe.asked().last().unwrap(), "https://icanhazip.com");
+    }
+
+    #[test]
+    fn public_ip_is_none_with_every_service_down() {
+        let probe = MockProbe::default();
+        assert!(get_public_ip(&probe).is_none());
+        for service in DEFAULT_IP_SERVICES {
+            assert!(probe.asked().iter().any(|url| url == service));
+        }
+    }
+
+    #[test]
+    fn garbage_is_not_retried() {
+        let probe = MockProbe::default()
+            .answer(
+                "https://api.ipify.org",
+                MockAnswer::Body("<html>Log in</html>"),
+            )
+            .answer("https://icanhazip.com", MockAnswer::Body("not an ip"))
+            .answer(
+                "https://ifconfig.me/ip",
+                MockAnswer::Status(StatusCode::FORBIDDEN),
+            );
+        assert!(get_public_ip(&probe).is_none());
+        assert_eq!(probe.asked().len(), 3);
+    }
+
+    #[test]
+    fn ipv6_check_turns_down_an_ipv4_answer() {
+        let probe = MockProbe::default()
+            .answer(IPV6_SERVICES[0], MockAnswer::Body("203.0.113.7"))
+            .answer(IPV6_SERVICES[1], MockAnswer::Body("2001:db8::7"));
+        assert_eq!(get_public_ipv6(&probe).unwrap().ip, "2001:db8::7");
+    }
+
+    #[test]
+    fn tor_check_reads_is_tor() {
+        let through_tor = MockProbe::default().answer(
+            TOR_CHECK,
+            MockAnswer::Body(r#"{"IsTor":true,"IP":"198.51.100.1"}"#),
+        );
+        assert!(check_tor_connection(&through_tor));
+        let not_tor = MockProbe::default().answer(
+            TOR_CHECK,
+            MockAnswer::Body(r#"{"IsTor":false,"IP":"198.51.100.1"}"#),
+        );
+        assert!(!check_tor_connection(&not_tor));
+        assert_eq!(not_tor.asked().len(), 1);
+    }
+
+    /// An IP service on loopback answering every request with `status`
+    /// and `body` after `delay`. Returns its URL.
+    fn local_service(status: &'static str, body: &'static str, delay: Duration) -> &'static str {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", server.local_addr().unwrap());
+        thread::spawn(move || {
+            for mut stream in server.incoming().flatten() {
+                thread::spawn(move || {
+                    let mut request = [0; 1024];
+                    let _ = stream.read(&mut request);
+                    thread::sleep(delay);
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                });
+            }
+        });
+        url.leak()
+    }
+
+    /// A loopback URL nothing listens on.
+    fn closed_service() -> &'static str {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/", server.local_addr().unwrap()).leak()
+    }
+
+    fn quick_client() -> Client {
+        Client::builder()
+            .no_proxy()
+            .timeout(Duration::from_millis(300))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn local_service_answers_with_the_exit_ip() {
+        let service = local_service("200 OK", "203.0.113.9\n", Duration::ZERO);
+        let found = ask_ip_services(&quick_client(), [service], |_| true).unwrap();
+        assert_eq!(found.ip, "203.0.113.9");
+    }
+
+    #[test]
+    fn local_services_down_garbage_or_slow_give_no_ip() {
+        let services = [
+            closed_service(),
+            local_service("200 OK", "<html>captive portal</html>", Duration::ZERO),
+            local_service("200 OK", "203.0.113.9", Duration::from_secs(2)),
+        ];
+        let started = Instant::now();
+        assert!(ask_ip_services(&quick_client(), services, |_| true).is_none());
+        assert!(started.elapsed() < CHECK_DEADLINE);
+    }
+
+    #[test]
+    fn local_services_past_a_portal_and_an_error_give_the_v4_answer() {
+        let html = "<!DOCTYPE html><html><body>Sign in to continue</body></html>";
+        let services = [
+            local_service("200 OK", html, Duration::ZERO),
+            local_service("403 Forbidden", "198.51.100.4", Duration::ZERO),
+            local_service("200 OK", "garbage 1.2.3", Duration::ZERO),
+            local_service("200 OK", " 198.51.100.5\r\n", Duration::ZERO),
+        ];
+        let found = ask_ip_services(&quick_client(), services, |_| true).unwrap();
+        assert_eq!(found.ip, "198.51.100.5");
+        assert_eq!(found.service, service_name(services[3]));
+    }
+
+    #[test]
+    fn local_service_answering_v6_gives_the_v6_answer() {
+        let service = local_service("200 OK", "2001:db8::5\n", Duration::ZERO);
+        let found = ask_ip_services(&quick_client(), [service], IpAddr::is_ipv6).unwrap();
+        assert_eq!(found.ip, "2001:db8::5");
+        assert_eq!(public_ip_label(&found.ip), "Public IPv6");
+    }
+}
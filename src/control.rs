@@ -0,0 +1,951 @@
+// src/control.rs
+// Control socket of a running session: the versioned JSON lines protocol,
+// the client the companion subcommands use, and the serving side. On
+// Windows the socket is a named pipe; see crate::windows.
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::os::unix::{
+    fs::PermissionsExt,
+    net::{UnixListener, UnixStream},
+};
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, TryLockError},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Bumped on incompatible protocol changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const DEFAULT_SESSION: &str = "default";
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+/// How long a request waits for the previous one before the session says
+/// it is busy.
+const BUSY_WAIT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Command {
+    Status {
+        /// Also send a probe through the route and time it.
+        #[serde(default)]
+        probe: bool,
+    },
+    Stats,
+    Rotate,
+    Stop,
+    Reload,
+    /// Asks Tor for new circuits.
+    NewIdentity,
+    /// The latest log lines.
+    Log {
+        lines: usize,
+    },
+    /// What the current identity presents.
+    Identity {
+        /// Keep the proxy's credentials in its URL.
+        #[serde(default)]
+        include_secrets: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    v: u32,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ListenerStatus {
+    pub spec: String,
+    pub active_connections: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StatusSnapshot {
+    pub session: String,
+    pub pid: u32,
+    pub uptime_secs: u64,
+    /// Current route, without credentials.
+    pub current_proxy: String,
+    /// What the proxy list says about the current proxy, e.g. its country.
+    #[serde(default)]
+    pub current_details: Option<String>,
+    /// How the current proxy has fared, like "12 ok, 1 failed, 230ms";
+    /// `None` while the route is Tor.
+    #[serde(default)]
+    pub current_health: Option<String>,
+    /// Failed tunnels and heartbeats in a row through the current proxy.
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    /// What the probe `status` asked for found, when it asked.
+    #[serde(default)]
+    pub probe: Option<RouteProbe>,
+    /// What the latest heartbeat found, with --heartbeat.
+    #[serde(default)]
+    pub last_heartbeat: Option<HeartbeatResult>,
+    /// Seconds since a heartbeat, rotation check or status probe last got
+    /// through the current route; `None` when none has since it was
+    /// rotated onto.
+    #[serde(default)]
+    pub verified_secs_ago: Option<u64>,
+    /// The whole chain, when the proxy is chained with Tor.
+    #[serde(default)]
+    pub route: Option<String>,
+    /// Exit IP seen by the latest check, at startup or after a rotation.
+    #[serde(default)]
+    pub exit_ip: Option<String>,
+    /// The service that reported `exit_ip`.
+    #[serde(default)]
+    pub exit_ip_service: Option<String>,
+    /// Where `exit_ip` is, like "DE, AS3320 Deutsche Telekom AG", once known.
+    #[serde(default)]
+    pub exit_geo: Option<String>,
+    /// User agent the current route presents.
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// Whether the session was started with --no-ip-check.
+    #[serde(default)]
+    pub ip_check_disabled: bool,
+    pub strategy: String,
+    /// The interval in force, which adaptive rotation may have shortened.
+    pub rotation_interval_secs: u64,
+    #[serde(default)]
+    pub adaptive: Option<AdaptiveStatus>,
+    #[serde(default)]
+    pub next_rotation_secs: u64,
+    /// Largest random shift of each timed rotation.
+    #[serde(default)]
+    pub rotation_jitter_secs: u64,
+    /// Requests per route with --rotate-requests.
+    #[serde(default)]
+    pub rotate_requests: Option<u64>,
+    /// Requests carried by the current route so far.
+    #[serde(default)]
+    pub requests_since_rotation: u64,
+    /// Shortest time a route is kept, with --min-dwell.
+    #[serde(default)]
+    pub min_dwell_secs: Option<u64>,
+    /// Longest time a route is kept, with --max-identity-lifetime.
+    #[serde(default)]
+    pub max_identity_lifetime_secs: Option<u64>,
+    /// Bytes an identity carries before rotating, with
+    /// --max-bytes-per-identity.
+    #[serde(default)]
+    pub max_bytes_per_identity: Option<u64>,
+    /// Bytes tunnels opened under the current identity carried each way.
+    #[serde(default)]
+    pub identity_bytes_up: u64,
+    #[serde(default)]
+    pub identity_bytes_down: u64,
+    pub proxies_alive: usize,
+    pub proxies_quarantined: usize,
+    pub listeners: Vec<ListenerStatus>,
+    /// Tor/proxy blend summary when Tor takes part in rotation.
+    pub blend: Option<String>,
+    /// Geolocation provider state, when the session geolocates proxies.
+    #[serde(default)]
+    pub geo: Option<String>,
+    /// Fastest proxies by measured latency.
+    #[serde(default)]
+    pub fastest: Vec<ProxyLatency>,
+    /// Workers that crashed and were not restarted, with their panic
+    /// messages.
+    pub degraded: Vec<(String, String)>,
+    /// Why forwarding is stopped, while the kill switch is engaged.
+    #[serde(default)]
+    pub forwarding_stopped: Option<String>,
+    /// Encrypted DNS in use, e.g. "DoH via cloudflare".
+    #[serde(default)]
+    pub dns: Option<String>,
+    /// Tor's state, like "bootstrapped" or "failed after 3 restarts".
+    #[serde(default)]
+    pub tor: Option<String>,
+    /// Tor's circuits and current exit relay while routes go through Tor,
+    /// or why they are unknown.
+    #[serde(default)]
+    pub tor_circuit: Option<String>,
+    /// The --fallback-order transport in use, and why when it is not the
+    /// preferred one.
+    #[serde(default)]
+    pub transport: Option<String>,
+    /// Patterns of the --no-proxy rules the listeners send direct.
+    #[serde(default)]
+    pub bypass: Vec<String>,
+}
+
+/// Adaptive rotation's bounds and the route health it is reacting to.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdaptiveStatus {
+    pub min_secs: u64,
+    pub max_secs: u64,
+    /// Recent tunnels through the current route.
+    pub samples: usize,
+    pub error_rate_pct: u32,
+    pub p90_latency_ms: Option<u64>,
+}
+
+/// A HEAD to an IP service through the route, sent while `status` waits.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RouteProbe {
+    /// Round trip, when it was answered.
+    pub latency_ms: Option<u64>,
+    /// Why it was not.
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HeartbeatResult {
+    pub answered: bool,
+    pub secs_ago: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProxyLatency {
+    pub proxy: String,
+    pub latency_ms: u64,
+}
+
+/// Current load of one proxy.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProxyLoad {
+    pub proxy: String,
+    pub active: usize,
+    pub max_connections: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    /// Rotations this session by reason.
+    pub rotations: BTreeMap<String, u64>,
+    /// Listener connection usage per proxy; empty without listeners.
+    pub utilization: Vec<ProxyLoad>,
+    pub worker_restarts: u64,
+    pub tor_restarts: u32,
+    /// Probe of the proxy health check; `None` with --no-precheck.
+    #[serde(default)]
+    pub precheck_probe: Option<String>,
+    /// Probe sent through the route after each rotation.
+    #[serde(default)]
+    pub verify_probe: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RotateResult {
+    pub from: String,
+    pub to: String,
+}
+
+/// What the current identity presents, for tools that must look the same:
+/// all of it read at once, so never half from before a rotation.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IdentityDescriptor {
+    /// Current route, without credentials unless they were asked for.
+    pub proxy: String,
+    pub user_agent: String,
+    /// Every other header the session's own requests send, in order.
+    pub headers: Vec<(String, String)>,
+    /// Encrypted DNS in use, e.g. "DoH via cloudflare".
+    pub dns: Option<String>,
+    /// When the identity was rotated onto, or the session started.
+    pub started: String,
+    /// Rotations before this identity.
+    pub rotation: u64,
+}
+
+/// What re-reading the proxy source changed.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReloadResult {
+    pub added: usize,
+    /// Gone from the source; no longer picked once rotated away from.
+    pub retired: usize,
+    /// Retired earlier and back in the source.
+    pub restored: usize,
+}
+
+/// What the session answers with besides errors.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+    Status(Box<StatusSnapshot>),
+    Stats(StatsSnapshot),
+    Rotated(RotateResult),
+    Stopping,
+    Reloaded(ReloadResult),
+    NewIdentity,
+    Log { lines: Vec<String> },
+    Identity(IdentityDescriptor),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorKind {
+    Busy,
+    UnsupportedVersion,
+    BadRequest,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ErrorBody {
+    kind: ErrorKind,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    v: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply: Option<Reply>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// No session with a live control socket was found.
+    NoSession,
+    /// No session was named and several are running.
+    Ambiguous(Vec<String>),
+    PermissionDenied(PathBuf),
+    VersionMismatch {
+        ours: u32,
+        theirs: u32,
+    },
+    /// The session is still handling another request.
+    Busy,
+    /// The session understood the request but could not carry it out.
+    Failed(String),
+    /// The session's answer made no sense.
+    Protocol(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::NoSession => write!(f, "no running session found"),
+            ClientError::Ambiguous(sessions) => write!(
+                f,
+                "several sessions are running ({}); pass --session",
+                sessions.join(", ")
+            ),
+            ClientError::PermissionDenied(path) => {
+                write!(f, "permission denied on control socket {}", path.display())
+            }
+            ClientError::VersionMismatch { ours, theirs } => write!(
+                f,
+                "session speaks control protocol v{}, this binary speaks v{}",
+                theirs, ours
+            ),
+            ClientError::Busy => write!(f, "session is busy; try again"),
+            ClientError::Failed(msg) => write!(f, "session could not do that: {}", msg),
+            ClientError::Protocol(msg) => write!(f, "malformed reply from session: {}", msg),
+            ClientError::Io(e) => write!(f, "control socket error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Where control sockets and PID files live.
+pub fn runtime_dir() -> PathBuf {
+    dirs::runtime_dir()
+        .or_else(dirs::data_dir)
+        .unwrap_or_else(env::temp_dir)
+        .join("veko-dome")
+}
+
+#[cfg(not(windows))]
+pub fn socket_path(session: &str) -> PathBuf {
+    runtime_dir().join(format!("{}.sock", session))
+}
+
+#[cfg(windows)]
+pub fn socket_path(session: &str) -> PathBuf {
+    PathBuf::from(crate::windows::pipe_name(session))
+}
+
+pub fn pid_path(session: &str) -> PathBuf {
+    runtime_dir().join(format!("{}.pid", session))
+}
+
+#[cfg(unix)]
+pub(crate) fn pid_alive(pid: u32) -> bool {
+    // Signal 0 only checks that the process exists
+    std::process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+#[cfg(windows)]
+pub(crate) fn pid_alive(pid: u32) -> bool {
+    // tasklist names the process when it exists and says so when not
+    std::process::Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/NH", "/FO", "CSV"])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .is_ok_and(|out| String::from_utf8_lossy(&out.stdout).contains(&format!("\"{}\"", pid)))
+}
+
+/// Whether `session` looks like it is running. A unix socket is left
+/// only by a live session or a crashed one; opening a pipe to look would
+/// take up one of its instances, so on Windows the PID file decides.
+#[cfg(unix)]
+fn listening(session: &str) -> bool {
+    socket_path(session).exists()
+}
+
+#[cfg(windows)]
+fn listening(session: &str) -> bool {
+    read_pid(&pid_path(session)).is_some_and(pid_alive)
+}
+
+pub(crate) fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Names of sessions whose PID file points at a live process.
+#[cfg(any(unix, windows))]
+pub fn live_sessions() -> Vec<String> {
+    let Ok(entries) = fs::read_dir(runtime_dir()) else {
+        return Vec::new();
+    };
+    let mut sessions: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            let name = path.file_stem()?.to_str()?.to_string();
+            (path.extension()? == "pid" && read_pid(&path).is_some_and(pid_alive)).then_some(name)
+        })
+        .collect();
+    sessions.sort();
+    sessions
+}
+
+/// Talks to a running session over its control socket.
+pub struct Client {
+    path: PathBuf,
+}
+
+impl Client {
+    /// Finds the session's socket. Without a name the default session is
+    /// used, or failing that the only live session found by PID file.
+    #[cfg(any(unix, windows))]
+    pub fn connect(session: Option<&str>) -> Result<Client, ClientError> {
+        let name = match session {
+            Some(name) => name.to_string(),
+            None if listening(DEFAULT_SESSION) => DEFAULT_SESSION.to_string(),
+            None => {
+                let mut sessions = live_sessions();
+                match sessions.len() {
+                    0 => return Err(ClientError::NoSession),
+                    1 => sessions.remove(0),
+                    _ => return Err(ClientError::Ambiguous(sessions)),
+                }
+            }
+        };
+        if !listening(&name) {
+            return Err(ClientError::NoSession);
+        }
+        let client = Client {
+            path: socket_path(&name),
+        };
+        // Probe now so callers get NoSession instead of a later I/O error
+        client.open()?;
+        Ok(client)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    pub fn connect(_session: Option<&str>) -> Result<Client, ClientError> {
+        Err(ClientError::NoSession)
+    }
+
+    #[cfg(unix)]
+    fn open(&self) -> Result<UnixStream, ClientError> {
+        let attempt = || UnixStream::connect(&self.path);
+        // One retry covers a session that is just restarting its listener
+        let stream = attempt()
+            .or_else(|e| match e.kind() {
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::Interrupted => {
+                    thread::sleep(RETRY_DELAY);
+                    attempt()
+                }
+                _ => Err(e),
+            })
+            .map_err(|e| self.open_error(e))?;
+        stream
+            .set_read_timeout(Some(IO_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+            .map_err(ClientError::Io)?;
+        Ok(stream)
+    }
+
+    /// Opens the session's pipe. Pipes have no timeouts, but the session
+    /// answers each request within BUSY_WAIT or says it is busy.
+    #[cfg(windows)]
+    fn open(&self) -> Result<fs::File, ClientError> {
+        use windows_sys::Win32::Foundation::ERROR_PIPE_BUSY;
+        let attempt = || {
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&self.path)
+        };
+        // Every instance is taken while the session opens the next one
+        attempt()
+            .or_else(|e| match e.raw_os_error() {
+                Some(code) if code == ERROR_PIPE_BUSY as i32 => {
+                    thread::sleep(RETRY_DELAY);
+                    attempt()
+                }
+                _ => Err(e),
+            })
+            .map_err(|e| self.open_error(e))
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn open(&self) -> Result<std::net::TcpStream, ClientError> {
+        Err(ClientError::NoSession)
+    }
+
+    #[cfg(any(unix, windows))]
+    fn open_error(&self, e: io::Error) -> ClientError {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => ClientError::PermissionDenied(self.path.clone()),
+            io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => ClientError::NoSession,
+            _ => ClientError::Io(e),
+        }
+    }
+
+    fn call(&self, command: Command) -> Result<Reply, ClientError> {
+        let mut stream = self.open()?;
+        let mut line = serde_json::to_string(&Request {
+            v: PROTOCOL_VERSION,
+            command,
+        })
+        .map_err(|e| ClientError::Protocol(e.to_string()))?;
+        line.push('\n');
+        stream.write_all(line.as_bytes()).map_err(ClientError::Io)?;
+
+        let mut answer = String::new();
+        BufReader::new(stream)
+            .read_line(&mut answer)
+            .map_err(ClientError::Io)?;
+        let response: Response =
+            serde_json::from_str(&answer).map_err(|e| ClientError::Protocol(e.to_string()))?;
+        if response.v != PROTOCOL_VERSION {
+            return Err(ClientError::VersionMismatch {
+                ours: PROTOCOL_VERSION,
+                theirs: response.v,
+            });
+        }
+        match (response.reply, response.error) {
+            (Some(reply), None) => Ok(reply),
+            (_, Some(error)) => Err(match error.kind {
+                ErrorKind::Busy => ClientError::Busy,
+                ErrorKind::UnsupportedVersion => ClientError::VersionMismatch {
+                    ours: PROTOCOL_VERSION,
+                    theirs: response.v,
+                },
+                ErrorKind::BadRequest | ErrorKind::Failed => ClientError::Failed(error.message),
+            }),
+            (None, None) => Err(ClientError::Protocol("empty reply".to_string())),
+        }
+    }
+
+    pub fn status(&self) -> Result<StatusSnapshot, ClientError> {
+        self.status_with(false)
+    }
+
+    /// The status with a probe sent through the route, which takes up to a
+    /// few seconds.
+    pub fn probed_status(&self) -> Result<StatusSnapshot, ClientError> {
+        self.status_with(true)
+    }
+
+    fn status_with(&self, probe: bool) -> Result<StatusSnapshot, ClientError> {
+        match self.call(Command::Status { probe })? {
+            Reply::Status(status) => Ok(*status),
+            _ => Err(ClientError::Protocol("expected a status reply".to_string())),
+        }
+    }
+
+    pub fn stats(&self) -> Result<StatsSnapshot, ClientError> {
+        match self.call(Command::Stats)? {
+            Reply::Stats(stats) => Ok(stats),
+            _ => Err(ClientError::Protocol("expected a stats reply".to_string())),
+        }
+    }
+
+    /// Rotates now and returns the route before and after.
+    pub fn rotate(&self) -> Result<RotateResult, ClientError> {
+        match self.call(Command::Rotate)? {
+            Reply::Rotated(result) => Ok(result),
+            _ => Err(ClientError::Protocol("expected a rotate reply".to_string())),
+        }
+    }
+
+    /// Has the session re-read its proxy source.
+    pub fn reload(&self) -> Result<ReloadResult, ClientError> {
+        match self.call(Command::Reload)? {
+            Reply::Reloaded(result) => Ok(result),
+            _ => Err(ClientError::Protocol("expected a reload reply".to_string())),
+        }
+    }
+
+    /// Asks the session to shut down cleanly.
+    pub fn stop(&self) -> Result<(), ClientError> {
+        match self.call(Command::Stop)? {
+            Reply::Stopping => Ok(()),
+            _ => Err(ClientError::Protocol("expected a stop reply".to_string())),
+        }
+    }
+
+    /// Has the session's Tor switch to new circuits.
+    pub fn new_identity(&self) -> Result<(), ClientError> {
+        match self.call(Command::NewIdentity)? {
+            Reply::NewIdentity => Ok(()),
+            _ => Err(ClientError::Protocol(
+                "expected a new identity reply".to_string(),
+            )),
+        }
+    }
+
+    /// Up to `lines` of the session's latest log lines, oldest first.
+    pub fn log(&self, lines: usize) -> Result<Vec<String>, ClientError> {
+        match self.call(Command::Log { lines })? {
+            Reply::Log { lines } => Ok(lines),
+            _ => Err(ClientError::Protocol("expected a log reply".to_string())),
+        }
+    }
+
+    /// The current identity, with the proxy's credentials when
+    /// `include_secrets`.
+    pub fn identity(&self, include_secrets: bool) -> Result<IdentityDescriptor, ClientError> {
+        match self.call(Command::Identity { include_secrets })? {
+            Reply::Identity(identity) => Ok(identity),
+            _ => Err(ClientError::Protocol(
+                "expected an identity reply".to_string(),
+            )),
+        }
+    }
+}
+
+/// Serving side of the control socket.
+#[cfg(any(unix, windows))]
+pub struct Server {
+    #[cfg(unix)]
+    listener: UnixListener,
+    #[cfg(windows)]
+    listener: crate::windows::PipeListener,
+    #[cfg(unix)]
+    path: PathBuf,
+    pid_path: PathBuf,
+    busy: Mutex<()>,
+}
+
+#[cfg(unix)]
+impl Server {
+    /// Binds `session`'s socket (owner-only) and writes its PID file. Fails
+    /// if a live session of that name already exists.
+    pub fn bind(session: &str) -> io::Result<Server> {
+        let path = socket_path(session);
+        let pid_path = pid_path(session);
+        fs::create_dir_all(runtime_dir())?;
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("session '{}' is already running", session),
+                ));
+            }
+            // Left behind by a session that did not shut down cleanly
+            fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        fs::write(&pid_path, std::process::id().to_string())?;
+        Ok(Server {
+            listener,
+            path,
+            pid_path,
+            busy: Mutex::new(()),
+        })
+    }
+
+    /// Answers requests with `handler` until the listener fails. Each client
+    /// gets its own thread, but the handler runs for one request at a time;
+    /// the others are told the session is busy if they wait too long.
+    pub fn serve<F>(&self, handler: F)
+    where
+        F: Fn(Command) -> Result<Reply, String> + Sync,
+    {
+        thread::scope(|scope| {
+            for stream in self.listener.incoming().flatten() {
+                let handler = &handler;
+                scope.spawn(move || {
+                    if stream.set_read_timeout(Some(IO_TIMEOUT)).is_ok() {
+                        let _ = self.answer(stream, handler);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Removes the socket and PID file so clients stop finding the session.
+    pub fn close(&self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_file(&self.pid_path);
+    }
+}
+
+#[cfg(windows)]
+impl Server {
+    /// Creates `session`'s pipe, which no remote client may open, and
+    /// writes its PID file. Fails if a live session of that
+    /// name already exists.
+    pub fn bind(session: &str) -> io::Result<Server> {
+        let pid_path = pid_path(session);
+        fs::create_dir_all(runtime_dir())?;
+        let pipe = crate::windows::pipe_name(session);
+        let listener = crate::windows::PipeListener::bind(&pipe).map_err(|e| match e.kind() {
+            io::ErrorKind::AddrInUse => io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("session '{}' is already running", session),
+            ),
+            _ => e,
+        })?;
+        fs::write(&pid_path, std::process::id().to_string())?;
+        Ok(Server {
+            listener,
+            pid_path,
+            busy: Mutex::new(()),
+        })
+    }
+
+    /// Answers requests with `handler` until the pipe fails, as the unix
+    /// socket does.
+    pub fn serve<F>(&self, handler: F)
+    where
+        F: Fn(Command) -> Result<Reply, String> + Sync,
+    {
+        thread::scope(|scope| {
+            while let Ok(stream) = self.listener.accept() {
+                let handler = &handler;
+                scope.spawn(move || {
+                    let _ = self.answer(stream, handler);
+                });
+            }
+        });
+    }
+
+    /// Removes the PID file so clients stop finding the session. The pipe
+    /// goes away with the process.
+    pub fn close(&self) {
+        let _ = fs::remove_file(&self.pid_path);
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl Server {
+    fn answer<F>(&self, stream: impl Read + Write, handler: &F) -> io::Result<()>
+    where
+        F: Fn(Command) -> Result<Reply, String>,
+    {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let response = match serde_json::from_str::<Request>(&line) {
+            Err(e) => error(ErrorKind::BadRequest, e.to_string()),
+            Ok(request) if request.v != PROTOCOL_VERSION => error(
+                ErrorKind::UnsupportedVersion,
+                format!("unsupported protocol version {}", request.v),
+            ),
+            Ok(request) => match self.lock_or_busy() {
+                None => error(
+                    ErrorKind::Busy,
+                    "another request is in progress".to_string(),
+                ),
+                Some(_guard) => match handler(request.command) {
+                    Ok(reply) => Response {
+                        v: PROTOCOL_VERSION,
+                        reply: Some(reply),
+                        error: None,
+                    },
+                    Err(message) => error(ErrorKind::Failed, message),
+                },
+            },
+        };
+        let mut answer = serde_json::to_string(&response)?;
+        answer.push('\n');
+        let mut stream = reader.into_inner();
+        stream.write_all(answer.as_bytes())?;
+        stream.flush()
+    }
+
+    fn lock_or_busy(&self) -> Option<MutexGuard<'_, ()>> {
+        let deadline = Instant::now() + BUSY_WAIT;
+        loop {
+            match self.busy.try_lock() {
+                Ok(guard) => return Some(guard),
+                Err(TryLockError::Poisoned(e)) => return Some(e.into_inner()),
+                Err(TryLockError::WouldBlock) => {}
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+fn error(kind: ErrorKind, message: String) -> Response {
+    Response {
+        v: PROTOCOL_VERSION,
+        reply: None,
+        error: Some(ErrorBody { kind, message }),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_socket() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        env::temp_dir().join(format!(
+            "veko-dome-test-{}-{}.sock",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    /// A daemon that answers one request with `answer` and hands back the
+    /// request line it got.
+    fn mock_daemon(answer: &'static str) -> (Client, thread::JoinHandle<String>) {
+        let path = temp_socket();
+        let listener = UnixListener::bind(&path).unwrap();
+        let socket = path.clone();
+        let daemon = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = fs::remove_file(socket);
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let mut stream = reader.into_inner();
+            stream.write_all(answer.as_bytes()).unwrap();
+            stream.write_all(b"\n").unwrap();
+            request
+        });
+        (Client { path }, daemon)
+    }
+
+    #[test]
+    fn sockets_and_pid_files_are_per_session_in_the_runtime_dir() {
+        let dir = runtime_dir();
+        assert!(dir.is_absolute() && dir.ends_with("veko-dome"));
+        assert_eq!(socket_path("work"), dir.join("work.sock"));
+        assert_eq!(pid_path("work"), dir.join("work.pid"));
+        assert_ne!(socket_path("work"), socket_path("home"));
+    }
+
+    #[test]
+    fn requests_carry_the_protocol_version() {
+        let (client, daemon) =
+            mock_daemon(r#"{"v":1,"reply":{"type":"rotated","from":"a","to":"b"}}"#);
+        let result = client.rotate().unwrap();
+        assert_eq!((result.from.as_str(), result.to.as_str()), ("a", "b"));
+        let request: serde_json::Value = serde_json::from_str(&daemon.join().unwrap()).unwrap();
+        assert_eq!(request["v"], PROTOCOL_VERSION);
+        assert_eq!(request["cmd"], "rotate");
+    }
+
+    #[test]
+    fn a_reply_from_another_version_is_a_mismatch() {
+        let (client, _) = mock_daemon(r#"{"v":2,"reply":{"type":"stopping"}}"#);
+        assert!(matches!(
+            client.stop(),
+            Err(ClientError::VersionMismatch { ours: 1, theirs: 2 })
+        ));
+    }
+
+    #[test]
+    fn a_session_refusing_our_version_is_a_mismatch() {
+        let (client, _) = mock_daemon(
+            r#"{"v":1,"error":{"kind":"unsupported_version","message":"unsupported protocol version 1"}}"#,
+        );
+        assert!(matches!(
+            client.stop(),
+            Err(ClientError::VersionMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn a_busy_session_says_so() {
+        let (client, _) = mock_daemon(
+            r#"{"v":1,"error":{"kind":"busy","message":"another request is in progress"}}"#,
+        );
+        assert!(matches!(client.stats(), Err(ClientError::Busy)));
+    }
+
+    #[test]
+    fn a_failed_command_keeps_the_sessions_message() {
+        let (client, _) =
+            mock_daemon(r#"{"v":1,"error":{"kind":"failed","message":"no Tor to ask"}}"#);
+        match client.rotate() {
+            Err(ClientError::Failed(message)) => assert_eq!(message, "no Tor to ask"),
+            other => panic!("expected a failure, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn nonsense_and_unexpected_replies_are_protocol_errors() {
+        let (client, _) = mock_daemon("not json");
+        assert!(matches!(client.stats(), Err(ClientError::Protocol(_))));
+        let (client, _) = mock_daemon(r#"{"v":1}"#);
+        assert!(matches!(client.stats(), Err(ClientError::Protocol(_))));
+        let (client, _) = mock_daemon(r#"{"v":1,"reply":{"type":"stopping"}}"#);
+        assert!(matches!(client.stats(), Err(ClientError::Protocol(_))));
+    }
+
+    #[test]
+    fn no_socket_means_no_session() {
+        let client = Client {
+            path: temp_socket(),
+        };
+        assert!(matches!(client.status(), Err(ClientError::NoSession)));
+    }
+
+    #[test]
+    fn the_server_answers_the_client() {
+        let path = temp_socket();
+        let server = Server {
+            listener: UnixListener::bind(&path).unwrap(),
+            path: path.clone(),
+            pid_path: temp_socket(),
+            busy: Mutex::new(()),
+        };
+        thread::spawn(move || {
+            server.serve(|command| match command {
+                Command::Rotate => Ok(Reply::Rotated(RotateResult {
+                    from: "a".to_string(),
+                    to: "b".to_string(),
+                })),
+                _ => Err("not in this test".to_string()),
+            })
+        });
+        let client = Client { path };
+        assert_eq!(client.rotate().unwrap().to, "b");
+        assert!(matches!(client.stop(), Err(ClientError::Failed(m)) if m == "not in this test"));
+        let _ = fs::remove_file(&client.path);
+    }
+}
@@ -0,0 +1,359 @@
+// src/daemon.rs
+// start --daemon: a session detached from its terminal, for init scripts.
+// On unix it double-forks into a session of its own; on Windows, which
+// cannot fork, it runs itself again as a detached process. Either way it
+// leaves a PID file, and next to it how it was launched, for `stop` to
+// find it and `restart` to launch it again. A PID file whose process has
+// exited, or is another program that has since been given the PID, is
+// stale: it is removed rather than signalled.
+use crate::{control, logging};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::{self, Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Set for the process a Windows daemon runs itself again as.
+#[cfg(windows)]
+const CHILD_ENV: &str = "VEKO_DOME_DAEMON_CHILD";
+/// How often a stopping daemon is looked at.
+const EXIT_POLL: Duration = Duration::from_millis(200);
+/// How long a Windows daemon gets to write its PID file.
+#[cfg(windows)]
+const DETACH_WAIT: Duration = Duration::from_secs(10);
+
+/// How a daemon was launched, for `restart` to launch it the same way.
+#[derive(Serialize, Deserialize)]
+pub struct Launch {
+    pub session: String,
+    /// The directory it was started in, which relative paths in `args`
+    /// are relative to.
+    pub cwd: PathBuf,
+    /// Its arguments, without the program.
+    pub args: Vec<String>,
+}
+
+impl Launch {
+    /// The launch of this process, running `session`.
+    pub fn current(session: &str) -> io::Result<Self> {
+        Ok(Launch {
+            session: session.to_string(),
+            cwd: env::current_dir()?,
+            args: env::args_os()
+                .skip(1)
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+        })
+    }
+
+    /// The launch recorded next to `pid_file`, if there is one.
+    pub fn load(pid_file: &Path) -> Option<Self> {
+        let text = fs::read_to_string(launch_path(pid_file)).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    fn save(&self, pid_file: &Path) -> io::Result<()> {
+        fs::write(launch_path(pid_file), serde_json::to_string(self)?)
+    }
+
+    /// Launches it again, returning once the new daemon has detached.
+    pub fn relaunch(&self) -> io::Result<ExitStatus> {
+        Command::new(env::current_exe()?)
+            .args(&self.args)
+            .current_dir(&self.cwd)
+            .status()
+    }
+}
+
+fn launch_path(pid_file: &Path) -> PathBuf {
+    pid_file.with_extension("launch.json")
+}
+
+/// What a PID file says.
+pub enum PidFile {
+    Missing,
+    /// It names no live Veko Dome, for the given reason.
+    Stale(String),
+    Live(u32),
+}
+
+pub fn read(pid_file: &Path) -> PidFile {
+    if !pid_file.exists() {
+        return PidFile::Missing;
+    }
+    match control::read_pid(pid_file) {
+        None => PidFile::Stale("it holds no PID".to_string()),
+        Some(pid) if !control::pid_alive(pid) => PidFile::Stale(format!("PID {} has exited", pid)),
+        Some(pid) if !is_veko_dome(pid) => {
+            PidFile::Stale(format!("PID {} is now another program", pid))
+        }
+        Some(pid) => PidFile::Live(pid),
+    }
+}
+
+/// This program's file name, e.g. veko_dome.
+fn program_name() -> Option<String> {
+    let exe = env::current_exe().ok()?;
+    Some(exe.file_name()?.to_string_lossy().into_owned())
+}
+
+/// Longest process name ps reports on Linux; longer ones are cut to it.
+const COMM_LEN: usize = 15;
+
+/// Whether `theirs`, the name a process runs under, is `ours`. Linux adds
+/// " (deleted)" once the binary is replaced, and ps may have cut the name
+/// to [`COMM_LEN`] characters.
+fn same_program(ours: &str, theirs: &str) -> bool {
+    let theirs = theirs.strip_suffix(" (deleted)").unwrap_or(theirs);
+    theirs == ours || (theirs.len() == COMM_LEN && ours.starts_with(theirs))
+}
+
+/// Whether the live process `pid` runs this program. When either name
+/// cannot be told, it is taken not to, so a PID file is never trusted
+/// blindly.
+#[cfg(unix)]
+fn is_veko_dome(pid: u32) -> bool {
+    let Some(ours) = program_name() else {
+        return false;
+    };
+    let linked = fs::read_link(format!("/proc/{}/exe", pid))
+        .ok()
+        .and_then(|exe| Some(exe.file_name()?.to_string_lossy().into_owned()));
+    let theirs = linked.or_else(|| {
+        let out = Command::new("ps")
+            .args(["-p", &pid.to_string(), "-o", "comm="])
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        let comm = String::from_utf8_lossy(&out.stdout).trim().to_string();
+        // Some systems give the full path
+        Some(comm.rsplit('/').next().unwrap_or_default().to_string())
+    });
+    theirs.is_some_and(|theirs| same_program(&ours, &theirs))
+}
+
+#[cfg(windows)]
+fn is_veko_dome(pid: u32) -> bool {
+    let Some(ours) = program_name() else {
+        return false;
+    };
+    // tasklist's CSV line starts with the quoted image name
+    Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/NH", "/FO", "CSV"])
+        .stderr(Stdio::null())
+        .output()
+        .is_ok_and(|out| {
+            let line = String::from_utf8_lossy(&out.stdout).to_ascii_lowercase();
+            line.trim_start()
+                .strip_prefix('"')
+                .and_then(|rest| rest.split('"').next())
+                .is_some_and(|theirs| same_program(&ours.to_ascii_lowercase(), theirs))
+        })
+}
+
+/// Removes `pid_file` and the launch next to it.
+pub fn remove(pid_file: &Path) {
+    let _ = fs::remove_file(pid_file);
+    let _ = fs::remove_file(launch_path(pid_file));
+}
+
+/// Removes what a session that has exited leaves of itself: its PID file,
+/// launch and control socket.
+pub fn clean_up(pid_file: &Path, session: &str) {
+    remove(pid_file);
+    remove(&control::pid_path(session));
+    #[cfg(unix)]
+    let _ = fs::remove_file(control::socket_path(session));
+}
+
+/// Detaches this process from its terminal, writing the daemon's PID to
+/// `pid_file` and `launch` next to it. Returns in the daemon; the process
+/// it was called in exits once the PID file is written.
+#[cfg(unix)]
+pub fn detach(pid_file: &Path, launch: &Launch) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if let Some(dir) = pid_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // SAFETY: called before the session starts any thread, so each child
+    // is a whole copy of a single-threaded process
+    let middle = unsafe { libc::fork() };
+    if middle < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if middle > 0 {
+        let mut status = 0;
+        // SAFETY: `middle` is our child, waited on once
+        unsafe { libc::waitpid(middle, &mut status, 0) };
+        if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+            logging::write("The daemon could not write its PID file", "FATAL");
+            process::exit(1);
+        }
+        if let Some(pid) = control::read_pid(pid_file) {
+            logging::write(
+                &format!(
+                    "Veko Dome is running in the background as PID {} ({})",
+                    pid,
+                    pid_file.display()
+                ),
+                "SYSTEM",
+            );
+        }
+        process::exit(0);
+    }
+    // SAFETY: plain system calls in the forked child. Leaving the
+    // terminal's session keeps its hangup from reaching the daemon, and
+    // forking once more keeps the daemon from ever gaining a terminal
+    unsafe {
+        if libc::setsid() < 0 {
+            libc::_exit(1);
+        }
+        let daemon = libc::fork();
+        if daemon != 0 {
+            let written = daemon > 0
+                && fs::write(pid_file, daemon.to_string()).is_ok()
+                && launch.save(pid_file).is_ok();
+            if !written && daemon > 0 {
+                libc::kill(daemon, libc::SIGKILL);
+            }
+            libc::_exit(if written { 0 } else { 1 });
+        }
+    }
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        // SAFETY: both descriptors are open
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Runs this program again as a detached process with the same
+/// arguments, and exits once it has written its PID file. In that
+/// process, which is the daemon, writes the PID file and returns.
+#[cfg(windows)]
+pub fn detach(pid_file: &Path, launch: &Launch) -> io::Result<()> {
+    use std::os::windows::process::CommandExt;
+    // No console, and out of reach of Ctrl-C in the one it was started from
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+
+    if let Some(dir) = pid_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    if env::var_os(CHILD_ENV).is_some() {
+        fs::write(pid_file, process::id().to_string())?;
+        return launch.save(pid_file);
+    }
+    let mut child = Command::new(env::current_exe()?)
+        .args(&launch.args)
+        .env(CHILD_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP)
+        .spawn()?;
+    let deadline = Instant::now() + DETACH_WAIT;
+    while control::read_pid(pid_file) != Some(child.id()) {
+        if child.try_wait()?.is_some() || Instant::now() >= deadline {
+            logging::write("The daemon did not start; see its log file", "FATAL");
+            process::exit(1);
+        }
+        thread::sleep(EXIT_POLL);
+    }
+    logging::write(
+        &format!(
+            "Veko Dome is running in the background as PID {} ({})",
+            child.id(),
+            pid_file.display()
+        ),
+        "SYSTEM",
+    );
+    process::exit(0);
+}
+
+/// Asks the daemon `pid`, running `session`, to shut down as Ctrl-C does.
+#[cfg(unix)]
+pub fn request_stop(pid: u32, _session: &str) -> io::Result<()> {
+    // 0 and what wraps to a negative pid_t would signal a whole group
+    let target = libc::pid_t::try_from(pid)
+        .ok()
+        .filter(|&target| target > 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is no PID", pid)))?;
+    if unsafe { libc::kill(target, libc::SIGTERM) } == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ESRCH) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("PID {} has exited", pid),
+        )),
+        Some(libc::EPERM) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("permission denied on PID {}", pid),
+        )),
+        _ => Err(e),
+    }
+}
+
+/// A detached process has no console to send Ctrl-C to, so the control
+/// pipe asks instead.
+#[cfg(windows)]
+pub fn request_stop(_pid: u32, session: &str) -> io::Result<()> {
+    control::Client::connect(Some(session))
+        .and_then(|client| client.stop())
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// Waits up to `timeout` for `pid` to exit; false if it is still running.
+pub fn wait_exit(pid: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while control::pid_alive(pid) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(EXIT_POLL);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_program_matches_the_exact_name() {
+        assert!(same_program("veko_dome", "veko_dome"));
+        assert!(same_program("veko_dome", "veko_dome (deleted)"));
+        assert!(!same_program("veko_dome", "veko"));
+        assert!(!same_program("veko", "veko_dome"));
+        assert!(!same_program("veko_dome", "veko_dome2"));
+        assert!(!same_program("veko_dome", ""));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stopping_an_exited_pid_says_so() {
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        let e = request_stop(pid, "test").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(e.to_string(), format!("PID {} has exited", pid));
+    }
+
+    #[test]
+    fn same_program_allows_only_the_comm_truncation() {
+        assert!(same_program("veko_dome-x86_64-linux", "veko_dome-x86_6"));
+        assert!(!same_program("veko_dome-x86_64-linux", "veko_dome-x86"));
+    }
+}
@@ -0,0 +1,291 @@
+// src/dashboard.rs
+// `watch`: a full-screen view of a running session, redrawn every second
+// from the same control socket replies `status` and `stats` print, with
+// keys that send the session the same commands `rotate` and `stop` do.
+// The terminal is switched to raw input on an alternate screen and put
+// back however the view ends, a panic included. Terminals that cannot do
+// that get the plain status print instead; see [`supported`].
+use crate::control::{Client, ClientError, StatsSnapshot, StatusSnapshot};
+use crate::rotation::{describe_identity_bounds, describe_rotation_policy};
+use std::{
+    env,
+    io::{self, IsTerminal, Read, Write},
+    panic,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const REFRESH: Duration = Duration::from_secs(1);
+/// Tenths of a second a read waits for a key, so refreshes stay on time.
+const KEY_WAIT: u8 = 2;
+const CTRL_C: u8 = 3;
+
+/// Terminal settings from before the view, while it is up.
+static SAVED: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// How the view ended.
+pub enum Ended {
+    /// Ctrl-C: the session keeps running.
+    Left,
+    /// `q`: the session was asked to shut down.
+    Stopped,
+    /// The session went away on its own.
+    SessionGone,
+}
+
+/// Whether the terminal can show the view: both ends are a terminal and
+/// TERM names one that understands cursor movement.
+pub fn supported() -> bool {
+    let term = env::var("TERM").unwrap_or_default();
+    io::stdin().is_terminal() && io::stdout().is_terminal() && !term.is_empty() && term != "dumb"
+}
+
+/// Shows the view until a key or the session ends it.
+pub fn run(client: &Client) -> Result<Ended, ClientError> {
+    let _terminal = RawTerminal::enter().map_err(ClientError::Io)?;
+    let mut stdin = io::stdin().lock();
+    let mut notice: Option<String> = None;
+    let mut due = Instant::now();
+    loop {
+        if Instant::now() >= due {
+            let (width, height) = size();
+            let view = match fetch(client, height) {
+                Ok(view) => view,
+                Err(ClientError::NoSession) => return Ok(Ended::SessionGone),
+                Err(e) => return Err(e),
+            };
+            draw(&view, notice.as_deref(), width, height).map_err(ClientError::Io)?;
+            due = Instant::now() + REFRESH;
+        }
+        let mut key = [0u8];
+        if stdin.read(&mut key).map_err(ClientError::Io)? == 0 {
+            continue;
+        }
+        notice = Some(match key[0] {
+            b'r' => match client.rotate() {
+                Ok(result) if result.from == result.to => {
+                    format!("No other proxy is available; still on {}", result.to)
+                }
+                Ok(result) => format!("Rotated from {} to {}", result.from, result.to),
+                Err(e) => e.to_string(),
+            },
+            b'n' => match client.new_identity() {
+                Ok(()) => "Tor was asked for new circuits".to_string(),
+                Err(e) => e.to_string(),
+            },
+            b'q' => {
+                client.stop()?;
+                return Ok(Ended::Stopped);
+            }
+            CTRL_C => return Ok(Ended::Left),
+            _ => continue,
+        });
+        due = Instant::now();
+    }
+}
+
+struct View {
+    status: StatusSnapshot,
+    stats: StatsSnapshot,
+    log: Vec<String>,
+}
+
+fn fetch(client: &Client, height: usize) -> Result<View, ClientError> {
+    Ok(View {
+        status: client.status()?,
+        stats: client.stats()?,
+        log: client.log(height)?,
+    })
+}
+
+/// E.g. "42s", "3m 05s" or "2h 10m".
+fn describe_secs(secs: u64) -> String {
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, _) => format!("{}h {:02}m", h, m),
+    }
+}
+
+fn lines(view: &View) -> Vec<String> {
+    let s = &view.status;
+    let mut lines = vec![
+        format!(
+            "Veko Dome: session {} (pid {}, up {})",
+            s.session,
+            s.pid,
+            describe_secs(s.uptime_secs)
+        ),
+        "r rotate   n new Tor identity   q stop the session   Ctrl-C leave".to_string(),
+        String::new(),
+    ];
+    let exit = match (&s.exit_ip, &s.exit_geo) {
+        (Some(ip), Some(geo)) => format!("{} ({})", ip, geo),
+        (Some(ip), None) => ip.clone(),
+        (None, _) if s.ip_check_disabled => "not checked (--no-ip-check)".to_string(),
+        (None, _) => "unknown".to_string(),
+    };
+    lines.push(format!("Exit IP    {}", exit));
+    let mut proxy = s.current_proxy.clone();
+    if let Some(details) = &s.current_details {
+        proxy.push_str(&format!(" ({})", details));
+    }
+    lines.push(format!("Proxy      {}", proxy));
+    if let Some(health) = &s.current_health {
+        lines.push(format!("Health     {}", health));
+    }
+    if let Some(route) = &s.route {
+        lines.push(format!("Chain      {}", route));
+    }
+    lines.push(format!(
+        "Pool       {} alive, {} quarantined",
+        s.proxies_alive, s.proxies_quarantined
+    ));
+    let mut rotation = describe_rotation_policy(s.rotation_interval_secs, s.rotate_requests);
+    if s.rotation_interval_secs > 0 {
+        let approx = if s.rotation_jitter_secs > 0 { "~" } else { "" };
+        rotation.push_str(&format!(
+            ", next in {}{}",
+            approx,
+            describe_secs(s.next_rotation_secs)
+        ));
+    }
+    if let Some(limit) = s.rotate_requests {
+        rotation.push_str(&format!(
+            ", {} of {} requests used",
+            s.requests_since_rotation, limit
+        ));
+    }
+    lines.push(format!("Rotation   {} ({})", rotation, s.strategy));
+    if let Some(bounds) = describe_identity_bounds(s.min_dwell_secs, s.max_identity_lifetime_secs) {
+        lines.push(format!("Identity   {}", bounds));
+    }
+    if let Some(tor) = &s.tor {
+        lines.push(format!("Tor        {}", tor));
+    }
+    if let Some(circuit) = &s.tor_circuit {
+        lines.push(format!("Circuit    {}", circuit));
+    }
+    if let Some(transport) = &s.transport {
+        lines.push(format!("Transport  {}", transport));
+    }
+    for listener in &s.listeners {
+        lines.push(format!(
+            "Listener   {} ({} active)",
+            listener.spec, listener.active_connections
+        ));
+    }
+    let rotations: Vec<String> = view
+        .stats
+        .rotations
+        .iter()
+        .map(|(reason, n)| format!("{} {}", n, reason))
+        .collect();
+    lines.push(format!(
+        "Counters   {} rotations{}, {} Tor restarts, {} worker restarts",
+        view.stats.rotations.values().sum::<u64>(),
+        match rotations.is_empty() {
+            true => String::new(),
+            false => format!(" ({})", rotations.join(", ")),
+        },
+        view.stats.tor_restarts,
+        view.stats.worker_restarts
+    ));
+    if let Some(reason) = &s.forwarding_stopped {
+        lines.push(format!("FORWARDING STOPPED (fail-closed): {}", reason));
+    }
+    for (worker, message) in &s.degraded {
+        lines.push(format!(
+            "DEGRADED: worker {} stopped after crashing: {}",
+            worker, message
+        ));
+    }
+    lines
+}
+
+fn draw(view: &View, notice: Option<&str>, width: usize, height: usize) -> io::Result<()> {
+    let mut screen = lines(view);
+    screen.push(String::new());
+    // The log takes what is left, its newest lines at the bottom
+    let room = height.saturating_sub(screen.len() + 2);
+    let log = &view.log[view.log.len().saturating_sub(room)..];
+    screen.extend(log.iter().cloned());
+    screen.resize(height.saturating_sub(1).max(screen.len()), String::new());
+    screen.truncate(height.saturating_sub(1));
+    screen.push(notice.unwrap_or_default().to_string());
+
+    let mut frame = String::from("\x1b[H");
+    for (n, line) in screen.iter().enumerate() {
+        if n > 0 {
+            frame.push_str("\r\n");
+        }
+        // Control characters from the session would move the cursor
+        frame.extend(line.chars().filter(|c| !c.is_control()).take(width));
+        frame.push_str("\x1b[K");
+    }
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(frame.as_bytes())?;
+    stdout.flush()
+}
+
+/// Columns and rows of the terminal, or 80x24 when it does not say.
+fn size() -> (usize, usize) {
+    // SAFETY: TIOCGWINSZ fills the winsize it is handed and nothing else
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    let known = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) } == 0;
+    match known && ws.ws_col > 0 && ws.ws_row > 0 {
+        true => (ws.ws_col as usize, ws.ws_row as usize),
+        false => (80, 24),
+    }
+}
+
+/// Raw input on the alternate screen for as long as it lives.
+struct RawTerminal;
+
+impl RawTerminal {
+    fn enter() -> io::Result<Self> {
+        // SAFETY: tcgetattr fills the termios it is handed
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = saved;
+        // Keys arrive one at a time, unechoed, and Ctrl-C as a byte
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = KEY_WAIT;
+        // SAFETY: `raw` is a valid termios derived from the current one
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        *SAVED.lock().unwrap_or_else(|e| e.into_inner()) = Some(saved);
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore();
+            previous(info);
+        }));
+        // Alternate screen, cursor hidden
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(b"\x1b[?1049h\x1b[?25l\x1b[2J")?;
+        stdout.flush()?;
+        Ok(RawTerminal)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Puts the terminal back as it was before the view, once.
+fn restore() {
+    let Some(saved) = SAVED.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(b"\x1b[?25h\x1b[?1049l");
+    let _ = stdout.flush();
+    // SAFETY: `saved` came from tcgetattr on the same descriptor
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved) };
+}
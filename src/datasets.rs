@@ -0,0 +1,228 @@
+// src/datasets.rs
+// Curated data shipped in the binary: user agents per impersonation preset,
+// decoy targets and hosting-provider ASNs. `data update` installs signed
+// newer copies under the data dir, and those take precedence.
+use base64::Engine as _;
+use ed25519_dalek::{Signature, VerifyingKey};
+use reqwest::blocking::Client;
+use std::{
+    env, fs,
+    io::Read,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+/// Where updated datasets, the geolocation cache and the event log are
+/// kept.
+pub fn data_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(env::temp_dir)
+        .join("veko-dome")
+}
+
+/// Public half of the key published datasets are signed with.
+const PINNED_KEY: [u8; 32] = [
+    123, 44, 146, 19, 40, 240, 123, 219, 57, 244, 180, 165, 208, 110, 105, 21, 193, 143, 120, 165,
+    136, 54, 192, 219, 32, 175, 70, 130, 173, 139, 80, 151,
+];
+/// Largest dataset or signature accepted from the update URL.
+const MAX_DATASET_BYTES: u64 = 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Dataset {
+    pub name: &'static str,
+    /// File name both under the update URL and in the data dir.
+    file: &'static str,
+    embedded: &'static str,
+}
+
+pub const USER_AGENTS: &Dataset = &Dataset {
+    name: "user-agents",
+    file: "user_agents.txt",
+    embedded: include_str!("../data/user_agents.txt"),
+};
+pub const DECOY_TARGETS: &Dataset = &Dataset {
+    name: "decoy-targets",
+    file: "decoy_targets.txt",
+    embedded: include_str!("../data/decoy_targets.txt"),
+};
+pub const HOSTING_ASNS: &Dataset = &Dataset {
+    name: "hosting-asns",
+    file: "hosting_asns.txt",
+    embedded: include_str!("../data/hosting_asns.txt"),
+};
+
+pub const ALL: [&Dataset; 3] = [USER_AGENTS, DECOY_TARGETS, HOSTING_ASNS];
+
+/// Where a dataset's contents currently come from.
+pub enum Source {
+    Embedded,
+    /// Installed by `data update`, at this time.
+    Updated(SystemTime),
+}
+
+impl Dataset {
+    fn installed_path(&self) -> PathBuf {
+        data_dir().join("datasets").join(self.file)
+    }
+
+    fn installed(&self) -> Option<String> {
+        fs::read_to_string(self.installed_path()).ok()
+    }
+
+    /// The installed copy if there is a readable one, else the embedded.
+    pub fn contents(&self) -> String {
+        self.installed()
+            .unwrap_or_else(|| self.embedded.to_string())
+    }
+
+    pub fn source(&self) -> Source {
+        match fs::metadata(self.installed_path()).and_then(|m| m.modified()) {
+            Ok(at) => Source::Updated(at),
+            Err(_) => Source::Embedded,
+        }
+    }
+
+    /// Number of entries in the current contents.
+    pub fn entry_count(&self) -> usize {
+        entries(&self.contents())
+            .filter(|line| !line.starts_with('['))
+            .count()
+    }
+}
+
+/// Lines that carry data, without comments and blank lines.
+fn entries(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn preset_agents(text: &str, preset: &str) -> Vec<String> {
+    let mut in_preset = false;
+    let mut agents = Vec::new();
+    for line in entries(text) {
+        match line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            Some(name) => in_preset = name.trim() == preset,
+            None if in_preset => agents.push(line.to_string()),
+            None => {}
+        }
+    }
+    agents
+}
+
+/// Names of the impersonation presets, e.g. "paranoid".
+pub fn presets() -> Vec<String> {
+    let text = format!("{}\n{}", USER_AGENTS.contents(), USER_AGENTS.embedded);
+    let mut names: Vec<String> = entries(&text)
+        .filter_map(|line| line.strip_prefix('[')?.strip_suffix(']'))
+        .map(|name| name.trim().to_string())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// User agents of `preset`, from the embedded list when an installed one
+/// lacks it.
+pub fn user_agents(preset: &str) -> Vec<String> {
+    USER_AGENTS
+        .installed()
+        .map(|text| preset_agents(&text, preset))
+        .filter(|agents| !agents.is_empty())
+        .unwrap_or_else(|| preset_agents(USER_AGENTS.embedded, preset))
+}
+
+/// URLs decoy traffic is sent to.
+pub fn decoy_targets() -> Vec<String> {
+    entries(&DECOY_TARGETS.contents())
+        .map(str::to_string)
+        .collect()
+}
+
+fn fetch(client: &Client, url: &str) -> Result<Option<Vec<u8>>, String> {
+    let response = client
+        .get(url)
+        .send()
+        .map_err(|e| e.without_url().to_string())?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let response = response
+        .error_for_status()
+        .map_err(|e| e.without_url().to_string())?;
+    let mut body = Vec::new();
+    response
+        .take(MAX_DATASET_BYTES + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| e.to_string())?;
+    if body.len() as u64 > MAX_DATASET_BYTES {
+        return Err(format!("larger than {} bytes", MAX_DATASET_BYTES));
+    }
+    Ok(Some(body))
+}
+
+fn verify(body: &[u8], signature: &[u8]) -> Result<(), String> {
+    let key = VerifyingKey::from_bytes(&PINNED_KEY).map_err(|e| e.to_string())?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(String::from_utf8_lossy(signature).trim())
+        .map_err(|e| format!("signature is not base64: {}", e))?;
+    let signature = Signature::from_slice(&decoded).map_err(|e| e.to_string())?;
+    key.verify_strict(body, &signature)
+        .map_err(|_| "signature does not match the pinned key".to_string())
+}
+
+/// What `update` did with one dataset.
+pub enum Outcome {
+    Installed,
+    Unchanged,
+    /// The update URL does not publish it.
+    NotPublished,
+}
+
+/// Fetches every dataset and its `.sig` from `base_url` and installs the
+/// changed ones. Nothing is installed unless every fetched dataset carries
+/// a valid signature.
+pub fn update(base_url: &str) -> Result<Vec<(&'static str, Outcome)>, String> {
+    let client = Client::builder()
+        .no_proxy()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let base = base_url.trim_end_matches('/');
+    let mut verified = Vec::new();
+    let mut outcomes = Vec::new();
+    for dataset in ALL {
+        let url = format!("{}/{}", base, dataset.file);
+        let Some(body) =
+            fetch(&client, &url).map_err(|e| format!("cannot fetch {}: {}", dataset.name, e))?
+        else {
+            outcomes.push((dataset.name, Outcome::NotPublished));
+            continue;
+        };
+        let signature = fetch(&client, &format!("{}.sig", url))
+            .map_err(|e| format!("cannot fetch the signature of {}: {}", dataset.name, e))?
+            .ok_or_else(|| format!("{} is published without a signature", dataset.name))?;
+        verify(&body, &signature).map_err(|e| format!("{}: {}", dataset.name, e))?;
+        let text =
+            String::from_utf8(body).map_err(|_| format!("{} is not valid UTF-8", dataset.name))?;
+        verified.push((dataset, text));
+    }
+    for (dataset, text) in verified {
+        if dataset.installed().as_deref() == Some(text.as_str()) {
+            outcomes.push((dataset.name, Outcome::Unchanged));
+            continue;
+        }
+        let path = dataset.installed_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        // Written aside first so a failed write leaves the old copy in use
+        let partial = path.with_extension("partial");
+        fs::write(&partial, &text)
+            .and_then(|_| fs::rename(&partial, &path))
+            .map_err(|e| format!("cannot install {}: {}", dataset.name, e))?;
+        outcomes.push((dataset.name, Outcome::Installed));
+    }
+    Ok(outcomes)
+}
@@ -0,0 +1,597 @@
+// src/decisions.rs
+// Opt-in journal of proxy selection decisions, and the pure selection logic
+// it can be replayed against.
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// Bumped whenever the record format changes; replay skips other versions.
+pub const JOURNAL_VERSION: u32 = 1;
+
+/// How a selection was made.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Rotation steps to the next proxy in list order.
+    RoundRobin,
+    /// The preferred proxy if it has room, else the next one that does.
+    FirstWithRoom,
+    /// Tor or the proxy pool, by the configured Tor weight.
+    Weighted,
+    /// Rotation picks a uniformly random proxy other than the current one.
+    Random,
+    /// Rotation picks the proxy unused for the longest time.
+    LeastRecentlyUsed,
+    /// Rotation picks a proxy with probability inversely proportional to
+    /// its latency.
+    LatencyWeighted,
+    /// A user script picked the proxy; such decisions cannot be replayed.
+    Scripted,
+}
+
+/// How `--rotation-strategy` picks the next proxy.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum RotationStrategy {
+    Sequential,
+    Random,
+    Lru,
+    /// Faster proxies more often, by measured latency.
+    Weighted,
+    /// Whatever --selection-script's select() returns.
+    #[cfg(feature = "scripting")]
+    Scripted,
+}
+
+/// Latency assumed for proxies that have not been measured yet, so they
+/// still get picked now and then.
+pub const DEFAULT_LATENCY_MS: u64 = 500;
+
+impl RotationStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RotationStrategy::Sequential => "sequential",
+            RotationStrategy::Random => "random",
+            RotationStrategy::Lru => "lru",
+            RotationStrategy::Weighted => "weighted",
+            #[cfg(feature = "scripting")]
+            RotationStrategy::Scripted => "scripted",
+        }
+    }
+
+    /// The journal's name for this strategy.
+    pub fn journal_strategy(&self) -> Strategy {
+        match self {
+            RotationStrategy::Sequential => Strategy::RoundRobin,
+            RotationStrategy::Random => Strategy::Random,
+            RotationStrategy::Lru => Strategy::LeastRecentlyUsed,
+            RotationStrategy::Weighted => Strategy::LatencyWeighted,
+            #[cfg(feature = "scripting")]
+            RotationStrategy::Scripted => Strategy::Scripted,
+        }
+    }
+}
+
+/// Inputs and outcome of one decision. Proxy lists never carry credentials.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum Decision {
+    Rotation {
+        pool: Vec<String>,
+        current: usize,
+        /// Which proxies were in quarantine; empty in older journals.
+        #[serde(default)]
+        quarantined: Vec<bool>,
+        /// Per proxy, the rotation number it was last used at (0 = never);
+        /// only recorded for LRU.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        last_used: Vec<u64>,
+        /// Per proxy, the measured latency in milliseconds if there is one;
+        /// only recorded for latency weighting.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        latency_ms: Vec<Option<u64>>,
+        outcome: Option<usize>,
+    },
+    PoolAcquire {
+        pool: Vec<String>,
+        /// Active and maximum connections per proxy.
+        load: Vec<(usize, usize)>,
+        #[serde(default)]
+        quarantined: Vec<bool>,
+        preferred: usize,
+        outcome: Option<usize>,
+    },
+    /// Whether a rotation lands on Tor rather than the proxy pool.
+    Blend {
+        /// Percentage of rotations meant to go to Tor.
+        tor_weight: u8,
+        tor_ready: bool,
+        outcome: bool,
+    },
+}
+
+impl Decision {
+    fn outcome(&self) -> String {
+        match self {
+            Decision::Rotation { outcome, .. } | Decision::PoolAcquire { outcome, .. } => {
+                outcome.map_or_else(|| "none".to_string(), |i| i.to_string())
+            }
+            Decision::Blend { outcome, .. } => if *outcome { "tor" } else { "proxy" }.to_string(),
+        }
+    }
+
+    /// Hash of the decision's inputs, so replay can tell a damaged record
+    /// from a real divergence.
+    fn input_hash(&self) -> String {
+        let (mut inputs, quarantined, last_used, latency_ms) = match self {
+            Decision::Rotation {
+                pool,
+                current,
+                quarantined,
+                last_used,
+                latency_ms,
+                ..
+            } => (
+                vec![serde_json::json!(pool), serde_json::json!(current)],
+                quarantined,
+                last_used,
+                latency_ms,
+            ),
+            Decision::PoolAcquire {
+                pool,
+                load,
+                quarantined,
+                preferred,
+                ..
+            } => (
+                vec![
+                    serde_json::json!(pool),
+                    serde_json::json!(load),
+                    serde_json::json!(preferred),
+                ],
+                quarantined,
+                &Vec::new(),
+                &Vec::new(),
+            ),
+            Decision::Blend {
+                tor_weight,
+                tor_ready,
+                ..
+            } => (
+                vec![serde_json::json!(tor_weight), serde_json::json!(tor_ready)],
+                &Vec::new(),
+                &Vec::new(),
+                &Vec::new(),
+            ),
+        };
+        // Added after the first journals were written; only hashed when set
+        // so those still verify
+        if !quarantined.is_empty() {
+            inputs.push(serde_json::json!(quarantined));
+        }
+        if !last_used.is_empty() {
+            inputs.push(serde_json::json!(last_used));
+        }
+        if !latency_ms.is_empty() {
+            inputs.push(serde_json::json!(latency_ms));
+        }
+        let inputs = serde_json::Value::Array(inputs);
+        // FNV-1a; stable across builds, unlike std's hasher
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in inputs.to_string().bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        format!("{:016x}", hash)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Record {
+    pub v: u32,
+    pub ts: String,
+    pub strategy: Strategy,
+    /// Drawn from the OS RNG for every decision and passed to the strategy.
+    pub seed: u64,
+    pub snapshot_hash: String,
+    #[serde(flatten)]
+    pub decision: Decision,
+}
+
+/// A fresh seed for one decision.
+pub fn draw_seed() -> u64 {
+    let mut buf = [0u8; 8];
+    match getrandom::getrandom(&mut buf) {
+        Ok(()) => u64::from_le_bytes(buf),
+        Err(_) => fastrand::u64(..),
+    }
+}
+
+fn is_quarantined(quarantined: &[bool], i: usize) -> bool {
+    quarantined.get(i).copied().unwrap_or(false)
+}
+
+/// Index of the proxy to rotate onto: the next one in list order that is
+/// not quarantined, or `None` if all are. Round robin ignores the seed.
+pub fn next_proxy(
+    pool_len: usize,
+    current: usize,
+    quarantined: &[bool],
+    _seed: u64,
+) -> Option<usize> {
+    (1..=pool_len)
+        .map(|n| (current + n) % pool_len)
+        .find(|&i| !is_quarantined(quarantined, i))
+}
+
+/// A uniformly random proxy that is neither quarantined nor `current`, or
+/// `current` itself if it is the only one left.
+pub fn random_proxy(
+    pool_len: usize,
+    current: usize,
+    quarantined: &[bool],
+    seed: u64,
+) -> Option<usize> {
+    let others: Vec<usize> = (0..pool_len)
+        .filter(|&i| i != current && !is_quarantined(quarantined, i))
+        .collect();
+    if others.is_empty() {
+        return (current < pool_len && !is_quarantined(quarantined, current)).then_some(current);
+    }
+    Some(others[fastrand::Rng::with_seed(seed).usize(..others.len())])
+}
+
+/// The proxy that was used longest ago, skipping quarantined ones and
+/// `current` when there is a choice. Ties go to list order.
+pub fn lru_proxy(current: usize, quarantined: &[bool], last_used: &[u64]) -> Option<usize> {
+    let eligible = |i: &usize| !is_quarantined(quarantined, *i);
+    (0..last_used.len())
+        .filter(|i| *i != current && eligible(i))
+        .min_by_key(|&i| last_used[i])
+        .or_else(|| Some(current).filter(|i| *i < last_used.len() && eligible(i)))
+}
+
+/// A proxy picked with probability proportional to 1/latency, skipping
+/// quarantined ones and `current` when there is a choice. Unmeasured proxies
+/// count as `DEFAULT_LATENCY_MS`.
+pub fn weighted_proxy(
+    current: usize,
+    quarantined: &[bool],
+    latency_ms: &[Option<u64>],
+    seed: u64,
+) -> Option<usize> {
+    let len = latency_ms.len();
+    let eligible = |i: &usize| !is_quarantined(quarantined, *i);
+    let mut candidates: Vec<usize> = (0..len).filter(|i| *i != current && eligible(i)).collect();
+    if candidates.is_empty() {
+        candidates.extend(Some(current).filter(|i| *i < len && eligible(i)));
+    }
+    let weights: Vec<f64> = candidates
+        .iter()
+        .map(|&i| 1.0 / latency_ms[i].unwrap_or(DEFAULT_LATENCY_MS).max(1) as f64)
+        .collect();
+    let mut point = fastrand::Rng::with_seed(seed).f64() * weights.iter().sum::<f64>();
+    for (&i, weight) in candidates.iter().zip(&weights) {
+        if point < *weight {
+            return Some(i);
+        }
+        point -= weight;
+    }
+    // Rounding can leave the point just past the last weight
+    candidates.last().copied()
+}
+
+/// Tunnels a route needs before adaptive rotation reacts to it.
+const ADAPT_MIN_SAMPLES: usize = 5;
+/// Error rate at which the interval reaches its minimum.
+const ADAPT_WORST_ERROR_RATE: f64 = 0.5;
+/// Tail latencies between these bounds move the interval from its maximum
+/// to its minimum.
+const ADAPT_GOOD_LATENCY_MS: f64 = 500.0;
+const ADAPT_BAD_LATENCY_MS: f64 = 3000.0;
+
+/// What adaptive rotation saw of a route.
+pub struct RouteHealth {
+    pub samples: usize,
+    pub error_rate: f64,
+    /// 90th percentile setup time of the tunnels that worked.
+    pub p90_latency_ms: Option<u64>,
+}
+
+/// Summarizes recent tunnels through a route: setup time in ms, or `None`
+/// for a failure.
+pub fn route_health(window: &[Option<u64>]) -> RouteHealth {
+    let mut latencies: Vec<u64> = window.iter().flatten().copied().collect();
+    latencies.sort_unstable();
+    let failures = window.len() - latencies.len();
+    RouteHealth {
+        samples: window.len(),
+        error_rate: if window.is_empty() {
+            0.0
+        } else {
+            failures as f64 / window.len() as f64
+        },
+        p90_latency_ms: (!latencies.is_empty())
+            .then(|| latencies[(latencies.len() * 9 / 10).min(latencies.len() - 1)]),
+    }
+}
+
+/// Rotation interval for a route: `max` while it is healthy, moving toward
+/// `min` as its error rate or tail latency rises, whichever is worse.
+pub fn adaptive_interval(min: Duration, max: Duration, health: &RouteHealth) -> Duration {
+    if health.samples < ADAPT_MIN_SAMPLES || min >= max {
+        return max;
+    }
+    let errors = (health.error_rate / ADAPT_WORST_ERROR_RATE).clamp(0.0, 1.0);
+    let latency = health.p90_latency_ms.map_or(0.0, |ms| {
+        ((ms as f64 - ADAPT_GOOD_LATENCY_MS) / (ADAPT_BAD_LATENCY_MS - ADAPT_GOOD_LATENCY_MS))
+            .clamp(0.0, 1.0)
+    });
+    max - (max - min).mul_f64(errors.max(latency))
+}
+
+/// Index of the first proxy with room that is not quarantined, starting
+/// from `preferred`.
+pub fn pick_with_room(
+    load: &[(usize, usize)],
+    quarantined: &[bool],
+    preferred: usize,
+    _seed: u64,
+) -> Option<usize> {
+    let count = load.len();
+    (0..count)
+        .map(|n| (preferred + n) % count)
+        .find(|&i| load[i].0 < load[i].1 && !is_quarantined(quarantined, i))
+}
+
+/// Whether a rotation goes to Tor: `tor_weight` percent of the time, and
+/// only while Tor is ready.
+pub fn use_tor(tor_weight: u8, tor_ready: bool, seed: u64) -> bool {
+    tor_ready && seed % 100 < tor_weight as u64
+}
+
+/// Re-runs the selection logic for `strategy` against a decision's inputs.
+fn rerun(strategy: Strategy, decision: &Decision, seed: u64) -> Result<Decision, String> {
+    let mut rerun = decision.clone();
+    match (strategy, &mut rerun) {
+        (
+            Strategy::RoundRobin,
+            Decision::Rotation {
+                pool,
+                current,
+                quarantined,
+                outcome,
+                ..
+            },
+        ) => *outcome = next_proxy(pool.len(), *current, quarantined, seed),
+        (
+            Strategy::Random,
+            Decision::Rotation {
+                pool,
+                current,
+                quarantined,
+                outcome,
+                ..
+            },
+        ) => *outcome = random_proxy(pool.len(), *current, quarantined, seed),
+        (
+            Strategy::LeastRecentlyUsed,
+            Decision::Rotation {
+                current,
+                quarantined,
+                last_used,
+                outcome,
+                ..
+            },
+        ) => *outcome = lru_proxy(*current, quarantined, last_used),
+        (
+            Strategy::LatencyWeighted,
+            Decision::Rotation {
+                current,
+                quarantined,
+                latency_ms,
+                outcome,
+                ..
+            },
+        ) => *outcome = weighted_proxy(*current, quarantined, latency_ms, seed),
+        (
+            Strategy::FirstWithRoom,
+            Decision::PoolAcquire {
+                load,
+                quarantined,
+                preferred,
+                outcome,
+                ..
+            },
+        ) => *outcome = pick_with_room(load, quarantined, *preferred, seed),
+        (
+            Strategy::Weighted,
+            Decision::Blend {
+                tor_weight,
+                tor_ready,
+                outcome,
+            },
+        ) => *outcome = use_tor(*tor_weight, *tor_ready, seed),
+        _ => return Err("strategy does not apply to this decision".to_string()),
+    }
+    Ok(rerun)
+}
+
+/// Appends decisions to a JSON lines file.
+pub struct Journal {
+    file: Mutex<File>,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Journal {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, strategy: Strategy, seed: u64, decision: Decision) {
+        let record = Record {
+            v: JOURNAL_VERSION,
+            ts: chrono::Local::now().to_rfc3339(),
+            strategy,
+            seed,
+            snapshot_hash: decision.input_hash(),
+            decision,
+        };
+        let Ok(mut line) = serde_json::to_string(&record) else {
+            return;
+        };
+        line.push('\n');
+        // Journaling must never take the session down
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+/// Outcome of replaying a journal.
+pub struct ReplayReport {
+    pub checked: usize,
+    /// Lines that could not be parsed, have another format version, or
+    /// record a scripted selection.
+    pub skipped: usize,
+    /// Line number and explanation for every decision that came out
+    /// differently.
+    pub divergences: Vec<(usize, String)>,
+}
+
+pub fn replay(path: &Path) -> io::Result<ReplayReport> {
+    let text = fs::read_to_string(path)?;
+    let mut report = ReplayReport {
+        checked: 0,
+        skipped: 0,
+        divergences: Vec::new(),
+    };
+    for (n, line) in text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+    {
+        let line_no = n + 1;
+        let record: Record = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(_) => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+        if record.v != JOURNAL_VERSION || record.strategy == Strategy::Scripted {
+            report.skipped += 1;
+            continue;
+        }
+        report.checked += 1;
+        if record.decision.input_hash() != record.snapshot_hash {
+            report.divergences.push((
+                line_no,
+                "snapshot hash does not match recorded inputs".to_string(),
+            ));
+            continue;
+        }
+        match rerun(record.strategy, &record.decision, record.seed) {
+            Ok(rerun) => {
+                let (recorded, replayed) = (record.decision.outcome(), rerun.outcome());
+                if recorded != replayed {
+                    report.divergences.push((
+                        line_no,
+                        format!(
+                            "recorded outcome {} but replay gives {}",
+                            recorded, replayed
+                        ),
+                    ));
+                }
+            }
+            Err(e) => report.divergences.push((line_no, e)),
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN: Duration = Duration::from_secs(60);
+    const MAX: Duration = Duration::from_secs(600);
+
+    fn health(samples: usize, error_rate: f64, p90_latency_ms: Option<u64>) -> RouteHealth {
+        RouteHealth {
+            samples,
+            error_rate,
+            p90_latency_ms,
+        }
+    }
+
+    #[test]
+    fn route_health_counts_failures_and_the_tail_latency() {
+        let window: Vec<Option<u64>> = (1..=10).map(|ms| Some(ms * 100)).chain([None]).collect();
+        let found = route_health(&window);
+        assert_eq!(found.samples, 11);
+        assert!((found.error_rate - 1.0 / 11.0).abs() < 1e-9);
+        assert_eq!(found.p90_latency_ms, Some(1000));
+        let empty = route_health(&[]);
+        assert_eq!((empty.samples, empty.error_rate), (0, 0.0));
+        assert_eq!(route_health(&[None, None]).p90_latency_ms, None);
+    }
+
+    #[test]
+    fn a_healthy_route_keeps_the_longest_interval() {
+        assert_eq!(
+            adaptive_interval(MIN, MAX, &health(20, 0.0, Some(200))),
+            MAX
+        );
+    }
+
+    #[test]
+    fn too_few_samples_keep_the_longest_interval() {
+        let failing = health(ADAPT_MIN_SAMPLES - 1, 1.0, None);
+        assert_eq!(adaptive_interval(MIN, MAX, &failing), MAX);
+    }
+
+    #[test]
+    fn errors_move_the_interval_toward_the_minimum() {
+        let half = adaptive_interval(MIN, MAX, &health(20, ADAPT_WORST_ERROR_RATE / 2.0, None));
+        assert_eq!(half, Duration::from_secs(330));
+        let worst = adaptive_interval(MIN, MAX, &health(20, ADAPT_WORST_ERROR_RATE, None));
+        assert_eq!(worst, MIN);
+        assert_eq!(adaptive_interval(MIN, MAX, &health(20, 1.0, None)), MIN);
+    }
+
+    #[test]
+    fn tail_latency_moves_the_interval_toward_the_minimum() {
+        let at = |ms| adaptive_interval(MIN, MAX, &health(20, 0.0, Some(ms)));
+        assert_eq!(at(ADAPT_GOOD_LATENCY_MS as u64), MAX);
+        assert_eq!(at(1750), Duration::from_secs(330));
+        assert_eq!(at(ADAPT_BAD_LATENCY_MS as u64), MIN);
+        assert_eq!(at(60_000), MIN);
+    }
+
+    #[test]
+    fn the_worse_signal_decides() {
+        let both = adaptive_interval(
+            MIN,
+            MAX,
+            &health(20, ADAPT_WORST_ERROR_RATE / 2.0, Some(3000)),
+        );
+        assert_eq!(both, MIN);
+    }
+
+    #[test]
+    fn bounds_that_leave_no_room_keep_the_maximum() {
+        let failing = health(20, 1.0, None);
+        assert_eq!(adaptive_interval(MAX, MAX, &failing), MAX);
+        assert_eq!(adaptive_interval(MAX, MIN, &failing), MIN);
+    }
+}
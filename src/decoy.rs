@@ -0,0 +1,86 @@
+// src/decoy.rs
+// Cover traffic for --decoy: plain GETs to widely visited sites, sent
+// through the session's route at random times so real requests do not
+// stand out by when they happen. Answers are read and thrown away; nothing
+// in them is followed or run. Nothing is sent while the kill switch is
+// engaged, and never over the direct connection.
+use crate::throttle;
+use reqwest::blocking::Client;
+use std::{
+    io::{self, Read},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Most of an answer read before it is dropped.
+const MAX_BODY_BYTES: u64 = 256 * 1024;
+/// How often the wait for the next request looks for a shutdown.
+const TICK: Duration = Duration::from_millis(100);
+
+pub struct Decoy {
+    targets: Vec<String>,
+    /// Mean time between requests.
+    mean_gap: Duration,
+    sent: AtomicU64,
+}
+
+impl Decoy {
+    /// Sends to `targets` about `per_minute` times a minute.
+    pub fn new(targets: Vec<String>, per_minute: u32) -> Self {
+        Decoy {
+            targets,
+            mean_gap: Duration::from_secs(60) / per_minute.max(1),
+            sent: AtomicU64::new(0),
+        }
+    }
+
+    /// Requests that got an answer so far.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::SeqCst)
+    }
+
+    /// Time to the next request: exponential, so requests arrive like a
+    /// Poisson process, but never under a tenth of the mean gap, so a run
+    /// of short draws cannot burst past the rate.
+    fn next_gap(&self) -> Duration {
+        let draw = -(1.0 - fastrand::f64()).ln();
+        self.mean_gap.mul_f64(draw.max(0.1))
+    }
+
+    /// Sends until `running` goes false. `blocked` says whether sending
+    /// has to wait, and `client` gives the client of the current route, or
+    /// `None` when there is none to send through.
+    pub fn run(
+        &self,
+        running: &AtomicBool,
+        blocked: impl Fn() -> bool,
+        mut client: impl FnMut() -> Option<Client>,
+    ) {
+        let mut due = Instant::now() + self.next_gap();
+        while running.load(Ordering::SeqCst) {
+            thread::sleep(TICK);
+            if Instant::now() < due {
+                continue;
+            }
+            due = Instant::now() + self.next_gap();
+            if blocked() {
+                continue;
+            }
+            let Some(client) = client() else {
+                continue;
+            };
+            let target = &self.targets[fastrand::usize(..self.targets.len())];
+            throttle::wait(target);
+            match client.get(target).send() {
+                Ok(response) => {
+                    let status = response.status();
+                    let _ = io::copy(&mut response.take(MAX_BODY_BYTES), &mut io::sink());
+                    self.sent.fetch_add(1, Ordering::SeqCst);
+                    log::debug!("Decoy request to {} answered {}", target, status);
+                }
+                Err(e) => log::debug!("Decoy request to {} failed: {}", target, e.without_url()),
+            }
+        }
+    }
+}
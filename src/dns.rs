@@ -0,0 +1,240 @@
+// src/dns.rs
+// Encrypted DNS for the names veko_dome resolves itself: proxy hosts, and
+// targets behind socks5:// hops. socks5h:// and http:// proxies resolve
+// their targets on the far side. Without --doh or --dot the system resolver
+// is used. --block-ipv6 drops IPv6 answers either way.
+use clap::ValueEnum;
+use hickory_resolver::{
+    config::{NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts},
+    Resolver,
+};
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+/// The only path the resolver sends DoH queries to.
+const DOH_PATH: &str = "/dns-query";
+const DOT_PORT: u16 = 853;
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Looked up once at startup to tell that the server answers.
+const CHECK_NAME: &str = "example.com";
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DohProvider {
+    Cloudflare,
+    Google,
+    Quad9,
+}
+
+impl DohProvider {
+    fn name(self) -> &'static str {
+        match self {
+            DohProvider::Cloudflare => "cloudflare",
+            DohProvider::Google => "google",
+            DohProvider::Quad9 => "quad9",
+        }
+    }
+}
+
+/// A DoH server given by URL.
+#[derive(Clone)]
+pub struct DohUrl {
+    host: String,
+    port: u16,
+}
+
+impl DohUrl {
+    /// The server's address when the URL names it by IP.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.host.trim_matches(['[', ']']).parse().ok()
+    }
+}
+
+impl fmt::Display for DohUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.port {
+            443 => write!(f, "https://{}{}", self.host, DOH_PATH),
+            port => write!(f, "https://{}:{}{}", self.host, port, DOH_PATH),
+        }
+    }
+}
+
+/// Parses `--doh-url`: an https URL whose path, if any, is /dns-query.
+pub fn parse_doh_url(s: &str) -> Result<DohUrl, String> {
+    let url = reqwest::Url::parse(s).map_err(|e| format!("invalid URL: {}", e))?;
+    if url.scheme() != "https" {
+        return Err("DoH URLs must use https".to_string());
+    }
+    if !matches!(url.path(), "" | "/" | DOH_PATH) || url.query().is_some() {
+        return Err(format!("only the {} path is supported", DOH_PATH));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| "DoH URL has no host".to_string())?;
+    Ok(DohUrl {
+        host: host.to_string(),
+        port: url.port_or_known_default().unwrap_or(443),
+    })
+}
+
+/// A DNS-over-TLS server, whose certificate must match `host`.
+#[derive(Clone)]
+pub struct DotServer {
+    host: String,
+    port: u16,
+}
+
+impl fmt::Display for DotServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// Parses `--dot`: a host name or IP, with port 853 unless given.
+pub fn parse_dot(s: &str) -> Result<DotServer, String> {
+    // Parsed as a URL authority, which checks the host is a valid name
+    let url = reqwest::Url::parse(&format!("https://{}", s))
+        .map_err(|e| format!("invalid server: {}", e))?;
+    if url.path() != "/" || !url.username().is_empty() || url.query().is_some() {
+        return Err("expected host[:port]".to_string());
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| "server has no host".to_string())?;
+    Ok(DotServer {
+        host: host.to_string(),
+        port: url.port().unwrap_or(DOT_PORT),
+    })
+}
+
+/// Where encrypted lookups go.
+pub enum Server {
+    Doh(DohProvider),
+    /// A DoH server by URL, reached at the given address.
+    DohUrl(DohUrl, IpAddr),
+    Dot(DotServer),
+}
+
+struct Encrypted {
+    resolver: Resolver,
+    description: String,
+}
+
+static ENCRYPTED: OnceLock<Encrypted> = OnceLock::new();
+/// Set by --resolve-locally.
+static LOCAL_TARGETS: AtomicBool = AtomicBool::new(false);
+/// Set by --block-ipv6.
+static IPV6_BLOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Sends every later lookup to `server`, once it has answered a test
+/// lookup. Returns how it is described in logs and status.
+pub fn install(server: Server) -> Result<&'static str, String> {
+    let (config, description) = match server {
+        Server::Doh(provider) => {
+            let config = match provider {
+                DohProvider::Cloudflare => ResolverConfig::cloudflare_https(),
+                DohProvider::Google => ResolverConfig::google_https(),
+                DohProvider::Quad9 => ResolverConfig::quad9_https(),
+            };
+            (config, format!("DoH via {}", provider.name()))
+        }
+        Server::DohUrl(url, ip) => {
+            let mut server = NameServerConfig::new(SocketAddr::new(ip, url.port), Protocol::Https);
+            server.tls_dns_name = Some(url.host.trim_matches(['[', ']']).to_string());
+            let config =
+                ResolverConfig::from_parts(None, vec![], NameServerConfigGroup::from(vec![server]));
+            (config, format!("DoH via {}", url))
+        }
+        Server::Dot(server) => {
+            // Only the server's own name goes to the system resolver
+            let addrs: Vec<SocketAddr> = (server.host.trim_matches(['[', ']']), server.port)
+                .to_socket_addrs()
+                .map_err(|e| format!("cannot resolve DoT server {}: {}", server, e))?
+                .collect();
+            let tls_name = server.host.trim_matches(['[', ']']).to_string();
+            let servers: Vec<NameServerConfig> = addrs
+                .into_iter()
+                .map(|addr| {
+                    let mut config = NameServerConfig::new(addr, Protocol::Tls);
+                    config.tls_dns_name = Some(tls_name.clone());
+                    config
+                })
+                .collect();
+            let config =
+                ResolverConfig::from_parts(None, vec![], NameServerConfigGroup::from(servers));
+            (config, format!("DoT via {}", server))
+        }
+    };
+    let mut opts = ResolverOpts::default();
+    opts.timeout = LOOKUP_TIMEOUT;
+    let resolver = Resolver::new(config, opts).map_err(|e| e.to_string())?;
+    resolver
+        .lookup_ip(CHECK_NAME)
+        .map_err(|e| format!("{} does not answer: {}", description, e))?;
+    let installed = ENCRYPTED.get_or_init(|| Encrypted {
+        resolver,
+        description,
+    });
+    Ok(&installed.description)
+}
+
+/// The encrypted DNS in use, if any.
+pub fn active() -> Option<&'static str> {
+    ENCRYPTED.get().map(|e| e.description.as_str())
+}
+
+/// Makes forwarders resolve every target themselves and hand proxies only
+/// addresses.
+pub fn resolve_targets_locally() {
+    LOCAL_TARGETS.store(true, Ordering::SeqCst);
+}
+
+pub fn resolves_targets_locally() -> bool {
+    LOCAL_TARGETS.load(Ordering::SeqCst)
+}
+
+/// Keeps IPv6 addresses out of every later lookup.
+pub fn block_ipv6() {
+    IPV6_BLOCKED.store(true, Ordering::SeqCst);
+}
+
+pub fn ipv6_blocked() -> bool {
+    IPV6_BLOCKED.load(Ordering::SeqCst)
+}
+
+/// Resolves `host` over encrypted DNS when it is set up, else with the
+/// system resolver.
+pub fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = match ENCRYPTED.get() {
+        None => (host, port).to_socket_addrs()?.collect(),
+        Some(encrypted) => encrypted
+            .resolver
+            .lookup_ip(host)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("cannot resolve {} ({}): {}", host, encrypted.description, e),
+                )
+            })?
+            .iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect(),
+    };
+    if !ipv6_blocked() {
+        return Ok(addrs);
+    }
+    let v4: Vec<SocketAddr> = addrs.into_iter().filter(SocketAddr::is_ipv4).collect();
+    if v4.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has only IPv6 addresses, and IPv6 is blocked", host),
+        ));
+    }
+    Ok(v4)
+}
@@ -0,0 +1,186 @@
+// src/doctor.rs
+// `doctor`: the environment a session needs, checked before one is
+// started. Each check passes, warns or fails with a hint on what to do.
+// Only `--fix` changes anything, and then only by creating directories
+// Veko Dome would create itself.
+use crate::audit::Verdict;
+use crate::client::HttpProbe;
+use crate::retry::Failure;
+use crate::tor_integration::{CONTROL_ADDR, SOCKS_ADDR};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    net::TcpListener,
+    path::Path,
+    process::Command,
+};
+
+/// One line of the checkup.
+pub struct Diagnosis {
+    pub check: &'static str,
+    pub verdict: Verdict,
+    pub detail: String,
+    /// What to do about a warning or failure.
+    pub hint: Option<String>,
+}
+
+impl Diagnosis {
+    pub fn pass(check: &'static str, detail: String) -> Self {
+        Diagnosis {
+            check,
+            verdict: Verdict::Pass,
+            detail,
+            hint: None,
+        }
+    }
+
+    pub fn warn(check: &'static str, detail: String, hint: &str) -> Self {
+        Diagnosis {
+            check,
+            verdict: Verdict::Warn,
+            detail,
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    pub fn fail(check: &'static str, detail: String, hint: &str) -> Self {
+        Diagnosis {
+            check,
+            verdict: Verdict::Fail,
+            detail,
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+/// Whether `binary` runs, and the version it reports.
+pub fn tor_binary(binary: &Path) -> Diagnosis {
+    let output = match Command::new(binary).arg("--version").output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Diagnosis::fail(
+                "tor",
+                format!("{} was not found", binary.display()),
+                "install Tor (e.g. apt install tor) or pass --tor-binary PATH",
+            )
+        }
+        Err(e) => {
+            return Diagnosis::fail(
+                "tor",
+                format!("{} cannot be run: {}", binary.display(), e),
+                "check that the file is executable, or pass --tor-binary PATH",
+            )
+        }
+    };
+    let text = String::from_utf8_lossy(&output.stdout);
+    match text.lines().find(|line| line.starts_with("Tor version")) {
+        Some(version) => Diagnosis::pass("tor", version.trim_end_matches('.').to_string()),
+        None => Diagnosis::warn(
+            "tor",
+            format!("{} did not report a Tor version", binary.display()),
+            "make sure --tor-binary points at tor itself, not a wrapper",
+        ),
+    }
+}
+
+/// Whether this machine may open `addr`, which the session's Tor listens on.
+fn port_free(check: &'static str, addr: &str, hint: &str) -> Diagnosis {
+    match TcpListener::bind(addr) {
+        Ok(_) => Diagnosis::pass(check, format!("{} is free", addr)),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            Diagnosis::fail(check, format!("{} is already in use", addr), hint)
+        }
+        Err(e) => Diagnosis::fail(check, format!("cannot listen on {}: {}", addr, e), hint),
+    }
+}
+
+/// Whether the SOCKS port the session's Tor opens is free.
+pub fn socks_port() -> Diagnosis {
+    port_free(
+        "socks-port",
+        SOCKS_ADDR,
+        "another Tor, often the system service, holds it; stop it (e.g. systemctl stop tor) \
+         so the session's own Tor can start",
+    )
+}
+
+/// Whether the control port --tor-weight uses is free, and its cookie can
+/// be written to `cookie_dir` once that exists.
+pub fn control_port(cookie_dir: &Path) -> Diagnosis {
+    let port = port_free(
+        "control-port",
+        CONTROL_ADDR,
+        "another Tor holds it; stop it, or leave --tor-weight at 0, which needs no control port",
+    );
+    if port.verdict != Verdict::Pass || !cookie_dir.exists() {
+        return port;
+    }
+    match writable(cookie_dir) {
+        Ok(()) => Diagnosis::pass(
+            "control-port",
+            format!(
+                "{} is free and its cookie can go in {}",
+                CONTROL_ADDR,
+                cookie_dir.display()
+            ),
+        ),
+        Err(e) => Diagnosis::fail(
+            "control-port",
+            format!(
+                "the cookie cannot be written to {}: {}",
+                cookie_dir.display(),
+                e
+            ),
+            "make the directory writable, or run doctor --fix if it is missing",
+        ),
+    }
+}
+
+/// Whether `url`, an IP service, answers over HTTPS without a proxy.
+pub fn outbound_https(client: &dyn HttpProbe, url: &str) -> Diagnosis {
+    match client.get_text(url) {
+        Ok(_) => Diagnosis::pass("outbound-https", format!("{} answered", url)),
+        Err(Failure::Retry(e) | Failure::Stop(e)) => Diagnosis::fail(
+            "outbound-https",
+            format!("{} did not answer: {}", url, e),
+            "outbound HTTPS (port 443) or DNS may be blocked by a firewall; allow them, or \
+             start from a network that does",
+        ),
+    }
+}
+
+/// Whether files can be created in `dir`. With `fix`, a missing `dir` is
+/// created first.
+pub fn directory(check: &'static str, dir: &Path, fix: bool) -> Diagnosis {
+    if !dir.exists() {
+        if !fix {
+            return Diagnosis::warn(
+                check,
+                format!("{} does not exist", dir.display()),
+                "run doctor --fix to create it",
+            );
+        }
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Diagnosis::fail(
+                check,
+                format!("{} cannot be created: {}", dir.display(), e),
+                "create it by hand with write access for this user",
+            );
+        }
+    }
+    match writable(dir) {
+        Ok(()) => Diagnosis::pass(check, format!("{} is writable", dir.display())),
+        Err(e) => Diagnosis::fail(
+            check,
+            format!("{} is not writable: {}", dir.display(), e),
+            "give this user write access to it",
+        ),
+    }
+}
+
+/// Creates and removes a file in `dir`.
+fn writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(format!(".veko-dome-doctor-{}", std::process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
@@ -0,0 +1,1010 @@
+// src/engine.rs
+// The steps a session goes through once its options are settled: building
+// the security profile, starting Tor, opening the local listeners and
+// running the rotation loop. Each step takes what it needs rather than a
+// command line, and hands back what keeps it from going on as an error;
+// stopping the process is left to whoever drives the session.
+#[cfg(feature = "arti")]
+use crate::arti;
+use crate::audit::Blocklist;
+use crate::capture::Capture;
+use crate::client::{create_http_client, strip_credentials, PublicIp};
+#[cfg(feature = "arti")]
+use crate::datasets::data_dir;
+use crate::events::{Event, EventLog, HookEvent, HookKind, RotationEvent, RotationReason};
+use crate::forwarder::{Chain, Drain, Forwarder, Hop};
+use crate::kill_switch::KillSwitch;
+use crate::listener::{ListenSpec, Listener};
+use crate::no_proxy::NoProxy;
+use crate::probe::{self, ProbeLevel};
+use crate::profile::{parse_user_agent, Catalog, HttpVersion, SecurityProfile};
+use crate::rotation::{ProxyEntry, ProxyRotator};
+use crate::tor_integration::{TorBackend, TorEvent, TorManager, TorOptions};
+use crate::{hooks, logging, workers};
+use reqwest::header;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How --chain puts Tor and the proxies on the route.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ChainMode {
+    /// client -> proxy -> Tor -> destination
+    ProxyThenTor,
+    /// client -> Tor -> proxy -> destination
+    TorThenProxy,
+    /// client -> each proxy of --chain-file in turn -> destination
+    Hops,
+}
+
+impl ChainMode {
+    /// Whether the chain puts Tor on the route.
+    pub fn through_tor(self) -> bool {
+        self != ChainMode::Hops
+    }
+}
+
+/// Which hops of a --chain hops route a rotation moves.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ChainRotate {
+    /// The entry hop; the exit stays put
+    First,
+    /// The exit hop
+    Last,
+    /// Every hop, with as many rotating proxies
+    All,
+}
+
+/// Which implementation of Tor a session runs.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum TorBackendKind {
+    /// The tor executable, see --tor-binary
+    Binary,
+    /// Arti, the Rust implementation of Tor, embedded
+    Arti,
+}
+
+/// The least time a request may take while Tor is in use, unless --timeout
+/// says otherwise.
+const TOR_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What a session changes about the security profile its mode names.
+#[derive(Default)]
+pub struct ProfileOptions {
+    /// Skip TLS certificate checks.
+    pub insecure_tls: bool,
+    /// A file of user agents to present instead of the profile's.
+    pub user_agents: Option<PathBuf>,
+    /// The one user agent every route presents.
+    pub pin_user_agent: Option<String>,
+    pub no_referer: bool,
+    pub max_redirects: Option<usize>,
+    pub no_keepalive: bool,
+    pub cookies: bool,
+    pub no_tor_isolation: bool,
+    /// The HTTP version to speak instead of the profile's.
+    pub http_version: Option<HttpVersion>,
+    /// The request timeout instead of the profile's.
+    pub timeout: Option<Duration>,
+    /// Whether Tor is on the route, which raises the timeout to
+    /// [`TOR_TIMEOUT`] when none is given.
+    pub through_tor: bool,
+}
+
+/// The security profile `mode` names, as found in `catalog` or built in,
+/// with what `options` change about it.
+pub fn build_profile(
+    mode: &str,
+    catalog: Option<&Catalog>,
+    options: &ProfileOptions,
+) -> Result<SecurityProfile, String> {
+    let mut profile = SecurityProfile::for_mode(mode, catalog, options.insecure_tls)?;
+    if let Some(path) = &options.user_agents {
+        profile.set_user_agents(load_user_agents(path)?)?;
+        logging::write(
+            &format!(
+                "Loaded {} user agents from {}",
+                profile.user_agents().len(),
+                path.display()
+            ),
+            "SECURITY",
+        );
+    }
+    if let Some(agent) = &options.pin_user_agent {
+        profile.set_user_agents(vec![agent.clone()])?;
+    }
+    if options.no_referer {
+        profile.headers.remove(header::REFERER);
+    }
+    if let Some(limit) = options.max_redirects {
+        profile.redirect_limit = limit;
+    }
+    if options.no_keepalive {
+        profile.keepalive = false;
+    }
+    if options.cookies {
+        profile.cookies = true;
+    }
+    if options.no_tor_isolation {
+        profile.tor_isolation = false;
+    }
+    if let Some(version) = options.http_version {
+        profile.http_version = version;
+    }
+    match options.timeout {
+        Some(timeout) => profile.timeout = timeout,
+        // Building a circuit takes much of the time a request to a proxy
+        // gets
+        None if options.through_tor && profile.timeout < TOR_TIMEOUT => {
+            profile.timeout = TOR_TIMEOUT;
+            logging::write(
+                &format!(
+                    "Tor is in use; requests may take {}s",
+                    TOR_TIMEOUT.as_secs()
+                ),
+                "SECURITY",
+            );
+        }
+        None => {}
+    }
+    Ok(profile)
+}
+
+/// Reads a --user-agents file: one user agent per line, and lines
+/// starting with # left out.
+fn load_user_agents(path: &Path) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read user agents {}: {}", path.display(), e))?;
+    let mut agents = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let agent = parse_user_agent(line)
+            .map_err(|e| format!("{} line {}: {}", path.display(), i + 1, e))?;
+        agents.push(agent);
+    }
+    if agents.is_empty() {
+        return Err(format!("{} has no user agents", path.display()));
+    }
+    Ok(agents)
+}
+
+/// Tor as [`start_tor`] left it.
+pub struct StartedTor {
+    pub backend: Arc<dyn TorBackend>,
+    /// Whether it bootstrapped in time; `None` when it cannot tell.
+    pub bootstrapped: Option<bool>,
+}
+
+/// Starts Tor on the `kind` of backend and, where it can tell, waits for it
+/// to bootstrap until `deadline` or until `abandon` is set.
+pub fn start_tor<F>(
+    kind: TorBackendKind,
+    options: TorOptions,
+    on_event: F,
+    deadline: Instant,
+    abandon: &AtomicBool,
+) -> Result<StartedTor, String>
+where
+    F: Fn(TorEvent) + Send + 'static,
+{
+    let backend: Arc<dyn TorBackend> =
+        match kind {
+            TorBackendKind::Binary => Arc::new(TorManager::start(options, on_event)?),
+            #[cfg(feature = "arti")]
+            TorBackendKind::Arti => Arc::new(arti::ArtiTor::start(
+                arti::ArtiOptions {
+                    state_dir: data_dir().join("arti").join("state"),
+                    cache_dir: data_dir().join("arti").join("cache"),
+                },
+                on_event,
+            )?),
+            #[cfg(not(feature = "arti"))]
+            TorBackendKind::Arti => return Err(
+                "This build has no Arti; rebuild with --features arti, or use --tor-backend binary"
+                    .to_string(),
+            ),
+        };
+    if !backend.controllable() {
+        return Ok(StartedTor {
+            backend,
+            bootstrapped: None,
+        });
+    }
+    loop {
+        if backend.bootstrapped().unwrap_or(false) {
+            break;
+        }
+        if backend.has_failed() {
+            return Err("Tor gave up before it bootstrapped".to_string());
+        }
+        if Instant::now() >= deadline || abandon.load(Ordering::SeqCst) {
+            return Ok(StartedTor {
+                backend,
+                bootstrapped: Some(false),
+            });
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    Ok(StartedTor {
+        backend,
+        bootstrapped: Some(true),
+    })
+}
+
+/// What every listener of a session is set up with.
+pub struct ListenerSettings {
+    /// Accept clients from other machines, not only this one.
+    pub allow_remote: bool,
+    /// Log each connection.
+    pub log_connections: bool,
+    pub kill_switch: Option<Arc<KillSwitch>>,
+    pub capture: Option<Arc<Capture>>,
+    /// How long tunnels left on the old route get to finish at a rotation.
+    pub drain_timeout: Duration,
+    pub no_proxy: Option<Arc<NoProxy>>,
+}
+
+/// Opens a listener on each of `specs`, following the same route as the
+/// session client. Their tunnels are counted towards the health of the
+/// proxies in `proxy_rotator`.
+pub fn start_listeners(
+    specs: &[ListenSpec],
+    chain: Option<ChainMode>,
+    forwarder: Option<&Forwarder>,
+    proxy_rotator: &Arc<Mutex<ProxyRotator>>,
+    settings: &ListenerSettings,
+) -> Result<Vec<Arc<Listener>>, String> {
+    if specs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let client_proxy = client_route(chain, forwarder, &proxy_rotator.lock().unwrap());
+    let chain = listener_chain(chain, forwarder, &client_proxy)?;
+    specs
+        .iter()
+        .map(|spec| {
+            let listener = Listener::start(spec, chain.clone(), settings.allow_remote)?;
+            logging::write(&format!("Listening on {}", listener.spec()), "PROXY");
+            // Listener tunnels feed proxy quarantine decisions
+            let rotator = proxy_rotator.clone();
+            listener.set_outcome_hook(Arc::new(move |hop: &Hop, latency: Option<Duration>| {
+                let mut rotator = rotator.lock().unwrap_or_else(|e| e.into_inner());
+                if latency.is_some() {
+                    rotator.requests += 1;
+                }
+                if let Some(index) = rotator.index_of_hop(hop) {
+                    rotator.record_outcome(index, latency.is_some());
+                    rotator.observe_route(index, latency);
+                    if let Some(latency) = latency {
+                        rotator.record_latency(index, latency);
+                    }
+                }
+            }));
+            if settings.log_connections {
+                let name = listener.spec().to_string();
+                listener.set_connection_log(Arc::new(move |line: &str| {
+                    logging::write(&format!("[{}] {}", name, line), "PROXY")
+                }));
+            }
+            if let Some(switch) = &settings.kill_switch {
+                listener.set_kill_switch(switch.clone());
+            }
+            if let Some(capture) = &settings.capture {
+                listener.set_capture(capture.clone());
+            }
+            listener.set_drain_timeout(settings.drain_timeout);
+            if let Some(rules) = &settings.no_proxy {
+                listener.set_no_proxy(rules.clone());
+            }
+            Ok(Arc::new(listener))
+        })
+        .collect()
+}
+
+/// Upstream route for local listeners: the chain when chaining, otherwise
+/// the current rotating proxy.
+fn listener_chain(
+    mode: Option<ChainMode>,
+    forwarder: Option<&Forwarder>,
+    client_proxy: &str,
+) -> Result<Chain, String> {
+    match (mode, forwarder) {
+        (Some(ChainMode::TorThenProxy | ChainMode::Hops), Some(forwarder)) => Ok(forwarder.chain()),
+        // Tor already reaches out through the proxy; listeners just use Tor
+        (Some(ChainMode::ProxyThenTor), _) => Ok(Chain {
+            hops: vec![Hop::parse(client_proxy)?],
+            rotating: None,
+        }),
+        _ => Ok(Chain {
+            hops: vec![Hop::parse(client_proxy)
+                .map_err(|e| format!("Listeners cannot use proxy {}: {}", client_proxy, e))?],
+            rotating: Some(0),
+        }),
+    }
+}
+/// The proxy URL the session client goes through.
+pub fn client_route(
+    chain: Option<ChainMode>,
+    forwarder: Option<&Forwarder>,
+    rotator: &ProxyRotator,
+) -> String {
+    match (chain, forwarder) {
+        (Some(ChainMode::ProxyThenTor), _) => rotator.tor_url().to_string(),
+        (Some(ChainMode::TorThenProxy | ChainMode::Hops), Some(forwarder)) => forwarder.proxy_url(),
+        _ => rotator.current().to_string(),
+    }
+}
+
+/// Flags that ask the rotation thread for an out-of-turn rotation.
+#[derive(Clone, Default)]
+pub struct RotationTriggers {
+    /// Set by SIGUSR1.
+    pub signal: Arc<AtomicBool>,
+    /// Set by the `rotate` command and cleared once the rotation is done.
+    pub control: Arc<AtomicBool>,
+    /// Set when the route missed --heartbeat-failures heartbeats in a row.
+    pub heartbeat: Arc<AtomicBool>,
+    /// Set outside --active-hours, holding every rotation back.
+    pub paused: Arc<AtomicBool>,
+    /// Set when --fallback-order moves the session between Tor and the
+    /// proxies.
+    pub fallback: Arc<AtomicBool>,
+}
+
+/// The route something last got through, without credentials, and when.
+static ROUTE_VERIFIED: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+
+/// Notes that a check just got through `route`.
+pub fn route_verified(route: &str) {
+    *ROUTE_VERIFIED.lock().unwrap_or_else(|e| e.into_inner()) =
+        Some((strip_credentials(route), Instant::now()));
+}
+
+/// Time since a check last got through `route`, if one has since the
+/// route changed.
+pub fn since_verified(route: &str) -> Option<Duration> {
+    let verified = ROUTE_VERIFIED.lock().unwrap_or_else(|e| e.into_inner());
+    verified
+        .as_ref()
+        .filter(|(verified, _)| *verified == strip_credentials(route))
+        .map(|(_, at)| at.elapsed())
+}
+
+/// Starts the thread that rotates the route whenever [`rotation_reason`]
+/// finds a reason to, and follows each rotation up. `blend` carries Tor and its readiness flag when rotations may land on
+/// Tor.
+pub fn start_rotation(
+    proxy_rotator: Arc<Mutex<ProxyRotator>>,
+    running: Arc<AtomicBool>,
+    triggers: RotationTriggers,
+    forwarder: Option<Arc<Forwarder>>,
+    listeners: Vec<Arc<Listener>>,
+    follow_up: RotationFollowUp,
+    blend: Option<(Arc<dyn TorBackend>, Arc<AtomicBool>)>,
+) -> Result<(), String> {
+    workers::try_spawn("rotation", 5, move || {
+        // A crash while rotating leaves the lock poisoned; the rotator's
+        // state is still usable, so carry on with it
+        proxy_rotator.clear_poison();
+        while running.load(Ordering::SeqCst) {
+            if triggers.paused.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+            let rotated = {
+                let mut rotator = proxy_rotator.lock().unwrap();
+                rotator.release_expired();
+                rotator.adapt_interval();
+                let capped = follow_up
+                    .exit_cap
+                    .and_then(|cap| follow_up.exits.lock().unwrap().over_cap(cap));
+                let blocked = follow_up.exits.lock().unwrap().blocked_exit();
+                if let Some(ip) = blocked.as_ref().or(capped.as_ref()) {
+                    // Whatever the reason, the next proxy must exit elsewhere
+                    rotator.avoid = follow_up
+                        .exits
+                        .lock()
+                        .unwrap()
+                        .sharing(ip, &rotator.proxies);
+                }
+                let reason =
+                    rotation_reason(&triggers, &rotator, blocked.is_some(), capped.is_some());
+                let tor_ready = blend
+                    .as_ref()
+                    .is_some_and(|(_, ready)| ready.load(Ordering::SeqCst));
+                let event = reason.and_then(|reason| rotator.rotate(reason, tor_ready));
+                if let (Some(ip), None) = (&capped, &event) {
+                    follow_up.exits.lock().unwrap().report_uncapped(ip);
+                }
+                if let (Some(ip), None) = (&blocked, &event) {
+                    logging::write(
+                        &format!(
+                            "Exit IP {} is on --exit-blocklist, but no proxy is left to rotate to",
+                            ip
+                        ),
+                        "WARNING",
+                    );
+                    follow_up.exits.lock().unwrap().give_up_blocked();
+                }
+                if event.is_some() {
+                    // Isolated identities already have circuits of their own
+                    if let (true, false, Some((tor, _))) =
+                        (rotator.on_tor, rotator.tor_isolation, &blend)
+                    {
+                        if let Err(e) = tor.new_identity() {
+                            logging::write(
+                                &format!("Could not get a new Tor identity: {}", e),
+                                "ERROR",
+                            );
+                        }
+                    }
+                    // Chained sessions and listeners rotate by retargeting
+                    // their upstream hop, or with --chain-rotate all every
+                    // hop; established tunnels drain off the old route
+                    if forwarder.is_some() || !listeners.is_empty() {
+                        let whole = follow_up.chain == Some(ChainMode::Hops)
+                            && follow_up.chain_rotate == ChainRotate::All;
+                        let moved = match (whole, &forwarder) {
+                            (true, Some(forwarder)) => {
+                                rotated_hops(&rotator, forwarder.chain().hops.len()).map(|hops| {
+                                    let mut drains: Vec<Drain> = listeners
+                                        .iter()
+                                        .map(|listener| listener.set_hops(hops.clone()))
+                                        .collect();
+                                    drains.push(forwarder.set_hops(hops));
+                                    drains
+                                })
+                            }
+                            _ => Hop::parse(rotator.current()).map(|hop| {
+                                let mut drains: Vec<Drain> = listeners
+                                    .iter()
+                                    .map(|listener| listener.set_rotating(hop.clone()))
+                                    .collect();
+                                if let Some(forwarder) = &forwarder {
+                                    drains.push(forwarder.set_rotating(hop));
+                                }
+                                drains
+                            }),
+                        };
+                        match moved {
+                            Ok(drains) => drain_old_route(drains, follow_up.drain_timeout),
+                            Err(e) => {
+                                logging::write(&format!("Cannot route via proxy: {}", e), "ERROR")
+                            }
+                        }
+                    }
+                }
+                if reason == Some(RotationReason::Control) {
+                    triggers.control.store(false, Ordering::SeqCst);
+                }
+                // Through Tor the exit only changes with a new identity
+                let route = (follow_up.chain != Some(ChainMode::ProxyThenTor))
+                    .then(|| client_route(follow_up.chain, forwarder.as_deref(), &rotator));
+                let index = (!rotator.on_tor).then_some(rotator.current_index);
+                event.map(|event| (event, route, rotator.user_agent, index))
+            };
+            // Checked without holding the rotator, which listeners need
+            if let Some((event, route, user_agent, index)) = rotated {
+                follow_up.record(event, route, user_agent, index);
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    })
+    .map(drop)
+    .map_err(|e| format!("Cannot start the rotation thread: {}", e))
+}
+
+/// The chain --chain-rotate all moves to: the `len` - 1 usable proxies
+/// after the rotator's current one, in list order, then the current one as
+/// the exit.
+fn rotated_hops(rotator: &ProxyRotator, len: usize) -> Result<Vec<Hop>, String> {
+    let count = rotator.proxies.len();
+    let mut indices: Vec<usize> = (1..count)
+        .map(|step| (rotator.current_index + step) % count)
+        .filter(|&index| !rotator.is_quarantined(index))
+        .take(len.saturating_sub(1))
+        .collect();
+    if indices.len() + 1 < len {
+        return Err(format!(
+            "only {} proxies are usable for a chain of {} hops",
+            indices.len() + 1,
+            len
+        ));
+    }
+    indices.push(rotator.current_index);
+    indices
+        .iter()
+        .map(|&index| Hop::parse(&rotator.proxies[index].url))
+        .collect()
+}
+
+/// Lets the tunnels a rotation left on the old route finish, off the
+/// rotation thread, and logs how many did and how many had to be cut.
+fn drain_old_route(drains: Vec<Drain>, timeout: Duration) {
+    let open: usize = drains.iter().map(Drain::len).sum();
+    if open == 0 {
+        return;
+    }
+    logging::write(
+        &format!(
+            "Draining {} connections on the old route for up to {}s",
+            open,
+            timeout.as_secs()
+        ),
+        "ROTATION",
+    );
+    thread::spawn(move || {
+        let (finished, cut) = drains
+            .into_iter()
+            .map(Drain::finish)
+            .fold((0, 0), |(f, c), (finished, cut)| (f + finished, c + cut));
+        logging::write(
+            &format!(
+                "Old route drained: {} connections finished, {} cut after --drain-timeout",
+                finished, cut
+            ),
+            "ROTATION",
+        );
+    });
+}
+
+/// Why the rotation thread rotates on this pass, if it does, taking the
+/// most pressing reason. `blocked` and `capped` say the exit IP is on
+/// --exit-blocklist or has been used for --max-time-per-exit. The triggers
+/// it goes by are cleared, except the `rotate` command's, which is cleared
+/// once its rotation is done.
+fn rotation_reason(
+    triggers: &RotationTriggers,
+    rotator: &ProxyRotator,
+    blocked: bool,
+    capped: bool,
+) -> Option<RotationReason> {
+    if triggers.fallback.swap(false, Ordering::SeqCst) {
+        Some(RotationReason::Fallback)
+    } else if triggers.heartbeat.swap(false, Ordering::SeqCst) {
+        Some(RotationReason::Heartbeat)
+    } else if !rotator.on_tor && rotator.is_quarantined(rotator.current_index) {
+        Some(RotationReason::Quarantine)
+    } else if blocked {
+        Some(RotationReason::BlockedExit)
+    } else if capped {
+        Some(RotationReason::ExitCap)
+    } else if rotator.lifetime_over() {
+        Some(RotationReason::Lifetime)
+    } else if rotator.dwell_left().is_some() {
+        // --min-dwell holds back the rest, which stay pending
+        None
+    } else if triggers.signal.swap(false, Ordering::SeqCst) {
+        Some(RotationReason::Signal)
+    } else if triggers.control.load(Ordering::SeqCst) {
+        Some(RotationReason::Control)
+    } else if rotator.request_quota_used() {
+        Some(RotationReason::Requests)
+    } else if rotator.byte_quota_used() {
+        Some(RotationReason::Bytes)
+    } else if rotator.should_rotate() {
+        Some(RotationReason::Timer)
+    } else {
+        None
+    }
+}
+
+/// What the rotation thread does once it has switched routes.
+pub struct RotationFollowUp {
+    pub events: EventLog,
+    pub exits: Arc<Mutex<ExitHistory>>,
+    /// Profile the checking client presents, as the session client does.
+    pub profile: Arc<SecurityProfile>,
+    pub chain: Option<ChainMode>,
+    /// --max-time-per-exit.
+    pub exit_cap: Option<Duration>,
+    /// --verify-probe.
+    pub probe: ProbeLevel,
+    /// --drain-timeout.
+    pub drain_timeout: Duration,
+    /// --chain-rotate, under --chain hops.
+    pub chain_rotate: ChainRotate,
+}
+
+impl RotationFollowUp {
+    /// Probes `route`, if given, presenting the route's user agent, and
+    /// records the rotation with what the probe found, telling the hooks
+    /// it went to the proxy at `index`.
+    fn record(
+        &self,
+        mut event: RotationEvent,
+        route: Option<String>,
+        user_agent: usize,
+        index: Option<usize>,
+    ) {
+        logging::write(
+            &format!("User agent: {}", self.profile.user_agent(user_agent)),
+            "DEBUG",
+        );
+        if let Some(route) = route {
+            let started = Instant::now();
+            let ip = match create_http_client(&route, &self.profile, user_agent)
+                .and_then(|client| probe::run(self.probe, &client, &route, Duration::from_secs(10)))
+            {
+                Ok(ip) => {
+                    event.settle_ms = Some(started.elapsed().as_millis() as u64);
+                    route_verified(&event.to);
+                    ip
+                }
+                Err(e) => {
+                    logging::write(
+                        &format!(
+                            "Could not verify the route through {} ({} probe): {}",
+                            strip_credentials(&route),
+                            self.probe.name(),
+                            e
+                        ),
+                        "WARNING",
+                    );
+                    None
+                }
+            };
+            let exit_ip = ip.as_ref().map(|found| found.ip.clone());
+            let mut exits = self.exits.lock().unwrap();
+            if !self.probe.sees_exit_ip() {
+                exits.unchecked();
+            } else if let Some(alarm) = exits.observe(ip, &event.to) {
+                exits.log_alarm(&alarm);
+            }
+            exits.screen(&event.to);
+            // The event log is kept on disk, where the baseline must not go
+            event.exit_ip = exit_ip.filter(|_| !exits.exposed);
+        }
+        hooks::fire(HookEvent {
+            event: HookKind::Rotation,
+            ts: event.ts.clone(),
+            reason: event.reason.to_string(),
+            new_ip: event.exit_ip.clone(),
+            proxy_index: index,
+        });
+        if let Err(e) = self.events.append(&Event::Rotation(event)) {
+            logging::write(&format!("Could not record rotation: {}", e), "ERROR");
+        }
+    }
+}
+
+/// Exit IPs a session keeps for spotting repeated exits.
+const EXIT_HISTORY_LEN: usize = 100;
+/// Rotations in a row made to get off exits on --exit-blocklist before
+/// the session stops trying and, unless --fail-open, stops forwarding.
+const BLOCKED_EXIT_RETRIES: u32 = 5;
+
+/// Exit IPs seen through the session's route, at startup and after each
+/// rotation.
+#[derive(Default)]
+pub struct ExitHistory {
+    /// This machine's own public IP, looked up without a proxy.
+    pub direct: Option<String>,
+    /// What the latest check found; `None` when it failed.
+    pub current: Option<String>,
+    /// The service that reported `current`.
+    pub service: Option<&'static str>,
+    /// Successful checks, oldest first.
+    recent: VecDeque<String>,
+    pub distinct: HashSet<String>,
+    /// Latest reason to distrust the route, for --strict to act on.
+    pub alarm: Option<String>,
+    /// Whether the latest successful check found the direct IP.
+    pub exposed: bool,
+    /// When the exit in `recent.back()` started carrying traffic.
+    stretch_start: Option<Instant>,
+    /// Longest finished stretch on one exit, with that exit.
+    longest: Option<(String, Duration)>,
+    /// Exit each proxy was last seen using, keyed without credentials.
+    by_proxy: HashMap<String, String>,
+    /// Whether this stretch was reported as over the cap with nowhere to go.
+    cap_reported: bool,
+    /// --exit-blocklist.
+    blocklist: Option<Blocklist>,
+    /// The current exit and the --exit-blocklist entry it matched, when the
+    /// latest check found it listed.
+    blocked: Option<(String, String)>,
+    /// Checks in a row that found the exit listed.
+    blocked_in_row: u32,
+    /// Whether rotating leaves the exit as it was, as --chain-rotate first
+    /// does, so that it staying the same is no alarm.
+    fixed_exit: bool,
+}
+
+impl ExitHistory {
+    /// Nothing seen yet. `direct` is this machine's own IP, and
+    /// `fixed_exit` says rotating leaves the exit as it was.
+    pub fn new(direct: Option<String>, blocklist: Option<Blocklist>, fixed_exit: bool) -> Self {
+        ExitHistory {
+            direct,
+            blocklist,
+            fixed_exit,
+            ..ExitHistory::default()
+        }
+    }
+
+    /// Puts a reloaded --exit-blocklist in place, with a fresh count of
+    /// retries.
+    pub fn set_blocklist(&mut self, blocklist: Blocklist) {
+        self.blocklist = Some(blocklist);
+        self.blocked_in_row = 0;
+    }
+
+    /// Records what a check through `proxy` found. Returns an alarm when
+    /// the exit is this machine's own IP or, unless it is fixed, the same
+    /// as the last one seen.
+    pub fn observe(&mut self, found: Option<PublicIp>, proxy: &str) -> Option<String> {
+        self.current = found.as_ref().map(|found| found.ip.clone());
+        self.service = found.as_ref().map(|found| found.service);
+        let ip = found?.ip;
+        self.by_proxy.insert(proxy.to_string(), ip.clone());
+        if self.recent.back() != Some(&ip) {
+            self.longest = self.longest_stretch();
+            self.stretch_start = Some(Instant::now());
+            self.cap_reported = false;
+        }
+        self.exposed = self.direct.as_ref() == Some(&ip);
+        let alarm = if self.exposed {
+            Some(format!(
+                "The exit through {} is this machine's own IP; traffic is not anonymized",
+                proxy
+            ))
+        } else if self.recent.back() == Some(&ip) && !self.fixed_exit {
+            Some(format!(
+                "Exit IP {} did not change on rotating to {}",
+                ip, proxy
+            ))
+        } else {
+            None
+        };
+        if self.recent.len() == EXIT_HISTORY_LEN {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(ip.clone());
+        self.distinct.insert(ip);
+        if alarm.is_some() {
+            self.alarm.clone_from(&alarm);
+        }
+        alarm
+    }
+
+    /// Checks the exit the latest check found against --exit-blocklist,
+    /// logging it with the entry it matched when it is listed. An exit
+    /// that could not be looked up is taken as not listed.
+    pub fn screen(&mut self, proxy: &str) {
+        let listed = self
+            .blocklist
+            .as_ref()
+            .zip(self.current.as_ref())
+            .and_then(|(blocklist, ip)| Some((ip.clone(), blocklist.find(ip.parse().ok()?)?)));
+        let Some((ip, entry)) = listed else {
+            if self.current.is_some() {
+                self.blocked_in_row = 0;
+            }
+            self.blocked = None;
+            return;
+        };
+        self.blocked_in_row += 1;
+        let next = if self.blocked_in_row <= BLOCKED_EXIT_RETRIES {
+            format!(
+                "rotating away ({}/{})",
+                self.blocked_in_row, BLOCKED_EXIT_RETRIES
+            )
+        } else {
+            format!("no more rotating away after {} tries", BLOCKED_EXIT_RETRIES)
+        };
+        logging::write_json(
+            &format!(
+                "Exit IP {} through {} is on --exit-blocklist as {}; {}",
+                ip, proxy, entry, next
+            ),
+            "SECURITY",
+            serde_json::json!({ "event": "exit_blocklisted", "entry": entry }),
+        );
+        self.blocked = Some((ip, entry));
+    }
+
+    /// The listed exit to rotate away from at once, while retries are
+    /// left.
+    fn blocked_exit(&self) -> Option<String> {
+        let (ip, _) = self.blocked.as_ref()?;
+        (self.blocked_in_row <= BLOCKED_EXIT_RETRIES).then(|| ip.clone())
+    }
+
+    /// Why forwarding stops, once retries ran out on listed exits.
+    pub fn blocked_for_good(&self) -> Option<String> {
+        let (ip, entry) = self.blocked.as_ref()?;
+        (self.blocked_in_row > BLOCKED_EXIT_RETRIES).then(|| {
+            format!(
+                "exit IP {} is on --exit-blocklist as {}, as were the {} before it",
+                ip, entry, BLOCKED_EXIT_RETRIES
+            )
+        })
+    }
+
+    /// Gives up rotating away from the listed exit when there was nothing
+    /// to rotate to.
+    fn give_up_blocked(&mut self) {
+        self.blocked_in_row = BLOCKED_EXIT_RETRIES + 1;
+    }
+
+    /// Logs the alarm `observe` just returned.
+    pub fn log_alarm(&self, alarm: &str) {
+        let (category, event) = if self.exposed {
+            ("SECURITY", "exit_is_direct_ip")
+        } else {
+            ("WARNING", "exit_ip_unchanged")
+        };
+        logging::write_json(
+            alarm,
+            category,
+            serde_json::json!({ "event": event, "exit_ip_changed": false }),
+        );
+    }
+
+    /// Records a rotation whose exit IP was not looked up. What was known
+    /// about the previous exit, exposure included, no longer applies.
+    fn unchecked(&mut self) {
+        self.current = None;
+        self.service = None;
+        self.exposed = false;
+    }
+
+    /// The exit traffic has been on the longest, so far.
+    pub fn longest_stretch(&self) -> Option<(String, Duration)> {
+        let current = self
+            .recent
+            .back()
+            .cloned()
+            .zip(self.stretch_start.map(|start| start.elapsed()));
+        match (current, self.longest.clone()) {
+            (Some(current), Some(longest)) if longest.1 > current.1 => Some(longest),
+            (current, longest) => current.or(longest),
+        }
+    }
+
+    /// The current exit, once it has carried traffic for `cap` in a row.
+    /// An exit the latest check could not confirm is not held to it.
+    fn over_cap(&self, cap: Duration) -> Option<String> {
+        self.current.as_ref()?;
+        let start = self.stretch_start?;
+        (start.elapsed() >= cap).then(|| self.recent.back().cloned())?
+    }
+
+    /// Which `proxies` were last seen exiting through `ip`.
+    fn sharing(&self, ip: &str, proxies: &[ProxyEntry]) -> Vec<bool> {
+        proxies
+            .iter()
+            .map(|p| {
+                self.by_proxy
+                    .get(&strip_credentials(&p.url))
+                    .map(String::as_str)
+                    == Some(ip)
+            })
+            .collect()
+    }
+
+    /// Warns, once per stretch, that the cap is hit but every other proxy
+    /// shares the exit or is quarantined.
+    fn report_uncapped(&mut self, ip: &str) {
+        if !self.cap_reported {
+            self.cap_reported = true;
+            logging::write(
+                &format!(
+                    "Exit IP {} is over --max-time-per-exit, but no available proxy is known to exit elsewhere",
+                    ip
+                ),
+                "WARNING",
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotator(interval_secs: u64) -> ProxyRotator {
+        let proxies = ["socks5://127.0.0.1:1080", "socks5://127.0.0.1:1081"]
+            .map(|url| ProxyEntry::parse(url).unwrap())
+            .to_vec();
+        ProxyRotator::new(proxies, interval_secs).unwrap()
+    }
+
+    fn reason(triggers: &RotationTriggers, rotator: &ProxyRotator) -> Option<RotationReason> {
+        rotation_reason(triggers, rotator, false, false)
+    }
+
+    #[test]
+    fn nothing_due_is_no_rotation() {
+        assert!(reason(&RotationTriggers::default(), &rotator(600)).is_none());
+    }
+
+    #[test]
+    fn one_shot_triggers_tag_their_reason_once() {
+        type Flag = fn(&RotationTriggers) -> &AtomicBool;
+        let triggers: [(Flag, RotationReason); 3] = [
+            (|t| &t.fallback, RotationReason::Fallback),
+            (|t| &t.heartbeat, RotationReason::Heartbeat),
+            (|t| &t.signal, RotationReason::Signal),
+        ];
+        let rotator = rotator(600);
+        for (flag, expected) in triggers {
+            let triggers = RotationTriggers::default();
+            flag(&triggers).store(true, Ordering::SeqCst);
+            assert!(reason(&triggers, &rotator) == Some(expected));
+            assert!(reason(&triggers, &rotator).is_none());
+        }
+    }
+
+    #[test]
+    fn control_stays_pending_until_its_rotation_is_done() {
+        let triggers = RotationTriggers::default();
+        triggers.control.store(true, Ordering::SeqCst);
+        let rotator = rotator(600);
+        assert!(reason(&triggers, &rotator) == Some(RotationReason::Control));
+        assert!(reason(&triggers, &rotator) == Some(RotationReason::Control));
+    }
+
+    #[test]
+    fn route_state_tags_its_reason() {
+        let mut quarantined = rotator(600);
+        quarantined.health[0].quarantined_until = Some(Instant::now() + Duration::from_secs(60));
+        let triggers = RotationTriggers::default();
+        triggers.signal.store(true, Ordering::SeqCst);
+        assert!(reason(&triggers, &quarantined) == Some(RotationReason::Quarantine));
+
+        let healthy = rotator(600);
+        let exit = |blocked, capped| rotation_reason(&triggers, &healthy, blocked, capped);
+        assert!(exit(true, true) == Some(RotationReason::BlockedExit));
+        assert!(exit(false, true) == Some(RotationReason::ExitCap));
+
+        let mut lifetime = rotator(600);
+        lifetime.max_lifetime = Some(Duration::ZERO);
+        assert!(reason(&triggers, &lifetime) == Some(RotationReason::Lifetime));
+
+        let mut requests = rotator(600);
+        requests.request_limit = Some(1);
+        requests.requests = 1;
+        let idle = RotationTriggers::default();
+        assert!(reason(&idle, &requests) == Some(RotationReason::Requests));
+
+        let mut bytes = rotator(600);
+        bytes.max_identity_bytes = Some(0);
+        assert!(reason(&idle, &bytes) == Some(RotationReason::Bytes));
+    }
+
+    #[test]
+    fn min_dwell_holds_back_signal_and_timer() {
+        let triggers = RotationTriggers::default();
+        triggers.signal.store(true, Ordering::SeqCst);
+        let mut dwelling = rotator(1);
+        dwelling.min_dwell = Some(Duration::from_secs(60));
+        assert!(reason(&triggers, &dwelling).is_none());
+        // Still pending once the dwell is over
+        dwelling.min_dwell = None;
+        assert!(reason(&triggers, &dwelling) == Some(RotationReason::Signal));
+    }
+
+    #[test]
+    fn elapsed_interval_is_a_timer_rotation() {
+        let mut rotator = rotator(1);
+        let idle = RotationTriggers::default();
+        thread::sleep(Duration::from_millis(1100));
+        assert!(reason(&idle, &rotator) == Some(RotationReason::Timer));
+        let event = rotator.rotate(RotationReason::Timer, false).unwrap();
+        assert!(event.reason == RotationReason::Timer);
+        assert_eq!(rotator.rotations[&RotationReason::Timer], 1);
+        assert!(reason(&idle, &rotator).is_none());
+    }
+}
@@ -0,0 +1,96 @@
+// src/events.rs
+pub mod payloads;
+
+pub use payloads::{
+    Event, HookEvent, HookKind, ProxyStats, RotationEvent, RotationReason, SessionStats,
+    WorkerCrashedEvent,
+};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// How many events the log keeps when it is compacted at session start.
+const KEEP_EVENTS: usize = 1000;
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum EventKind {
+    Rotation,
+    WorkerCrashed,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Rotation(_) => EventKind::Rotation,
+            Event::WorkerCrashed(_) => EventKind::WorkerCrashed,
+        }
+    }
+}
+
+/// Append-only JSON lines file of session events.
+pub struct EventLog {
+    path: PathBuf,
+}
+
+impl EventLog {
+    pub fn new(path: &Path) -> Self {
+        EventLog {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn append(&self, event: &Event) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())
+    }
+
+    /// Every readable event in the file, oldest first. Lines that fail to
+    /// parse (e.g. from a newer version) are skipped.
+    pub fn read(&self) -> io::Result<Vec<Event>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(text
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    /// The last `n` events of `kind`, oldest first.
+    pub fn last(&self, kind: Option<EventKind>, n: usize) -> io::Result<Vec<Event>> {
+        let mut events: Vec<Event> = self
+            .read()?
+            .into_iter()
+            .filter(|e| kind.is_none_or(|k| e.kind() == k))
+            .collect();
+        let skip = events.len().saturating_sub(n);
+        events.drain(..skip);
+        Ok(events)
+    }
+
+    /// Trims the file to the most recent events so it cannot grow forever.
+    pub fn compact(&self) -> io::Result<()> {
+        let events = self.read()?;
+        if events.len() <= KEEP_EVENTS {
+            return Ok(());
+        }
+        let mut text = String::new();
+        for event in &events[events.len() - KEEP_EVENTS..] {
+            text.push_str(&serde_json::to_string(event)?);
+            text.push('\n');
+        }
+        fs::write(&self.path, text)
+    }
+}
@@ -0,0 +1,332 @@
+// src/events/payloads.rs
+// Everything Veko Dome writes out about a session, in its wire form. Event
+// log lines are these types serialized as JSON, so any change here changes
+// what consumers of the log read.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Why the active proxy changed.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum RotationReason {
+    /// The rotation interval elapsed.
+    Timer,
+    /// SIGUSR1 asked for a rotation.
+    Signal,
+    /// The current proxy was quarantined after repeated failures.
+    Quarantine,
+    /// The `rotate` command asked for a rotation.
+    Control,
+    /// The route carried its --rotate-requests quota.
+    Requests,
+    /// The identity carried --max-bytes-per-identity.
+    Bytes,
+    /// One exit IP carried traffic for --max-time-per-exit.
+    ExitCap,
+    /// The exit IP found after the last rotation is on --exit-blocklist.
+    BlockedExit,
+    /// The route missed --heartbeat-failures heartbeats in a row.
+    Heartbeat,
+    /// The route was kept for --max-identity-lifetime.
+    Lifetime,
+    /// --fallback-order moved the session to another transport.
+    Fallback,
+}
+
+impl RotationReason {
+    pub const ALL: [RotationReason; 11] = [
+        RotationReason::Timer,
+        RotationReason::Signal,
+        RotationReason::Quarantine,
+        RotationReason::Control,
+        RotationReason::Requests,
+        RotationReason::Bytes,
+        RotationReason::ExitCap,
+        RotationReason::BlockedExit,
+        RotationReason::Heartbeat,
+        RotationReason::Lifetime,
+        RotationReason::Fallback,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RotationReason::Timer => "timer",
+            RotationReason::Signal => "signal",
+            RotationReason::Quarantine => "quarantine",
+            RotationReason::Control => "control",
+            RotationReason::Requests => "requests",
+            RotationReason::Bytes => "bytes",
+            RotationReason::ExitCap => "exit_cap",
+            RotationReason::BlockedExit => "blocked_exit",
+            RotationReason::Heartbeat => "heartbeat",
+            RotationReason::Lifetime => "lifetime",
+            RotationReason::Fallback => "fallback",
+        }
+    }
+}
+
+impl fmt::Display for RotationReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RotationEvent {
+    /// RFC 3339 timestamp of the rotation.
+    pub ts: String,
+    pub reason: RotationReason,
+    /// Proxy before and after, with credentials removed.
+    pub from: String,
+    pub to: String,
+    /// Time until the new route was verified, when verification ran.
+    #[serde(default)]
+    pub settle_ms: Option<u64>,
+    /// Exit IP observed through the new route, when verification ran.
+    #[serde(default)]
+    pub exit_ip: Option<String>,
+}
+
+/// A background worker panicked.
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WorkerCrashedEvent {
+    pub ts: String,
+    pub worker: String,
+    pub message: String,
+    /// Whether the worker was restarted; if not, the session is degraded.
+    pub restarting: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Rotation(RotationEvent),
+    WorkerCrashed(WorkerCrashedEvent),
+}
+
+/// What --on-rotate, --on-failure and --webhook-url are told about an
+/// event. Proxies are named by their place in the list, never by URL.
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct HookEvent {
+    pub event: HookKind,
+    pub ts: String,
+    /// The rotation reason, or what broke the anonymization path.
+    pub reason: String,
+    /// Exit IP seen through the new route, when the rotation checked it.
+    #[serde(default)]
+    pub new_ip: Option<String>,
+    /// Place of the new proxy in the list, from 0; none on Tor.
+    #[serde(default)]
+    pub proxy_index: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum HookKind {
+    /// The route rotated.
+    Rotation,
+    /// The kill switch stopped forwarding.
+    Failure,
+}
+
+impl HookKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookKind::Rotation => "rotation",
+            HookKind::Failure => "failure",
+        }
+    }
+}
+
+/// What a session did, written when it ends: by default as text, as JSON
+/// with `--output json`, and to `--report`. Addresses are redacted as log
+/// lines are, and no request contents are kept.
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SessionStats {
+    pub session: String,
+    /// RFC 3339 timestamp of the start.
+    pub started: String,
+    pub duration_secs: u64,
+    pub rotations: u64,
+    /// Rotations per reason, for the reasons that happened.
+    pub rotations_by_reason: Vec<(RotationReason, u64)>,
+    pub distinct_exit_ips: usize,
+    /// Rotations that could not keep to --rotate-distinct, when it is set.
+    #[serde(default)]
+    pub distinct_relaxed: Option<u64>,
+    /// Longest time one exit IP carried traffic in a row.
+    #[serde(default)]
+    pub longest_exit_secs: Option<u64>,
+    /// Connection attempts per proxy, in list order.
+    pub proxies: Vec<ProxyStats>,
+    /// New identities Tor was given.
+    pub tor_new_identities: u64,
+    /// Bytes listener and chain tunnels carried, both ways.
+    pub bytes_forwarded: u64,
+    /// Identities rotated away from, when there was a rotation.
+    #[serde(default)]
+    pub identities: Option<u64>,
+    /// Most bytes the tunnels of any one of those identities carried.
+    #[serde(default)]
+    pub largest_identity_bytes: Option<u64>,
+    /// Decoy requests answered, with --decoy.
+    #[serde(default)]
+    pub decoy_requests: Option<u64>,
+    pub worker_restarts: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ProxyStats {
+    /// Without credentials.
+    pub proxy: String,
+    pub successes: u64,
+    pub failures: u64,
+    /// Bytes tunnels through the proxy carried from and to clients.
+    #[serde(default)]
+    pub bytes_up: u64,
+    #[serde(default)]
+    pub bytes_down: u64,
+}
+
+/// Stand-in addresses from the documentation ranges, so samples never look
+/// like a real route.
+const SAMPLE_PROXY: &str = "http://203.0.113.7:8080";
+const SAMPLE_EXIT_IP: &str = "198.51.100.23";
+const SAMPLE_TS: &str = "2024-01-01T12:00:00+00:00";
+
+/// A representative event of `kind` with every field filled in, for
+/// developing consumers without a running session.
+pub fn sample(kind: super::EventKind) -> Event {
+    match kind {
+        super::EventKind::Rotation => Event::Rotation(RotationEvent {
+            ts: SAMPLE_TS.to_string(),
+            reason: RotationReason::Timer,
+            from: "socks5h://127.0.0.1:9050".to_string(),
+            to: SAMPLE_PROXY.to_string(),
+            settle_ms: Some(420),
+            exit_ip: Some(SAMPLE_EXIT_IP.to_string()),
+        }),
+        super::EventKind::WorkerCrashed => Event::WorkerCrashed(WorkerCrashedEvent {
+            ts: SAMPLE_TS.to_string(),
+            worker: "rotation".to_string(),
+            message: "called `Option::unwrap()` on a `None` value".to_string(),
+            restarting: true,
+        }),
+    }
+}
+
+/// JSON schema of an event log line.
+#[cfg(feature = "schema")]
+pub fn schema() -> String {
+    serde_json::to_string_pretty(&schemars::schema_for!(Event)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use serde::de::DeserializeOwned;
+
+    /// Compares `value` on the wire with a checked-in fixture, and checks
+    /// the fixture still reads back to the same wire shape. A failure prints
+    /// what is sent now, to review and paste into the fixture.
+    fn assert_golden<T: Serialize + DeserializeOwned>(value: &T, fixture: &str) {
+        let sent = serde_json::to_value(value).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(fixture).unwrap();
+        assert!(
+            sent == expected,
+            "wire shape changed; now sent:\n{}",
+            serde_json::to_string_pretty(&sent).unwrap()
+        );
+        let read: T = serde_json::from_str(fixture).unwrap();
+        assert_eq!(serde_json::to_value(read).unwrap(), expected);
+    }
+
+    #[test]
+    fn rotation_event_matches_its_fixture() {
+        assert_golden(
+            &sample(EventKind::Rotation),
+            include_str!("fixtures/rotation.json"),
+        );
+    }
+
+    #[test]
+    fn worker_crashed_event_matches_its_fixture() {
+        assert_golden(
+            &sample(EventKind::WorkerCrashed),
+            include_str!("fixtures/worker_crashed.json"),
+        );
+    }
+
+    #[test]
+    fn hook_events_match_their_fixtures() {
+        assert_golden(
+            &HookEvent {
+                event: HookKind::Rotation,
+                ts: SAMPLE_TS.to_string(),
+                reason: RotationReason::Quarantine.to_string(),
+                new_ip: Some(SAMPLE_EXIT_IP.to_string()),
+                proxy_index: Some(3),
+            },
+            include_str!("fixtures/hook_rotation.json"),
+        );
+        assert_golden(
+            &HookEvent {
+                event: HookKind::Failure,
+                ts: SAMPLE_TS.to_string(),
+                reason: "Tor stopped answering".to_string(),
+                new_ip: None,
+                proxy_index: None,
+            },
+            include_str!("fixtures/hook_failure.json"),
+        );
+    }
+
+    #[test]
+    fn session_stats_match_their_fixture() {
+        assert_golden(
+            &SessionStats {
+                session: "default".to_string(),
+                started: SAMPLE_TS.to_string(),
+                duration_secs: 3600,
+                rotations: 5,
+                rotations_by_reason: vec![
+                    (RotationReason::Timer, 4),
+                    (RotationReason::BlockedExit, 1),
+                ],
+                distinct_exit_ips: 4,
+                distinct_relaxed: None,
+                longest_exit_secs: Some(900),
+                proxies: vec![ProxyStats {
+                    proxy: SAMPLE_PROXY.to_string(),
+                    successes: 12,
+                    failures: 1,
+                    bytes_up: 2048,
+                    bytes_down: 65536,
+                }],
+                tor_new_identities: 0,
+                bytes_forwarded: 67584,
+                identities: Some(5),
+                largest_identity_bytes: Some(30000),
+                decoy_requests: None,
+                worker_restarts: 0,
+            },
+            include_str!("fixtures/session_stats.json"),
+        );
+    }
+
+    #[test]
+    fn every_reason_is_sent_as_its_name() {
+        for reason in RotationReason::ALL {
+            assert_eq!(serde_json::to_value(reason).unwrap(), reason.as_str());
+        }
+    }
+}
@@ -0,0 +1,221 @@
+// src/fallback.rs
+// --fallback-order: the transports a session may carry traffic over, most
+// preferred first, and what takes over when the one in use stops working.
+// The session moves on as soon as its transport fails, but only moves back
+// up once a preferred transport has stayed healthy for the recovery
+// window, so one that comes and goes does not make the route flap. A
+// direct connection is only ever used when the order lists it, and `fail`,
+// like running out of transports, ends the session.
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Transport {
+    Tor,
+    Proxy,
+    /// This machine's own connection, bypassing the route.
+    Direct,
+    /// Stop the session.
+    Fail,
+}
+
+impl Transport {
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Tor => "tor",
+            Transport::Proxy => "proxy",
+            Transport::Direct => "direct",
+            Transport::Fail => "fail",
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How a transport is doing.
+#[derive(Clone)]
+pub enum Health {
+    Up,
+    /// Not usable yet, but not failed either, such as Tor while it
+    /// bootstraps. The session waits on it rather than leaving it.
+    Starting,
+    Down(String),
+}
+
+/// One `--fallback-order` value.
+#[derive(Clone)]
+pub struct FallbackOrder(Vec<Transport>);
+
+impl FallbackOrder {
+    /// Parses a comma-separated list such as tor,proxy,fail. Each transport
+    /// may be listed once, and nothing may follow `fail`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut order = Vec::new();
+        for name in s.split(',').map(str::trim) {
+            let transport = match name {
+                "tor" => Transport::Tor,
+                "proxy" => Transport::Proxy,
+                "direct" => Transport::Direct,
+                "fail" => Transport::Fail,
+                other => {
+                    return Err(format!(
+                        "unknown transport '{}'; expected tor, proxy, direct or fail",
+                        other
+                    ))
+                }
+            };
+            if order.contains(&transport) {
+                return Err(format!("{} is listed twice", transport));
+            }
+            if order.last() == Some(&Transport::Fail) {
+                return Err("nothing may follow fail".to_string());
+            }
+            order.push(transport);
+        }
+        if order.first() == Some(&Transport::Fail) {
+            return Err("fail cannot come first; list a transport before it".to_string());
+        }
+        Ok(FallbackOrder(order))
+    }
+
+    pub fn uses(&self, transport: Transport) -> bool {
+        self.0.contains(&transport)
+    }
+
+    /// Whether a transport other than `lost` is listed to carry traffic.
+    pub fn survives(&self, lost: &[Transport]) -> bool {
+        self.0
+            .iter()
+            .any(|t| *t != Transport::Fail && !lost.contains(t))
+    }
+}
+
+impl fmt::Display for FallbackOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.0.iter().map(|t| t.name()).collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+/// A move from one transport to another.
+pub struct Transition {
+    pub from: Transport,
+    pub to: Transport,
+    /// Whether `to` comes before `from` in the order.
+    pub recovered: bool,
+    pub reason: String,
+}
+
+/// Which transport a session is on, and since when the others are healthy.
+pub struct Fallback {
+    order: FallbackOrder,
+    recovery: Duration,
+    active: usize,
+    /// Per entry in the order, since when it has been up.
+    up_since: Vec<Option<Instant>>,
+    /// Why the session left the first transport, while it is not on it.
+    reason: Option<String>,
+}
+
+impl Fallback {
+    /// Starts on the first transport in `order`, moving back up to a
+    /// preferred one after it has been up for `recovery`.
+    pub fn new(order: FallbackOrder, recovery: Duration) -> Self {
+        Fallback {
+            up_since: vec![None; order.0.len()],
+            order,
+            recovery,
+            active: 0,
+            reason: None,
+        }
+    }
+
+    pub fn active(&self) -> Transport {
+        self.order.0[self.active]
+    }
+
+    /// Looks at how each transport is doing and moves the session when it
+    /// should: off the active transport once it is down, to the first one
+    /// that is up (or to [`Transport::Fail`] when none is), and back to a
+    /// preferred one once it has been up for the recovery window.
+    pub fn evaluate<F>(&mut self, health: F, now: Instant) -> Option<Transition>
+    where
+        F: Fn(Transport) -> Health,
+    {
+        let healths: Vec<Health> = self
+            .order
+            .0
+            .iter()
+            .map(|&transport| match transport {
+                Transport::Direct | Transport::Fail => Health::Up,
+                transport => health(transport),
+            })
+            .collect();
+        for (since, health) in self.up_since.iter_mut().zip(&healths) {
+            match health {
+                Health::Up => *since = since.or(Some(now)),
+                _ => *since = None,
+            }
+        }
+        let from = self.active();
+        let (to, reason) = match &healths[self.active] {
+            Health::Down(why) => {
+                let to = healths.iter().position(|h| matches!(h, Health::Up));
+                (to, format!("{} is down: {}", from, why))
+            }
+            Health::Up | Health::Starting => {
+                let to = self.up_since[..self.active]
+                    .iter()
+                    .position(|since| since.is_some_and(|t| now - t >= self.recovery))?;
+                let reason = format!(
+                    "{} has been up for {}s",
+                    self.order.0[to],
+                    self.recovery.as_secs()
+                );
+                (Some(to), reason)
+            }
+        };
+        // Running out of transports ends the session as `fail` does
+        let Some(to) = to else {
+            return Some(Transition {
+                from,
+                to: Transport::Fail,
+                recovered: false,
+                reason: format!("{}, and no other transport listed is up", reason),
+            });
+        };
+        let recovered = to < self.active;
+        self.active = to;
+        if to == 0 {
+            self.reason = None;
+        } else if !recovered {
+            self.reason = Some(reason.clone());
+        }
+        Some(Transition {
+            from,
+            to: self.active(),
+            recovered,
+            reason,
+        })
+    }
+
+    /// The transport in use, and why when it is not the preferred one, e.g.
+    /// "proxy (falling back: tor is down: Tor gave up; order tor,proxy,fail)".
+    pub fn describe(&self) -> String {
+        match &self.reason {
+            Some(reason) => format!(
+                "{} (falling back: {}; order {})",
+                self.active(),
+                reason,
+                self.order
+            ),
+            None => format!("{} (preferred; order {})", self.active(), self.order),
+        }
+    }
+}
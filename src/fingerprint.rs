@@ -0,0 +1,153 @@
+// src/fingerprint.rs
+// Headers a browser sends along with its User-Agent. A Chrome UA without
+// client hints, or with Firefox's Accept, gives the client away, so the
+// headers every request carries are picked to match the UA presented.
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Browser {
+    Chrome,
+    Edge,
+    Firefox,
+    Safari,
+}
+
+impl Browser {
+    /// The browser `user_agent` names, going by the tokens each one adds
+    /// last: Edge's UA also names Chrome and Safari, Chrome's Safari.
+    fn of(user_agent: &str) -> Option<Browser> {
+        if user_agent.contains("Edg/") {
+            Some(Browser::Edge)
+        } else if user_agent.contains("Chrome/") || user_agent.contains("CriOS/") {
+            Some(Browser::Chrome)
+        } else if user_agent.contains("Firefox/") {
+            Some(Browser::Firefox)
+        } else if user_agent.contains("Safari/") {
+            Some(Browser::Safari)
+        } else {
+            None
+        }
+    }
+
+    fn chromium(self) -> bool {
+        matches!(self, Browser::Chrome | Browser::Edge)
+    }
+}
+
+/// The headers the browser of `user_agent` sends with a top-level
+/// navigation, in its order. A UA of no known browser gets only a bare
+/// Accept.
+pub fn headers(user_agent: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let Some(browser) = Browser::of(user_agent) else {
+        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+        return headers;
+    };
+    if browser.chromium() {
+        let brand = client_hint_brands(browser, user_agent);
+        let mobile = if user_agent.contains("Mobile") {
+            "?1"
+        } else {
+            "?0"
+        };
+        insert(&mut headers, "sec-ch-ua", &brand);
+        insert(&mut headers, "sec-ch-ua-mobile", mobile);
+        insert(
+            &mut headers,
+            "sec-ch-ua-platform",
+            &format!("\"{}\"", platform(user_agent)),
+        );
+    }
+    headers.insert(
+        header::UPGRADE_INSECURE_REQUESTS,
+        HeaderValue::from_static("1"),
+    );
+    let accept = match browser {
+        Browser::Chrome | Browser::Edge => {
+            "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,\
+             image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.7"
+        }
+        Browser::Firefox => {
+            "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8"
+        }
+        Browser::Safari => "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+    };
+    headers.insert(header::ACCEPT, HeaderValue::from_static(accept));
+    for (name, value) in [
+        ("sec-fetch-site", "none"),
+        ("sec-fetch-mode", "navigate"),
+        ("sec-fetch-user", "?1"),
+        ("sec-fetch-dest", "document"),
+    ] {
+        insert(&mut headers, name, value);
+    }
+    headers.insert(
+        header::ACCEPT_ENCODING,
+        HeaderValue::from_static("gzip, deflate, br"),
+    );
+    let language = match browser {
+        Browser::Firefox => "en-US,en;q=0.5",
+        _ => "en-US,en;q=0.9",
+    };
+    headers.insert(header::ACCEPT_LANGUAGE, HeaderValue::from_static(language));
+    headers
+}
+
+/// Whether the browser of `user_agent` offers HTTP/2. Every browser known
+/// here does, through ALPN; anything else is taken for an HTTP/1.1 tool.
+pub fn offers_http2(user_agent: &str) -> bool {
+    Browser::of(user_agent).is_some()
+}
+
+/// sec-ch-ua, e.g. `"Not_A Brand";v="8", "Chromium";v="120", "Google
+/// Chrome";v="120"`, with the major version the UA gives.
+fn client_hint_brands(browser: Browser, user_agent: &str) -> String {
+    let chromium = major_version(user_agent, "Chrome/").unwrap_or("120");
+    let (name, version) = match browser {
+        Browser::Edge => (
+            "Microsoft Edge",
+            major_version(user_agent, "Edg/").unwrap_or(chromium),
+        ),
+        _ => ("Google Chrome", chromium),
+    };
+    format!(
+        "\"Not_A Brand\";v=\"8\", \"Chromium\";v=\"{}\", \"{}\";v=\"{}\"",
+        chromium, name, version
+    )
+}
+
+fn major_version<'a>(user_agent: &'a str, token: &str) -> Option<&'a str> {
+    let start = user_agent.find(token)? + token.len();
+    let version = &user_agent[start..];
+    let end = version
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(version.len());
+    (end > 0).then(|| &version[..end])
+}
+
+/// sec-ch-ua-platform, from the UA's system token.
+fn platform(user_agent: &str) -> &'static str {
+    if user_agent.contains("Android") {
+        "Android"
+    } else if user_agent.contains("Windows") {
+        "Windows"
+    } else if user_agent.contains("Macintosh") {
+        "macOS"
+    } else if user_agent.contains("CrOS") {
+        "Chrome OS"
+    } else if user_agent.contains("iPhone") || user_agent.contains("iPad") {
+        "iOS"
+    } else if user_agent.contains("Linux") {
+        "Linux"
+    } else {
+        "Unknown"
+    }
+}
+
+/// Inserts a header whose value comes from the UA. The UA was checked to be
+/// a valid header value, and the parts taken from it are digits.
+fn insert(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(HeaderName::from_static(name), value);
+    }
+}
@@ -0,0 +1,1426 @@
+// src/forwarder.rs
+use crate::capture::{Capture, Exchange};
+use crate::http_proxy::{self, ProxyRequest};
+use crate::kill_switch::{KillSwitch, Tracked};
+use crate::no_proxy::{NoProxy, NoProxyRule, Policy};
+use crate::pool::{ProxyPool, Slot};
+use crate::socks::{self, TargetAddr};
+use crate::workers;
+use base64::Engine;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+const MAX_HTTP_RESPONSE_HEAD: usize = 8 * 1024;
+/// UDP associations with no traffic for this long are torn down, since the
+/// upstream's NAT mapping has most likely expired by then.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const UDP_POLL: Duration = Duration::from_secs(1);
+/// How often a drain looks whether the old route's tunnels have closed.
+const DRAIN_POLL: Duration = Duration::from_millis(200);
+
+/// Bytes tunnels carried this session, both ways.
+static FORWARDED: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes every tunnel of the session carried so far, both ways.
+pub fn bytes_forwarded() -> u64 {
+    FORWARDED.load(Ordering::Relaxed)
+}
+
+/// Bytes tunnels carried each way: up from the client, down to it.
+#[derive(Default)]
+pub struct Traffic {
+    up: AtomicU64,
+    down: AtomicU64,
+}
+
+impl Traffic {
+    pub fn up(&self) -> u64 {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub fn down(&self) -> u64 {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.up() + self.down()
+    }
+
+    fn add(&self, up: bool, n: u64) {
+        match up {
+            true => self.up.fetch_add(n, Ordering::Relaxed),
+            false => self.down.fetch_add(n, Ordering::Relaxed),
+        };
+    }
+}
+
+/// Per rotating hop, what tunnels through it carried.
+static BY_HOP: OnceLock<Mutex<HashMap<String, Arc<Traffic>>>> = OnceLock::new();
+/// What tunnels opened under the current identity carried. Tunnels keep
+/// counting towards the identity they were opened under.
+static IDENTITY: OnceLock<Mutex<Arc<Traffic>>> = OnceLock::new();
+/// Identities ended so far, which numbers the current one.
+static IDENTITIES: AtomicU64 = AtomicU64::new(0);
+
+/// What tunnels through `hop` carried this session.
+pub fn hop_traffic(hop: &Hop) -> Arc<Traffic> {
+    BY_HOP
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap()
+        .entry(hop.to_string())
+        .or_default()
+        .clone()
+}
+
+/// What tunnels opened since the last rotation carried.
+pub fn identity_traffic() -> Arc<Traffic> {
+    IDENTITY.get_or_init(Mutex::default).lock().unwrap().clone()
+}
+
+/// Starts counting for a new identity, and returns the one it ends.
+pub fn next_identity() -> Arc<Traffic> {
+    let mut identity = IDENTITY.get_or_init(Mutex::default).lock().unwrap();
+    IDENTITIES.fetch_add(1, Ordering::Relaxed);
+    std::mem::take(&mut *identity)
+}
+
+/// The current identity's number, rotations before it, and its counters.
+fn current_identity() -> (u64, Arc<Traffic>) {
+    let identity = IDENTITY.get_or_init(Mutex::default).lock().unwrap();
+    (IDENTITIES.load(Ordering::Relaxed), identity.clone())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum HopKind {
+    /// SOCKS5; `remote_dns` is false for socks5:// (resolve locally) and
+    /// true for socks5h:// (let the proxy resolve).
+    Socks5 { remote_dns: bool },
+    /// HTTP proxy reached with CONNECT.
+    Http,
+}
+
+/// One proxy in a chain.
+#[derive(Clone)]
+pub struct Hop {
+    pub kind: HopKind,
+    pub addr: TargetAddr,
+    auth: Option<(String, String)>,
+}
+
+impl Hop {
+    /// Parses a proxy URL with a socks5, socks5h or http scheme.
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let parsed = reqwest::Url::parse(url).map_err(|e| format!("invalid proxy URL: {}", e))?;
+        let kind = match parsed.scheme() {
+            "socks5" => HopKind::Socks5 { remote_dns: false },
+            "socks5h" => HopKind::Socks5 { remote_dns: true },
+            "http" => HopKind::Http,
+            other => {
+                return Err(format!(
+                    "{}:// proxies cannot be chained; use socks5, socks5h or http",
+                    other
+                ))
+            }
+        };
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| "proxy URL has no host".to_string())?;
+        let port = parsed
+            .port_or_known_default()
+            .or(match kind {
+                HopKind::Socks5 { .. } => Some(1080),
+                HopKind::Http => None,
+            })
+            .ok_or_else(|| "proxy URL has no port".to_string())?;
+        let addr = TargetAddr::parse(&format!("{}:{}", host, port))
+            .ok_or_else(|| format!("invalid proxy address {}:{}", host, port))?;
+        let auth = if parsed.username().is_empty() {
+            None
+        } else {
+            Some((
+                parsed.username().to_string(),
+                parsed.password().unwrap_or("").to_string(),
+            ))
+        };
+        Ok(Hop { kind, addr, auth })
+    }
+
+    /// Asks this hop to connect to `target`. `behind_remote_dns` says an
+    /// earlier hop resolves names remotely (Tor, socks5h), so a socks5://
+    /// hop is sent the name too rather than leaking it to local DNS.
+    fn handshake(
+        &self,
+        stream: &mut TcpStream,
+        target: &TargetAddr,
+        behind_remote_dns: bool,
+    ) -> io::Result<()> {
+        match self.kind {
+            HopKind::Socks5 { remote_dns } => {
+                let target = if remote_dns || behind_remote_dns {
+                    target.clone()
+                } else {
+                    resolve(target)?
+                };
+                let auth = self.auth.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+                socks::client_connect(stream, &target, auth)
+            }
+            HopKind::Http => self.http_connect(stream, target),
+        }
+    }
+
+    fn http_connect(&self, stream: &mut TcpStream, target: &TargetAddr) -> io::Result<()> {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some((user, pass)) = &self.auth {
+            let token =
+                base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, pass));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        // Read the response head byte by byte so nothing past it is consumed
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_HTTP_RESPONSE_HEAD {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "oversized CONNECT response",
+                ));
+            }
+            stream.read_exact(&mut byte)?;
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status_line = head.lines().next().unwrap_or("");
+        let status = status_line.split_whitespace().nth(1).unwrap_or("");
+        if status == "200" {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("HTTP CONNECT to {} failed: {}", target, status_line.trim()),
+            ))
+        }
+    }
+}
+
+impl fmt::Display for Hop {
+    // Never includes credentials
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.kind {
+            HopKind::Socks5 { remote_dns: false } => "socks5",
+            HopKind::Socks5 { remote_dns: true } => "socks5h",
+            HopKind::Http => "http",
+        };
+        write!(f, "{}://{}", scheme, self.addr)
+    }
+}
+
+/// Refuses IPv6 addresses under --block-ipv6.
+fn check_family(addr: &SocketAddr) -> io::Result<()> {
+    if addr.is_ipv6() && crate::dns::ipv6_blocked() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is IPv6, which is blocked", addr),
+        ));
+    }
+    Ok(())
+}
+
+fn resolve(target: &TargetAddr) -> io::Result<TargetAddr> {
+    match target {
+        TargetAddr::Ip(addr) => check_family(addr).map(|_| target.clone()),
+        TargetAddr::Domain(host, port) => crate::dns::resolve(host, *port)?
+            .into_iter()
+            .next()
+            .map(TargetAddr::Ip)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", host))
+            }),
+    }
+}
+
+fn open(addr: &TargetAddr) -> io::Result<TcpStream> {
+    let TargetAddr::Ip(addr) = resolve(addr)? else {
+        unreachable!("resolve always yields an IP");
+    };
+    TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+}
+
+/// Opens a tunnel to `target` by connecting to the first hop and asking each
+/// hop in turn to connect to the next one. A failure carries the index of
+/// the hop to blame; `hops.len()` means the target itself. How long
+/// reaching each hop took, and then the target, goes to `timings`.
+fn connect_chain(
+    hops: &[Hop],
+    target: &TargetAddr,
+    timings: &mut Vec<Duration>,
+) -> Result<TcpStream, (usize, io::Error)> {
+    // Never fall back to a direct connection
+    let Some(first) = hops.first() else {
+        return Err((0, io::Error::other("proxy chain is empty")));
+    };
+    let hop_error = |n: usize, e: io::Error| {
+        io::Error::new(e.kind(), format!("hop {} ({}): {}", n + 1, hops[n], e))
+    };
+
+    let mut lap = Instant::now();
+    let mut stream = open(&first.addr).map_err(|e| (0, hop_error(0, e)))?;
+    timings.push(lap.elapsed());
+    stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(|e| (0, e))?;
+    let mut remote_dns = false;
+    for (n, hop) in hops.iter().enumerate() {
+        let next = hops.get(n + 1).map(|h| &h.addr).unwrap_or(target);
+        lap = Instant::now();
+        hop.handshake(&mut stream, next, remote_dns).map_err(|e| {
+            // A refusal means this hop works but could not reach the next
+            let blame = if e.kind() == io::ErrorKind::ConnectionRefused {
+                n + 1
+            } else {
+                n
+            };
+            (blame, hop_error(n, e))
+        })?;
+        timings.push(lap.elapsed());
+        remote_dns |= hop.kind == HopKind::Socks5 { remote_dns: true };
+    }
+    stream.set_read_timeout(None).map_err(|e| (0, e))?;
+    Ok(stream)
+}
+
+/// Copies bytes both ways until either side closes.
+pub fn pipe(client: TcpStream, upstream: TcpStream) {
+    pipe_counted(client, upstream, Vec::new(), None);
+}
+
+/// [`pipe`], adding what goes each way to every one of `meters` too. With
+/// `status`, the status of the response upstream sends first is put there.
+fn pipe_counted(
+    client: TcpStream,
+    upstream: TcpStream,
+    meters: Vec<Arc<Traffic>>,
+    status: Option<&mut Option<u16>>,
+) {
+    let (Ok(mut client_read), Ok(mut upstream_write)) = (client.try_clone(), upstream.try_clone())
+    else {
+        return;
+    };
+    let uplink = {
+        let meters = meters.clone();
+        thread::spawn(move || {
+            copy_counted(&mut client_read, &mut upstream_write, &meters, true, None);
+            let _ = upstream_write.shutdown(Shutdown::Write);
+        })
+    };
+
+    let (mut upstream_read, mut client_write) = (upstream, client);
+    copy_counted(
+        &mut upstream_read,
+        &mut client_write,
+        &meters,
+        false,
+        status,
+    );
+    let _ = client_write.shutdown(Shutdown::Write);
+    let _ = uplink.join();
+}
+
+/// Copies until `from` closes or fails, adding what got through to
+/// [`bytes_forwarded`] and `meters` as it goes. With `status`, the first
+/// chunk is read as the start of an HTTP response.
+fn copy_counted(
+    from: &mut TcpStream,
+    to: &mut TcpStream,
+    meters: &[Arc<Traffic>],
+    up: bool,
+    mut status: Option<&mut Option<u16>>,
+) {
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        if let Some(status) = status.take() {
+            *status = http_proxy::response_status(&buf[..n]);
+        }
+        if to.write_all(&buf[..n]).is_err() {
+            return;
+        }
+        FORWARDED.fetch_add(n as u64, Ordering::Relaxed);
+        for meter in meters {
+            meter.add(up, n as u64);
+        }
+    }
+}
+
+/// Ordered hops plus the position of the one that follows proxy rotation,
+/// if any.
+#[derive(Clone)]
+pub struct Chain {
+    pub hops: Vec<Hop>,
+    pub rotating: Option<usize>,
+}
+
+impl fmt::Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hops: Vec<String> = self.hops.iter().map(|h| h.to_string()).collect();
+        write!(f, "{}", hops.join(" -> "))
+    }
+}
+
+/// What local clients speak to a forwarder.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Frontend {
+    Socks5,
+    /// HTTP proxy: CONNECT plus absolute-URI requests for plain HTTP.
+    Http,
+}
+
+/// Receives one line per client connection.
+pub type ConnectionLog = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Told about each tunnel attempt through the rotating hop: how long the
+/// tunnel took to set up, or `None` if the hop failed.
+pub type OutcomeHook = Arc<dyn Fn(&Hop, Option<Duration>) + Send + Sync>;
+
+/// Unauthenticated SOCKS5 or HTTP proxy endpoint that tunnels every
+/// connection through a retargetable chain of upstream hops.
+pub struct Forwarder {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    active: Arc<AtomicUsize>,
+    /// Connections accepted so far.
+    accepted: Arc<AtomicU64>,
+}
+
+struct Shared {
+    frontend: Frontend,
+    chain: Mutex<Chain>,
+    /// Upstream proxies known to support UDP ASSOCIATE.
+    udp_hops: Mutex<Vec<Hop>>,
+    connection_log: Mutex<Option<ConnectionLog>>,
+    /// When set, the rotating hop is picked from here subject to
+    /// per-proxy connection limits.
+    pool: Mutex<Option<Arc<ProxyPool>>>,
+    on_outcome: Mutex<Option<OutcomeHook>>,
+    kill_switch: Mutex<Option<Arc<KillSwitch>>>,
+    no_proxy: Mutex<Option<Arc<NoProxy>>>,
+    /// Set while --fallback-order has fallen back to direct connections.
+    direct: AtomicBool,
+    /// Both ends of every tunnel open through the chain, so a rotation can
+    /// drain them.
+    routed: Mutex<Routed>,
+    /// How long tunnels left on the old route get to finish.
+    drain_timeout: Mutex<Duration>,
+    /// Where requests are recorded, with --capture.
+    capture: Mutex<Option<Arc<Capture>>>,
+}
+
+#[derive(Default)]
+struct Routed {
+    tunnels: HashMap<u64, Vec<TcpStream>>,
+    next_id: u64,
+}
+
+/// Keeps a tunnel among those a rotation drains until it is dropped.
+struct RoutedGuard<'a> {
+    shared: &'a Shared,
+    id: Option<u64>,
+}
+
+impl Drop for RoutedGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.shared.routed.lock().unwrap().tunnels.remove(&id);
+        }
+    }
+}
+
+/// The tunnels a rotation left on the old route. Dropping it leaves them
+/// open.
+pub struct Drain {
+    shared: Arc<Shared>,
+    ids: Vec<u64>,
+    /// When what is left gets cut.
+    deadline: Instant,
+}
+
+impl Drain {
+    /// Tunnels that were open on the old route.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Waits until the drain timeout after the rotation for the tunnels
+    /// to close, then cuts what is left. Returns how many finished, and
+    /// how many were cut.
+    pub fn finish(self) -> (usize, usize) {
+        let deadline = self.deadline;
+        loop {
+            let open = {
+                let routed = self.shared.routed.lock().unwrap();
+                self.ids
+                    .iter()
+                    .filter(|id| routed.tunnels.contains_key(id))
+                    .count()
+            };
+            if open == 0 || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(DRAIN_POLL.min(deadline.saturating_duration_since(Instant::now())));
+        }
+        let mut routed = self.shared.routed.lock().unwrap();
+        let mut cut = 0;
+        for id in &self.ids {
+            if let Some(streams) = routed.tunnels.remove(id) {
+                for stream in streams {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                cut += 1;
+            }
+        }
+        (self.ids.len() - cut, cut)
+    }
+}
+
+impl Shared {
+    /// Why new connections are refused, while the kill switch is engaged.
+    fn refusal(&self) -> Option<String> {
+        self.kill_switch.lock().unwrap().as_ref()?.reason()
+    }
+
+    /// Registers an open tunnel with the kill switch, if there is one.
+    fn track(&self, streams: &[&TcpStream]) -> Result<Option<Tracked>, String> {
+        match self.kill_switch.lock().unwrap().as_ref() {
+            Some(switch) => switch.track(streams).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Registers a tunnel through the chain, for the next rotation to
+    /// drain. A stream that cannot be cloned is left alone.
+    fn route(&self, streams: &[&TcpStream]) -> RoutedGuard<'_> {
+        let clones = streams
+            .iter()
+            .map(|s| s.try_clone())
+            .collect::<Result<Vec<_>, _>>();
+        let id = clones.ok().map(|clones| {
+            let mut routed = self.routed.lock().unwrap();
+            let id = routed.next_id;
+            routed.next_id += 1;
+            routed.tunnels.insert(id, clones);
+            id
+        });
+        RoutedGuard { shared: self, id }
+    }
+
+    /// The --no-proxy rule that keeps `target` off the route, if any. The
+    /// decision is logged at debug level either way.
+    fn exclusion(&self, target: &TargetAddr) -> Option<NoProxyRule> {
+        let rules = self.no_proxy.lock().unwrap().clone()?;
+        let rule = rules.matching(target).cloned();
+        match &rule {
+            Some(rule) => log::debug!("{}: kept off the route by --no-proxy {}", target, rule),
+            None => log::debug!("{}: no --no-proxy rule matches, proxied", target),
+        }
+        rule
+    }
+
+    fn log_connection(&self, peer: Option<SocketAddr>, target: &TargetAddr, outcome: &str) {
+        let Some(log) = self.connection_log.lock().unwrap().clone() else {
+            return;
+        };
+        let peer = peer.map_or_else(|| "?".to_string(), |p| p.to_string());
+        log(&format!("{} -> {}: {}", peer, target, outcome));
+    }
+}
+
+/// Keeps the active-connection count right however a tunnel ends.
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    fn new(active: &Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        ActiveGuard(active.clone())
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Forwarder {
+    /// Starts a forwarder on an ephemeral loopback port.
+    pub fn start(chain: Chain) -> io::Result<Self> {
+        Self::bind("127.0.0.1:0".parse().unwrap(), chain, Frontend::Socks5)
+    }
+
+    pub fn bind(addr: SocketAddr, chain: Chain, frontend: Frontend) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            frontend,
+            chain: Mutex::new(chain),
+            udp_hops: Mutex::new(Vec::new()),
+            connection_log: Mutex::new(None),
+            pool: Mutex::new(None),
+            on_outcome: Mutex::new(None),
+            kill_switch: Mutex::new(None),
+            no_proxy: Mutex::new(None),
+            direct: AtomicBool::new(false),
+            routed: Mutex::default(),
+            drain_timeout: Mutex::new(Duration::ZERO),
+            capture: Mutex::new(None),
+        });
+        let active = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::new(AtomicU64::new(0));
+
+        let serving = shared.clone();
+        let counter = active.clone();
+        let total = accepted.clone();
+        let name = format!("accept {}", addr);
+        workers::spawn(&name, 5, move || {
+            for stream in listener.incoming().flatten() {
+                total.fetch_add(1, Ordering::Relaxed);
+                let shared = serving.clone();
+                let guard = ActiveGuard::new(&counter);
+                thread::spawn(move || {
+                    match shared.frontend {
+                        Frontend::Socks5 => serve(stream, &shared),
+                        Frontend::Http => serve_http(stream, &shared),
+                    }
+                    drop(guard);
+                });
+            }
+        });
+        Ok(Forwarder {
+            addr,
+            shared,
+            active,
+            accepted,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// URL for pointing a client at this forwarder.
+    pub fn proxy_url(&self) -> String {
+        format!("socks5h://{}", self.addr)
+    }
+
+    /// Retargets the rotating hop. New tunnels take the new route at once;
+    /// the returned drain holds those still open on the old one.
+    pub fn set_rotating(&self, hop: Hop) -> Drain {
+        let rotating = {
+            let mut chain = self.shared.chain.lock().unwrap();
+            if let Some(index) = chain.rotating {
+                chain.hops[index] = hop;
+            }
+            chain.rotating.is_some()
+        };
+        // A chain with nothing rotating keeps its route
+        self.drain(rotating)
+    }
+
+    /// Replaces every hop of the chain at once, as --chain-rotate all
+    /// does; the rotating hop keeps its position. Tunnels still open on the
+    /// old route are in the returned drain.
+    pub fn set_hops(&self, hops: Vec<Hop>) -> Drain {
+        self.shared.chain.lock().unwrap().hops = hops;
+        self.drain(true)
+    }
+
+    /// The tunnels open now, when the route `moved`.
+    fn drain(&self, moved: bool) -> Drain {
+        let ids = match moved {
+            true => {
+                let routed = self.shared.routed.lock().unwrap();
+                routed.tunnels.keys().copied().collect()
+            }
+            false => Vec::new(),
+        };
+        Drain {
+            shared: self.shared.clone(),
+            ids,
+            deadline: Instant::now() + *self.shared.drain_timeout.lock().unwrap(),
+        }
+    }
+
+    /// Gives tunnels left on the old route by a rotation `timeout` to
+    /// finish before they are cut. Zero cuts them at once.
+    pub fn set_drain_timeout(&self, timeout: Duration) {
+        *self.shared.drain_timeout.lock().unwrap() = timeout;
+    }
+
+    pub fn chain(&self) -> Chain {
+        self.shared.chain.lock().unwrap().clone()
+    }
+
+    /// Records every request an HTTP frontend serves to `capture`.
+    pub fn set_capture(&self, capture: Arc<Capture>) {
+        *self.shared.capture.lock().unwrap() = Some(capture);
+    }
+
+    /// Reports every client connection and its outcome to `log`.
+    pub fn set_connection_log(&self, log: ConnectionLog) {
+        *self.shared.connection_log.lock().unwrap() = Some(log);
+    }
+
+    /// Counts new tunnels against `pool`, moving off the rotating proxy
+    /// while it is at its connection limit.
+    pub fn set_pool(&self, pool: Arc<ProxyPool>) {
+        *self.shared.pool.lock().unwrap() = Some(pool);
+    }
+
+    /// Reports whether tunnels through the rotating hop succeed.
+    pub fn set_outcome_hook(&self, hook: OutcomeHook) {
+        *self.shared.on_outcome.lock().unwrap() = Some(hook);
+    }
+
+    /// Stops forwarding whenever `switch` is engaged.
+    pub fn set_kill_switch(&self, switch: Arc<KillSwitch>) {
+        *self.shared.kill_switch.lock().unwrap() = Some(switch);
+    }
+
+    /// Keeps the destinations `rules` match off the route.
+    pub fn set_no_proxy(&self, rules: Arc<NoProxy>) {
+        *self.shared.no_proxy.lock().unwrap() = Some(rules);
+    }
+
+    /// Connects to every destination directly, bypassing the chain, while
+    /// `direct` is set.
+    pub fn set_direct(&self, direct: bool) {
+        self.shared.direct.store(direct, Ordering::SeqCst);
+    }
+
+    /// Sets which upstream proxies may carry UDP ASSOCIATE traffic.
+    pub fn set_udp_hops(&self, hops: Vec<Hop>) {
+        *self.shared.udp_hops.lock().unwrap() = hops;
+    }
+
+    /// Connections currently being served.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Connections accepted since the forwarder started.
+    pub fn connections(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+}
+
+fn serve(mut stream: TcpStream, shared: &Shared) {
+    let request = match socks::server_accept(&mut stream) {
+        Ok(request) => request,
+        Err(e) => {
+            log::debug!("Forwarder dropped a malformed request: {}", e);
+            return;
+        }
+    };
+    let peer = stream.peer_addr().ok();
+    if let Some(reason) = shared.refusal() {
+        shared.log_connection(peer, &request.target, &refused(&reason));
+        let _ = socks::reply(&mut stream, socks::REPLY_NOT_ALLOWED);
+        return;
+    }
+    if socks::is_udp_associate(&request) {
+        serve_udp(stream, udp_hop(shared), shared);
+        return;
+    }
+    if !socks::is_connect(&request) {
+        let _ = socks::reply(&mut stream, socks::REPLY_COMMAND_NOT_SUPPORTED);
+        return;
+    }
+    if let Some(rule) = shared.exclusion(&request.target) {
+        if rule.policy == Policy::Block {
+            shared.log_connection(peer, &request.target, &excluded(&rule));
+            let _ = socks::reply(&mut stream, socks::REPLY_NOT_ALLOWED);
+            return;
+        }
+        match open(&request.target) {
+            Ok(direct) => {
+                shared.log_connection(peer, &request.target, &excluded(&rule));
+                if socks::reply(&mut stream, socks::REPLY_SUCCEEDED).is_ok() {
+                    pipe(stream, direct);
+                }
+            }
+            Err(e) => {
+                shared.log_connection(peer, &request.target, &format!("failed ({})", e));
+                let _ = socks::reply(&mut stream, socks::REPLY_HOST_UNREACHABLE);
+            }
+        }
+        return;
+    }
+
+    match connect(shared, &request.target) {
+        Ok(upstream) => {
+            // The path may have broken while connecting
+            let _tracked = match shared.track(&[&stream, &upstream.stream]) {
+                Ok(tracked) => tracked,
+                Err(reason) => {
+                    shared.log_connection(peer, &request.target, &refused(&reason));
+                    let _ = socks::reply(&mut stream, socks::REPLY_NOT_ALLOWED);
+                    return;
+                }
+            };
+            let _routed = upstream
+                .routed
+                .then(|| shared.route(&[&stream, &upstream.stream]));
+            shared.log_connection(peer, &request.target, "connected");
+            if socks::reply(&mut stream, socks::REPLY_SUCCEEDED).is_ok() {
+                pipe_counted(stream, upstream.stream, upstream.meters, None);
+            }
+        }
+        Err(e) => {
+            log::warn!("Forwarder could not reach {}: {}", request.target, e);
+            shared.log_connection(peer, &request.target, &format!("failed ({})", e));
+            let _ = socks::reply(&mut stream, socks::REPLY_HOST_UNREACHABLE);
+        }
+    }
+}
+
+fn refused(reason: &str) -> String {
+    format!("refused (fail-closed: {})", reason)
+}
+
+fn excluded(rule: &NoProxyRule) -> String {
+    match rule.policy {
+        Policy::Block => format!("refused (--no-proxy {})", rule),
+        Policy::Direct => format!("connected directly (--no-proxy {})", rule),
+    }
+}
+
+/// A tunnel through the chain, with what it holds while open.
+struct Upstream {
+    stream: TcpStream,
+    /// The pool slot of the rotating hop, which must live as long as the
+    /// tunnel.
+    _slot: Option<Slot>,
+    /// The identity's counters, and the rotating hop's.
+    meters: Vec<Arc<Traffic>>,
+    /// Whether the tunnel goes through the chain rather than directly.
+    routed: bool,
+    /// The number of the identity it was opened under, when routed.
+    identity: Option<u64>,
+    /// The rotating hop, if the chain has one.
+    via: Option<String>,
+}
+
+/// Connects through the current chain, taking a pool slot for the rotating
+/// hop when a pool is set.
+fn connect(shared: &Shared, target: &TargetAddr) -> io::Result<Upstream> {
+    if shared.direct.load(Ordering::SeqCst) {
+        log::debug!("{}: connected directly (--fallback-order direct)", target);
+        return open(target).map(|stream| Upstream {
+            stream,
+            _slot: None,
+            meters: Vec::new(),
+            routed: false,
+            identity: None,
+            via: None,
+        });
+    }
+    let (mut hops, rotating) = {
+        let chain = shared.chain.lock().unwrap();
+        (chain.hops.clone(), chain.rotating)
+    };
+    let pool = shared.pool.lock().unwrap().clone();
+    let slot = match (pool, rotating) {
+        // A hop outside the pool (Tor in a blended rotation) is not limited
+        (Some(pool), Some(index)) if pool.contains(&hops[index]) => {
+            let slot = pool.acquire(&hops[index])?;
+            hops[index] = slot.hop().clone();
+            Some(slot)
+        }
+        _ => None,
+    };
+    if let TargetAddr::Ip(addr) = target {
+        check_family(addr)?;
+    }
+    // With --resolve-locally no hop is given the name
+    let target = &match target {
+        TargetAddr::Domain(..) if crate::dns::resolves_targets_locally() => resolve(target)?,
+        _ => target.clone(),
+    };
+    let started = Instant::now();
+    let result = connect_chain(&hops, target, &mut Vec::new());
+    let hook = shared.on_outcome.lock().unwrap().clone();
+    if let (Some(hook), Some(index)) = (hook, rotating) {
+        match &result {
+            Ok(_) => hook(&hops[index], Some(started.elapsed())),
+            Err((blame, _)) if *blame == index => hook(&hops[index], None),
+            Err(_) => {}
+        }
+    }
+    let stream = result.map_err(|(_, e)| e)?;
+    let (identity, traffic) = current_identity();
+    let mut meters = vec![traffic];
+    meters.extend(rotating.map(|index| hop_traffic(&hops[index])));
+    Ok(Upstream {
+        stream,
+        _slot: slot,
+        meters,
+        routed: true,
+        identity: Some(identity),
+        via: rotating.map(|index| hops[index].to_string()),
+    })
+}
+
+/// A request's --capture record, written however serving it ends.
+struct Captured {
+    capture: Arc<Capture>,
+    exchange: Exchange,
+    /// What the request and its response carried.
+    traffic: Arc<Traffic>,
+}
+
+impl Drop for Captured {
+    fn drop(&mut self) {
+        self.exchange.request_bytes = self.traffic.up();
+        self.exchange.response_bytes = self.traffic.down();
+        self.capture.record(&mut self.exchange);
+    }
+}
+
+/// Answers the client with an error, noting its status for --capture.
+fn reject(stream: &mut TcpStream, captured: &mut Option<Captured>, status: &str, message: &str) {
+    if let Some(captured) = captured {
+        captured.exchange.status = status.split(' ').next().and_then(|code| code.parse().ok());
+    }
+    let _ = http_proxy::error_response(stream, status, message);
+}
+
+fn serve_http(mut stream: TcpStream, shared: &Shared) {
+    let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+    let (head, request) = match http_proxy::read_request_head(&mut stream)
+        .map_err(|e| e.to_string())
+        .and_then(|head| match http_proxy::parse_request(&head) {
+            Ok(request) => Ok((head, request)),
+            Err(e) => Err(e.to_string()),
+        }) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            log::debug!("Forwarder dropped a malformed HTTP request: {}", e);
+            let _ = http_proxy::error_response(&mut stream, "400 Bad Request", &e);
+            return;
+        }
+    };
+    let _ = stream.set_read_timeout(None);
+
+    let peer = stream.peer_addr().ok();
+    let capture = shared.capture.lock().unwrap().clone();
+    let mut captured = capture.map(|capture| Captured {
+        capture,
+        exchange: Exchange::new(peer, &request.method, &request.target, &head),
+        traffic: Arc::default(),
+    });
+    let refuse = |stream: &mut TcpStream, captured: &mut Option<Captured>, reason: &str| {
+        shared.log_connection(peer, &request.target, &refused(reason));
+        reject(
+            stream,
+            captured,
+            "503 Service Unavailable",
+            "Anonymization path is down; forwarding is stopped until it recovers",
+        );
+    };
+    if let Some(reason) = shared.refusal() {
+        refuse(&mut stream, &mut captured, &reason);
+        return;
+    }
+    if let Some(rule) = shared.exclusion(&request.target) {
+        if rule.policy == Policy::Block {
+            shared.log_connection(peer, &request.target, &excluded(&rule));
+            reject(
+                &mut stream,
+                &mut captured,
+                "403 Forbidden",
+                "Destination excluded from the proxy route",
+            );
+            return;
+        }
+        let direct = match open(&request.target) {
+            Ok(direct) => direct,
+            Err(e) => {
+                shared.log_connection(
+                    peer,
+                    &request.target,
+                    &format!("{} failed ({})", request.method, e),
+                );
+                reject(
+                    &mut stream,
+                    &mut captured,
+                    "502 Bad Gateway",
+                    "Destination unreachable",
+                );
+                return;
+            }
+        };
+        shared.log_connection(peer, &request.target, &excluded(&rule));
+        let meters: Vec<Arc<Traffic>> = captured.iter().map(|c| c.traffic.clone()).collect();
+        if let Some(captured) = &mut captured {
+            captured.exchange.proxy = Some("direct".to_string());
+        }
+        forward(stream, direct, &request, meters, &mut captured);
+        return;
+    }
+    let started = Instant::now();
+    let mut upstream = match connect(shared, &request.target) {
+        Ok(connected) => connected,
+        Err(e) if e.kind() == io::ErrorKind::ResourceBusy => {
+            shared.log_connection(peer, &request.target, &format!("refused ({})", e));
+            reject(
+                &mut stream,
+                &mut captured,
+                "503 Service Unavailable",
+                "Proxy pool saturated",
+            );
+            return;
+        }
+        Err(e) => {
+            log::warn!("Forwarder could not reach {}: {}", request.target, e);
+            shared.log_connection(
+                peer,
+                &request.target,
+                &format!("{} failed ({})", request.method, e),
+            );
+            // Fail fast rather than leaving the client waiting
+            reject(
+                &mut stream,
+                &mut captured,
+                "502 Bad Gateway",
+                "Upstream proxy unreachable",
+            );
+            return;
+        }
+    };
+    let _tracked = match shared.track(&[&stream, &upstream.stream]) {
+        Ok(tracked) => tracked,
+        Err(reason) => {
+            refuse(&mut stream, &mut captured, &reason);
+            return;
+        }
+    };
+    let _routed = upstream
+        .routed
+        .then(|| shared.route(&[&stream, &upstream.stream]));
+    shared.log_connection(peer, &request.target, &request.method);
+    if let Some(captured) = &mut captured {
+        captured.exchange.connect_ms = Some(started.elapsed().as_millis() as u64);
+        captured.exchange.identity = upstream.identity;
+        captured.exchange.proxy =
+            Some(upstream.via.clone().unwrap_or_else(|| "direct".to_string()));
+        upstream.meters.push(captured.traffic.clone());
+    }
+    forward(
+        stream,
+        upstream.stream,
+        &request,
+        upstream.meters,
+        &mut captured,
+    );
+}
+
+/// Sends a plain HTTP request on, or tells a CONNECT client its tunnel is
+/// open, then copies both ways until either side closes.
+fn forward(
+    mut stream: TcpStream,
+    mut upstream: TcpStream,
+    request: &ProxyRequest,
+    meters: Vec<Arc<Traffic>>,
+    captured: &mut Option<Captured>,
+) {
+    let ready = match &request.forward_head {
+        Some(head) => upstream.write_all(head).map(|_| {
+            for meter in &meters {
+                meter.add(true, head.len() as u64);
+            }
+        }),
+        None => http_proxy::connection_established(&mut stream),
+    };
+    if ready.is_err() {
+        return;
+    }
+    let status = captured.as_mut().map(|c| &mut c.exchange.status);
+    match (&request.forward_head, status) {
+        (None, Some(status)) => {
+            *status = Some(200);
+            pipe_counted(stream, upstream, meters, None);
+        }
+        (_, status) => pipe_counted(stream, upstream, meters, status),
+    }
+}
+
+/// Picks the upstream for a UDP association: the current proxy, if it can
+/// carry UDP. Another proxy would show a second exit IP for the identity,
+/// so without it there is none. Multi-hop chains cannot carry UDP.
+fn udp_hop(shared: &Shared) -> Option<Hop> {
+    let chain = shared.chain.lock().unwrap();
+    if chain.hops.len() != 1 {
+        return None;
+    }
+    let current = chain.hops[0].to_string();
+    shared
+        .udp_hops
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|hop| hop.to_string() == current)
+        .cloned()
+}
+
+/// Opens a UDP association on `hop`, returning the control connection that
+/// keeps it alive and the relay address to send datagrams to.
+fn open_udp_association(hop: &Hop) -> io::Result<(TcpStream, SocketAddr)> {
+    if hop.kind == HopKind::Http {
+        return Err(io::Error::other("HTTP proxies cannot relay UDP"));
+    }
+    let mut control = open(&hop.addr)?;
+    control.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let auth = hop.auth.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+    let TargetAddr::Ip(mut relay) = resolve(&socks::client_udp_associate(&mut control, auth)?)?
+    else {
+        unreachable!("resolve always yields an IP");
+    };
+    // Servers commonly answer 0.0.0.0 meaning "the address you connected to"
+    if relay.ip().is_unspecified() {
+        relay.set_ip(control.peer_addr()?.ip());
+    }
+    control.set_read_timeout(None)?;
+    Ok((control, relay))
+}
+
+/// Opens a tunnel to `target` through `hop` alone.
+pub fn tunnel(hop: &Hop, target: &TargetAddr) -> io::Result<TcpStream> {
+    connect_chain(std::slice::from_ref(hop), target, &mut Vec::new()).map_err(|(_, e)| e)
+}
+
+/// Opens a tunnel to `target` through `hops` and closes it again, timing
+/// each step: reaching the first hop, then each handshake that has a hop
+/// connect on to the next one or, last, to `target`. An error names the
+/// hop that failed.
+pub fn hop_latencies(hops: &[Hop], target: &TargetAddr) -> Result<Vec<Duration>, String> {
+    let mut timings = Vec::new();
+    connect_chain(hops, target, &mut timings)
+        .map(|_| timings)
+        .map_err(|(_, e)| e.to_string())
+}
+
+/// Whether `hop` accepts UDP ASSOCIATE.
+pub fn probe_udp(hop: &Hop) -> bool {
+    open_udp_association(hop).is_ok()
+}
+
+fn serve_udp(mut control: TcpStream, hop: Option<Hop>, shared: &Shared) {
+    let Some(hop) = hop else {
+        let _ = socks::reply(&mut control, socks::REPLY_COMMAND_NOT_SUPPORTED);
+        return;
+    };
+    let (upstream_control, relay) = match open_udp_association(&hop) {
+        Ok(association) => association,
+        Err(e) => {
+            log::warn!("UDP ASSOCIATE via {} failed: {}", hop, e);
+            let _ = socks::reply(&mut control, socks::REPLY_HOST_UNREACHABLE);
+            return;
+        }
+    };
+    // Cutting the control connections ends the association
+    let Ok(_tracked) = shared.track(&[&control, &upstream_control]) else {
+        let _ = socks::reply(&mut control, socks::REPLY_NOT_ALLOWED);
+        return;
+    };
+    let _routed = shared.route(&[&control, &upstream_control]);
+    let setup = || -> io::Result<(UdpSocket, UdpSocket, IpAddr)> {
+        let client_ip = control.peer_addr()?.ip();
+        let local = UdpSocket::bind((control.local_addr()?.ip(), 0))?;
+        let unspecified: IpAddr = if relay.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let upstream = UdpSocket::bind((unspecified, 0))?;
+        upstream.connect(relay)?;
+        local.set_read_timeout(Some(UDP_POLL))?;
+        upstream.set_read_timeout(Some(UDP_POLL))?;
+        Ok((local, upstream, client_ip))
+    };
+    let (local, upstream, client_ip) = match setup() {
+        Ok(sockets) => sockets,
+        Err(e) => {
+            log::warn!("Cannot set up UDP relay: {}", e);
+            let _ = socks::reply(&mut control, socks::REPLY_HOST_UNREACHABLE);
+            return;
+        }
+    };
+    let Ok(bound) = local.local_addr() else {
+        return;
+    };
+    if socks::reply_bound(&mut control, bound).is_err() {
+        return;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let last_activity = Arc::new(Mutex::new(Instant::now()));
+    // The client's UDP source port may change mid-association (NAT
+    // rebinding), so always answer the latest source from its IP
+    let client_addr: Arc<Mutex<Option<SocketAddr>>> = Arc::new(Mutex::new(None));
+    let (Ok(local_rx), Ok(upstream_rx)) = (local.try_clone(), upstream.try_clone()) else {
+        return;
+    };
+
+    let uplink = {
+        let (done, last_activity, client_addr) =
+            (done.clone(), last_activity.clone(), client_addr.clone());
+        thread::spawn(move || {
+            let mut buf = [0u8; 65535];
+            while !done.load(Ordering::SeqCst) {
+                let Ok((n, src)) = local_rx.recv_from(&mut buf) else {
+                    continue;
+                };
+                // Only the client that opened the association may use it,
+                // and fragmented datagrams are not supported
+                if src.ip() != client_ip || socks::parse_udp_datagram(&buf[..n]).is_none() {
+                    continue;
+                }
+                *client_addr.lock().unwrap() = Some(src);
+                *last_activity.lock().unwrap() = Instant::now();
+                // Same header format upstream, so forward unchanged
+                let _ = upstream.send(&buf[..n]);
+            }
+        })
+    };
+    let downlink = {
+        let (done, last_activity, client_addr) =
+            (done.clone(), last_activity.clone(), client_addr.clone());
+        thread::spawn(move || {
+            let mut buf = [0u8; 65535];
+            while !done.load(Ordering::SeqCst) {
+                let Ok(n) = upstream_rx.recv(&mut buf) else {
+                    continue;
+                };
+                let Some(addr) = *client_addr.lock().unwrap() else {
+                    continue;
+                };
+                *last_activity.lock().unwrap() = Instant::now();
+                let _ = local.send_to(&buf[..n], addr);
+            }
+        })
+    };
+
+    // The association ends when the client drops its TCP connection or the
+    // relay has been idle too long
+    let _ = control.set_read_timeout(Some(UDP_POLL));
+    let mut byte = [0u8; 1];
+    loop {
+        match control.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(_) => break,
+        }
+        if last_activity.lock().unwrap().elapsed() >= UDP_IDLE_TIMEOUT {
+            log::debug!("UDP association via {} idle, closing", hop);
+            break;
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    let _ = uplink.join();
+    let _ = downlink.join();
+    drop(upstream_control);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kill_switch::Cause;
+
+    /// Plays every SOCKS5 hop of `chain` on one listener, in the order the
+    /// exchanges arrive through it, and returns what the last hop was
+    /// asked to connect to. `PORT` in the URLs is the listener's.
+    fn last_hop_target(chain: &[&str], target: TargetAddr) -> TargetAddr {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let exchanges = chain.len();
+        let relay = thread::spawn(move || {
+            let (mut stream, _) = server.accept().unwrap();
+            let mut asked = None;
+            for _ in 0..exchanges {
+                asked = Some(socks::server_accept(&mut stream).unwrap().target);
+                socks::reply(&mut stream, socks::REPLY_SUCCEEDED).unwrap();
+            }
+            asked.unwrap()
+        });
+        let hops: Vec<Hop> = chain
+            .iter()
+            .map(|url| Hop::parse(&url.replace("PORT", &port.to_string())).unwrap())
+            .collect();
+        connect_chain(&hops, &target, &mut Vec::new()).unwrap();
+        relay.join().unwrap()
+    }
+
+    fn example() -> TargetAddr {
+        TargetAddr::Domain("localhost".to_string(), 443)
+    }
+
+    #[test]
+    fn socks5_hop_behind_tor_gets_the_name() {
+        let asked = last_hop_target(
+            &["socks5h://127.0.0.1:PORT", "socks5://127.0.0.1:1080"],
+            example(),
+        );
+        assert!(
+            matches!(&asked, TargetAddr::Domain(host, 443) if host == "localhost"),
+            "resolved locally to {}",
+            asked
+        );
+    }
+
+    #[test]
+    fn socks5_hop_on_its_own_resolves_locally() {
+        let asked = last_hop_target(&["socks5://127.0.0.1:PORT"], example());
+        assert!(matches!(asked, TargetAddr::Ip(addr) if addr.port() == 443));
+    }
+
+    /// Delay of the slow echo server's every answer.
+    const ECHO_DELAY: Duration = Duration::from_millis(100);
+
+    /// A SOCKS5 proxy on loopback that, whatever it is asked to connect
+    /// to, echoes what it gets after `ECHO_DELAY`. Returns its hop and how
+    /// many tunnels were opened through it.
+    fn slow_echo_proxy() -> (Hop, Arc<AtomicUsize>) {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("socks5://{}", server.local_addr().unwrap());
+        let opened = Arc::new(AtomicUsize::new(0));
+        let counter = opened.clone();
+        thread::spawn(move || {
+            for mut stream in server.incoming().flatten() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    socks::server_accept(&mut stream)?;
+                    socks::reply(&mut stream, socks::REPLY_SUCCEEDED)?;
+                    let mut buf = [0; 64];
+                    loop {
+                        let n = stream.read(&mut buf)?;
+                        if n == 0 {
+                            return Ok::<_, io::Error>(());
+                        }
+                        thread::sleep(ECHO_DELAY);
+                        stream.write_all(&buf[..n])?;
+                    }
+                });
+            }
+        });
+        (Hop::parse(&url).unwrap(), opened)
+    }
+
+    fn forwarder_to(hop: Hop) -> Forwarder {
+        Forwarder::start(Chain {
+            hops: vec![hop],
+            rotating: Some(0),
+        })
+        .unwrap()
+    }
+
+    /// A tunnel through `forwarder` that has carried one echo.
+    fn tunnel_through(forwarder: &Forwarder) -> TcpStream {
+        let mut stream = TcpStream::connect(forwarder.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let target = TargetAddr::Ip("192.0.2.7:7".parse().unwrap());
+        socks::client_connect(&mut stream, &target, None).unwrap();
+        assert!(echoes(&mut stream, b"hello"));
+        stream
+    }
+
+    /// Whether `stream` still carries `message` there and back.
+    fn echoes(stream: &mut TcpStream, message: &[u8]) -> bool {
+        let mut answer = vec![0; message.len()];
+        stream.write_all(message).is_ok()
+            && stream.read_exact(&mut answer).is_ok()
+            && answer == message
+    }
+
+    #[test]
+    fn a_rotation_lets_open_tunnels_finish_on_the_old_route() {
+        let (old, on_old) = slow_echo_proxy();
+        let (new, on_new) = slow_echo_proxy();
+        let forwarder = forwarder_to(old);
+        forwarder.set_drain_timeout(Duration::from_secs(10));
+        let mut open = tunnel_through(&forwarder);
+
+        let drain = forwarder.set_rotating(new);
+        assert_eq!(drain.len(), 1);
+        tunnel_through(&forwarder);
+        assert_eq!(on_new.load(Ordering::SeqCst), 1);
+        assert!(echoes(&mut open, b"still here"));
+        assert_eq!(on_old.load(Ordering::SeqCst), 1);
+
+        drop(open);
+        let started = Instant::now();
+        assert_eq!(drain.finish(), (1, 0));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn a_rotation_cuts_tunnels_open_past_the_drain_timeout() {
+        let (old, _) = slow_echo_proxy();
+        let (new, _) = slow_echo_proxy();
+        let forwarder = forwarder_to(old);
+        forwarder.set_drain_timeout(Duration::from_millis(300));
+        let mut open = tunnel_through(&forwarder);
+
+        let started = Instant::now();
+        let drain = forwarder.set_rotating(new);
+        assert_eq!(drain.finish(), (0, 1));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(!echoes(&mut open, b"cut"));
+    }
+
+    #[test]
+    fn the_kill_switch_cuts_tunnels_at_once() {
+        let (old, _) = slow_echo_proxy();
+        let forwarder = forwarder_to(old);
+        forwarder.set_drain_timeout(Duration::from_secs(10));
+        let switch = Arc::new(KillSwitch::default());
+        forwarder.set_kill_switch(switch.clone());
+        let mut open = tunnel_through(&forwarder);
+
+        let started = Instant::now();
+        assert!(switch.engage(Cause::Tor, "Tor exited".to_string()));
+        assert!(!echoes(&mut open, b"cut"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let mut refused = TcpStream::connect(forwarder.addr()).unwrap();
+        let target = TargetAddr::Ip("192.0.2.7:7".parse().unwrap());
+        assert!(socks::client_connect(&mut refused, &target, None).is_err());
+    }
+}
@@ -0,0 +1,682 @@
+// src/geo.rs
+use crate::throttle;
+use reqwest::{blocking::Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// ip-api's batch endpoint accepts at most this many queries per request.
+const BATCH_SIZE: usize = 100;
+/// ip-api documents 15 batch requests per minute for the free tier.
+const BATCH_GAP: Duration = Duration::from_secs(4);
+/// Plain HTTP, as the free tier has no HTTPS: the proxy addresses looked up
+/// are readable past the route's exit. Lookups go over the route, never
+/// directly, so this machine's own IP is not tied to them.
+const BATCH_ENDPOINT: &str =
+    "http://ip-api.com/batch?fields=status,message,countryCode,city,as,org";
+const CACHE_VERSION: u32 = 1;
+/// Longest a single provider request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a failed provider is left alone before it is tried again.
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(60);
+/// Longest a proxy hostname may take to resolve for a database lookup.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Looks up the exit IP unless --geo-service or --geoip-db says otherwise.
+pub const DEFAULT_EXIT_SERVICE: &str = "https://ipinfo.io/{ip}/json";
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct GeoInfo {
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<String>,
+    pub org: Option<String>,
+}
+
+impl GeoInfo {
+    /// Short form like "DE, AS3320 Deutsche Telekom AG".
+    pub fn summary(&self) -> String {
+        let parts: Vec<&str> = [self.country.as_deref(), self.asn.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect();
+        if parts.is_empty() {
+            "unknown".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    fetched_at: i64,
+    #[serde(flatten)]
+    info: GeoInfo,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    hits: u64,
+    misses: u64,
+    entries: HashMap<String, CacheEntry>,
+}
+
+/// On-disk geolocation cache keyed by IP (or host), with lifetime hit/miss
+/// counters kept in the same file.
+pub struct GeoCache {
+    path: PathBuf,
+    max_age: Duration,
+    file: CacheFile,
+}
+
+pub struct GeoCacheStats {
+    pub path: PathBuf,
+    pub entries: usize,
+    pub expired: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl GeoCache {
+    /// Loads the cache at `path`. A missing or unreadable file yields an empty cache.
+    pub fn load(path: &Path, max_age: Duration) -> Self {
+        let file = fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str::<CacheFile>(&text).ok())
+            .filter(|file| file.version == CACHE_VERSION)
+            .unwrap_or(CacheFile {
+                version: CACHE_VERSION,
+                ..CacheFile::default()
+            });
+        GeoCache {
+            path: path.to_path_buf(),
+            max_age,
+            file,
+        }
+    }
+
+    fn is_fresh(&self, entry: &CacheEntry) -> bool {
+        let age = chrono::Utc::now().timestamp() - entry.fetched_at;
+        age >= 0 && (age as u64) < self.max_age.as_secs()
+    }
+
+    /// Fresh entry for `key`, counting the lookup as a hit or miss.
+    pub fn get(&mut self, key: &str) -> Option<GeoInfo> {
+        let fresh = self
+            .file
+            .entries
+            .get(key)
+            .filter(|entry| self.is_fresh(entry))
+            .map(|entry| entry.info.clone());
+        if fresh.is_some() {
+            self.file.hits += 1;
+        } else {
+            self.file.misses += 1;
+        }
+        fresh
+    }
+
+    /// Entry for `key` regardless of age, for when the provider is unavailable.
+    pub fn get_stale(&self, key: &str) -> Option<GeoInfo> {
+        self.file.entries.get(key).map(|entry| entry.info.clone())
+    }
+
+    pub fn insert(&mut self, key: &str, info: GeoInfo) {
+        let entry = CacheEntry {
+            fetched_at: chrono::Utc::now().timestamp(),
+            info,
+        };
+        self.file.entries.insert(key.to_string(), entry);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let text = serde_json::to_string(&self.file)?;
+        fs::write(&self.path, text)
+    }
+
+    pub fn stats(&self) -> GeoCacheStats {
+        GeoCacheStats {
+            path: self.path.clone(),
+            entries: self.file.entries.len(),
+            expired: self
+                .file
+                .entries
+                .values()
+                .filter(|entry| !self.is_fresh(entry))
+                .count(),
+            hits: self.file.hits,
+            misses: self.file.misses,
+        }
+    }
+}
+
+/// Removes the cache file. Returns false if there was nothing to remove.
+pub fn purge_cache(path: &Path) -> io::Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Offline country lookups from a MaxMind-format (MMDB) database, such as
+/// GeoLite2-Country.
+pub struct GeoDb {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl GeoDb {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        maxminddb::Reader::open_readfile(path)
+            .map(|reader| GeoDb { reader })
+            .map_err(|e| format!("cannot open GeoIP database {}: {}", path.display(), e))
+    }
+
+    /// ISO country code of `host`, resolving it first if it is a name.
+    pub fn country(&self, host: &str) -> Option<String> {
+        let country: maxminddb::geoip2::Country = self.reader.lookup(host_ip(host)?).ok()?;
+        country.country.and_then(|c| c.iso_code).map(str::to_string)
+    }
+
+    /// [`GeoDb::info`] about `host`, resolving it first if it is a name.
+    pub fn host_info(&self, host: &str) -> Option<GeoInfo> {
+        self.info(host_ip(host)?)
+    }
+
+    /// Whatever the database knows about `ip`: country and city from a
+    /// Country or City database, the AS from an ASN one.
+    pub fn info(&self, ip: IpAddr) -> Option<GeoInfo> {
+        let city: Option<maxminddb::geoip2::City> = self.reader.lookup(ip).ok();
+        let asn: Option<maxminddb::geoip2::Asn> = self.reader.lookup(ip).ok();
+        let org = asn
+            .as_ref()
+            .and_then(|a| a.autonomous_system_organization)
+            .map(str::to_string);
+        let info = GeoInfo {
+            country: city
+                .as_ref()
+                .and_then(|c| c.country.as_ref())
+                .and_then(|c| c.iso_code)
+                .map(str::to_string),
+            city: city
+                .as_ref()
+                .and_then(|c| c.city.as_ref())
+                .and_then(|c| c.names.as_ref())
+                .and_then(|names| names.get("en"))
+                .map(|name| name.to_string()),
+            asn: asn
+                .and_then(|a| a.autonomous_system_number)
+                .map(|n| match &org {
+                    Some(org) => format!("AS{} {}", n, org),
+                    None => format!("AS{}", n),
+                }),
+            org,
+        };
+        (info.country.is_some() || info.asn.is_some()).then_some(info)
+    }
+}
+
+fn host_ip(host: &str) -> Option<IpAddr> {
+    match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => Some(ip),
+        Err(_) => resolve(host),
+    }
+}
+
+/// Parses `--geo-service`: an https URL with `{ip}` where the address goes.
+pub fn parse_exit_service(s: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(&s.replace("{ip}", "192.0.2.1"))
+        .map_err(|e| format!("invalid URL: {}", e))?;
+    if url.scheme() != "https" || url.host_str().is_none() {
+        return Err("expected an https:// URL".to_string());
+    }
+    if !s.contains("{ip}") {
+        return Err("the URL must contain {ip}".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Where the exit IP's location comes from.
+pub enum ExitGeo {
+    /// --geoip-db, which sends nothing anywhere.
+    Db(GeoDb),
+    /// A service answering JSON about the IP in its URL.
+    Service(String),
+}
+
+impl ExitGeo {
+    /// Locates `ip`, asking the service through `client` only when `cache`
+    /// has nothing fresh about it.
+    pub fn lookup(&self, client: &Client, cache: &mut GeoCache, ip: &str) -> Option<GeoInfo> {
+        let url = match self {
+            ExitGeo::Db(db) => return db.info(ip.parse().ok()?),
+            ExitGeo::Service(url) => url,
+        };
+        if let Some(info) = cache.get(ip) {
+            return Some(info);
+        }
+        throttle::wait(url);
+        let answer: serde_json::Value = client
+            .get(url.replace("{ip}", ip))
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(|e| log::warn!("Exit IP geolocation failed: {}", e.without_url()))
+            .ok()?;
+        let info = info_from_json(&answer)?;
+        cache.insert(ip, info.clone());
+        Some(info)
+    }
+
+    /// What is already known about `ip`, without asking anyone.
+    pub fn known(&self, cache: &GeoCache, ip: &str) -> Option<GeoInfo> {
+        match self {
+            ExitGeo::Db(db) => db.info(ip.parse().ok()?),
+            ExitGeo::Service(_) => cache.get_stale(ip),
+        }
+    }
+}
+
+/// Reads the field names of the common services: ipinfo's country and
+/// "AS3320 Name" org, ipapi's country_code and asn, ip-api's countryCode
+/// and as.
+fn info_from_json(answer: &serde_json::Value) -> Option<GeoInfo> {
+    let field = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| answer.get(*name)?.as_str())
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+    let org = field(&["org"]);
+    let asn = match field(&["as", "asn"]) {
+        Some(asn) if !asn.contains(' ') => Some(match &org {
+            Some(org) => format!("{} {}", asn, org),
+            None => asn,
+        }),
+        Some(asn) => Some(asn),
+        None => org.clone().filter(|org| org.starts_with("AS")),
+    };
+    let info = GeoInfo {
+        country: field(&["countryCode", "country_code", "country"]).filter(|c| c.len() == 2),
+        city: field(&["city"]),
+        asn,
+        org,
+    };
+    (info.country.is_some() || info.asn.is_some()).then_some(info)
+}
+
+/// First address of `host`, or `None` if it does not resolve within
+/// `RESOLVE_TIMEOUT`. The system resolver cannot be interrupted, so a slow
+/// lookup is left to finish on its own thread.
+fn resolve(host: &str) -> Option<IpAddr> {
+    let (tx, rx) = mpsc::channel();
+    let host = host.to_string();
+    thread::spawn(move || {
+        let ip = (host.as_str(), 0)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .map(|addr| addr.ip());
+        let _ = tx.send(ip);
+    });
+    rx.recv_timeout(RESOLVE_TIMEOUT).ok().flatten()
+}
+
+/// Whether the online provider is answering, shared with whoever reports
+/// on it.
+#[derive(Default)]
+pub struct ProviderHealth {
+    /// `None` until the provider is first asked.
+    state: Mutex<Option<ProviderState>>,
+}
+
+#[derive(Clone, Copy)]
+pub enum ProviderState {
+    Up,
+    /// Failing since then.
+    Down(Instant),
+}
+
+impl ProviderHealth {
+    pub fn state(&self) -> Option<ProviderState> {
+        *self.state.lock().unwrap()
+    }
+
+    fn record(&self, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        *state = match (*state, ok) {
+            (_, true) => Some(ProviderState::Up),
+            (Some(ProviderState::Down(since)), false) => Some(ProviderState::Down(since)),
+            (_, false) => Some(ProviderState::Down(Instant::now())),
+        };
+    }
+}
+
+#[derive(Deserialize)]
+struct BatchAnswer {
+    status: String,
+    #[serde(rename = "countryCode")]
+    country_code: Option<String>,
+    city: Option<String>,
+    #[serde(rename = "as")]
+    asn: Option<String>,
+    org: Option<String>,
+}
+
+/// Result of a `lookup` pass.
+pub struct GeoLookup {
+    pub found: HashMap<String, GeoInfo>,
+    pub from_cache: usize,
+    pub fetched: usize,
+    /// Set when the provider refused or failed and stale cache data was used.
+    pub throttled: bool,
+}
+
+/// Batched, rate-limited client for the ip-api batch endpoint. After a
+/// failure it stops asking for `BREAKER_COOLDOWN`, so an unreachable
+/// provider costs one timeout rather than one per batch.
+pub struct GeoClient {
+    endpoint: String,
+    next_request: Instant,
+    /// While set and in the future, lookups only use the cache.
+    retry_at: Option<Instant>,
+    health: Arc<ProviderHealth>,
+}
+
+impl Default for GeoClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GeoClient {
+    pub fn new() -> Self {
+        GeoClient {
+            endpoint: BATCH_ENDPOINT.to_string(),
+            next_request: Instant::now(),
+            retry_at: None,
+            health: Arc::new(ProviderHealth::default()),
+        }
+    }
+
+    pub fn health(&self) -> Arc<ProviderHealth> {
+        self.health.clone()
+    }
+
+    /// Geolocates `keys` (IPs or hostnames), serving fresh entries from
+    /// `cache` and fetching the rest in batches through `client`.
+    pub fn lookup(&mut self, client: &Client, cache: &mut GeoCache, keys: &[String]) -> GeoLookup {
+        let mut result = GeoLookup {
+            found: HashMap::new(),
+            from_cache: 0,
+            fetched: 0,
+            throttled: false,
+        };
+
+        let mut missing = Vec::new();
+        for key in keys {
+            if result.found.contains_key(key) || missing.contains(key) {
+                continue;
+            }
+            match cache.get(key) {
+                Some(info) => {
+                    result.found.insert(key.clone(), info);
+                    result.from_cache += 1;
+                }
+                None => missing.push(key.clone()),
+            }
+        }
+
+        result.throttled = self.retry_at.is_some_and(|at| at > Instant::now());
+        for (n, chunk) in missing.chunks(BATCH_SIZE).enumerate() {
+            if result.throttled {
+                break;
+            }
+            let answered = self.fetch_batch(client, chunk);
+            self.health.record(answered.is_ok());
+            self.retry_at = answered.is_err().then(|| Instant::now() + BREAKER_COOLDOWN);
+            match answered {
+                Ok(answers) => {
+                    // Answers come in the order of the queries. Their own
+                    // `query` is the IP a hostname resolved to, so entries
+                    // are keyed by what was asked instead
+                    for (key, answer) in chunk.iter().zip(answers) {
+                        if answer.status != "success" {
+                            continue;
+                        }
+                        let info = GeoInfo {
+                            country: answer.country_code,
+                            city: answer.city,
+                            asn: answer.asn,
+                            org: answer.org,
+                        };
+                        cache.insert(key, info.clone());
+                        result.found.insert(key.clone(), info);
+                        result.fetched += 1;
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Geolocation batch {} failed: {}", n + 1, e);
+                    result.throttled = true;
+                }
+            }
+        }
+
+        if result.throttled {
+            for key in &missing {
+                if result.found.contains_key(key) {
+                    continue;
+                }
+                if let Some(info) = cache.get_stale(key) {
+                    result.found.insert(key.clone(), info);
+                    result.from_cache += 1;
+                }
+            }
+        }
+        result
+    }
+
+    fn fetch_batch(
+        &mut self,
+        client: &Client,
+        keys: &[String],
+    ) -> Result<Vec<BatchAnswer>, String> {
+        let now = Instant::now();
+        if self.next_request > now {
+            thread::sleep(self.next_request - now);
+        }
+        self.next_request = Instant::now() + BATCH_GAP;
+        throttle::wait(&self.endpoint);
+
+        let response = client
+            .post(&self.endpoint)
+            .timeout(REQUEST_TIMEOUT)
+            .json(keys)
+            .send()
+            .map_err(|e| e.to_string())?;
+
+        // X-Rl is the number of requests left in the window, X-Ttl the
+        // seconds until it resets
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+        };
+        if let (Some(0), Some(ttl)) = (header("X-Rl"), header("X-Ttl")) {
+            self.next_request = Instant::now() + Duration::from_secs(ttl);
+        }
+
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err("rate limited by provider".to_string());
+        }
+        if !response.status().is_success() {
+            return Err(format!("provider answered {}", response.status()));
+        }
+        response.json().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::RotationReason;
+    use crate::rotation::{ProxyEntry, ProxyRotator};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Answers one batch request with `body` and hands back the queries.
+    fn serve_batch(body: &'static str) -> (String, thread::JoinHandle<Vec<String>>) {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/batch", server.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = server.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut queries = vec![0; length];
+            reader.read_exact(&mut queries).unwrap();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            serde_json::from_slice(&queries).unwrap()
+        });
+        (endpoint, handle)
+    }
+
+    /// A provider that takes connections and never answers. Sends on the
+    /// channel as each one comes in.
+    fn hanging_provider() -> (String, mpsc::Receiver<()>) {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/batch", server.local_addr().unwrap());
+        let (accepted, connections) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in server.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = accepted.send(());
+                // Hold the connection until the client gives up on it
+                thread::spawn(move || io::copy(&mut stream, &mut io::sink()));
+            }
+        });
+        (endpoint, connections)
+    }
+
+    #[test]
+    fn results_are_keyed_by_what_was_asked() {
+        let (endpoint, server) = serve_batch(
+            r#"[{"status":"success","countryCode":"DE","as":"AS3320 DTAG","query":"192.0.2.7"},
+                {"status":"fail","message":"private range","query":"10.0.0.1"}]"#,
+        );
+        let mut geo = GeoClient {
+            endpoint,
+            ..GeoClient::new()
+        };
+        let path = std::env::temp_dir().join(format!("veko-geo-test-{}.json", std::process::id()));
+        let mut cache = GeoCache::load(&path, Duration::from_secs(3600));
+        let keys = vec!["proxy.example".to_string(), "10.0.0.1".to_string()];
+
+        let lookup = geo.lookup(&Client::new(), &mut cache, &keys);
+        assert_eq!(server.join().unwrap(), keys);
+        assert_eq!(lookup.fetched, 1);
+        let info = &lookup.found["proxy.example"];
+        assert_eq!(info.country.as_deref(), Some("DE"));
+        assert!(!lookup.found.contains_key("192.0.2.7"));
+
+        // The next run is served from the cache under the same key
+        let again = geo.lookup(&Client::new(), &mut cache, &keys[..1]);
+        assert_eq!((again.from_cache, again.fetched), (1, 0));
+    }
+
+    #[test]
+    fn a_provider_that_times_out_costs_one_timeout() {
+        let (endpoint, connections) = hanging_provider();
+        let mut geo = GeoClient {
+            endpoint,
+            ..GeoClient::new()
+        };
+        let path = std::env::temp_dir().join(format!("veko-geo-down-{}.json", std::process::id()));
+        // Everything in it is out of date, so all of it is asked for
+        let mut cache = GeoCache::load(&path, Duration::ZERO);
+        let known = GeoInfo {
+            country: Some("NL".to_string()),
+            city: None,
+            asn: None,
+            org: None,
+        };
+        cache.insert("192.0.2.1", known);
+        let keys = vec!["192.0.2.1".to_string(), "192.0.2.2".to_string()];
+
+        let client = Client::new();
+        let lookup = geo.lookup(&client, &mut cache, &keys);
+        assert!(lookup.throttled);
+        assert_eq!(lookup.fetched, 0);
+        // Stale data beats none while the provider is down
+        assert_eq!(lookup.found["192.0.2.1"].country.as_deref(), Some("NL"));
+        assert!(matches!(geo.health().state(), Some(ProviderState::Down(_))));
+
+        // The breaker is open: the next pass does not wait on the provider
+        let started = Instant::now();
+        let again = geo.lookup(&client, &mut cache, &keys);
+        assert!(again.throttled);
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(connections.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn a_hanging_provider_does_not_hold_up_rotation() {
+        let (endpoint, connections) = hanging_provider();
+        let proxies = ["socks5://127.0.0.1:1080", "socks5://127.0.0.1:1081"]
+            .map(|url| ProxyEntry::parse(url).unwrap())
+            .to_vec();
+        let mut rotator = ProxyRotator::new(proxies, 600).unwrap();
+        let lookup = thread::spawn(move || {
+            let mut geo = GeoClient {
+                endpoint,
+                ..GeoClient::new()
+            };
+            let path =
+                std::env::temp_dir().join(format!("veko-geo-hang-{}.json", std::process::id()));
+            let mut cache = GeoCache::load(&path, Duration::from_secs(3600));
+            geo.lookup(&Client::new(), &mut cache, &["192.0.2.3".to_string()])
+        });
+        connections.recv().unwrap();
+
+        // The lookup is stuck on the provider while the route changes
+        let started = Instant::now();
+        rotator.rotate(RotationReason::Timer, false).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert!(!lookup.is_finished());
+        assert!(lookup.join().unwrap().throttled);
+    }
+}
@@ -0,0 +1,218 @@
+// src/hooks.rs
+// What the user has run when the route rotates or the anonymization path
+// breaks: --on-rotate and --on-failure commands, told about the event in
+// VEKO_* variables, and a --webhook-url POSTed the event as JSON through
+// the session's route. Each runs on a thread of its own for at most
+// TIMEOUT, so a hung hook cannot hold up rotation, and a hook still busy
+// with one event skips the next. Hooks learn the exit IP, the proxy's
+// place in the list and the reason, never a proxy URL or its credentials.
+// A failing hook is reported once and never retried.
+use crate::events::{HookEvent, HookKind};
+use reqwest::blocking::Client;
+use std::{
+    env,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Longest a command or webhook request may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest a webhook waits for a stopped route to carry it again.
+const ROUTE_WAIT: Duration = Duration::from_secs(300);
+/// How often a running command or a waiting webhook is looked at.
+const POLL: Duration = Duration::from_millis(100);
+
+static HOOKS: OnceLock<Hooks> = OnceLock::new();
+
+struct Hooks {
+    on_rotate: Option<(String, State)>,
+    on_failure: Option<(String, State)>,
+    webhook: Option<(Webhook, State)>,
+}
+
+pub struct Webhook {
+    pub url: String,
+    /// The client of the current route, or `None` when there is none.
+    pub client: Box<dyn Fn() -> Option<Client> + Send + Sync>,
+    /// Whether the route is stopped, so nothing may be sent.
+    pub blocked: Box<dyn Fn() -> bool + Send + Sync>,
+}
+
+/// One hook, with what keeps it from piling up and from repeating itself.
+#[derive(Clone, Default)]
+struct State {
+    busy: Arc<AtomicBool>,
+    reported: Arc<AtomicBool>,
+}
+
+impl State {
+    /// Marks the hook busy, or returns false if it already is.
+    fn start(&self) -> bool {
+        !self.busy.swap(true, Ordering::SeqCst)
+    }
+
+    fn done(&self) {
+        self.busy.store(false, Ordering::SeqCst);
+    }
+
+    /// Logs that `what` failed, loudly the first time only.
+    fn failed(&self, what: &str, e: &str) {
+        if self.reported.swap(true, Ordering::SeqCst) {
+            log::debug!("{} failed: {}", what, e);
+        } else {
+            log::warn!(
+                "{} failed: {}. Later failures of it are only logged with --verbose",
+                what,
+                e
+            );
+        }
+    }
+}
+
+/// Runs the hooks given on every [`fire`] from now on. Only the first
+/// call counts.
+pub fn install(on_rotate: Option<String>, on_failure: Option<String>, webhook: Option<Webhook>) {
+    let _ = HOOKS.set(Hooks {
+        on_rotate: on_rotate.map(|command| (command, State::default())),
+        on_failure: on_failure.map(|command| (command, State::default())),
+        webhook: webhook.map(|webhook| (webhook, State::default())),
+    });
+}
+
+/// Hands `event` to the hooks for it, without waiting for any of them.
+pub fn fire(event: HookEvent) {
+    let Some(hooks) = HOOKS.get() else {
+        return;
+    };
+    let command = match event.event {
+        HookKind::Rotation => &hooks.on_rotate,
+        HookKind::Failure => &hooks.on_failure,
+    };
+    if let Some((command, state)) = command {
+        run_command(command, state.clone(), &event);
+    }
+    if let Some((webhook, state)) = &hooks.webhook {
+        post(webhook, state.clone(), event);
+    }
+}
+
+fn shell(command: &str) -> Command {
+    #[cfg(unix)]
+    let (shell, flag) = ("sh", "-c");
+    #[cfg(not(unix))]
+    let (shell, flag) = ("cmd", "/C");
+    let mut shell = Command::new(shell);
+    shell.arg(flag).arg(command);
+    shell
+}
+
+fn run_command(command: &str, state: State, event: &HookEvent) {
+    let what = format!("The {} hook", event.event.as_str());
+    if !state.start() {
+        log::debug!("{} is still running; skipped this event", what);
+        return;
+    }
+    let mut process = shell(command);
+    // VEKO_* variables set start options, proxies with credentials among them
+    for (name, _) in env::vars_os() {
+        if name.to_string_lossy().starts_with("VEKO_") {
+            process.env_remove(name);
+        }
+    }
+    process
+        .env("VEKO_EVENT", event.event.as_str())
+        .env("VEKO_REASON", &event.reason)
+        .env("VEKO_NEW_IP", event.new_ip.as_deref().unwrap_or(""))
+        .env(
+            "VEKO_PROXY_INDEX",
+            event.proxy_index.map(|i| i.to_string()).unwrap_or_default(),
+        )
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    thread::spawn(move || {
+        match wait_for(process) {
+            Ok(()) => log::debug!("{} finished", what),
+            Err(e) => state.failed(&what, &e),
+        }
+        state.done();
+    });
+}
+
+/// Runs `process` to the end, killing it once it takes TIMEOUT.
+fn wait_for(mut process: Command) -> Result<(), String> {
+    let mut child = process
+        .spawn()
+        .map_err(|e| format!("could not start it: {}", e))?;
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return Ok(()),
+            Ok(Some(status)) => return Err(format!("it exited with {}", status)),
+            Ok(None) if Instant::now() < deadline => thread::sleep(POLL),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("it was killed after {}s", TIMEOUT.as_secs()));
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// POSTs `event` through the route once it carries traffic, waiting up
+/// to ROUTE_WAIT for that: a failure is told once the path is back.
+fn post(webhook: &'static Webhook, state: State, event: HookEvent) {
+    if !state.start() {
+        log::debug!("The webhook is still busy; skipped this event");
+        return;
+    }
+    thread::spawn(move || {
+        match deliver(webhook, &event) {
+            Ok(()) => log::debug!("Webhook told of the {}", event.event.as_str()),
+            Err(e) => state.failed("The webhook", &e),
+        }
+        state.done();
+    });
+}
+
+fn deliver(webhook: &Webhook, event: &HookEvent) -> Result<(), String> {
+    let deadline = Instant::now() + ROUTE_WAIT;
+    while (webhook.blocked)() {
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "forwarding stayed stopped for {}s",
+                ROUTE_WAIT.as_secs()
+            ));
+        }
+        thread::sleep(POLL);
+    }
+    let client = (webhook.client)().ok_or("no route to send it through")?;
+    let response = client
+        .post(&webhook.url)
+        .timeout(TIMEOUT)
+        .json(event)
+        .send()
+        .map_err(|e| e.without_url().to_string())?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(format!("the endpoint answered {}", status)),
+    }
+}
+
+/// A --webhook-url: https only, since the event goes over the route's exit.
+pub fn parse_webhook_url(s: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(s).map_err(|e| format!("invalid URL: {}", e))?;
+    if url.scheme() != "https" {
+        return Err("webhook URLs must use https".to_string());
+    }
+    if url.host_str().is_none() {
+        return Err("webhook URL has no host".to_string());
+    }
+    Ok(s.to_string())
+}
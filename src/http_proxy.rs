@@ -0,0 +1,125 @@
+// src/http_proxy.rs
+// Server side of a plain HTTP proxy: CONNECT tunnels and absolute-URI
+// requests for plain http://.
+use crate::socks::TargetAddr;
+use std::io::{self, Read, Write};
+
+const MAX_REQUEST_HEAD: usize = 64 * 1024;
+
+/// A parsed client request.
+pub struct ProxyRequest {
+    pub method: String,
+    pub target: TargetAddr,
+    /// For plain HTTP, the request head to send to the origin; `None` for
+    /// CONNECT, where nothing is sent before the tunnel.
+    pub forward_head: Option<Vec<u8>>,
+}
+
+/// Reads the request head byte by byte so any body stays in the stream.
+pub fn read_request_head(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "oversized request head",
+            ));
+        }
+        stream.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    Ok(head)
+}
+
+pub fn parse_request(head: &[u8]) -> Result<ProxyRequest, &'static str> {
+    let text = std::str::from_utf8(head).map_err(|_| "request head is not UTF-8")?;
+    let mut lines = text.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(uri), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err("malformed request line");
+    };
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let target = TargetAddr::parse(uri).ok_or("CONNECT target must be host:port")?;
+        return Ok(ProxyRequest {
+            method: method.to_string(),
+            target,
+            forward_head: None,
+        });
+    }
+
+    let rest = uri
+        .strip_prefix("http://")
+        .ok_or("only absolute http:// URIs can be proxied")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    // Credentials in the URI are not forwarded
+    let authority = authority.rsplit('@').next().unwrap_or(authority);
+    let has_port = authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.is_empty() && !port.contains(']'));
+    let target = if has_port {
+        TargetAddr::parse(authority)
+    } else {
+        TargetAddr::parse(&format!("{}:80", authority))
+    }
+    .ok_or("invalid host in request URI")?;
+
+    // Rewrite to origin form, drop hop-by-hop proxy headers and ask for the
+    // connection to close so a later request cannot reach the wrong host
+    let mut forward = format!("{} {} {}\r\n", method, path, version);
+    for line in lines.filter(|l| !l.is_empty()) {
+        let name = line.split(':').next().unwrap_or("").trim();
+        if [
+            "proxy-connection",
+            "proxy-authorization",
+            "connection",
+            "keep-alive",
+        ]
+        .iter()
+        .any(|h| name.eq_ignore_ascii_case(h))
+        {
+            continue;
+        }
+        forward.push_str(line);
+        forward.push_str("\r\n");
+    }
+    forward.push_str("Connection: close\r\n\r\n");
+    Ok(ProxyRequest {
+        method: method.to_string(),
+        target,
+        forward_head: Some(forward.into_bytes()),
+    })
+}
+
+/// The status code on the first line of a response, when `start` begins
+/// with one.
+pub fn response_status(start: &[u8]) -> Option<u16> {
+    let line = start.split(|&b| b == b'\r').next()?;
+    let mut parts = std::str::from_utf8(line).ok()?.split_whitespace();
+    parts
+        .next()
+        .filter(|version| version.starts_with("HTTP/"))?;
+    parts.next()?.parse().ok()
+}
+
+pub fn connection_established(stream: &mut impl Write) -> io::Result<()> {
+    stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+}
+
+/// Sends a short plain-text error response.
+pub fn error_response(stream: &mut impl Write, status: &str, message: &str) -> io::Result<()> {
+    let body = format!("{}\n", message);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
@@ -0,0 +1,125 @@
+// src/integrity.rs
+// Checks that a proxy list is the one its publisher meant: its SHA-256
+// against --proxy-sha256, and an ed25519 signature in --proxy-sig made
+// with the key in --proxy-pubkey. A list is checked as it was read, before
+// it is parsed: at startup, on every --proxy-refresh fetch and on reload.
+// The signature file is read again each time, so a publisher can replace
+// it along with the list.
+use base64::Engine as _;
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub struct ListCheck {
+    sha256: Option<[u8; 32]>,
+    /// The signature file, and the key it must verify under.
+    signature: Option<(PathBuf, VerifyingKey)>,
+}
+
+impl ListCheck {
+    /// `None` when neither a digest nor a signature is given.
+    /// validate::check makes sure `signature` and `key` come together.
+    pub fn new(
+        sha256: Option<[u8; 32]>,
+        signature: Option<&Path>,
+        key: Option<&Path>,
+    ) -> Result<Option<Self>, String> {
+        let signature = match signature.zip(key) {
+            Some((signature, key)) => Some((signature.to_path_buf(), read_key(key)?)),
+            None => None,
+        };
+        Ok((sha256.is_some() || signature.is_some()).then_some(ListCheck { sha256, signature }))
+    }
+
+    /// Checks `list` as read, byte for byte. Returns what was verified,
+    /// e.g. "SHA-256 matches, ed25519 signature valid".
+    pub fn verify(&self, list: &[u8]) -> Result<String, String> {
+        let mut passed = Vec::new();
+        if let Some(expected) = &self.sha256 {
+            let digest: [u8; 32] = Sha256::digest(list).into();
+            if digest != *expected {
+                return Err(format!(
+                    "SHA-256 is {}, not the {} given",
+                    hex(&digest),
+                    hex(expected)
+                ));
+            }
+            passed.push("SHA-256 matches");
+        }
+        if let Some((path, key)) = &self.signature {
+            let signature = read_signature(path)?;
+            key.verify_strict(list, &signature).map_err(|_| {
+                format!(
+                    "the signature in {} does not match the list and key",
+                    path.display()
+                )
+            })?;
+            passed.push("ed25519 signature valid");
+        }
+        Ok(passed.join(", "))
+    }
+}
+
+/// Parses `--proxy-sha256`, 64 hex digits.
+pub fn parse_sha256(value: &str) -> Result<[u8; 32], String> {
+    decode_hex(value.trim())
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| "expected a SHA-256 digest as 64 hex digits".to_string())
+}
+
+/// A public key file: the 32 bytes themselves, or them in base64 or hex.
+fn read_key(path: &Path) -> Result<VerifyingKey, String> {
+    let bytes = fs::read(path).map_err(|e| format!("cannot read key {}: {}", path.display(), e))?;
+    let key: [u8; 32] = decode(&bytes, 32)
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| {
+            format!(
+                "{} is not an ed25519 public key: expected 32 bytes, raw, base64 or hex",
+                path.display()
+            )
+        })?;
+    VerifyingKey::from_bytes(&key).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// A signature file: the 64 bytes themselves, or them in base64 or hex.
+fn read_signature(path: &Path) -> Result<Signature, String> {
+    let bytes =
+        fs::read(path).map_err(|e| format!("cannot read signature {}: {}", path.display(), e))?;
+    decode(&bytes, 64)
+        .and_then(|signature| Signature::from_slice(&signature).ok())
+        .ok_or_else(|| {
+            format!(
+                "{} is not an ed25519 signature: expected 64 bytes, raw, base64 or hex",
+                path.display()
+            )
+        })
+}
+
+/// `bytes` as given when they are `len` long, else decoded from hex or
+/// base64 text.
+fn decode(bytes: &[u8], len: usize) -> Option<Vec<u8>> {
+    if bytes.len() == len {
+        return Some(bytes.to_vec());
+    }
+    let text = std::str::from_utf8(bytes).ok()?.trim();
+    decode_hex(text)
+        .or_else(|| base64::engine::general_purpose::STANDARD.decode(text).ok())
+        .filter(|decoded| decoded.len() == len)
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
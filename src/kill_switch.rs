@@ -0,0 +1,102 @@
+// src/kill_switch.rs
+// Fail-closed state shared by every forwarder. While it is engaged, open
+// tunnels are cut and new connections refused, rather than carried on over
+// a broken anonymization path.
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+/// What broke the anonymization path. Each is released on its own.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Cause {
+    /// Every proxy is quarantined.
+    Proxies,
+    /// The Tor child exited and has not been relaunched yet.
+    Tor,
+    /// The latest exit IP check found this machine's own IP.
+    ExitIp,
+    /// Exits kept turning out to be on --exit-blocklist.
+    BlockedExit,
+    /// The session is outside its --active-hours.
+    Schedule,
+}
+
+#[derive(Default)]
+pub struct KillSwitch {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    causes: BTreeMap<Cause, String>,
+    /// Both ends of every open tunnel, so engaging can cut them.
+    tunnels: HashMap<u64, Vec<TcpStream>>,
+    next_id: u64,
+}
+
+impl State {
+    fn reason(&self) -> Option<String> {
+        let reasons: Vec<&str> = self.causes.values().map(String::as_str).collect();
+        (!reasons.is_empty()).then(|| reasons.join("; "))
+    }
+}
+
+/// Keeps a tunnel registered until it is dropped.
+pub struct Tracked {
+    switch: Arc<KillSwitch>,
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.switch.state.lock().unwrap().tunnels.remove(&self.id);
+    }
+}
+
+impl KillSwitch {
+    /// Stops forwarding because of `cause`. Returns false if `cause` had
+    /// already stopped it.
+    pub fn engage(&self, cause: Cause, reason: String) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.causes.insert(cause, reason).is_some() {
+            return false;
+        }
+        for stream in state.tunnels.drain().flat_map(|(_, streams)| streams) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        true
+    }
+
+    /// Clears `cause`. Returns false if it was not engaged.
+    pub fn release(&self, cause: Cause) -> bool {
+        self.state.lock().unwrap().causes.remove(&cause).is_some()
+    }
+
+    /// Why forwarding is stopped, or `None` while it runs.
+    pub fn reason(&self) -> Option<String> {
+        self.state.lock().unwrap().reason()
+    }
+
+    /// Registers a tunnel's streams to be shut down on engagement. Fails
+    /// with the reason while engaged, or if a stream cannot be tracked.
+    pub fn track(self: &Arc<Self>, streams: &[&TcpStream]) -> Result<Tracked, String> {
+        let clones = streams
+            .iter()
+            .map(|s| s.try_clone())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("cannot track tunnel: {}", e))?;
+        let mut state = self.state.lock().unwrap();
+        if let Some(reason) = state.reason() {
+            return Err(reason);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.tunnels.insert(id, clones);
+        Ok(Tracked {
+            switch: self.clone(),
+            id,
+        })
+    }
+}
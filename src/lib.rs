@@ -1,2 +1,58 @@
 // src/lib.rs
+// The anonymization engine behind the veko_dome command: security
+// profiles, proxy rotation, clients through a route and Tor, and in
+// `engine` the steps a session is run with. The command line is one user
+// of it; anything else can drive a session through the same steps.
+pub mod anonymity;
+#[cfg(feature = "arti")]
+pub mod arti;
+pub mod audit;
+pub mod capture;
+pub mod client;
+pub mod control;
+#[cfg(any(unix, windows))]
+pub mod daemon;
+#[cfg(unix)]
+pub mod dashboard;
+pub mod datasets;
+pub mod decisions;
+pub mod decoy;
+pub mod dns;
+pub mod doctor;
+pub mod engine;
+pub mod events;
+pub mod fallback;
+pub mod fingerprint;
+pub mod forwarder;
+pub mod geo;
+pub mod hooks;
+pub mod http_proxy;
+pub mod integrity;
+pub mod kill_switch;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod no_proxy;
+pub mod output;
+pub mod pool;
+pub mod portal;
+pub mod probe;
+pub mod profile;
+pub mod redact;
+pub mod retry;
+pub mod rotation;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod shutdown;
+pub mod socks;
+pub mod state;
+pub mod status_page;
+pub mod system_proxy;
+pub mod throttle;
+pub mod tls;
 pub mod tor_integration;
+#[cfg(windows)]
+pub mod windows;
+pub mod workers;
+
+pub use tor_integration as tor;
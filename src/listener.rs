@@ -0,0 +1,158 @@
+// src/listener.rs
+use crate::capture::Capture;
+use crate::forwarder::{Chain, ConnectionLog, Drain, Forwarder, Frontend, Hop, OutcomeHook};
+use crate::kill_switch::KillSwitch;
+use crate::no_proxy::NoProxy;
+use crate::pool::ProxyPool;
+use std::{
+    fmt,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ListenKind {
+    Socks5,
+    Http,
+}
+
+/// A `--listen` value such as `socks5://127.0.0.1:1080` or
+/// `http://127.0.0.1:8080`.
+#[derive(Clone)]
+pub struct ListenSpec {
+    pub kind: ListenKind,
+    pub addr: SocketAddr,
+}
+
+impl ListenSpec {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (kind, rest) = if let Some(rest) = s.strip_prefix("socks5://") {
+            (ListenKind::Socks5, rest)
+        } else if let Some(rest) = s.strip_prefix("http://") {
+            (ListenKind::Http, rest.trim_end_matches('/'))
+        } else {
+            return Err(format!(
+                "unsupported listener '{}'; expected socks5://host:port or http://host:port",
+                s
+            ));
+        };
+        let addr = rest
+            .to_socket_addrs()
+            .map_err(|e| format!("invalid listen address '{}': {}", rest, e))?
+            .next()
+            .ok_or_else(|| format!("'{}' does not resolve to an address", rest))?;
+        Ok(ListenSpec { kind, addr })
+    }
+}
+
+impl fmt::Display for ListenSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ListenKind::Socks5 => write!(f, "socks5://{}", self.addr),
+            ListenKind::Http => write!(f, "http://{}", self.addr),
+        }
+    }
+}
+
+/// A local endpoint other applications can point at to use the session's
+/// current upstream route.
+pub struct Listener {
+    spec: ListenSpec,
+    forwarder: Forwarder,
+}
+
+impl Listener {
+    /// Binds the listener. Without `allow_remote` only loopback addresses are
+    /// accepted, since the endpoint has no authentication.
+    pub fn start(spec: &ListenSpec, chain: Chain, allow_remote: bool) -> Result<Self, String> {
+        if !spec.addr.ip().is_loopback() && !allow_remote {
+            return Err(format!(
+                "Refusing to expose unauthenticated listener {} beyond loopback; pass --listen-allow-remote to allow it",
+                spec
+            ));
+        }
+        let frontend = match spec.kind {
+            ListenKind::Socks5 => Frontend::Socks5,
+            ListenKind::Http => Frontend::Http,
+        };
+        let forwarder = Forwarder::bind(spec.addr, chain, frontend)
+            .map_err(|e| format!("Cannot listen on {}: {}", spec, e))?;
+        // Report the real port when 0 was requested
+        let spec = ListenSpec {
+            addr: forwarder.addr(),
+            ..spec.clone()
+        };
+        Ok(Listener { spec, forwarder })
+    }
+
+    pub fn spec(&self) -> &ListenSpec {
+        &self.spec
+    }
+
+    /// Points new connections at `hop`; established ones drain off the old
+    /// route, see [`Forwarder::set_rotating`].
+    pub fn set_rotating(&self, hop: Hop) -> Drain {
+        self.forwarder.set_rotating(hop)
+    }
+
+    /// Points new connections at a whole new chain of `hops`, see
+    /// [`Forwarder::set_hops`].
+    pub fn set_hops(&self, hops: Vec<Hop>) -> Drain {
+        self.forwarder.set_hops(hops)
+    }
+
+    pub fn set_drain_timeout(&self, timeout: Duration) {
+        self.forwarder.set_drain_timeout(timeout);
+    }
+
+    /// Records the requests an http:// listener serves to `capture`.
+    pub fn set_capture(&self, capture: Arc<Capture>) {
+        self.forwarder.set_capture(capture);
+    }
+
+    pub fn set_connection_log(&self, log: ConnectionLog) {
+        self.forwarder.set_connection_log(log);
+    }
+
+    pub fn set_outcome_hook(&self, hook: OutcomeHook) {
+        self.forwarder.set_outcome_hook(hook);
+    }
+
+    /// Enforces per-proxy connection limits when picking the upstream.
+    pub fn set_pool(&self, pool: Arc<ProxyPool>) {
+        self.forwarder.set_pool(pool);
+    }
+
+    /// Stops forwarding and refuses connections whenever `switch` is
+    /// engaged.
+    pub fn set_kill_switch(&self, switch: Arc<KillSwitch>) {
+        self.forwarder.set_kill_switch(switch);
+    }
+
+    /// Refuses the destinations `rules` match, or connects to them directly
+    /// where a rule says so, rather than sending them over the route.
+    pub fn set_no_proxy(&self, rules: Arc<NoProxy>) {
+        self.forwarder.set_no_proxy(rules);
+    }
+
+    /// Connects to every destination from this machine while `direct` is
+    /// set, as --fallback-order direct asks.
+    pub fn set_direct(&self, direct: bool) {
+        self.forwarder.set_direct(direct);
+    }
+
+    /// Sets which upstream proxies may carry UDP ASSOCIATE traffic. Without
+    /// any, clients asking for UDP get "command not supported".
+    pub fn set_udp_hops(&self, hops: Vec<Hop>) {
+        self.forwarder.set_udp_hops(hops);
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.forwarder.active_connections()
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.forwarder.connections()
+    }
+}
@@ -0,0 +1,467 @@
+// src/logging.rs
+// Levels for everything veko_dome logs. The category of a line decides its
+// level, so --no-log, --quiet and --verbose cut the same lines whether they
+// come from log() or from the log crate's macros in the modules.
+// --log-file mirrors the lines to a file, written on its own thread so a
+// slow disk never holds up the caller. --log-format json turns each line
+// into one JSON object, with fields the caller attaches. The latest lines
+// are also kept in memory for `watch` to show.
+use crate::redact;
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Errors that end the process or the session.
+    Fatal,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    /// The level lines of `category` are logged at.
+    pub fn of(category: &str) -> Level {
+        match category {
+            "FATAL" => Level::Fatal,
+            "ERROR" => Level::Error,
+            "WARNING" | "SECURITY" => Level::Warn,
+            "DEBUG" => Level::Debug,
+            _ => Level::Info,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Fatal => "fatal",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// "[timestamp] [CATEGORY] message"
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static FILE: Mutex<Option<FileWriter>> = Mutex::new(None);
+static JSON: AtomicBool = AtomicBool::new(false);
+/// Lines kept for [`recent`].
+const RECENT_LINES: usize = 200;
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Drops every later line below `level`.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::SeqCst)
+}
+
+/// Writes every later line in `format`.
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::SeqCst);
+}
+
+/// Writes one line to stdout, unless its category is below the level in
+/// force.
+pub fn write(message: &str, category: &str) {
+    write_fields(message, category, Map::new());
+}
+
+/// [`write_fields`] for fields built with `serde_json::json!`: an object
+/// is added to JSON lines, anything else left out.
+pub fn write_json(message: &str, category: &str, fields: Value) {
+    let Value::Object(fields) = fields else {
+        return write(message, category);
+    };
+    write_fields(message, category, fields);
+}
+
+/// [`write`], with `fields` added to JSON lines.
+pub fn write_fields(message: &str, category: &str, fields: Map<String, Value>) {
+    if !enabled(Level::of(category)) {
+        return;
+    }
+    let message = redact::text(message);
+    remember(&message, category);
+    let line = format_line(&message, category, fields);
+    crate::output::write(format_args!("{}", line));
+    if let Some(file) = FILE.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        // Fails once the file is given up on, when stdout is all that is left
+        let _ = file.lines.send(line);
+    }
+}
+
+fn remember(message: &str, category: &str) {
+    let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    if recent.len() == RECENT_LINES {
+        recent.pop_front();
+    }
+    let time = chrono::Local::now().format("%H:%M:%S");
+    recent.push_back(format!("{} [{}] {}", time, category, message));
+}
+
+/// Up to the `n` latest lines logged, oldest first, as "time [CATEGORY]
+/// message" whatever --log-format says.
+pub fn recent(n: usize) -> Vec<String> {
+    let recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    recent
+        .iter()
+        .skip(recent.len().saturating_sub(n))
+        .cloned()
+        .collect()
+}
+
+fn format_line(message: &str, category: &str, fields: Map<String, Value>) -> String {
+    if !JSON.load(Ordering::SeqCst) {
+        let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+        return format!("[{}] [{}] {}\n", timestamp, category, message);
+    }
+    let line = JsonLine {
+        ts: chrono::Local::now().to_rfc3339(),
+        level: Level::of(category).name(),
+        category,
+        msg: message,
+        fields,
+    };
+    format!("{}\n", serde_json::to_string(&line).unwrap_or_default())
+}
+
+/// A --log-format json line, its keys in this order.
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    ts: String,
+    level: &'static str,
+    category: &'a str,
+    msg: &'a str,
+    fields: Map<String, Value>,
+}
+
+/// How --log-file is written.
+pub struct FileOptions {
+    pub path: PathBuf,
+    /// Size past which the file is rotated; 0 never rotates it.
+    pub max_size: u64,
+    /// Rotated files kept, as path.1 (newest) to path.N.
+    pub keep: usize,
+}
+
+struct FileWriter {
+    lines: Sender<String>,
+    thread: JoinHandle<()>,
+    path: PathBuf,
+    keep: usize,
+}
+
+/// Mirrors every later line to the file `options` describe, appending to
+/// it if it exists.
+pub fn open_file(options: FileOptions) -> io::Result<()> {
+    let file = LogFile::open(options)?;
+    let path = file.path.clone();
+    let keep = file.keep;
+    let (lines, received) = mpsc::channel();
+    let thread = thread::Builder::new()
+        .name("log-file".to_string())
+        .spawn(move || write_file(file, received))?;
+    *FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(FileWriter {
+        lines,
+        thread,
+        path,
+        keep,
+    });
+    Ok(())
+}
+
+/// Writes out what is still queued and closes the log file. With `shred`
+/// the file and its rotated copies are overwritten and removed; returns
+/// how many were.
+pub fn close_file(shred: bool) -> io::Result<usize> {
+    let Some(writer) = FILE.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return Ok(0);
+    };
+    drop(writer.lines);
+    let _ = writer.thread.join();
+    if !shred {
+        return Ok(0);
+    }
+    let mut shredded = 0;
+    for n in 0..=writer.keep {
+        if shred_file(&rotated_path(&writer.path, n))? {
+            shredded += 1;
+        }
+    }
+    Ok(shredded)
+}
+
+fn write_file(mut file: LogFile, lines: Receiver<String>) {
+    while let Ok(line) = lines.recv() {
+        let mut written = file.write(&line);
+        // Whatever queued up meanwhile goes out in the same flush
+        while written.is_ok() {
+            match lines.try_recv() {
+                Ok(line) => written = file.write(&line),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = written.and_then(|_| file.writer.flush()) {
+            crate::output::write(format_args!(
+                "{}",
+                format_line(
+                    &format!(
+                        "Cannot write log file {} ({}); logging to stdout only",
+                        file.path.display(),
+                        e
+                    ),
+                    "WARNING",
+                    Map::new(),
+                )
+            ));
+            return;
+        }
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl LogFile {
+    fn open(options: FileOptions) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&options.path)?;
+        Ok(LogFile {
+            size: file.metadata()?.len(),
+            writer: BufWriter::new(file),
+            path: options.path,
+            max_size: options.max_size,
+            keep: options.keep,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64;
+        if self.max_size > 0 && self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        self.writer.write_all(line.as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Moves path to path.1, path.1 to path.2 and so on, dropping the
+    /// oldest, and starts an empty file at path.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        for n in (1..=self.keep).rev() {
+            match fs::rename(rotated_path(&self.path, n - 1), rotated_path(&self.path, n)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// The log file itself for 0, else its `n`th rotated copy.
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Overwrites `path` with zeros before removing it. Returns false if there
+/// was nothing to remove.
+pub fn shred_file(path: &Path) -> io::Result<bool> {
+    let mut file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let zeros = [0u8; 8192];
+    let mut left = file.metadata()?.len();
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    file.sync_all()?;
+    fs::remove_file(path)?;
+    Ok(true)
+}
+
+/// Sends the log crate's records through [`write`].
+struct Bridge;
+
+fn category(level: log::Level) -> &'static str {
+    match level {
+        log::Level::Error => "ERROR",
+        log::Level::Warn => "WARNING",
+        log::Level::Info => "SYSTEM",
+        log::Level::Debug | log::Level::Trace => "DEBUG",
+    }
+}
+
+impl log::Log for Bridge {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        enabled(Level::of(category(metadata.level())))
+    }
+
+    fn log(&self, record: &log::Record) {
+        let mut fields = Map::new();
+        fields.insert("module".to_string(), record.target().into());
+        write_fields(&record.args().to_string(), category(record.level()), fields);
+    }
+
+    fn flush(&self) {}
+}
+
+static BRIDGE: Bridge = Bridge;
+
+/// Routes the log crate's macros through [`write`].
+pub fn install() {
+    if log::set_logger(&BRIDGE).is_ok() {
+        log::set_max_level(log::LevelFilter::Trace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::RotationReason;
+    use crate::rotation::{ProxyEntry, ProxyRotator};
+    use std::{env, process};
+
+    /// Set to the level a copy of the test binary logs at as a test's child.
+    const CHILD: &str = "VEKO_LOGGING_TEST_CHILD";
+
+    #[test]
+    fn categories_map_to_levels() {
+        for (category, level) in [
+            ("FATAL", Level::Fatal),
+            ("ERROR", Level::Error),
+            ("WARNING", Level::Warn),
+            ("SECURITY", Level::Warn),
+            ("ROTATION", Level::Info),
+            ("SYSTEM", Level::Info),
+            ("DEBUG", Level::Debug),
+        ] {
+            assert!(Level::of(category) == level, "{}", category);
+        }
+        assert!(Level::Fatal < Level::Warn && Level::Warn < Level::Debug);
+    }
+
+    #[test]
+    fn logs_a_session_at_the_level_given() {
+        let Ok(level) = env::var(CHILD) else {
+            return;
+        };
+        set_max_level(match level.as_str() {
+            "no-log" => Level::Fatal,
+            "quiet" => Level::Warn,
+            _ => Level::Info,
+        });
+        install();
+        let proxies = ["socks5://127.0.0.1:1080", "socks5://127.0.0.1:1081"]
+            .map(|url| ProxyEntry::parse(url).unwrap())
+            .to_vec();
+        ProxyRotator::new(proxies, 600)
+            .unwrap()
+            .rotate(RotationReason::Timer, false);
+        write("Leaving through socks5://127.0.0.1:1081", "SECURITY");
+        log::warn!("socks5://127.0.0.1:1080 refused the tunnel");
+        write("Handshake with 127.0.0.1:1081 took 80ms", "DEBUG");
+        write("Cannot go on", "FATAL");
+        // Before the harness reports on the test, which is not a log line
+        process::exit(0);
+    }
+
+    /// The log lines a session logging at `level` writes to stdout.
+    fn stdout_lines(level: &str) -> Vec<String> {
+        let out = process::Command::new(env::current_exe().unwrap())
+            .args([
+                "logging::tests::logs_a_session_at_the_level_given",
+                "--exact",
+                "--test-threads=1",
+            ])
+            .env(CHILD, level)
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        // The first one follows the harness's "test ... " on its line
+        String::from_utf8(out.stdout)
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.find('[').map(|start| line[start..].to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn no_log_keeps_proxy_addresses_off_stdout() {
+        let lines = stdout_lines("no-log");
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        assert!(lines[0].ends_with("[FATAL] Cannot go on"), "{}", lines[0]);
+        assert!(!lines.iter().any(|line| line.contains("127.0.0.1:108")));
+    }
+
+    #[test]
+    fn quiet_keeps_warnings_only() {
+        let lines = stdout_lines("quiet");
+        let categories: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| line.split("] [").nth(1)?.split(']').next())
+            .collect();
+        assert_eq!(categories, ["SECURITY", "WARNING", "FATAL"], "{:?}", lines);
+    }
+
+    #[test]
+    fn the_default_level_logs_rotations() {
+        let lines = stdout_lines("info");
+        assert!(
+            lines
+                .iter()
+                .any(|line| line.contains("[ROTATION]") && line.contains("127.0.0.1:1081")),
+            "{:?}",
+            lines
+        );
+        assert!(!lines.iter().any(|line| line.contains("[DEBUG]")));
+    }
+}
@@ -0,0 +1,258 @@
+// src/metrics.rs
+// Prometheus scrape endpoint for sessions that run for days. Values come
+// from the session's own state at scrape time, so answering a scrape never
+// sends anything through the route. Proxies are labeled by their place in
+// the list, never by address.
+use crate::workers;
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One scrape's worth of values.
+pub struct Metrics {
+    /// Rotations this session, by reason.
+    pub rotations: Vec<(String, u64)>,
+    /// Failed tunnels and heartbeats per proxy, by index in the list.
+    pub proxy_failures: Vec<u64>,
+    pub current_proxy_index: usize,
+    /// Whether the route is Tor itself rather than a proxy.
+    pub on_tor: bool,
+    pub tor_connected: bool,
+    pub since_rotation: Duration,
+    pub heartbeat_failures: u64,
+    /// Bytes up and down through each proxy, by index in the list.
+    pub proxy_bytes: Vec<(u64, u64)>,
+    /// Bytes the current identity's tunnels carried, both ways.
+    pub identity_bytes: u64,
+    /// Per listener: its address, connections so far and open ones.
+    pub listeners: Vec<(String, u64, usize)>,
+}
+
+/// Produces the values for each scrape.
+pub type MetricsSource = Arc<dyn Fn() -> Metrics + Send + Sync>;
+
+/// Serves /metrics on `addr`. Like the status page it has no
+/// authentication, so only loopback addresses are accepted unless
+/// `allow_remote`.
+pub fn start(
+    addr: SocketAddr,
+    allow_remote: bool,
+    source: MetricsSource,
+) -> io::Result<SocketAddr> {
+    if !addr.ip().is_loopback() && !allow_remote {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "refusing to serve metrics on {}; they have no authentication, so bind a loopback address or pass --metrics-allow-remote",
+                addr
+            ),
+        ));
+    }
+    let listener = TcpListener::bind(addr)?;
+    let bound = listener.local_addr()?;
+    workers::spawn("metrics", 5, move || {
+        for stream in listener.incoming().flatten() {
+            let _ = respond(stream, &source);
+        }
+    });
+    Ok(bound)
+}
+
+fn respond(stream: TcpStream, source: &MetricsSource) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream)
+        .take(8192)
+        .read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            render(&source()),
+        ),
+        (Some("GET"), Some(_)) => (
+            "404 Not Found",
+            "text/plain; charset=utf-8",
+            "Not found\n".to_string(),
+        ),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain; charset=utf-8",
+            "Only GET is supported\n".to_string(),
+        ),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    let mut stream = stream;
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body.as_bytes())
+}
+
+/// Escapes a label value for the text format.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP veko_dome_{} {}", name, help);
+    let _ = writeln!(text, "# TYPE veko_dome_{} {}", name, kind);
+}
+
+fn render(m: &Metrics) -> String {
+    let mut text = String::new();
+
+    header(
+        &mut text,
+        "rotations_total",
+        "counter",
+        "Rotations this session, by reason.",
+    );
+    for (reason, n) in &m.rotations {
+        let _ = writeln!(
+            text,
+            "veko_dome_rotations_total{{reason=\"{}\"}} {}",
+            label(reason),
+            n
+        );
+    }
+
+    header(
+        &mut text,
+        "proxy_failures_total",
+        "counter",
+        "Failed tunnels and heartbeats, by proxy index in the list.",
+    );
+    for (index, n) in m.proxy_failures.iter().enumerate() {
+        let _ = writeln!(
+            text,
+            "veko_dome_proxy_failures_total{{proxy=\"{}\"}} {}",
+            index, n
+        );
+    }
+
+    header(
+        &mut text,
+        "current_proxy_index",
+        "gauge",
+        "Index in the list of the proxy carrying the route.",
+    );
+    let _ = writeln!(
+        text,
+        "veko_dome_current_proxy_index {}",
+        m.current_proxy_index
+    );
+
+    header(
+        &mut text,
+        "route_on_tor",
+        "gauge",
+        "1 while the route is Tor itself.",
+    );
+    let _ = writeln!(text, "veko_dome_route_on_tor {}", u8::from(m.on_tor));
+
+    header(
+        &mut text,
+        "tor_connected",
+        "gauge",
+        "1 while Tor is running and can carry traffic.",
+    );
+    let _ = writeln!(
+        text,
+        "veko_dome_tor_connected {}",
+        u8::from(m.tor_connected)
+    );
+
+    header(
+        &mut text,
+        "seconds_since_last_rotation",
+        "gauge",
+        "Seconds since the route last rotated.",
+    );
+    let _ = writeln!(
+        text,
+        "veko_dome_seconds_since_last_rotation {}",
+        m.since_rotation.as_secs()
+    );
+
+    header(
+        &mut text,
+        "heartbeat_failures_total",
+        "counter",
+        "Missed heartbeats this session.",
+    );
+    let _ = writeln!(
+        text,
+        "veko_dome_heartbeat_failures_total {}",
+        m.heartbeat_failures
+    );
+
+    header(
+        &mut text,
+        "proxy_bytes_total",
+        "counter",
+        "Bytes tunnels carried, by proxy index in the list and direction.",
+    );
+    for (index, (up, down)) in m.proxy_bytes.iter().enumerate() {
+        for (direction, n) in [("up", up), ("down", down)] {
+            let _ = writeln!(
+                text,
+                "veko_dome_proxy_bytes_total{{proxy=\"{}\",direction=\"{}\"}} {}",
+                index, direction, n
+            );
+        }
+    }
+
+    header(
+        &mut text,
+        "identity_bytes",
+        "gauge",
+        "Bytes the current identity's tunnels carried, both ways.",
+    );
+    let _ = writeln!(text, "veko_dome_identity_bytes {}", m.identity_bytes);
+
+    if !m.listeners.is_empty() {
+        header(
+            &mut text,
+            "forwarded_connections_total",
+            "counter",
+            "Connections accepted by each listener.",
+        );
+        for (spec, total, _) in &m.listeners {
+            let _ = writeln!(
+                text,
+                "veko_dome_forwarded_connections_total{{listener=\"{}\"}} {}",
+                label(spec),
+                total
+            );
+        }
+        header(
+            &mut text,
+            "active_connections",
+            "gauge",
+            "Connections each listener is forwarding now.",
+        );
+        for (spec, _, active) in &m.listeners {
+            let _ = writeln!(
+                text,
+                "veko_dome_active_connections{{listener=\"{}\"}} {}",
+                label(spec),
+                active
+            );
+        }
+    }
+    text
+}
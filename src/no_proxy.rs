@@ -0,0 +1,144 @@
+// src/no_proxy.rs
+// --no-proxy: destinations the listeners keep off the upstream route.
+// Handing an internal hostname to a proxy tells whoever runs it about the
+// network behind this machine, so a destination a rule matches is refused,
+// or connected to directly when its rule says `direct`. Rules match the
+// destination as the client gave it: an address against CIDR ranges, a
+// name against exact hosts and `*.domain` wildcards. Names are never looked
+// up to be matched, since the lookup would give them away as well.
+use crate::audit::within;
+use crate::socks::TargetAddr;
+use std::{fmt, net::IpAddr};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Connect from this machine, bypassing the route.
+    Direct,
+    /// Refuse the connection.
+    Block,
+}
+
+impl Policy {
+    fn name(self) -> &'static str {
+        match self {
+            Policy::Direct => "direct",
+            Policy::Block => "block",
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Pattern {
+    Net(IpAddr, u8),
+    Host(String),
+    /// `*.example.com`: names under example.com, but not example.com itself.
+    Subdomains(String),
+}
+
+/// One `--no-proxy` value.
+#[derive(Clone)]
+pub struct NoProxyRule {
+    pattern: Pattern,
+    pub policy: Policy,
+}
+
+impl NoProxyRule {
+    /// Parses PATTERN or PATTERN=POLICY, e.g. 10.0.0.0/8, intranet or
+    /// *.corp.example.com=direct. Without a policy the rule blocks.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (pattern, policy) = match s.rsplit_once('=') {
+            Some((pattern, "direct")) => (pattern, Policy::Direct),
+            Some((pattern, "block")) => (pattern, Policy::Block),
+            Some((_, other)) => {
+                return Err(format!(
+                    "unknown policy '{}'; expected direct or block",
+                    other
+                ))
+            }
+            None => (s, Policy::Block),
+        };
+        let pattern = pattern.trim();
+        let bad = || {
+            format!(
+                "'{}' is not a CIDR range, a host or a *.domain wildcard",
+                pattern
+            )
+        };
+        let pattern = if let Some(domain) = pattern.strip_prefix("*.") {
+            Pattern::Subdomains(host_name(domain).ok_or_else(bad)?)
+        } else if let Ok(ip) = pattern.parse::<IpAddr>() {
+            Pattern::Net(ip, if ip.is_ipv4() { 32 } else { 128 })
+        } else if let Some((ip, len)) = pattern.split_once('/') {
+            let ip: IpAddr = ip.parse().map_err(|_| bad())?;
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let len = len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(bad)?;
+            Pattern::Net(ip, len)
+        } else {
+            Pattern::Host(host_name(pattern).ok_or_else(bad)?)
+        };
+        Ok(NoProxyRule { pattern, policy })
+    }
+
+    fn matches(&self, target: &TargetAddr) -> bool {
+        let (ip, name) = match target {
+            TargetAddr::Ip(addr) => (Some(addr.ip()), None),
+            // A client may send an address as a name
+            TargetAddr::Domain(host, _) => match host.parse::<IpAddr>() {
+                Ok(ip) => (Some(ip), None),
+                Err(_) => (None, host_name(host)),
+            },
+        };
+        match (&self.pattern, ip, name) {
+            (Pattern::Net(net, len), Some(ip), _) => within(ip, *net, *len),
+            (Pattern::Host(host), _, Some(name)) => *host == name,
+            (Pattern::Subdomains(domain), _, Some(name)) => name
+                .strip_suffix(domain.as_str())
+                .is_some_and(|rest| rest.ends_with('.')),
+            _ => false,
+        }
+    }
+
+    /// What the rule matches, as given without its policy, e.g.
+    /// 10.0.0.0/8 or *.corp.example.com.
+    pub fn pattern(&self) -> String {
+        match &self.pattern {
+            Pattern::Net(ip, len) => format!("{}/{}", ip, len),
+            Pattern::Host(host) => host.clone(),
+            Pattern::Subdomains(domain) => format!("*.{}", domain),
+        }
+    }
+}
+
+impl fmt::Display for NoProxyRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.pattern(), self.policy.name())
+    }
+}
+
+/// A name as rules compare it: lowercase, without a trailing dot.
+fn host_name(name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+    valid.then_some(name)
+}
+
+/// The rules in the order given; the first that matches decides.
+pub struct NoProxy(Vec<NoProxyRule>);
+
+impl NoProxy {
+    pub fn new(rules: Vec<NoProxyRule>) -> Self {
+        NoProxy(rules)
+    }
+
+    /// The rule that keeps `target` off the route, if any.
+    pub fn matching(&self, target: &TargetAddr) -> Option<&NoProxyRule> {
+        self.0.iter().find(|rule| rule.matches(target))
+    }
+}
@@ -0,0 +1,172 @@
+// src/output.rs
+// Stdout and stderr that cope with going away. Rust ignores SIGPIPE, so
+// once a reader closes the pipe (`veko_dome events | head -3`) every write
+// fails, and println! or eprintln! would panic on it.
+use std::{
+    fmt,
+    io::{self, Write},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Set for the session, which must keep running with nobody reading it.
+static KEEP_RUNNING: AtomicBool = AtomicBool::new(false);
+/// Set once stdout is found closed, so later output is skipped.
+static CLOSED: AtomicBool = AtomicBool::new(false);
+/// [`CLOSED`] for stderr.
+static ERR_CLOSED: AtomicBool = AtomicBool::new(false);
+
+/// `println!` to stdout through [`write`].
+#[macro_export]
+macro_rules! outln {
+    () => {
+        $crate::output::write(format_args!("\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::output::write(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// `eprintln!` to stderr through [`write_err`].
+#[macro_export]
+macro_rules! errln {
+    () => {
+        $crate::output::write_err(format_args!("\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::output::write_err(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Makes a closed stdout or stderr drop output instead of ending the
+/// process.
+pub fn keep_running_without_stdout() {
+    KEEP_RUNNING.store(true, Ordering::SeqCst);
+}
+
+/// Writes to stdout. Once that fails, output stops. A one-off command then
+/// exits, with code 0 if its reader just left early.
+pub fn write(args: fmt::Arguments) {
+    write_to(&mut io::stdout().lock(), &CLOSED, args);
+}
+
+/// Writes to stderr, and stops as [`write`] does once that fails.
+pub fn write_err(args: fmt::Arguments) {
+    write_to(&mut io::stderr().lock(), &ERR_CLOSED, args);
+}
+
+fn write_to(stream: &mut impl Write, closed: &AtomicBool, args: fmt::Arguments) {
+    if closed.load(Ordering::SeqCst) {
+        return;
+    }
+    let Err(e) = stream.write_fmt(args) else {
+        return;
+    };
+    closed.store(true, Ordering::SeqCst);
+    if !KEEP_RUNNING.load(Ordering::SeqCst) {
+        process::exit(if e.kind() == io::ErrorKind::BrokenPipe {
+            0
+        } else {
+            1
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        env,
+        io::Read,
+        process::{Command, Stdio},
+    };
+
+    /// Set in the copy of the test binary a test runs as its child.
+    const CHILD: &str = "VEKO_OUTPUT_TEST_CHILD";
+    /// What the child exits with if it outlives its stdout.
+    const STILL_RUNNING: i32 = 3;
+
+    /// Runs `test` alone in a copy of this binary, reads a little of its
+    /// stdout, or with `stderr` its stderr, closes the pipe and returns its
+    /// exit code.
+    fn exit_code_after_reader_leaves(test: &str, keep_running: bool, stderr: bool) -> Option<i32> {
+        let (stdout, stderr_pipe) = match stderr {
+            true => (Stdio::null(), Stdio::piped()),
+            false => (Stdio::piped(), Stdio::null()),
+        };
+        let mut child = Command::new(env::current_exe().unwrap())
+            .args([test, "--exact", "--test-threads=1"])
+            .env(CHILD, if keep_running { "keep" } else { "exit" })
+            .stdout(stdout)
+            .stderr(stderr_pipe)
+            .spawn()
+            .unwrap();
+        let mut pipe: Box<dyn Read> = match stderr {
+            true => Box::new(child.stderr.take().unwrap()),
+            false => Box::new(child.stdout.take().unwrap()),
+        };
+        let mut start = [0; 64];
+        pipe.read_exact(&mut start).unwrap();
+        drop(pipe);
+        child.wait().unwrap().code()
+    }
+
+    #[test]
+    fn writes_until_stdout_closes() {
+        let Ok(mode) = env::var(CHILD) else {
+            return;
+        };
+        if mode == "keep" {
+            keep_running_without_stdout();
+        }
+        for n in 0..1_000_000 {
+            outln!("line {}", n);
+        }
+        process::exit(STILL_RUNNING);
+    }
+
+    #[test]
+    fn writes_until_stderr_closes() {
+        let Ok(mode) = env::var(CHILD) else {
+            return;
+        };
+        if mode == "keep" {
+            keep_running_without_stdout();
+        }
+        for n in 0..1_000_000 {
+            errln!("line {}", n);
+        }
+        process::exit(STILL_RUNNING);
+    }
+
+    #[test]
+    fn a_closed_pipe_ends_a_command_cleanly() {
+        let code = exit_code_after_reader_leaves(
+            "output::tests::writes_until_stdout_closes",
+            false,
+            false,
+        );
+        assert_eq!(code, Some(0));
+    }
+
+    #[test]
+    fn a_closed_pipe_does_not_stop_the_session() {
+        let code =
+            exit_code_after_reader_leaves("output::tests::writes_until_stdout_closes", true, false);
+        assert_eq!(code, Some(STILL_RUNNING));
+    }
+
+    #[test]
+    fn a_closed_stderr_ends_a_command_cleanly() {
+        let code =
+            exit_code_after_reader_leaves("output::tests::writes_until_stderr_closes", false, true);
+        assert_eq!(code, Some(0));
+    }
+
+    #[test]
+    fn a_closed_stderr_does_not_stop_the_session() {
+        let code =
+            exit_code_after_reader_leaves("output::tests::writes_until_stderr_closes", true, true);
+        assert_eq!(code, Some(STILL_RUNNING));
+    }
+}
@@ -0,0 +1,175 @@
+// src/pool.rs
+use crate::control::ProxyLoad;
+use crate::decisions::{self, Decision, Journal, Strategy};
+use crate::forwarder::{ConnectionLog, Hop};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Concurrent connections allowed per proxy when its entry sets no limit.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 50;
+
+struct Member {
+    hop: Hop,
+    key: String,
+    max_connections: usize,
+    active: AtomicUsize,
+    quarantined: AtomicBool,
+}
+
+/// Per-proxy connection counters shared by every listener, so a provider's
+/// cap on concurrent connections holds across all of them.
+pub struct ProxyPool {
+    members: Vec<Member>,
+    saturated: AtomicBool,
+    on_saturated: Mutex<Option<ConnectionLog>>,
+    journal: Mutex<Option<Arc<Journal>>>,
+}
+
+/// A connection counted against one proxy until it is dropped.
+pub struct Slot {
+    pool: Arc<ProxyPool>,
+    index: usize,
+}
+
+impl Slot {
+    pub fn hop(&self) -> &Hop {
+        &self.pool.members[self.index].hop
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.pool.members[self.index]
+            .active
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ProxyPool {
+    pub fn new(proxies: Vec<(Hop, usize)>) -> Self {
+        ProxyPool {
+            members: proxies
+                .into_iter()
+                .map(|(hop, max_connections)| Member {
+                    key: hop.to_string(),
+                    hop,
+                    max_connections,
+                    active: AtomicUsize::new(0),
+                    quarantined: AtomicBool::new(false),
+                })
+                .collect(),
+            saturated: AtomicBool::new(false),
+            on_saturated: Mutex::new(None),
+            journal: Mutex::new(None),
+        }
+    }
+
+    /// Called once each time every proxy becomes full.
+    pub fn set_saturation_alert(&self, alert: ConnectionLog) {
+        *self.on_saturated.lock().unwrap() = Some(alert);
+    }
+
+    pub fn contains(&self, hop: &Hop) -> bool {
+        let key = hop.to_string();
+        self.members.iter().any(|m| m.key == key)
+    }
+
+    /// Keeps new tunnels off `hop` while it is quarantined.
+    pub fn set_quarantined(&self, hop: &Hop, quarantined: bool) {
+        let key = hop.to_string();
+        for member in self.members.iter().filter(|m| m.key == key) {
+            member.quarantined.store(quarantined, Ordering::SeqCst);
+        }
+    }
+
+    /// Records every selection made by `acquire`.
+    pub fn set_journal(&self, journal: Arc<Journal>) {
+        *self.journal.lock().unwrap() = Some(journal);
+    }
+
+    /// Takes a slot on `preferred`, or on the next proxy with room if it is
+    /// at capacity. Fails with `ResourceBusy` only when every proxy is full.
+    pub fn acquire(self: &Arc<Self>, preferred: &Hop) -> io::Result<Slot> {
+        let key = preferred.to_string();
+        let start = self.members.iter().position(|m| m.key == key).unwrap_or(0);
+        let journal = self.journal.lock().unwrap().clone();
+        loop {
+            let load: Vec<(usize, usize)> = self
+                .members
+                .iter()
+                .map(|m| (m.active.load(Ordering::SeqCst), m.max_connections))
+                .collect();
+            let quarantined: Vec<bool> = self
+                .members
+                .iter()
+                .map(|m| m.quarantined.load(Ordering::SeqCst))
+                .collect();
+            let seed = decisions::draw_seed();
+            let outcome = decisions::pick_with_room(&load, &quarantined, start, seed);
+            if let Some(journal) = &journal {
+                journal.record(
+                    Strategy::FirstWithRoom,
+                    seed,
+                    Decision::PoolAcquire {
+                        pool: self.members.iter().map(|m| m.key.clone()).collect(),
+                        load,
+                        quarantined,
+                        preferred: start,
+                        outcome,
+                    },
+                );
+            }
+            let Some(index) = outcome else {
+                break;
+            };
+            let member = &self.members[index];
+            let taken = member
+                .active
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    (n < member.max_connections).then_some(n + 1)
+                })
+                .is_ok();
+            // Another connection may have taken the last slot since the
+            // snapshot; decide again if so
+            if taken {
+                self.saturated.store(false, Ordering::SeqCst);
+                return Ok(Slot {
+                    pool: self.clone(),
+                    index,
+                });
+            }
+        }
+
+        let message = format!(
+            "pool saturated: all {} proxies are at their connection limit or quarantined",
+            self.members.len()
+        );
+        if !self.saturated.swap(true, Ordering::SeqCst) {
+            if let Some(alert) = self.on_saturated.lock().unwrap().clone() {
+                alert(&message);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::ResourceBusy, message))
+    }
+
+    /// Whether every proxy was full at the last attempt.
+    pub fn is_saturated(&self) -> bool {
+        self.saturated.load(Ordering::SeqCst)
+    }
+
+    pub fn utilization(&self) -> Vec<ProxyLoad> {
+        self.members
+            .iter()
+            .map(|m| ProxyLoad {
+                proxy: m.key.clone(),
+                active: m.active.load(Ordering::SeqCst),
+                max_connections: m.max_connections,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,67 @@
+// src/portal.rs
+// The captive portal check made before a session sets up Tor or proxies.
+// Hotel and airport networks answer every request with their login page
+// until someone logs in, so every later check would fail with no hint as
+// to why. One plain-http GET goes to an endpoint that answers 204 with no
+// body, over the direct connection; a portal shows itself by answering it
+// any other way.
+use crate::{client, throttle};
+use reqwest::{blocking::Client, header::LOCATION, redirect, StatusCode};
+use std::{io::Read, time::Duration};
+
+/// Plain http, since a portal cannot answer an https request as itself.
+pub const CHECK_URL: &str = "http://connectivitycheck.gstatic.com/generate_204";
+/// Exit status of a session that found a portal in the way.
+pub const EXIT_CODE: i32 = 3;
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Most of a portal's page read to look for its URL.
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+pub enum Network {
+    /// The check got its 204 untouched.
+    Open,
+    /// Something answered in its place, at this URL when it said where.
+    Portal(Option<String>),
+    /// The check got no answer at all.
+    Unreachable(String),
+}
+
+/// Sends the check over the direct connection, following no redirect.
+pub fn check() -> Network {
+    let client = Client::builder()
+        .no_proxy()
+        .redirect(redirect::Policy::none())
+        .local_address(client::ipv4_only_local_address())
+        .timeout(TIMEOUT)
+        .build();
+    throttle::wait(CHECK_URL);
+    let response = match client.and_then(|client| client.get(CHECK_URL).send()) {
+        Ok(response) => response,
+        Err(e) => return Network::Unreachable(e.without_url().to_string()),
+    };
+    let status = response.status();
+    let location = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let mut body = String::new();
+    let _ = response.take(MAX_BODY_BYTES).read_to_string(&mut body);
+    if status == StatusCode::NO_CONTENT && body.is_empty() {
+        return Network::Open;
+    }
+    Network::Portal(location.or_else(|| refresh_url(&body)))
+}
+
+/// Where a page's `<meta http-equiv="refresh" content="0; url=...">` sends
+/// the browser, which is how portals that do not redirect name their page.
+fn refresh_url(body: &str) -> Option<String> {
+    let lower = body.to_ascii_lowercase();
+    let meta = lower.find("http-equiv")?;
+    let start = meta + lower[meta..].find("url=")? + "url=".len();
+    let url: String = body[start..]
+        .chars()
+        .take_while(|c| !matches!(c, '"' | '\'' | '>' | ' '))
+        .collect();
+    url.starts_with("http").then_some(url)
+}
@@ -0,0 +1,151 @@
+// src/probe.rs
+// What the startup health check and the rotation verifier send through a
+// route. Lighter probes look less like a beacon to whoever watches the
+// proxy, but assert less: only `ip` learns the exit IP. Probes are retried
+// within their timeout, so one dropped Tor circuit does not fail them.
+use crate::client::{self, HttpProbe, PublicIp};
+use crate::forwarder::{self, Hop};
+use crate::retry::{self, Failure};
+use crate::socks::TargetAddr;
+use crate::throttle;
+use clap::ValueEnum;
+use native_tls::{HandshakeError, TlsConnector};
+use reqwest::StatusCode;
+use std::time::{Duration, Instant};
+
+/// Answers 204 with no body; anything else means something in the path
+/// intercepted the request.
+const NO_CONTENT_URL: &str = "https://www.gstatic.com/generate_204";
+/// Host the `tls` probe completes a handshake with.
+const TLS_HOST: &str = "www.gstatic.com";
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProbeLevel {
+    /// GET the exit IP from a public IP service
+    Ip,
+    /// HEAD a generate_204 endpoint, which answers with no body
+    Http204,
+    /// Open a tunnel and complete a TLS handshake, sending no HTTP
+    Tls,
+}
+
+impl ProbeLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            ProbeLevel::Ip => "ip",
+            ProbeLevel::Http204 => "http204",
+            ProbeLevel::Tls => "tls",
+        }
+    }
+
+    /// Whether a probe at this level finds out the exit IP.
+    pub fn sees_exit_ip(self) -> bool {
+        self == ProbeLevel::Ip
+    }
+}
+
+/// Probes the route `client` goes through, whose first hop is `proxy`.
+/// Returns the exit IP when the level sees it. Tries again while
+/// `timeout` allows; the ip level keeps to the IP services' own deadline.
+pub fn run(
+    level: ProbeLevel,
+    client: &dyn HttpProbe,
+    proxy: &str,
+    timeout: Duration,
+) -> Result<Option<PublicIp>, String> {
+    match level {
+        ProbeLevel::Ip => client::get_public_ip(client)
+            .map(Some)
+            .ok_or_else(|| "no service answered with an exit IP".to_string()),
+        ProbeLevel::Http204 => retry::until("http204 probe", Instant::now() + timeout, || {
+            let status = client.head_status(NO_CONTENT_URL)?;
+            if status != StatusCode::NO_CONTENT {
+                // Whatever intercepted it would do so again
+                return Err(Failure::Stop(format!(
+                    "{} answered {}, not 204",
+                    NO_CONTENT_URL, status
+                )));
+            }
+            Ok(None)
+        }),
+        ProbeLevel::Tls => {
+            let hop = Hop::parse(proxy)?;
+            retry::until("tls probe", Instant::now() + timeout, || {
+                tls_handshake(&hop, timeout)
+            })
+            .map(|_| None)
+        }
+    }
+}
+
+/// A handshake the server took part in and that failed points at an
+/// interception, which would fail the next one too.
+fn tls_handshake(hop: &Hop, timeout: Duration) -> Result<(), Failure> {
+    throttle::wait(TLS_HOST);
+    let stream = forwarder::tunnel(hop, &TargetAddr::Domain(TLS_HOST.to_string(), 443))
+        .map_err(|e| Failure::Retry(e.to_string()))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .map_err(|e| Failure::Retry(e.to_string()))?;
+    let connector = TlsConnector::new().map_err(|e| Failure::Stop(e.to_string()))?;
+    match connector.connect(TLS_HOST, stream) {
+        Ok(_) => Ok(()),
+        Err(HandshakeError::WouldBlock(_)) => {
+            Err(Failure::Retry("TLS handshake timed out".to_string()))
+        }
+        Err(HandshakeError::Failure(e)) => {
+            Err(Failure::Stop(format!("TLS handshake failed: {}", e)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{MockAnswer, MockProbe};
+
+    const PROXY: &str = "socks5h://127.0.0.1:9050";
+
+    #[test]
+    fn http204_passes_on_no_content() {
+        let probe =
+            MockProbe::default().answer(NO_CONTENT_URL, MockAnswer::Status(StatusCode::NO_CONTENT));
+        let found = run(ProbeLevel::Http204, &probe, PROXY, Duration::from_secs(5));
+        assert!(matches!(found, Ok(None)));
+    }
+
+    #[test]
+    fn http204_fails_at_once_on_an_interception() {
+        let probe = MockProbe::default().answer(NO_CONTENT_URL, MockAnswer::Body("<html>"));
+        let e = run(ProbeLevel::Http204, &probe, PROXY, Duration::from_secs(5))
+            .err()
+            .unwrap();
+        assert!(e.contains("not 204"), "{}", e);
+        assert_eq!(probe.asked().len(), 1);
+    }
+
+    #[test]
+    fn http204_retries_within_the_timeout() {
+        let probe = MockProbe::default().answer(NO_CONTENT_URL, MockAnswer::Fail("timed out"));
+        let started = Instant::now();
+        let e = run(
+            ProbeLevel::Http204,
+            &probe,
+            PROXY,
+            Duration::from_millis(200),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(e, "timed out");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn ip_level_reports_the_exit_ip() {
+        let probe =
+            MockProbe::default().answer("https://api.ipify.org", MockAnswer::Body("192.0.2.44"));
+        let found = run(ProbeLevel::Ip, &probe, PROXY, Duration::from_secs(5)).unwrap();
+        assert_eq!(found.unwrap().ip, "192.0.2.44");
+    }
+}
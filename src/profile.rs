@@ -0,0 +1,416 @@
+// src/profile.rs
+// Security profiles: how the clients of a route present themselves. The
+// built-in ones differ only in their user agents, one preset each, and in
+// paranoid alone isolating Tor circuits per identity; a [profile.NAME]
+// table of a config, or a .toml file of its own, sets the rest as well.
+// What a profile leaves out is taken from paranoid.
+use crate::{datasets, fingerprint, logging, tls};
+use clap::ValueEnum;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use serde::{de, Deserialize, Deserializer};
+use std::{collections::BTreeMap, fmt, fs, path::Path, time::Duration};
+
+/// Every key a security profile may set.
+pub const SECURITY_PROFILE_KEYS: &[&str] = &[
+    "user_agents",
+    "headers",
+    "redirect_limit",
+    "timeout_secs",
+    "keepalive",
+    "cookies",
+    "http_version",
+    "tls",
+    "tor_isolation",
+];
+
+/// The HTTP version a profile's clients speak.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum HttpVersion {
+    /// Offer what the user agent's browser offers, and let the server pick
+    Auto,
+    /// HTTP/1.1 only
+    Http1,
+    /// HTTP/2 without negotiating it first
+    Http2PriorKnowledge,
+}
+
+/// Checks `agent` is a plausible User-Agent: a valid header value that
+/// starts with a product/version token such as Mozilla/5.0.
+pub fn parse_user_agent(agent: &str) -> Result<String, String> {
+    let agent = agent.trim();
+    if HeaderValue::from_str(agent).is_err() {
+        return Err("not a valid User-Agent header value".to_string());
+    }
+    let product = agent.split_whitespace().next().unwrap_or_default();
+    match product.split_once('/') {
+        Some((name, version)) if !name.is_empty() && !version.is_empty() => Ok(agent.to_string()),
+        _ => Err(format!(
+            "{:?} does not start with a product/version token such as Mozilla/5.0",
+            agent
+        )),
+    }
+}
+
+/// How the clients of a route present themselves; see [`crate::client`].
+#[derive(Clone)]
+pub struct SecurityProfile {
+    /// One is picked per route, see [`SecurityProfile::user_agent`].
+    /// Never empty.
+    user_agents: Vec<String>,
+    /// Sent with every request, over those of the user agent's browser.
+    pub headers: HeaderMap,
+    /// Redirects followed before a request fails; 0 follows none.
+    pub redirect_limit: usize,
+    pub timeout: Duration,
+    /// Whether connections are kept open for the next request. Without it
+    /// each request opens one of its own, which takes longer but leaves
+    /// nothing to tie requests together.
+    pub keepalive: bool,
+    /// Whether cookies servers set are sent back. Each client of a route
+    /// has a jar of its own, so none outlive the route's identity.
+    pub cookies: bool,
+    pub http_version: HttpVersion,
+    pub tls: tls::Settings,
+    /// Whether each rotation onto Tor gets circuits no earlier identity
+    /// used, see [`crate::rotation::ProxyRotator::isolate_tor`].
+    pub tor_isolation: bool,
+}
+
+/// The security profile used unless --mode picks another.
+pub const DEFAULT_MODE: &str = "paranoid";
+
+impl SecurityProfile {
+    /// The built-in profile with the user agents of `preset`. Accept and
+    /// the like come with each user agent. Fails when the user agent list
+    /// has no such preset, or none under it.
+    pub fn new(preset: &str) -> Result<Self, String> {
+        let presets = datasets::presets();
+        if !presets.iter().any(|name| name == preset) {
+            return Err(format!(
+                "The user agent list has no preset {}, only {}",
+                preset,
+                presets.join(", ")
+            ));
+        }
+        let user_agents = datasets::user_agents(preset);
+        if user_agents.is_empty() {
+            return Err(format!(
+                "The user agent list has no user agents under [{}]",
+                preset
+            ));
+        }
+
+        let static_headers = [
+            (header::REFERER, "https://www.google.com/"),
+            (header::DNT, "1"),
+            (header::CACHE_CONTROL, "no-cache"),
+            (header::PRAGMA, "no-cache"),
+        ];
+        let headers = static_headers
+            .into_iter()
+            .map(|(name, value)| (name, header::HeaderValue::from_static(value)))
+            .collect();
+
+        Ok(SecurityProfile {
+            user_agents,
+            headers,
+            redirect_limit: 0,
+            timeout: Duration::from_secs(30),
+            keepalive: false,
+            cookies: false,
+            http_version: HttpVersion::Auto,
+            tls: tls::Settings::paranoid(),
+            tor_isolation: preset == DEFAULT_MODE,
+        })
+    }
+
+    /// The profile --mode names: a file of its own when it ends in .toml,
+    /// else a [profile.NAME] of the config, else a preset of the user
+    /// agent list. Fails when it names no profile, when its TLS leaves
+    /// nothing to connect with, or for paranoid with `insecure_tls`.
+    pub fn for_mode(
+        mode: &str,
+        defined: Option<&Catalog>,
+        insecure_tls: bool,
+    ) -> Result<Self, String> {
+        if insecure_tls && mode == "paranoid" {
+            return Err(
+                "The paranoid profile does not run with --insecure-tls; pick another --mode"
+                    .to_string(),
+            );
+        }
+        let mut profile = Self::named(mode, defined)?;
+        profile.tls.insecure = insecure_tls;
+        profile
+            .tls
+            .client_config(&[])
+            .map_err(|e| format!("Security profile {}: {}", mode, e))?;
+        Ok(profile)
+    }
+
+    fn named(mode: &str, defined: Option<&Catalog>) -> Result<Self, String> {
+        if mode.ends_with(".toml") {
+            let (spec, unknown) = load_profile(Path::new(mode))?;
+            if !unknown.is_empty() {
+                logging::write(
+                    &format!("Ignoring unknown keys in {}: {}", mode, unknown.join(", ")),
+                    "WARNING",
+                );
+            }
+            return Ok(Self::new(DEFAULT_MODE)?.with(spec));
+        }
+        if let Some(spec) = defined.and_then(|d| d.profiles.get(mode)) {
+            return Ok(Self::new(DEFAULT_MODE)?.with(spec.clone()));
+        }
+        let presets = datasets::presets();
+        if presets.iter().any(|preset| preset == mode) {
+            return Self::new(mode);
+        }
+        let defined_in = defined.map_or("no config defines it".to_string(), |d| {
+            format!("{} has no [profile.{}]", d.path.display(), mode)
+        });
+        Err(format!(
+            "--mode {} is not a security profile: {}, and the user agent list has {}",
+            mode,
+            defined_in,
+            presets.join(", ")
+        ))
+    }
+
+    /// This profile with what `spec` sets replacing its own.
+    fn with(self, spec: ProfileSpec) -> Self {
+        SecurityProfile {
+            user_agents: spec.user_agents.unwrap_or(self.user_agents),
+            headers: spec.headers.unwrap_or(self.headers),
+            redirect_limit: spec.redirect_limit.unwrap_or(self.redirect_limit),
+            timeout: spec.timeout_secs.map_or(self.timeout, Duration::from_secs),
+            keepalive: spec.keepalive.unwrap_or(self.keepalive),
+            cookies: spec.cookies.unwrap_or(self.cookies),
+            http_version: spec.http_version.unwrap_or(self.http_version),
+            tls: spec.tls.unwrap_or(self.tls),
+            tor_isolation: spec.tor_isolation.unwrap_or(self.tor_isolation),
+        }
+    }
+
+    /// The user agent a route with this index presents.
+    pub fn user_agent(&self, index: usize) -> &str {
+        &self.user_agents[index % self.user_agents.len()]
+    }
+
+    /// Every user agent a route may present, in the order indexes pick.
+    pub fn user_agents(&self) -> &[String] {
+        &self.user_agents
+    }
+
+    /// Replaces the user agents routes pick from. Fails on an empty list,
+    /// which would leave routes nothing to present.
+    pub fn set_user_agents(&mut self, agents: Vec<String>) -> Result<(), String> {
+        if agents.is_empty() {
+            return Err("a profile needs at least one user agent".to_string());
+        }
+        self.user_agents = agents;
+        Ok(())
+    }
+
+    /// The headers a route with this user agent sends: those its browser
+    /// would, with the profile's own replacing any of the same name.
+    pub fn headers_for(&self, index: usize) -> HeaderMap {
+        let mut headers = fingerprint::headers(self.user_agent(index));
+        for (name, value) in &self.headers {
+            headers.insert(name.clone(), value.clone());
+        }
+        headers
+    }
+}
+
+/// A security profile defined in a config or a file of its own. What it
+/// leaves out is taken from the paranoid profile.
+#[derive(Clone, Default, Deserialize)]
+pub struct ProfileSpec {
+    #[serde(default, deserialize_with = "user_agents")]
+    pub user_agents: Option<Vec<String>>,
+    /// Sent with every request, in the order given.
+    #[serde(default, deserialize_with = "headers")]
+    pub headers: Option<HeaderMap>,
+    /// Redirects followed before a request fails; 0 follows none.
+    pub redirect_limit: Option<usize>,
+    pub timeout_secs: Option<u64>,
+    /// Whether connections are kept open for the next request.
+    pub keepalive: Option<bool>,
+    /// Whether cookies servers set are sent back.
+    pub cookies: Option<bool>,
+    #[serde(default, deserialize_with = "value_enum")]
+    pub http_version: Option<HttpVersion>,
+    /// Replaces the paranoid TLS settings as a whole.
+    pub tls: Option<tls::Settings>,
+    /// Whether each rotation onto Tor gets circuits of its own.
+    pub tor_isolation: Option<bool>,
+}
+
+/// The security profiles a config file defines as [profile.NAME], for
+/// [`SecurityProfile::for_mode`].
+pub struct Catalog<'a> {
+    /// The file they are defined in, named in errors.
+    pub path: &'a Path,
+    pub profiles: &'a BTreeMap<String, ProfileSpec>,
+}
+
+/// Reads a file holding one security profile, its keys at the top level.
+/// Returns it with the keys that are not a profile's.
+pub fn load_profile(path: &Path) -> Result<(ProfileSpec, Vec<String>), String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read security profile {}: {}", path.display(), e))?;
+    let table: toml::Table = toml::from_str(&text).map_err(|e| parse_error(path, &text, &e))?;
+    let spec = toml::from_str(&text).map_err(|e| parse_error(path, &text, &e))?;
+    let mut unknown = Vec::new();
+    unknown_profile_keys(&table, "", &mut unknown);
+    Ok((spec, unknown))
+}
+
+/// Adds the keys of `profile` that a security profile does not have to
+/// `unknown`, named after `prefix`.
+pub fn unknown_profile_keys(profile: &toml::Table, prefix: &str, unknown: &mut Vec<String>) {
+    unknown_in(profile, SECURITY_PROFILE_KEYS, prefix, unknown);
+    if let Some(table) = profile.get("tls").and_then(toml::Value::as_table) {
+        unknown_in(table, tls::KEYS, &format!("{}tls.", prefix), unknown);
+    }
+}
+
+/// Adds the keys of `table` that are not in `known` to `unknown`, named
+/// after `prefix`.
+pub fn unknown_in(table: &toml::Table, known: &[&str], prefix: &str, unknown: &mut Vec<String>) {
+    for key in table.keys() {
+        if !known.contains(&key.as_str()) {
+            unknown.push(format!("{}{}", prefix, key));
+        }
+    }
+}
+
+/// "config.toml line 3, column 10: ...", from where toml found the error.
+pub fn parse_error(path: &Path, text: &str, e: &toml::de::Error) -> String {
+    let Some(span) = e.span() else {
+        return format!("{}: {}", path.display(), e.message());
+    };
+    let before = &text[..span.start.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    format!(
+        "{} line {}, column {}: {}",
+        path.display(),
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+        e.message().trim_end().replace('\n', "; ")
+    )
+}
+
+/// The name a command line option gives `value`.
+pub fn variant_name<T: ValueEnum>(value: T) -> String {
+    value
+        .to_possible_value()
+        .map(|v| v.get_name().to_string())
+        .unwrap_or_default()
+}
+
+/// A value the command line option takes, by the same name.
+pub fn value_enum<'de, D: Deserializer<'de>, T: ValueEnum>(d: D) -> Result<Option<T>, D::Error> {
+    let name = String::deserialize(d)?;
+    T::from_str(&name, true).map(Some).map_err(|_| {
+        let names: Vec<String> = T::value_variants()
+            .iter()
+            .map(|v| variant_name(v.clone()))
+            .collect();
+        de::Error::custom(format!(
+            "unknown value {:?}, expected one of {}",
+            name,
+            names.join(", ")
+        ))
+    })
+}
+
+/// A user agent, checked as it is read so errors point at the entry.
+struct UserAgent(String);
+
+impl<'de> Deserialize<'de> for UserAgent {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        parse_user_agent(&String::deserialize(d)?)
+            .map(UserAgent)
+            .map_err(de::Error::custom)
+    }
+}
+
+fn user_agents<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<String>>, D::Error> {
+    let agents = Vec::<UserAgent>::deserialize(d)?;
+    if agents.is_empty() {
+        return Err(de::Error::custom("a profile needs at least one user agent"));
+    }
+    Ok(Some(
+        agents.into_iter().map(|UserAgent(agent)| agent).collect(),
+    ))
+}
+
+/// A header name, checked as it is read so errors point at the key.
+struct Name(HeaderName);
+
+impl<'de> Deserialize<'de> for Name {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(d)?;
+        HeaderName::from_bytes(name.as_bytes())
+            .map(Name)
+            .map_err(|_| de::Error::custom(format!("{:?} is not a valid header name", name)))
+    }
+}
+
+struct Value(HeaderValue);
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(d)?;
+        HeaderValue::from_str(&value)
+            .map(Value)
+            .map_err(|_| de::Error::custom(format!("{:?} is not a valid header value", value)))
+    }
+}
+
+fn headers<'de, D: Deserializer<'de>>(d: D) -> Result<Option<HeaderMap>, D::Error> {
+    struct Headers;
+
+    impl<'de> de::Visitor<'de> for Headers {
+        type Value = HeaderMap;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a table of header names and values")
+        }
+
+        fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<HeaderMap, A::Error> {
+            let mut headers = HeaderMap::new();
+            while let Some(Name(name)) = map.next_key()? {
+                let Value(value) = map.next_value()?;
+                headers.insert(name, value);
+            }
+            Ok(headers)
+        }
+    }
+
+    d.deserialize_map(Headers).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_unknown_preset_is_no_profile() {
+        let e = SecurityProfile::new("no-such-preset").err().unwrap();
+        assert!(e.contains("no preset no-such-preset"), "{}", e);
+        assert!(e.contains(DEFAULT_MODE), "{}", e);
+    }
+
+    #[test]
+    fn a_profile_always_has_a_user_agent() {
+        let mut profile = SecurityProfile::new(DEFAULT_MODE).unwrap();
+        assert!(!profile.user_agents().is_empty());
+        assert!(profile.set_user_agents(Vec::new()).is_err());
+        let agent = "Mozilla/5.0 (X11; Linux x86_64)".to_string();
+        profile.set_user_agents(vec![agent.clone()]).unwrap();
+        assert_eq!(profile.user_agent(7), agent);
+    }
+}
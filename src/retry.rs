@@ -0,0 +1,113 @@
+// src/retry.rs
+// Retries for the checks sent through a route: the IP services, Tor's
+// check page, the startup health check and heartbeats. A Tor circuit
+// often fails a request and then works, so a single miss is no reason to
+// call the route broken. A failed attempt is retried after an exponential
+// backoff with jitter. An error that another attempt cannot fix ends the
+// retries at once, and no retry starts past the caller's deadline.
+use std::{
+    error::Error,
+    io,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Default for --check-attempts.
+pub const DEFAULT_ATTEMPTS: u32 = 3;
+/// Default for --check-backoff, in milliseconds.
+pub const DEFAULT_BACKOFF_MS: u64 = 500;
+
+static ATTEMPTS: AtomicU32 = AtomicU32::new(DEFAULT_ATTEMPTS);
+static BACKOFF_MS: AtomicU64 = AtomicU64::new(DEFAULT_BACKOFF_MS);
+
+/// Makes each check try up to `attempts` times. The first retry waits
+/// about `backoff`, and each one after that waits twice as long as the
+/// last.
+pub fn configure(attempts: u32, backoff: Duration) {
+    ATTEMPTS.store(attempts.max(1), Ordering::SeqCst);
+    BACKOFF_MS.store(backoff.as_millis() as u64, Ordering::SeqCst);
+}
+
+/// Why an attempt failed.
+pub enum Failure {
+    /// Another attempt may get through.
+    Retry(String),
+    /// Another attempt would fail the same way.
+    Stop(String),
+}
+
+impl From<reqwest::Error> for Failure {
+    /// A 4xx answer or a certificate that does not check out will not
+    /// change on the next attempt. Anything else might.
+    fn from(e: reqwest::Error) -> Self {
+        let stop = e.status().is_some_and(|s| s.is_client_error()) || bad_certificate(&e);
+        let message = e.without_url().to_string();
+        match stop {
+            true => Failure::Stop(message),
+            false => Failure::Retry(message),
+        }
+    }
+}
+
+/// Whether rustls turned down the server's certificate anywhere in the
+/// chain of `e`. Connect errors carry it inside an io::Error, whose source
+/// skips the error it wraps, so that is unwrapped as well.
+fn bad_certificate(e: &(dyn Error + 'static)) -> bool {
+    let mut next = Some(e);
+    while let Some(e) = next {
+        let wrapped = e
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::get_ref)
+            .map(|inner| inner as &(dyn Error + 'static));
+        let rejected = [Some(e), wrapped].into_iter().flatten().any(|e| {
+            matches!(
+                e.downcast_ref::<rustls::Error>(),
+                Some(rustls::Error::InvalidCertificate(_))
+            )
+        });
+        if rejected {
+            return true;
+        }
+        next = e.source();
+    }
+    false
+}
+
+/// Runs `attempt` until it succeeds, its failure is a [`Failure::Stop`],
+/// the attempts run out, or the next wait would end past `deadline`.
+/// Returns the last failure's message. `what` names the check in the
+/// debug lines about retries.
+pub fn until<T>(
+    what: &str,
+    deadline: Instant,
+    mut attempt: impl FnMut() -> Result<T, Failure>,
+) -> Result<T, String> {
+    let attempts = ATTEMPTS.load(Ordering::SeqCst);
+    let mut delay = Duration::from_millis(BACKOFF_MS.load(Ordering::SeqCst));
+    let mut n = 0;
+    loop {
+        n += 1;
+        let message = match attempt() {
+            Ok(value) => return Ok(value),
+            Err(Failure::Stop(message)) => return Err(message),
+            Err(Failure::Retry(message)) => message,
+        };
+        // Between half and one and a half times the delay, so checks that
+        // failed together do not all retry together
+        let wait = delay.mul_f64(0.5 + fastrand::f64());
+        if n >= attempts || Instant::now() + wait >= deadline {
+            return Err(message);
+        }
+        log::debug!(
+            "{} failed: {}; retrying in {}ms ({}/{})",
+            what,
+            message,
+            wait.as_millis(),
+            n + 1,
+            attempts
+        );
+        thread::sleep(wait);
+        delay *= 2;
+    }
+}
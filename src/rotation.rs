@@ -0,0 +1,1094 @@
+// src/rotation.rs
+// The proxy pool a session rotates through: which proxy carries traffic
+// now, when to move on and which proxy is next. Proxies that keep failing
+// sit out a cooldown, and each rotation is logged, journaled and counted
+// by reason.
+use crate::anonymity::Anonymity;
+use crate::client::strip_credentials;
+use crate::decisions::{self, Decision, Journal, RotationStrategy, Strategy};
+use crate::events::{RotationEvent, RotationReason};
+use crate::forwarder::{self, Hop};
+use crate::pool::ProxyPool;
+#[cfg(feature = "scripting")]
+use crate::scripting;
+use crate::state::{self, ProxyState, State};
+use crate::{logging, redact, tor_integration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Connection outcomes for one proxy this session.
+#[derive(Clone, Default)]
+pub struct ProxyHealth {
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub quarantined_until: Option<Instant>,
+    /// Smoothed tunnel setup time, from the startup health check and
+    /// listener tunnels.
+    pub latency_ms: Option<u64>,
+}
+
+impl ProxyHealth {
+    /// A fresh record, seeded with the latency the list expects.
+    fn expected(entry: &ProxyEntry) -> Self {
+        ProxyHealth {
+            latency_ms: entry.latency_ms,
+            ..ProxyHealth::default()
+        }
+    }
+}
+
+/// Tunnels adaptive rotation judges the current route by.
+const ROUTE_WINDOW: usize = 50;
+
+/// When rotation happens, e.g. "every 15s or 100 requests, whichever comes
+/// first".
+pub fn describe_rotation_policy(interval_secs: u64, request_limit: Option<u64>) -> String {
+    match (interval_secs, request_limit) {
+        (0, None) => "off".to_string(),
+        (secs, None) => format!("every {}s", secs),
+        (0, Some(n)) => format!("every {} requests", n),
+        (secs, Some(n)) => format!("every {}s or {} requests, whichever comes first", secs, n),
+    }
+}
+
+/// How long each identity is kept, e.g. "each identity kept at least 30s
+/// and at most 600s", or `None` when neither bound is set.
+pub fn describe_identity_bounds(min_secs: Option<u64>, max_secs: Option<u64>) -> Option<String> {
+    match (min_secs, max_secs) {
+        (None, None) => None,
+        (Some(min), None) => Some(format!("each identity kept at least {}s", min)),
+        (None, Some(max)) => Some(format!("each identity kept at most {}s", max)),
+        (Some(min), Some(max)) => Some(format!(
+            "each identity kept at least {}s and at most {}s",
+            min, max
+        )),
+    }
+}
+
+fn describe_route_health(health: &decisions::RouteHealth) -> String {
+    format!(
+        "{} tunnels, {:.0}% failed, p90 {}",
+        health.samples,
+        health.error_rate * 100.0,
+        health
+            .p90_latency_ms
+            .map_or_else(|| "-".to_string(), |ms| format!("{}ms", ms))
+    )
+}
+
+/// What `--rotate-distinct` keeps consecutive identities from sharing.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Distinct {
+    Country,
+    /// The autonomous system, e.g. AS3320
+    Asn,
+    None,
+}
+
+impl Distinct {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Distinct::Country => "country",
+            Distinct::Asn => "ASN",
+            Distinct::None => "none",
+        }
+    }
+
+    /// What `entry` is compared by, when its list or --geoip-db says.
+    fn key(self, entry: &ProxyEntry) -> Option<&str> {
+        match self {
+            Distinct::Country => entry.country.as_deref(),
+            Distinct::Asn => entry.asn.as_deref(),
+            Distinct::None => None,
+        }
+    }
+}
+
+/// Which proxy carries the route, and when and where it moves next.
+pub struct ProxyRotator {
+    pub proxies: Vec<ProxyEntry>,
+    pub current_index: usize,
+    pub strategy: RotationStrategy,
+    /// Per proxy, the rotation number it was last moved onto (0 = never).
+    last_used: Vec<u64>,
+    rotation_count: u64,
+    last_rotation: Instant,
+    pub interval: Duration,
+    /// Lower bound of the interval in adaptive mode, where `interval` is the
+    /// upper bound; `None` keeps the interval fixed.
+    pub min_interval: Option<Duration>,
+    /// The interval currently in force.
+    pub effective_interval: Duration,
+    /// Largest random shift of each timed rotation.
+    pub jitter: Duration,
+    /// Shift drawn for the next timed rotation, in ms either way.
+    jitter_offset_ms: i64,
+    /// Whether to log when each jittered rotation is due.
+    pub log_schedule: bool,
+    /// Recent listener tunnels through the current route since it was
+    /// rotated onto: setup time in ms, or `None` for a failure.
+    route_window: VecDeque<Option<u64>>,
+    /// Requests a route carries before it is rotated away from.
+    pub request_limit: Option<u64>,
+    /// Shortest time a route is kept; timed, request-count and manual
+    /// rotations wait for it.
+    pub min_dwell: Option<Duration>,
+    /// Longest time a route is kept, whatever else is set up.
+    pub max_lifetime: Option<Duration>,
+    /// Requests forwarded since the last rotation.
+    pub requests: u64,
+    /// Bytes an identity's tunnels carry before it is rotated away from.
+    pub max_identity_bytes: Option<u64>,
+    /// Identities rotated away from, and the most any one of them carried.
+    identities_ended: u64,
+    largest_identity: u64,
+    /// Proxies the next rotation must not pick, on top of quarantined ones.
+    pub avoid: Vec<bool>,
+    /// Per proxy, whether a reload found it gone from the source.
+    pub retired: Vec<bool>,
+    /// What the next proxy may not share with the current one.
+    pub distinct: Distinct,
+    /// Rotations that had to pick a proxy sharing it, for want of another.
+    distinct_relaxed: u64,
+    pub rotations: HashMap<RotationReason, u64>,
+    pub journal: Option<Arc<Journal>>,
+    pub health: Vec<ProxyHealth>,
+    pub max_failures: u32,
+    pub cooldown: Duration,
+    /// Listener pool kept in step with quarantine decisions.
+    pub pool: Option<Arc<ProxyPool>>,
+    /// Percentage of rotations that land on Tor rather than a proxy.
+    pub tor_weight: u8,
+    /// Whether the current route is Tor itself.
+    pub on_tor: bool,
+    /// Set by --fallback-order: whether rotations go to Tor (`true`) or to
+    /// the proxies, instead of a blend draw.
+    pub tor_pinned: Option<bool>,
+    /// Whether each rotation hands Tor new SOCKS credentials, set with
+    /// [`ProxyRotator::isolate_tor`].
+    pub tor_isolation: bool,
+    /// The Tor URL routes use, with the current identity's credentials.
+    tor_url: String,
+    tor_rotations: u64,
+    proxy_rotations: u64,
+    /// User agents in the security profile.
+    user_agents: usize,
+    /// The one the current route presents, kept until it is rotated away
+    /// from so one exit IP never switches browsers.
+    pub user_agent: usize,
+    /// Picks proxies with --rotation-strategy scripted.
+    #[cfg(feature = "scripting")]
+    pub script: Option<Arc<scripting::Selector>>,
+}
+
+impl ProxyRotator {
+    /// A rotator starting on the first of `proxies`, rotating every
+    /// `interval_secs` (0 for never). Fails when there are no proxies.
+    pub fn new(proxies: Vec<ProxyEntry>, interval_secs: u64) -> Result<Self, String> {
+        if proxies.is_empty() {
+            return Err("no proxies to rotate through".to_string());
+        }
+        let mut last_used = vec![0; proxies.len()];
+        last_used[0] = 1;
+        let retired = vec![false; proxies.len()];
+        Ok(ProxyRotator {
+            health: proxies.iter().map(ProxyHealth::expected).collect(),
+            proxies,
+            current_index: 0,
+            strategy: RotationStrategy::Sequential,
+            last_used,
+            rotation_count: 1,
+            last_rotation: Instant::now(),
+            interval: Duration::from_secs(interval_secs),
+            min_interval: None,
+            effective_interval: Duration::from_secs(interval_secs),
+            jitter: Duration::ZERO,
+            jitter_offset_ms: 0,
+            log_schedule: true,
+            route_window: VecDeque::new(),
+            request_limit: None,
+            min_dwell: None,
+            max_lifetime: None,
+            avoid: Vec::new(),
+            retired,
+            distinct: Distinct::None,
+            distinct_relaxed: 0,
+            requests: 0,
+            max_identity_bytes: None,
+            identities_ended: 0,
+            largest_identity: 0,
+            rotations: HashMap::new(),
+            journal: None,
+            max_failures: 3,
+            cooldown: Duration::from_secs(300),
+            pool: None,
+            tor_weight: 0,
+            on_tor: false,
+            tor_pinned: None,
+            tor_isolation: false,
+            tor_url: tor_integration::SOCKS_URL.to_string(),
+            tor_rotations: 0,
+            proxy_rotations: 0,
+            user_agents: 1,
+            user_agent: 0,
+            #[cfg(feature = "scripting")]
+            script: None,
+        })
+    }
+
+    /// Sets how many user agents there are to pick from, and picks the
+    /// first route's.
+    pub fn set_user_agents(&mut self, count: usize) {
+        self.user_agents = count.max(1);
+        self.user_agent = fastrand::usize(..self.user_agents);
+    }
+
+    /// Gives every identity from here on its own Tor circuits, through SOCKS
+    /// credentials drawn anew at each rotation. Tor must run with
+    /// [`tor_integration::isolation_args`].
+    pub fn isolate_tor(&mut self) {
+        self.tor_isolation = true;
+        self.tor_url = tor_integration::isolated_socks_url();
+    }
+
+    /// The Tor URL for the current identity: [`tor_integration::SOCKS_URL`],
+    /// with credentials under [`ProxyRotator::isolate_tor`].
+    pub fn tor_url(&self) -> &str {
+        &self.tor_url
+    }
+
+    /// Moves to Tor (when pinned to it, or when blending and `tor_ready`) or
+    /// to the next proxy that is not quarantined. Returns `None`, leaving the
+    /// route in place, when every proxy is quarantined.
+    pub fn rotate(&mut self, reason: RotationReason, tor_ready: bool) -> Option<RotationEvent> {
+        let from = strip_credentials(self.current());
+        let use_tor = match self.tor_pinned {
+            Some(pinned) => pinned,
+            None => self.tor_weight > 0 && self.blend_draw(tor_ready),
+        };
+        if use_tor {
+            self.on_tor = true;
+            self.tor_rotations += 1;
+            return Some(self.rotated(reason, from));
+        }
+
+        let mut quarantined = self.quarantined_flags();
+        for (flag, avoid) in quarantined.iter_mut().zip(std::mem::take(&mut self.avoid)) {
+            *flag |= avoid;
+        }
+        let quarantined = self.keep_distinct(quarantined);
+        let seed = decisions::draw_seed();
+        let (len, current) = (self.proxies.len(), self.current_index);
+        let next = match self.strategy {
+            RotationStrategy::Sequential => decisions::next_proxy(len, current, &quarantined, seed),
+            RotationStrategy::Random => decisions::random_proxy(len, current, &quarantined, seed),
+            RotationStrategy::Lru => decisions::lru_proxy(current, &quarantined, &self.last_used),
+            RotationStrategy::Weighted => {
+                decisions::weighted_proxy(current, &quarantined, &self.latencies(), seed)
+            }
+            #[cfg(feature = "scripting")]
+            RotationStrategy::Scripted => self.scripted_proxy(reason, &quarantined, seed),
+        };
+        if let Some(journal) = &self.journal {
+            let last_used = match self.strategy {
+                RotationStrategy::Lru => self.last_used.clone(),
+                _ => Vec::new(),
+            };
+            let latency_ms = match self.strategy {
+                RotationStrategy::Weighted => self.latencies(),
+                _ => Vec::new(),
+            };
+            journal.record(
+                self.strategy.journal_strategy(),
+                seed,
+                Decision::Rotation {
+                    pool: self
+                        .proxies
+                        .iter()
+                        .map(|p| strip_credentials(&p.url))
+                        .collect(),
+                    current,
+                    quarantined,
+                    last_used,
+                    latency_ms,
+                    outcome: next,
+                },
+            );
+        }
+        self.current_index = next?;
+        self.rotation_count += 1;
+        self.last_used[self.current_index] = self.rotation_count;
+        self.on_tor = false;
+        self.proxy_rotations += 1;
+        Some(self.rotated(reason, from))
+    }
+
+    /// `quarantined` with every proxy that shares the current one's country
+    /// or ASN, or whose is unknown, left out as well, so the strategy picks
+    /// among the rest. When that leaves nothing, the constraint is relaxed
+    /// for this rotation and `quarantined` is returned as it was. Nothing
+    /// is left out while on Tor, or when the current proxy's is unknown.
+    fn keep_distinct(&mut self, quarantined: Vec<bool>) -> Vec<bool> {
+        let distinct = self.distinct;
+        let Some(previous) = self
+            .current_entry()
+            .and_then(|entry| distinct.key(entry))
+            .map(str::to_string)
+        else {
+            return quarantined;
+        };
+        let flags: Vec<bool> = self
+            .proxies
+            .iter()
+            .zip(&quarantined)
+            .map(|(entry, q)| *q || distinct.key(entry).is_none_or(|key| key == previous))
+            .collect();
+        if flags.iter().any(|q| !q) {
+            return flags;
+        }
+        // With nothing left at all there is nothing to relax
+        if quarantined.iter().all(|q| *q) {
+            return quarantined;
+        }
+        self.distinct_relaxed += 1;
+        logging::write(
+            &format!(
+                "No proxy outside {} {} to rotate to; --rotate-distinct relaxed for this rotation",
+                distinct.as_str(),
+                previous
+            ),
+            "WARNING",
+        );
+        quarantined
+    }
+
+    /// Rotations --rotate-distinct had to relax.
+    pub fn distinct_relaxed(&self) -> u64 {
+        self.distinct_relaxed
+    }
+
+    /// Whether a blended rotation goes to Tor.
+    fn blend_draw(&self, tor_ready: bool) -> bool {
+        let seed = decisions::draw_seed();
+        let use_tor = decisions::use_tor(self.tor_weight, tor_ready, seed);
+        if let Some(journal) = &self.journal {
+            journal.record(
+                Strategy::Weighted,
+                seed,
+                Decision::Blend {
+                    tor_weight: self.tor_weight,
+                    tor_ready,
+                    outcome: use_tor,
+                },
+            );
+        }
+        use_tor
+    }
+
+    fn rotated(&mut self, reason: RotationReason, from: String) -> RotationEvent {
+        self.last_rotation = Instant::now();
+        // A new route starts out trusted
+        self.route_window.clear();
+        self.effective_interval = self.interval;
+        self.requests = 0;
+        let ended = forwarder::next_identity().total();
+        self.identities_ended += 1;
+        self.largest_identity = self.largest_identity.max(ended);
+        *self.rotations.entry(reason).or_insert(0) += 1;
+        if self.tor_isolation {
+            self.tor_url = tor_integration::isolated_socks_url();
+        }
+        // Without blending or a pin, isolation is on for a chain where
+        // every route goes through Tor
+        let all_tor = self.tor_weight == 0 && self.tor_pinned.is_none();
+        if self.tor_isolation && (self.on_tor || all_tor) {
+            logging::write(
+                "Tor circuits isolated: the new identity uses new SOCKS credentials",
+                "TOR",
+            );
+        }
+        let to = strip_credentials(self.current());
+        let index = (!self.on_tor).then_some(self.current_index);
+        if self.user_agents > 1 {
+            // A new exit presents another browser than the last one did
+            let step = fastrand::usize(1..self.user_agents);
+            self.user_agent = (self.user_agent + step) % self.user_agents;
+        }
+        logging::write_json(
+            &format!(
+                "Proxy rotated to: {} (reason: {}, user agent #{})",
+                to, reason, self.user_agent
+            ),
+            "ROTATION",
+            serde_json::json!({
+                "proxy_index": index,
+                "user_agent": self.user_agent,
+                "tor": self.on_tor,
+                "rotation": self.rotation_count(),
+                "reason": reason.to_string(),
+                "proxy": redact::shows_addresses().then(|| redact::text(&to)),
+            }),
+        );
+        self.draw_jitter();
+        RotationEvent {
+            ts: chrono::Local::now().to_rfc3339(),
+            reason,
+            from,
+            to: strip_credentials(self.current()),
+            settle_ms: None,
+            exit_ip: None,
+        }
+    }
+
+    /// Target and realized Tor share, e.g. "Tor 20% target, 3/14 rotations
+    /// (21%)".
+    pub fn blend_summary(&self) -> String {
+        let total = self.tor_rotations + self.proxy_rotations;
+        let realized = (self.tor_rotations * 100).checked_div(total).unwrap_or(0);
+        format!(
+            "Tor {}% target, {}/{} rotations ({}%)",
+            self.tor_weight, self.tor_rotations, total, realized
+        )
+    }
+
+    pub fn is_quarantined(&self, index: usize) -> bool {
+        self.health[index].quarantined_until.is_some()
+    }
+
+    /// Per proxy, whether a rotation may not pick it: quarantined, or
+    /// retired.
+    fn quarantined_flags(&self) -> Vec<bool> {
+        (0..self.proxies.len())
+            .map(|i| self.is_quarantined(i) || self.retired[i])
+            .collect()
+    }
+
+    /// Proxies a rotation may pick.
+    pub fn alive_count(&self) -> usize {
+        self.quarantined_flags().iter().filter(|q| !**q).count()
+    }
+
+    pub fn quarantined_count(&self) -> usize {
+        self.health
+            .iter()
+            .filter(|h| h.quarantined_until.is_some())
+            .count()
+    }
+
+    pub fn all_quarantined(&self) -> bool {
+        self.alive_count() == 0
+    }
+
+    pub fn index_of_hop(&self, hop: &Hop) -> Option<usize> {
+        let key = hop.to_string();
+        self.proxies
+            .iter()
+            .position(|p| Hop::parse(&p.url).is_ok_and(|h| h.to_string() == key))
+    }
+
+    /// Keeps `pool` in step with quarantine decisions, those already made
+    /// included.
+    pub fn set_pool(&mut self, pool: Arc<ProxyPool>) {
+        self.pool = Some(pool);
+        for index in 0..self.proxies.len() {
+            self.sync_pool(index);
+        }
+    }
+
+    fn sync_pool(&self, index: usize) {
+        if let (Some(pool), Ok(hop)) = (&self.pool, Hop::parse(&self.proxies[index].url)) {
+            pool.set_quarantined(&hop, self.is_quarantined(index) || self.retired[index]);
+        }
+    }
+
+    /// Records a connection attempt through proxy `index`, quarantining it
+    /// once it has failed `max_failures` times in a row.
+    pub fn record_outcome(&mut self, index: usize, ok: bool) {
+        let health = &mut self.health[index];
+        if ok {
+            health.successes += 1;
+            health.consecutive_failures = 0;
+            return;
+        }
+        health.failures += 1;
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= self.max_failures {
+            self.quarantine(index);
+        }
+    }
+
+    /// Keeps proxy `index` out of rotation for the cooldown, unless it
+    /// already is.
+    pub fn quarantine(&mut self, index: usize) {
+        let health = &mut self.health[index];
+        if health.quarantined_until.is_some() {
+            return;
+        }
+        health.quarantined_until = Some(Instant::now() + self.cooldown);
+        logging::write(
+            &format!(
+                "Proxy {} quarantined after {} consecutive failures ({} ok / {} failed this session); retrying in {}s",
+                strip_credentials(&self.proxies[index].url),
+                health.consecutive_failures,
+                health.successes,
+                health.failures,
+                self.cooldown.as_secs()
+            ),
+            "PROXY",
+        );
+        self.sync_pool(index);
+    }
+
+    /// Quarantines every proxy for the cooldown, as when none passed the
+    /// health check but the session carries on over another transport.
+    pub fn quarantine_all(&mut self) {
+        let until = Instant::now() + self.cooldown;
+        for index in 0..self.proxies.len() {
+            self.health[index].quarantined_until = Some(until);
+            self.sync_pool(index);
+        }
+    }
+
+    /// Adds proxies that are not in rotation yet, with their measured
+    /// latency if they were health-checked. The current proxy and every
+    /// existing proxy's health are kept. Returns how many were added.
+    pub fn merge(&mut self, found: Vec<(ProxyEntry, Option<Duration>)>) -> usize {
+        let mut added = 0;
+        for (entry, latency) in found {
+            if self.proxies.iter().any(|p| p.url == entry.url) {
+                continue;
+            }
+            let mut health = ProxyHealth::expected(&entry);
+            if let Some(latency) = latency {
+                health.latency_ms = Some(latency.as_millis() as u64);
+            }
+            self.proxies.push(entry);
+            self.health.push(health);
+            self.last_used.push(0);
+            self.retired.push(false);
+            added += 1;
+        }
+        added
+    }
+
+    /// Retires proxies missing from `urls`, and brings back retired ones
+    /// that are in it. A retired current proxy keeps carrying traffic until
+    /// the next rotation. Returns how many were retired and restored.
+    pub fn retire_missing(&mut self, urls: &HashSet<&str>) -> (usize, usize) {
+        let (mut retired, mut restored) = (0, 0);
+        for i in 0..self.proxies.len() {
+            let listed = urls.contains(self.proxies[i].url.as_str());
+            if listed == self.retired[i] {
+                if listed {
+                    restored += 1;
+                } else {
+                    retired += 1;
+                }
+                self.retired[i] = !listed;
+                self.sync_pool(i);
+            }
+        }
+        (retired, restored)
+    }
+
+    /// Folds a new latency sample for proxy `index` into its running
+    /// average, so one slow tunnel does not sideline a fast proxy.
+    pub fn record_latency(&mut self, index: usize, sample: Duration) {
+        let sample = sample.as_millis() as u64;
+        let latency = &mut self.health[index].latency_ms;
+        *latency = Some(latency.map_or(sample, |old| (old * 3 + sample) / 4));
+    }
+
+    fn latencies(&self) -> Vec<Option<u64>> {
+        self.health.iter().map(|h| h.latency_ms).collect()
+    }
+
+    /// Up to `n` measured proxies, fastest first.
+    pub fn fastest(&self, n: usize) -> Vec<(String, u64)> {
+        let mut measured: Vec<(String, u64)> = self
+            .proxies
+            .iter()
+            .zip(&self.health)
+            .filter_map(|(p, h)| h.latency_ms.map(|ms| (strip_credentials(&p.url), ms)))
+            .collect();
+        measured.sort_by_key(|(_, ms)| *ms);
+        measured.truncate(n);
+        measured
+    }
+
+    /// What the session knows about each proxy, for the next one.
+    pub fn export_state(&self) -> State {
+        let (now, unix_now) = (Instant::now(), chrono::Utc::now().timestamp());
+        let proxies = self
+            .proxies
+            .iter()
+            .zip(&self.health)
+            .map(|(entry, health)| {
+                let saved = ProxyState {
+                    successes: health.successes,
+                    failures: health.failures,
+                    consecutive_failures: health.consecutive_failures,
+                    latency_ms: health.latency_ms,
+                    anonymity: entry.anonymity,
+                    quarantined_until: health.quarantined_until.map(|until| {
+                        unix_now + until.saturating_duration_since(now).as_secs() as i64
+                    }),
+                };
+                (state::key(&entry.url), saved)
+            })
+            .collect();
+        State::new(proxies)
+    }
+
+    /// Takes over what an earlier session knew about the proxies that are
+    /// still listed, and moves off the first proxy if it is in a
+    /// quarantine that has not ended. Without `keep_quarantine`, as when
+    /// every proxy just passed the health check, quarantines are dropped.
+    /// Returns how many proxies were known.
+    pub fn restore_state(&mut self, saved: &State, keep_quarantine: bool) -> usize {
+        let unix_now = chrono::Utc::now().timestamp();
+        let mut known = 0;
+        for (entry, health) in self.proxies.iter_mut().zip(&mut self.health) {
+            let Some(saved) = saved.proxies.get(&state::key(&entry.url)) else {
+                continue;
+            };
+            known += 1;
+            health.successes = saved.successes;
+            health.failures = saved.failures;
+            health.consecutive_failures = saved.consecutive_failures;
+            health.latency_ms = saved.latency_ms.or(health.latency_ms);
+            entry.anonymity = entry.anonymity.or(saved.anonymity);
+            health.quarantined_until = saved
+                .quarantined_until
+                .filter(|until| keep_quarantine && *until > unix_now)
+                .map(|until| Instant::now() + Duration::from_secs((until - unix_now) as u64));
+            if health.quarantined_until.is_none() && saved.quarantined_until.is_some() {
+                health.consecutive_failures = 0;
+            }
+        }
+        // A session that starts with nothing to rotate to ends at once
+        if self.all_quarantined() {
+            for health in &mut self.health {
+                health.quarantined_until = None;
+                health.consecutive_failures = 0;
+            }
+        }
+        if self.is_quarantined(self.current_index) {
+            if let Some(index) = (0..self.proxies.len()).find(|i| !self.is_quarantined(*i)) {
+                self.last_used[self.current_index] = 0;
+                self.current_index = index;
+                self.last_used[index] = 1;
+            }
+        }
+        known
+    }
+
+    /// Lets proxies whose cooldown has passed back into rotation.
+    pub fn release_expired(&mut self) {
+        let now = Instant::now();
+        for index in 0..self.proxies.len() {
+            let health = &mut self.health[index];
+            if health.quarantined_until.is_some_and(|until| until <= now) {
+                health.quarantined_until = None;
+                health.consecutive_failures = 0;
+                logging::write(
+                    &format!(
+                        "Proxy {} is back from quarantine",
+                        strip_credentials(&self.proxies[index].url)
+                    ),
+                    "PROXY",
+                );
+                self.sync_pool(index);
+            }
+        }
+    }
+
+    pub fn current(&self) -> &str {
+        if self.on_tor {
+            &self.tor_url
+        } else {
+            &self.proxies[self.current_index].url
+        }
+    }
+
+    /// The current proxy's list entry, or `None` while on Tor.
+    pub fn current_entry(&self) -> Option<&ProxyEntry> {
+        (!self.on_tor).then(|| &self.proxies[self.current_index])
+    }
+
+    pub fn should_rotate(&self) -> bool {
+        !self.interval.is_zero()
+            && Instant::now().duration_since(self.last_rotation) >= self.rotation_due()
+            && self.dwell_left().is_none()
+    }
+
+    /// How much longer --min-dwell keeps the route, if it still does.
+    pub fn dwell_left(&self) -> Option<Duration> {
+        let left = self.min_dwell?.checked_sub(self.last_rotation.elapsed())?;
+        (!left.is_zero()).then_some(left)
+    }
+
+    /// Whether the route has been kept for --max-identity-lifetime.
+    pub fn lifetime_over(&self) -> bool {
+        self.max_lifetime
+            .is_some_and(|max| self.last_rotation.elapsed() >= max)
+    }
+
+    /// Sets the jitter, clamped below the interval so no rotation is ever
+    /// due before the route was set up.
+    pub fn set_jitter(&mut self, jitter: Duration) {
+        let limit = self.interval.saturating_sub(Duration::from_secs(1));
+        if jitter > limit {
+            logging::write(
+                &format!(
+                    "Rotation jitter of {}s is not smaller than the {}s interval; using {}s",
+                    jitter.as_secs(),
+                    self.interval.as_secs(),
+                    limit.as_secs()
+                ),
+                "ROTATION",
+            );
+        }
+        self.jitter = jitter.min(limit);
+        self.draw_jitter();
+    }
+
+    /// Picks the shift of the next timed rotation.
+    fn draw_jitter(&mut self) {
+        if self.jitter.is_zero() || self.interval.is_zero() {
+            return;
+        }
+        let max = self.jitter.as_millis() as i64;
+        self.jitter_offset_ms = fastrand::i64(-max..=max);
+        if self.log_schedule {
+            logging::write(
+                &format!("Next rotation in ~{}s", self.rotation_due().as_secs()),
+                "ROTATION",
+            );
+        }
+    }
+
+    /// Time after the last rotation at which the timer rotates again.
+    fn rotation_due(&self) -> Duration {
+        let due = self.effective_interval.as_millis() as i64 + self.jitter_offset_ms;
+        Duration::from_millis(due.max(1000) as u64)
+    }
+
+    pub fn until_rotation(&self) -> Duration {
+        self.rotation_due()
+            .saturating_sub(self.last_rotation.elapsed())
+    }
+
+    /// Rotations this session, whatever their reason.
+    pub fn rotation_count(&self) -> u64 {
+        self.rotations.values().sum()
+    }
+
+    /// Time since the route was last rotated, or since the session began.
+    pub fn since_rotation(&self) -> Duration {
+        self.last_rotation.elapsed()
+    }
+
+    pub fn request_quota_used(&self) -> bool {
+        self.request_limit
+            .is_some_and(|limit| self.requests >= limit)
+    }
+
+    pub fn byte_quota_used(&self) -> bool {
+        self.max_identity_bytes
+            .is_some_and(|limit| forwarder::identity_traffic().total() >= limit)
+    }
+
+    /// Identities rotated away from so far, and the most bytes any one
+    /// of them carried.
+    pub fn identity_bytes(&self) -> (u64, u64) {
+        (self.identities_ended, self.largest_identity)
+    }
+
+    /// Bytes up and down tunnels through proxy `index` carried.
+    pub fn proxy_traffic(&self, index: usize) -> (u64, u64) {
+        match Hop::parse(&self.proxies[index].url) {
+            Ok(hop) => {
+                let traffic = forwarder::hop_traffic(&hop);
+                (traffic.up(), traffic.down())
+            }
+            Err(_) => (0, 0),
+        }
+    }
+
+    pub fn policy_summary(&self) -> String {
+        let mut policy =
+            describe_rotation_policy(self.effective_interval.as_secs(), self.request_limit);
+        if !self.jitter.is_zero() {
+            policy.push_str(&format!(
+                ", timer jittered by up to {}s",
+                self.jitter.as_secs()
+            ));
+        }
+        let bounds = describe_identity_bounds(
+            self.min_dwell.map(|d| d.as_secs()),
+            self.max_lifetime.map(|d| d.as_secs()),
+        );
+        if let Some(bounds) = bounds {
+            policy.push_str(&format!("; {}", bounds));
+        }
+        policy
+    }
+
+    /// Notes a listener tunnel through proxy `index` for adaptive rotation,
+    /// if that proxy is the current route.
+    pub fn observe_route(&mut self, index: usize, latency: Option<Duration>) {
+        if self.min_interval.is_none() || self.on_tor || index != self.current_index {
+            return;
+        }
+        if self.route_window.len() == ROUTE_WINDOW {
+            self.route_window.pop_front();
+        }
+        self.route_window
+            .push_back(latency.map(|l| l.as_millis() as u64));
+    }
+
+    pub fn route_health(&self) -> decisions::RouteHealth {
+        let window: Vec<Option<u64>> = self.route_window.iter().copied().collect();
+        decisions::route_health(&window)
+    }
+
+    /// Recomputes the interval from the current route's health in adaptive
+    /// mode, logging the inputs whenever the result changes.
+    pub fn adapt_interval(&mut self) {
+        let Some(min) = self.min_interval else {
+            return;
+        };
+        let health = self.route_health();
+        let interval = decisions::adaptive_interval(min, self.interval, &health);
+        if interval.as_secs() != self.effective_interval.as_secs() {
+            logging::write(
+                &format!(
+                    "Adaptive interval now {}s ({})",
+                    interval.as_secs(),
+                    describe_route_health(&health)
+                ),
+                "ROTATION",
+            );
+        }
+        self.effective_interval = interval;
+    }
+}
+
+#[cfg(feature = "scripting")]
+impl ProxyRotator {
+    /// The script's pick, or the sequential one when the script fails.
+    fn scripted_proxy(
+        &self,
+        reason: RotationReason,
+        quarantined: &[bool],
+        seed: u64,
+    ) -> Option<usize> {
+        let fallback =
+            || decisions::next_proxy(self.proxies.len(), self.current_index, quarantined, seed);
+        let Some(script) = &self.script else {
+            return fallback();
+        };
+        let candidates: Vec<scripting::Candidate> = self
+            .proxies
+            .iter()
+            .zip(&self.health)
+            .enumerate()
+            .map(|(i, (entry, health))| scripting::Candidate {
+                proxy: strip_credentials(&entry.url),
+                country: entry.country.clone(),
+                provider: entry.provider.clone(),
+                label: entry.label.clone(),
+                latency_ms: health.latency_ms,
+                quarantined: quarantined[i],
+                current: !self.on_tor && i == self.current_index,
+                last_used: self.last_used[i],
+                successes: health.successes,
+                failures: health.failures,
+            })
+            .collect();
+        let context = scripting::Context {
+            rotation: self.rotation_count + 1,
+            reason: reason.to_string(),
+            proxies_alive: self.alive_count(),
+        };
+        // Nothing to pick from; the script would only fail
+        if quarantined.iter().all(|q| *q) {
+            return None;
+        }
+        script
+            .select(&candidates, &context)
+            .map(Some)
+            .unwrap_or_else(|e| {
+                if script.should_warn() {
+                    logging::write(
+                        &format!("Selection script failed, rotating sequentially: {}", e),
+                        "ERROR",
+                    );
+                }
+                fallback()
+            })
+    }
+}
+
+/// One proxy list entry: a URL with optional settings and metadata. In a
+/// plain list these follow the URL, e.g. `max_connections=20 country=DE`.
+#[derive(Clone, Default, serde::Deserialize)]
+pub struct ProxyEntry {
+    pub url: String,
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Country code as given by the list, e.g. "DE".
+    #[serde(default)]
+    pub country: Option<String>,
+    /// Autonomous system, e.g. "AS3320".
+    #[serde(default)]
+    pub asn: Option<String>,
+    #[serde(default)]
+    pub provider: Option<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Expected tunnel setup time, used until the proxy is measured.
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+    /// What the health check's judge found, when it was asked.
+    #[serde(skip)]
+    pub anonymity: Option<Anonymity>,
+}
+
+/// Schemes a proxy list entry may use.
+const PROXY_SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+impl ProxyEntry {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut fields = line.split_whitespace();
+        let mut entry = ProxyEntry {
+            url: normalize_proxy_url(fields.next().unwrap_or(""))?,
+            ..ProxyEntry::default()
+        };
+        for field in fields {
+            let Some((key, value)) = field.split_once('=') else {
+                logging::write(
+                    &format!(
+                        "Ignoring unknown setting {} for {}",
+                        field,
+                        strip_credentials(&entry.url)
+                    ),
+                    "ERROR",
+                );
+                continue;
+            };
+            if let Err(e) = entry.set(key, value) {
+                logging::write(
+                    &format!(
+                        "Ignoring {} for {}: {}",
+                        field,
+                        strip_credentials(&entry.url),
+                        e
+                    ),
+                    "ERROR",
+                );
+            }
+        }
+        entry.normalized()
+    }
+
+    /// Sets one named field from its text form. Unknown names are an error
+    /// so typos in a hand-written list are noticed.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let text = || Some(value.to_string()).filter(|v| !v.is_empty());
+        match key {
+            "url" => self.url = value.to_string(),
+            "max_connections" => {
+                self.max_connections = Some(value.parse().map_err(|_| "not a number")?)
+            }
+            "latency_ms" => self.latency_ms = Some(value.parse().map_err(|_| "not a number")?),
+            "country" => self.country = text(),
+            "asn" => self.asn = text(),
+            "provider" => self.provider = text(),
+            "label" => self.label = text(),
+            _ => return Err("unknown setting".to_string()),
+        }
+        Ok(())
+    }
+
+    pub fn normalized(mut self) -> Result<Self, String> {
+        self.url = normalize_proxy_url(self.url.trim())?;
+        self.country = self.country.map(|c| c.trim().to_ascii_uppercase());
+        self.asn = self.asn.as_deref().and_then(normalize_asn);
+        Ok(self)
+    }
+
+    /// Metadata for display, e.g. "DE, provider Acme, label fast-1, elite".
+    pub fn details(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(country) = &self.country {
+            parts.push(country.clone());
+        }
+        if let Some(asn) = &self.asn {
+            parts.push(asn.clone());
+        }
+        if let Some(provider) = &self.provider {
+            parts.push(format!("provider {}", provider));
+        }
+        if let Some(label) = &self.label {
+            parts.push(format!("label {}", label));
+        }
+        if let Some(anonymity) = self.anonymity {
+            parts.push(anonymity.name().to_string());
+        }
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+}
+
+/// An AS number as "AS3320", from "3320", "as3320" or "AS3320 Deutsche
+/// Telekom AG".
+pub fn normalize_asn(raw: &str) -> Option<String> {
+    let number = raw.split_whitespace().next()?.to_ascii_uppercase();
+    let digits = number.strip_prefix("AS").unwrap_or(&number);
+    digits.parse::<u32>().ok().map(|n| format!("AS{}", n))
+}
+
+/// Checks a proxy URL and fills in what may be left out: `http://` for a
+/// bare `host:port`, and port 1080 for SOCKS. Credentials are kept.
+fn normalize_proxy_url(raw: &str) -> Result<String, String> {
+    let has_scheme = raw.contains("://");
+    let with_scheme = if has_scheme {
+        raw.to_string()
+    } else {
+        format!("http://{}", raw)
+    };
+    let mut url = reqwest::Url::parse(&with_scheme).map_err(|e| format!("invalid URL: {}", e))?;
+    let scheme = url.scheme().to_string();
+    if scheme == "socks4" || scheme == "socks4a" {
+        return Err(format!("{}:// proxies are not supported", scheme));
+    }
+    if !PROXY_SCHEMES.contains(&scheme.as_str()) {
+        return Err(format!(
+            "unknown scheme {}://; expected one of {}",
+            scheme,
+            PROXY_SCHEMES.join(", ")
+        ));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err("no host".to_string());
+    }
+    // A bare entry must be host:port, or "name" would pass as a host
+    if url.port().is_none() && !has_scheme {
+        return Err("expected host:port or a proxy URL".to_string());
+    }
+    if url.port_or_known_default().is_none() {
+        let _ = url.set_port(Some(1080));
+    }
+    // Special schemes such as http always have a "/" path, others none
+    if !matches!(url.path(), "" | "/") || url.query().is_some() {
+        return Err("proxy URLs cannot have a path or query".to_string());
+    }
+    // Url always adds a trailing slash; the rest of the session compares
+    // proxies by the plain form
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
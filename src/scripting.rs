@@ -0,0 +1,201 @@
+// src/scripting.rs
+// User-scripted proxy selection for `--rotation-strategy scripted`, built
+// with the `scripting` feature. Scripts are Rhai and only see the maps
+// passed to them.
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Bumped whenever a context field changes meaning or goes away.
+pub const CONTEXT_VERSION: i64 = 1;
+/// Longest one call to `select` may run.
+const CALL_TIMEOUT: Duration = Duration::from_millis(100);
+const MAX_OPERATIONS: u64 = 1_000_000;
+/// Shortest gap between two warnings about a failing script.
+const WARNING_GAP: Duration = Duration::from_secs(60);
+
+/// What a script can rely on, printed by `profile show --script-context`.
+pub const CONTEXT_DOC: &str = "\
+Selection script API, version 1
+
+A script for --rotation-strategy scripted defines
+
+    fn select(candidates, context) { ... }
+
+and returns the index into `candidates` of the proxy to rotate to.
+
+candidates: array of maps, one per proxy in list order
+  index          int     position in the proxy list
+  proxy          string  proxy URL without credentials
+  country        string  country code from the list, or () when unknown
+  provider       string  or ()
+  label          string  or ()
+  latency_ms     int     smoothed tunnel setup time, or () before measured
+  quarantined    bool    picking a quarantined proxy is an error
+  current        bool    whether this is the route being rotated away from
+  last_used      int     rotation number it was last moved onto, 0 = never
+  successes      int     tunnels through it this session
+  failures       int     failed tunnels through it this session
+
+context: map
+  version        int     1; bumped when a field changes meaning or goes away
+  rotation       int     number of the rotation being decided
+  reason         string  timer, signal, quarantine, control, requests,
+                         bytes, exit_cap, blocked_exit, heartbeat,
+                         lifetime or fallback
+  uptime_secs    int     seconds since the script was loaded at startup
+  proxies_alive  int     proxies not in quarantine
+  destination    string  or (); rotations are not tied to one request, so
+                         this is () for now
+
+Each call gets 100ms and 1000000 operations. Errors, timeouts and invalid
+indexes fall back to sequential selection and are logged at most once a
+minute.
+";
+
+/// One proxy as the script sees it.
+pub struct Candidate {
+    pub proxy: String,
+    pub country: Option<String>,
+    pub provider: Option<String>,
+    pub label: Option<String>,
+    pub latency_ms: Option<u64>,
+    pub quarantined: bool,
+    pub current: bool,
+    pub last_used: u64,
+    pub successes: u64,
+    pub failures: u64,
+}
+
+/// Session state the script sees.
+pub struct Context {
+    pub rotation: u64,
+    pub reason: String,
+    pub proxies_alive: usize,
+}
+
+/// A compiled selection script.
+pub struct Selector {
+    engine: Engine,
+    ast: AST,
+    /// End of the running call's time budget.
+    deadline: Arc<Mutex<Instant>>,
+    last_warning: Mutex<Option<Instant>>,
+    loaded: Instant,
+}
+
+impl Selector {
+    /// Compiles the script at `path`, which must define `select` with two
+    /// parameters.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let deadline = Arc::new(Mutex::new(Instant::now()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let budget = deadline.clone();
+        engine.on_progress(move |_| {
+            (Instant::now() > *budget.lock().unwrap()).then(|| Dynamic::from("timed out"))
+        });
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| format!("cannot load {}: {}", path.display(), e))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == "select" && f.params.len() == 2)
+        {
+            return Err(format!(
+                "{} does not define fn select(candidates, context)",
+                path.display()
+            ));
+        }
+        Ok(Selector {
+            engine,
+            ast,
+            deadline,
+            last_warning: Mutex::new(None),
+            loaded: Instant::now(),
+        })
+    }
+
+    /// Runs `select`, checking that it names a proxy that is not
+    /// quarantined.
+    pub fn select(&self, candidates: &[Candidate], context: &Context) -> Result<usize, String> {
+        *self.deadline.lock().unwrap() = Instant::now() + CALL_TIMEOUT;
+        let array: Array = candidates
+            .iter()
+            .enumerate()
+            .map(|(index, c)| Dynamic::from_map(candidate_map(index, c)))
+            .collect();
+        let index = self
+            .engine
+            .call_fn::<i64>(
+                &mut Scope::new(),
+                &self.ast,
+                "select",
+                (array, context_map(context, self.loaded.elapsed())),
+            )
+            .map_err(|e| e.to_string())?;
+        match usize::try_from(index)
+            .ok()
+            .and_then(|i| candidates.get(i).map(|c| (i, c)))
+        {
+            Some((i, c)) if !c.quarantined => Ok(i),
+            Some(_) => Err(format!("select returned {}, which is quarantined", index)),
+            None => Err(format!(
+                "select returned {}, which is not a candidate",
+                index
+            )),
+        }
+    }
+
+    /// Whether a failure should be reported now, at most once per
+    /// `WARNING_GAP`.
+    pub fn should_warn(&self) -> bool {
+        let mut last = self.last_warning.lock().unwrap();
+        if last.is_some_and(|at| at.elapsed() < WARNING_GAP) {
+            return false;
+        }
+        *last = Some(Instant::now());
+        true
+    }
+}
+
+fn text(value: &Option<String>) -> Dynamic {
+    value.clone().map_or(Dynamic::UNIT, Dynamic::from)
+}
+
+fn candidate_map(index: usize, c: &Candidate) -> Map {
+    let mut map = Map::new();
+    map.insert("index".into(), (index as i64).into());
+    map.insert("proxy".into(), c.proxy.clone().into());
+    map.insert("country".into(), text(&c.country));
+    map.insert("provider".into(), text(&c.provider));
+    map.insert("label".into(), text(&c.label));
+    map.insert(
+        "latency_ms".into(),
+        c.latency_ms
+            .map_or(Dynamic::UNIT, |ms| Dynamic::from(ms as i64)),
+    );
+    map.insert("quarantined".into(), c.quarantined.into());
+    map.insert("current".into(), c.current.into());
+    map.insert("last_used".into(), (c.last_used as i64).into());
+    map.insert("successes".into(), (c.successes as i64).into());
+    map.insert("failures".into(), (c.failures as i64).into());
+    map
+}
+
+fn context_map(context: &Context, uptime: Duration) -> Map {
+    let mut map = Map::new();
+    map.insert("version".into(), CONTEXT_VERSION.into());
+    map.insert("rotation".into(), (context.rotation as i64).into());
+    map.insert("reason".into(), context.reason.clone().into());
+    map.insert("uptime_secs".into(), (uptime.as_secs() as i64).into());
+    map.insert(
+        "proxies_alive".into(),
+        (context.proxies_alive as i64).into(),
+    );
+    map.insert("destination".into(), Dynamic::UNIT);
+    map
+}
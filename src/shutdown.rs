@@ -0,0 +1,252 @@
+// src/shutdown.rs
+// How a session ends on a signal. Ctrl-C, SIGTERM and SIGQUIT (on Windows
+// Ctrl-C, Ctrl-Break and the console closing) all ask it to stop, so that
+// `systemctl stop` cleans up as Ctrl-C does. Cleanup is bounded: once the
+// session stops it has a grace period to finish, and a second request
+// while it shuts down, or running out of grace, kills Tor and exits the
+// process at once.
+use crate::logging;
+use std::{
+    io, process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Exit status of a forced exit.
+const FORCED_EXIT: i32 = 1;
+
+static RUNNING: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+/// Kills Tor before a forced exit.
+static KILL: OnceLock<Box<dyn Fn() + Send + Sync>> = OnceLock::new();
+
+/// Makes stop requests clear `running`, which the session runs while set,
+/// and has a forced exit call `kill` first.
+pub fn install<F>(running: Arc<AtomicBool>, kill: F) -> io::Result<()>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let _ = RUNNING.set(running);
+    let _ = KILL.set(Box::new(kill));
+    #[cfg(unix)]
+    {
+        use signal_hook::consts::{SIGINT, SIGQUIT, SIGTERM};
+        let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM, SIGQUIT])?;
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                request(match signal {
+                    SIGTERM => "Received SIGTERM",
+                    SIGQUIT => "Received SIGQUIT",
+                    _ => "Received Ctrl-C",
+                });
+            }
+        });
+    }
+    #[cfg(windows)]
+    crate::windows::on_console_events()?;
+    #[cfg(not(any(unix, windows)))]
+    ctrlc::set_handler(|| request("Received Ctrl-C")).map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// Asks the session to stop because of `what`, e.g. "Received SIGTERM",
+/// or forces the exit when it already is stopping.
+pub fn request(what: &str) {
+    let Some(running) = RUNNING.get() else {
+        return;
+    };
+    if running.swap(false, Ordering::SeqCst) {
+        logging::write(
+            &format!("{}; shutting down (again to exit at once)", what),
+            "SYSTEM",
+        );
+    } else {
+        force(&format!("{} while shutting down", what));
+    }
+}
+
+/// Forces the exit unless the process has ended within `grace`.
+pub fn force_after(grace: Duration) {
+    thread::spawn(move || {
+        thread::sleep(grace);
+        force(&format!(
+            "Shutdown did not finish within {}s",
+            grace.as_secs()
+        ));
+    });
+}
+
+/// Kills Tor and exits, leaving the rest of the cleanup undone.
+fn force(reason: &str) -> ! {
+    logging::write(
+        &format!("{}; killing Tor and exiting now", reason),
+        "WARNING",
+    );
+    if let Some(kill) = KILL.get() {
+        kill();
+    }
+    process::exit(FORCED_EXIT);
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tor_integration::{TorBackend, TorManager, TorOptions};
+    use std::{
+        env, fs,
+        path::PathBuf,
+        process::{Child, Command, Stdio},
+        sync::atomic::AtomicUsize,
+        time::Instant,
+    };
+
+    /// Set in the copy of the test binary a test runs as its child, to how
+    /// its shutdown goes: "clean", or "hung" for one that never finishes.
+    const CHILD: &str = "VEKO_SHUTDOWN_TEST_CHILD";
+    /// Where the child's Tor stand-in writes its pid.
+    const TOR_PID: &str = "VEKO_SHUTDOWN_TEST_TOR_PID";
+    /// The child's grace period, in milliseconds.
+    const GRACE: &str = "VEKO_SHUTDOWN_TEST_GRACE";
+
+    fn temp_path() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        env::temp_dir().join(format!(
+            "veko-shutdown-test-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    fn wait_for(what: &str, timeout: Duration, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + timeout;
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn signal(pid: u32, signal: libc::c_int) {
+        assert_eq!(unsafe { libc::kill(pid as libc::pid_t, signal) }, 0);
+    }
+
+    fn is_gone(pid: u32) -> bool {
+        (unsafe { libc::kill(pid as libc::pid_t, 0) }) != 0
+    }
+
+    /// A session in a copy of this binary: its Tor is a `sleep` under a
+    /// real [`TorManager`], and once ready it has written the stand-in's
+    /// pid. Returns the child and the stand-in's pid.
+    fn session(mode: &str, grace: Duration) -> (Child, u32) {
+        let tor_pid = temp_path();
+        let child = Command::new(env::current_exe().unwrap())
+            .args([
+                "shutdown::tests::runs_a_session_until_stopped",
+                "--exact",
+                "--test-threads=1",
+            ])
+            .env(CHILD, mode)
+            .env(TOR_PID, &tor_pid)
+            .env(GRACE, grace.as_millis().to_string())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let ready = tor_pid.with_extension("ready");
+        wait_for("the session to start", Duration::from_secs(20), || {
+            ready.exists()
+        });
+        let pid = fs::read_to_string(&tor_pid)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let _ = fs::remove_file(&tor_pid);
+        let _ = fs::remove_file(&ready);
+        (child, pid)
+    }
+
+    fn exit_code(mut child: Child) -> Option<i32> {
+        let mut status = None;
+        wait_for("the session to exit", Duration::from_secs(20), || {
+            status = child.try_wait().unwrap();
+            status.is_some()
+        });
+        status.unwrap().code()
+    }
+
+    #[test]
+    fn runs_a_session_until_stopped() {
+        let Ok(mode) = env::var(CHILD) else {
+            return;
+        };
+        let tor_pid = PathBuf::from(env::var(TOR_PID).unwrap());
+        let grace = Duration::from_millis(env::var(GRACE).unwrap().parse().unwrap());
+        let options = TorOptions {
+            binary: PathBuf::from("sh"),
+            grace_period: Duration::from_secs(5),
+            max_restarts: 0,
+            extra_args: vec![
+                "-c".to_string(),
+                format!("echo $$ > '{}'; exec sleep 600", tor_pid.display()),
+            ],
+            control_cookie: None,
+        };
+        let tor = Arc::new(TorManager::start(options, |_| {}).unwrap());
+        let running = Arc::new(AtomicBool::new(true));
+        let killed = tor.clone();
+        install(running.clone(), move || killed.kill()).unwrap();
+        fs::write(tor_pid.with_extension("ready"), "").unwrap();
+
+        while running.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(20));
+        }
+        force_after(grace);
+        if mode == "hung" {
+            loop {
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+        tor.stop();
+        process::exit(0);
+    }
+
+    #[test]
+    fn sigterm_stops_tor_and_exits_cleanly() {
+        let (child, tor) = session("clean", Duration::from_secs(30));
+        signal(child.id(), libc::SIGTERM);
+        assert_eq!(exit_code(child), Some(0));
+        assert!(is_gone(tor));
+    }
+
+    #[test]
+    fn sigquit_stops_the_session_as_sigterm_does() {
+        let (child, tor) = session("clean", Duration::from_secs(30));
+        signal(child.id(), libc::SIGQUIT);
+        assert_eq!(exit_code(child), Some(0));
+        assert!(is_gone(tor));
+    }
+
+    #[test]
+    fn a_second_signal_while_shutting_down_kills_tor_and_exits() {
+        let (child, tor) = session("hung", Duration::from_secs(30));
+        signal(child.id(), libc::SIGTERM);
+        thread::sleep(Duration::from_millis(300));
+        assert!(!is_gone(tor));
+        let started = Instant::now();
+        signal(child.id(), libc::SIGINT);
+        assert_eq!(exit_code(child), Some(FORCED_EXIT));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(is_gone(tor));
+    }
+
+    #[test]
+    fn a_shutdown_past_its_grace_is_forced() {
+        let (child, tor) = session("hung", Duration::from_millis(300));
+        signal(child.id(), libc::SIGTERM);
+        assert_eq!(exit_code(child), Some(FORCED_EXIT));
+        assert!(is_gone(tor));
+    }
+}
@@ -0,0 +1,327 @@
+// src/socks.rs
+// Just enough of SOCKS5 (RFC 1928/1929) to talk to upstream proxies and to
+// accept CONNECT requests from local clients.
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+const VERSION: u8 = 5;
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const METHOD_NONE: u8 = 0;
+const METHOD_USERPASS: u8 = 2;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+
+pub const REPLY_SUCCEEDED: u8 = 0;
+pub const REPLY_NOT_ALLOWED: u8 = 2;
+pub const REPLY_HOST_UNREACHABLE: u8 = 4;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+pub const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Where a client asked to be connected.
+#[derive(Clone)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    /// Parses "host:port", keeping hostnames unresolved.
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Some(TargetAddr::Ip(addr));
+        }
+        let (host, port) = s.rsplit_once(':')?;
+        let port = port.parse().ok()?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return None;
+        }
+        Some(TargetAddr::Domain(host.to_string(), port))
+    }
+
+    fn write_to(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => {
+                buf.push(ATYP_IPV4);
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            TargetAddr::Ip(SocketAddr::V6(addr)) => {
+                buf.push(ATYP_IPV6);
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            TargetAddr::Domain(host, port) => {
+                let len = u8::try_from(host.len())
+                    .map_err(|_| invalid("hostname longer than 255 bytes"))?;
+                buf.push(ATYP_DOMAIN);
+                buf.push(len);
+                buf.extend_from_slice(host.as_bytes());
+                buf.extend_from_slice(&port.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn read_from(stream: &mut impl Read, atyp: u8) -> io::Result<Self> {
+        match atyp {
+            ATYP_IPV4 => {
+                let mut raw = [0u8; 6];
+                stream.read_exact(&mut raw)?;
+                let ip = Ipv4Addr::new(raw[0], raw[1], raw[2], raw[3]);
+                let port = u16::from_be_bytes([raw[4], raw[5]]);
+                Ok(TargetAddr::Ip(SocketAddr::new(ip.into(), port)))
+            }
+            ATYP_IPV6 => {
+                let mut raw = [0u8; 18];
+                stream.read_exact(&mut raw)?;
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&raw[..16]);
+                let port = u16::from_be_bytes([raw[16], raw[17]]);
+                Ok(TargetAddr::Ip(SocketAddr::new(
+                    Ipv6Addr::from(octets).into(),
+                    port,
+                )))
+            }
+            ATYP_DOMAIN => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                let mut raw = vec![0u8; len[0] as usize + 2];
+                stream.read_exact(&mut raw)?;
+                let port = u16::from_be_bytes([raw[raw.len() - 2], raw[raw.len() - 1]]);
+                raw.truncate(raw.len() - 2);
+                let host = String::from_utf8(raw).map_err(|_| invalid("hostname is not UTF-8"))?;
+                Ok(TargetAddr::Domain(host, port))
+            }
+            _ => Err(invalid("unknown address type")),
+        }
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown SOCKS error",
+    }
+}
+
+/// Method negotiation and, if the server asks for it, username/password
+/// authentication.
+fn negotiate<S: Read + Write>(stream: &mut S, auth: Option<(&str, &str)>) -> io::Result<()> {
+    if auth.is_some() {
+        stream.write_all(&[VERSION, 2, METHOD_NONE, METHOD_USERPASS])?;
+    } else {
+        stream.write_all(&[VERSION, 1, METHOD_NONE])?;
+    }
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    if choice[0] != VERSION {
+        return Err(invalid("upstream is not a SOCKS5 server"));
+    }
+    match (choice[1], auth) {
+        (METHOD_NONE, _) => Ok(()),
+        (METHOD_USERPASS, Some((user, pass))) => {
+            let ulen = u8::try_from(user.len()).map_err(|_| invalid("username too long"))?;
+            let plen = u8::try_from(pass.len()).map_err(|_| invalid("password too long"))?;
+            let mut msg = vec![1, ulen];
+            msg.extend_from_slice(user.as_bytes());
+            msg.push(plen);
+            msg.extend_from_slice(pass.as_bytes());
+            stream.write_all(&msg)?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status)?;
+            if status[1] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "SOCKS5 authentication rejected",
+                ));
+            }
+            Ok(())
+        }
+        (METHOD_UNACCEPTABLE, _) | (METHOD_USERPASS, None) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 server requires authentication",
+        )),
+        _ => Err(invalid("SOCKS5 server chose an unsupported method")),
+    }
+}
+
+/// Sends a request and returns the bound address from the reply.
+fn request<S: Read + Write>(
+    stream: &mut S,
+    command: u8,
+    target: &TargetAddr,
+) -> io::Result<TargetAddr> {
+    let mut request = vec![VERSION, command, 0];
+    target.write_to(&mut request)?;
+    stream.write_all(&request)?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head)?;
+    if head[1] != REPLY_SUCCEEDED {
+        let what = if command == CMD_UDP_ASSOCIATE {
+            "UDP ASSOCIATE".to_string()
+        } else {
+            format!("CONNECT to {}", target)
+        };
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("SOCKS5 {} failed: {}", what, reply_message(head[1])),
+        ));
+    }
+    TargetAddr::read_from(stream, head[3])
+}
+
+/// Runs the client side of a SOCKS5 CONNECT to `target` over `stream`,
+/// authenticating with `auth` if the server asks for it.
+pub fn client_connect<S: Read + Write>(
+    stream: &mut S,
+    target: &TargetAddr,
+    auth: Option<(&str, &str)>,
+) -> io::Result<()> {
+    negotiate(stream, auth)?;
+    request(stream, CMD_CONNECT, target)?;
+    Ok(())
+}
+
+/// Asks the server for a UDP relay and returns the relay address it gave.
+/// The association lasts as long as `stream` stays open.
+pub fn client_udp_associate<S: Read + Write>(
+    stream: &mut S,
+    auth: Option<(&str, &str)>,
+) -> io::Result<TargetAddr> {
+    negotiate(stream, auth)?;
+    let any = TargetAddr::Ip(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+    request(stream, CMD_UDP_ASSOCIATE, &any)
+}
+
+/// Splits a SOCKS5 UDP datagram into its destination and payload. Returns
+/// `None` for malformed or fragmented datagrams, which are not supported.
+pub fn parse_udp_datagram(datagram: &[u8]) -> Option<(TargetAddr, &[u8])> {
+    if datagram.len() < 4 || datagram[2] != 0 {
+        return None;
+    }
+    let mut cursor = io::Cursor::new(&datagram[4..]);
+    let target = TargetAddr::read_from(&mut cursor, datagram[3]).ok()?;
+    let offset = 4 + cursor.position() as usize;
+    Some((target, &datagram[offset..]))
+}
+
+/// A request read from a local SOCKS5 client, waiting for `reply`.
+pub struct ServerRequest {
+    pub command: u8,
+    pub target: TargetAddr,
+    /// The username and password the client authenticated with, when
+    /// accepted with [`server_accept_auth`].
+    pub auth: Option<(String, String)>,
+}
+
+/// Runs the server side of the SOCKS5 greeting (no authentication) and reads
+/// the client's request.
+pub fn server_accept<S: Read + Write>(stream: &mut S) -> io::Result<ServerRequest> {
+    accept(stream, false)
+}
+
+/// Like [`server_accept`], but takes any username and password a client
+/// offers, so the caller can tell clients apart by them.
+pub fn server_accept_auth<S: Read + Write>(stream: &mut S) -> io::Result<ServerRequest> {
+    accept(stream, true)
+}
+
+fn accept<S: Read + Write>(stream: &mut S, take_auth: bool) -> io::Result<ServerRequest> {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head)?;
+    if head[0] != VERSION {
+        return Err(invalid("client is not speaking SOCKS5"));
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    stream.read_exact(&mut methods)?;
+    let auth = if take_auth && methods.contains(&METHOD_USERPASS) {
+        stream.write_all(&[VERSION, METHOD_USERPASS])?;
+        // RFC 1929: version, then the username and password, each after
+        // its length
+        let mut version = [0u8; 1];
+        stream.read_exact(&mut version)?;
+        let mut read_field = || -> io::Result<String> {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            let mut field = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut field)?;
+            Ok(String::from_utf8_lossy(&field).into_owned())
+        };
+        let user = read_field()?;
+        let pass = read_field()?;
+        stream.write_all(&[1, 0])?;
+        Some((user, pass))
+    } else if methods.contains(&METHOD_NONE) {
+        stream.write_all(&[VERSION, METHOD_NONE])?;
+        None
+    } else {
+        stream.write_all(&[VERSION, METHOD_UNACCEPTABLE])?;
+        return Err(invalid("client does not offer unauthenticated access"));
+    };
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request)?;
+    if request[0] != VERSION {
+        return Err(invalid("malformed SOCKS5 request"));
+    }
+    let target = match TargetAddr::read_from(stream, request[3]) {
+        Ok(target) => target,
+        Err(e) => {
+            reply(stream, REPLY_ADDRESS_NOT_SUPPORTED)?;
+            return Err(e);
+        }
+    };
+    Ok(ServerRequest {
+        command: request[1],
+        target,
+        auth,
+    })
+}
+
+pub fn is_connect(request: &ServerRequest) -> bool {
+    request.command == CMD_CONNECT
+}
+
+pub fn is_udp_associate(request: &ServerRequest) -> bool {
+    request.command == CMD_UDP_ASSOCIATE
+}
+
+/// Sends a reply with an all-zero bound address.
+pub fn reply<S: Write>(stream: &mut S, code: u8) -> io::Result<()> {
+    stream.write_all(&[VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+}
+
+/// Sends a success reply carrying `bound`, e.g. a UDP relay address.
+pub fn reply_bound<S: Write>(stream: &mut S, bound: SocketAddr) -> io::Result<()> {
+    let mut msg = vec![VERSION, REPLY_SUCCEEDED, 0];
+    TargetAddr::Ip(bound).write_to(&mut msg)?;
+    stream.write_all(&msg)
+}
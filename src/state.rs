@@ -0,0 +1,114 @@
+// src/state.rs
+// What a session learned about its proxies, kept for the next one so it
+// does not spend its first minutes rediscovering the same dead, slow and
+// transparent proxies. The rotator's record is written as JSON when the
+// session ends and read back as the next one starts. Proxies are keyed by
+// a hash of their URL without credentials, so neither credentials nor
+// proxy addresses reach the disk.
+use crate::anonymity::Anonymity;
+use crate::client::strip_credentials;
+use crate::datasets::data_dir;
+use crate::forwarder::Hop;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const STATE_VERSION: u32 = 1;
+
+/// Default for --state-file.
+pub fn default_path() -> PathBuf {
+    data_dir().join("proxy-state.json")
+}
+
+/// What is known about one proxy.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ProxyState {
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub latency_ms: Option<u64>,
+    pub anonymity: Option<Anonymity>,
+    /// Unix time the proxy's quarantine ends, if it was in one.
+    pub quarantined_until: Option<i64>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct State {
+    version: u32,
+    /// Unix time the state was written.
+    saved_at: i64,
+    /// By [`key`].
+    pub proxies: BTreeMap<String, ProxyState>,
+}
+
+impl State {
+    pub fn new(proxies: BTreeMap<String, ProxyState>) -> Self {
+        State {
+            version: STATE_VERSION,
+            saved_at: chrono::Utc::now().timestamp(),
+            proxies,
+        }
+    }
+
+    /// Reads the state at `path`; `Ok(None)` when there is none yet. A file
+    /// that does not parse, is of another version or was written longer
+    /// than `max_age` ago is an error, for the caller to discard.
+    pub fn load(path: &Path, max_age: Duration) -> Result<Option<Self>, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("cannot read {}: {}", path.display(), e)),
+        };
+        let state: State = serde_json::from_str(&text)
+            .map_err(|e| format!("{} is corrupt: {}", path.display(), e))?;
+        if state.version != STATE_VERSION {
+            return Err(format!(
+                "{} is of version {}, not {}",
+                path.display(),
+                state.version,
+                STATE_VERSION
+            ));
+        }
+        let age = chrono::Utc::now().timestamp() - state.saved_at;
+        if age < 0 || age as u64 > max_age.as_secs() {
+            return Err(format!(
+                "{} was written {}h ago, longer than --state-max-age",
+                path.display(),
+                age.max(0) / 3600
+            ));
+        }
+        Ok(Some(state))
+    }
+
+    /// Keeps what `earlier` knew about proxies this state leaves out, such
+    /// as ones this session's list did not have.
+    pub fn keep(&mut self, earlier: State) {
+        for (key, proxy) in earlier.proxies {
+            self.proxies.entry(key).or_insert(proxy);
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let text = serde_json::to_string(self)?;
+        fs::write(path, text)
+    }
+}
+
+/// The key `url` is stored under: a hash of its scheme, host and port,
+/// the same whatever credentials or spelling the list gives it.
+pub fn key(url: &str) -> String {
+    let normalized = match Hop::parse(url) {
+        Ok(hop) => hop.to_string(),
+        Err(_) => strip_credentials(url),
+    };
+    let digest = Sha256::digest(normalized.to_ascii_lowercase().as_bytes());
+    digest[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,233 @@
+// src/status_page.rs
+// Browser view of the running session. Rendered server-side with no scripts
+// or external assets, and reloaded by a meta refresh tag.
+use crate::control::StatusSnapshot;
+use crate::rotation::{describe_identity_bounds, describe_rotation_policy};
+use crate::workers;
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Seconds between browser reloads.
+const REFRESH_SECS: u64 = 5;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Everything one render shows. All strings must already be free of
+/// credentials.
+pub struct Page {
+    pub status: StatusSnapshot,
+    /// Location of the exit IP, when the geolocation cache knows it.
+    pub exit_geo: Option<String>,
+    /// Most recent events, newest last.
+    pub events: Vec<String>,
+    pub alerts: Vec<String>,
+}
+
+/// Produces the page for each request.
+pub type PageSource = Arc<dyn Fn() -> Page + Send + Sync>;
+
+/// Serves the page on `addr`. The page has no authentication, so only
+/// loopback addresses are accepted.
+pub fn start(addr: SocketAddr, source: PageSource) -> io::Result<SocketAddr> {
+    if !addr.ip().is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "refusing to serve the status page on {}; it has no authentication, so bind a loopback address",
+                addr
+            ),
+        ));
+    }
+    let listener = TcpListener::bind(addr)?;
+    let bound = listener.local_addr()?;
+    workers::spawn("status-page", 5, move || {
+        for stream in listener.incoming().flatten() {
+            let _ = respond(stream, &source);
+        }
+    });
+    Ok(bound)
+}
+
+fn respond(stream: TcpStream, source: &PageSource) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream)
+        .take(8192)
+        .read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/")) => ("200 OK", render(&source())),
+        (Some("GET"), Some(_)) => ("404 Not Found", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "Only GET is supported\n".to_string(),
+        ),
+    };
+    let content_type = if status.starts_with("200") {
+        "text/html; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nContent-Security-Policy: default-src 'none'; style-src 'unsafe-inline'\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    let mut stream = stream;
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body.as_bytes())
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn render(page: &Page) -> String {
+    let s = &page.status;
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"{}\"><title>Veko Dome</title><style>body{{font-family:monospace;margin:2em;max-width:60em}}h2{{margin-top:1.5em}}.bar{{width:20em;height:1em;background:#c33;display:inline-block;vertical-align:middle}}.bar span{{height:100%;background:#3a3;display:block}}.alert{{color:#c33}}</style></head><body>",
+        REFRESH_SECS
+    );
+    let _ = write!(
+        html,
+        "<h1>Veko Dome session {}</h1><p>pid {}, up {}s</p>",
+        escape(&s.session),
+        s.pid,
+        s.uptime_secs
+    );
+
+    if !page.alerts.is_empty() {
+        html.push_str("<h2>Alerts</h2><ul class=\"alert\">");
+        for alert in &page.alerts {
+            let _ = write!(html, "<li>{}</li>", escape(alert));
+        }
+        html.push_str("</ul>");
+    }
+
+    html.push_str("<h2>Exit</h2><p>");
+    match &s.exit_ip {
+        Some(ip) => {
+            let _ = write!(html, "Exit IP: {}", escape(ip));
+            if let Some(geo) = &page.exit_geo {
+                let _ = write!(html, " ({})", escape(geo));
+            }
+        }
+        None => html.push_str("Exit IP: unknown (the latest check through the route failed)"),
+    }
+    html.push_str("</p>");
+
+    html.push_str("<h2>Route</h2><p>");
+    let route = s.route.as_deref().unwrap_or(&s.current_proxy);
+    let _ = write!(html, "{}", escape(route));
+    if let Some(details) = &s.current_details {
+        let _ = write!(html, "<br>Proxy: {}", escape(details));
+    }
+    let _ = write!(html, "<br>Strategy: {}", escape(&s.strategy));
+    if let Some(blend) = &s.blend {
+        let _ = write!(html, "<br>Blend: {}", escape(blend));
+    }
+    if let Some(circuit) = &s.tor_circuit {
+        let _ = write!(html, "<br>Tor circuit: {}", escape(circuit));
+    }
+    if let Some(transport) = &s.transport {
+        let _ = write!(html, "<br>Transport: {}", escape(transport));
+    }
+    html.push_str("</p>");
+
+    let total = s.proxies_alive + s.proxies_quarantined;
+    let healthy = (s.proxies_alive * 100).checked_div(total).unwrap_or(0);
+    let _ = write!(
+        html,
+        "<h2>Pool health</h2><p><span class=\"bar\"><span style=\"width:{}%\"></span></span> {} alive, {} quarantined</p>",
+        healthy, s.proxies_alive, s.proxies_quarantined
+    );
+    if !s.fastest.is_empty() {
+        html.push_str("<ul>");
+        for p in &s.fastest {
+            let _ = write!(html, "<li>{} ({}ms)</li>", escape(&p.proxy), p.latency_ms);
+        }
+        html.push_str("</ul>");
+    }
+
+    let _ = write!(
+        html,
+        "<h2>Rotation</h2><p>Rotates {}",
+        describe_rotation_policy(s.rotation_interval_secs, s.rotate_requests)
+    );
+    if s.rotation_interval_secs > 0 {
+        let approx = if s.rotation_jitter_secs > 0 { "~" } else { "" };
+        let _ = write!(
+            html,
+            "<br>Next timed rotation in {}{}s",
+            approx, s.next_rotation_secs
+        );
+    }
+    if let Some(limit) = s.rotate_requests {
+        let _ = write!(
+            html,
+            "<br>{} of {} requests carried by this route",
+            s.requests_since_rotation, limit
+        );
+    }
+    if let Some(bounds) = describe_identity_bounds(s.min_dwell_secs, s.max_identity_lifetime_secs) {
+        let _ = write!(html, "<br>Identity: {}", bounds);
+    }
+    html.push_str("</p>");
+    if let Some(adaptive) = &s.adaptive {
+        let _ = write!(
+            html,
+            "<p>Adaptive between {}s and {}s: {} recent tunnels, {}% failed, p90 {}</p>",
+            adaptive.min_secs,
+            adaptive.max_secs,
+            adaptive.samples,
+            adaptive.error_rate_pct,
+            adaptive
+                .p90_latency_ms
+                .map_or_else(|| "-".to_string(), |ms| format!("{}ms", ms))
+        );
+    }
+
+    if !s.listeners.is_empty() {
+        html.push_str("<h2>Listeners</h2><ul>");
+        for listener in &s.listeners {
+            let _ = write!(
+                html,
+                "<li>{} ({} active connections)</li>",
+                escape(&listener.spec),
+                listener.active_connections
+            );
+        }
+        html.push_str("</ul>");
+    }
+
+    html.push_str("<h2>Recent events</h2>");
+    if page.events.is_empty() {
+        html.push_str("<p>No events recorded yet.</p>");
+    } else {
+        html.push_str("<ul>");
+        for event in &page.events {
+            let _ = write!(html, "<li>{}</li>", escape(event));
+        }
+        html.push_str("</ul>");
+    }
+    html.push_str("</body></html>\n");
+    html
+}
@@ -0,0 +1,170 @@
+// src/system_proxy.rs
+// Pointing other programs at a running session's listeners: shell exports
+// for `env` and a PAC file for `pac`. Both are built from what the session
+// reports over its control socket, so they name the ports it actually
+// bound, and they keep the destinations its --no-proxy rules send direct
+// off the listeners, as the listeners themselves would.
+use crate::listener::{ListenKind, ListenSpec};
+use std::{
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// Destinations no client should hand a proxy.
+const LOCAL: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Shell {
+    /// export NAME="value", for sh, bash and zsh
+    Sh,
+    /// set -gx NAME "value"
+    Fish,
+    /// $env:NAME = "value"
+    Powershell,
+}
+
+/// A session's listeners as clients reach them, and what they bypass.
+pub struct Endpoints {
+    http: Option<SocketAddr>,
+    socks: Option<SocketAddr>,
+    /// Patterns of the --no-proxy rules that go direct.
+    bypass: Vec<String>,
+}
+
+impl Endpoints {
+    /// From a session's listener specs, such as http://127.0.0.1:8080, and
+    /// direct --no-proxy patterns. `None` when it has no listener.
+    pub fn new(listeners: &[String], bypass: Vec<String>) -> Option<Self> {
+        let specs: Vec<ListenSpec> = listeners
+            .iter()
+            .filter_map(|spec| ListenSpec::parse(spec).ok())
+            .collect();
+        let first = |kind| {
+            specs
+                .iter()
+                .find(|spec| spec.kind == kind)
+                .map(|spec| reachable(spec.addr))
+        };
+        let endpoints = Endpoints {
+            http: first(ListenKind::Http),
+            socks: first(ListenKind::Socks5),
+            bypass,
+        };
+        (endpoints.http.is_some() || endpoints.socks.is_some()).then_some(endpoints)
+    }
+
+    /// Commands setting http_proxy, https_proxy, all_proxy and no_proxy
+    /// for `shell`. HTTP clients are sent to the HTTP listener when there
+    /// is one; all_proxy prefers the SOCKS one.
+    pub fn env(&self, shell: Shell) -> String {
+        let http = self
+            .http
+            .map(|addr| format!("http://{}", addr))
+            .or_else(|| self.socks.map(|addr| format!("socks5h://{}", addr)))
+            .unwrap_or_default();
+        let all = self
+            .socks
+            .map(|addr| format!("socks5h://{}", addr))
+            .unwrap_or_else(|| http.clone());
+        let no_proxy = LOCAL
+            .iter()
+            .map(|host| host.to_string())
+            .chain(self.bypass.iter().map(|pattern| no_proxy_entry(pattern)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let vars = [
+            ("http_proxy", &http),
+            ("https_proxy", &http),
+            ("all_proxy", &all),
+            ("no_proxy", &no_proxy),
+        ];
+        let mut out = String::new();
+        for (name, value) in vars {
+            match shell {
+                // Some programs only read one spelling
+                Shell::Sh => {
+                    let _ = writeln!(out, "export {}=\"{}\"", name, value);
+                    let _ = writeln!(out, "export {}=\"{}\"", name.to_uppercase(), value);
+                }
+                Shell::Fish => {
+                    let _ = writeln!(out, "set -gx {} \"{}\"", name, value);
+                    let _ = writeln!(out, "set -gx {} \"{}\"", name.to_uppercase(), value);
+                }
+                // Windows variable names ignore case
+                Shell::Powershell => {
+                    let _ = writeln!(out, "$env:{} = \"{}\"", name, value);
+                }
+            }
+        }
+        out
+    }
+
+    /// A PAC file sending everything through the listeners, the HTTP one
+    /// first, except local and bypassed destinations. It never falls back
+    /// to DIRECT, which would leave from this machine's own IP.
+    pub fn pac(&self, session: &str) -> String {
+        let proxies: Vec<String> = [
+            self.http.map(|addr| format!("PROXY {}", addr)),
+            self.socks.map(|addr| format!("SOCKS5 {}", addr)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let mut out = format!(
+            "// Written by veko_dome pac for session '{}'\n\
+             function FindProxyForURL(url, host) {{\n    \
+             var v4 = /^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host);\n    \
+             var v6 = host.indexOf(\":\") >= 0;\n",
+            session
+        );
+        for host in LOCAL {
+            let _ = writeln!(out, "    if (host == \"{}\") return \"DIRECT\";", host);
+        }
+        for pattern in &self.bypass {
+            let _ = writeln!(out, "    if ({}) return \"DIRECT\";", pac_test(pattern));
+        }
+        let _ = write!(out, "    return \"{}\";\n}}\n", proxies.join("; "));
+        out
+    }
+}
+
+/// Where a client reaches a listener bound to `addr`: loopback for one
+/// bound to every address.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+/// A --no-proxy pattern as no_proxy spells it: `.domain` for `*.domain`.
+fn no_proxy_entry(pattern: &str) -> String {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => format!(".{}", domain),
+        None => pattern.to_string(),
+    }
+}
+
+/// The PAC condition a --no-proxy pattern stands for. Ranges are only
+/// tested against addresses, since isInNet would look a name up.
+fn pac_test(pattern: &str) -> String {
+    if let Some(domain) = pattern.strip_prefix("*.") {
+        return format!("dnsDomainIs(host, \".{}\")", domain);
+    }
+    let Some((ip, len)) = pattern.split_once('/') else {
+        return format!("host == \"{}\"", pattern);
+    };
+    match (ip.parse::<IpAddr>(), len.parse::<u32>()) {
+        (Ok(IpAddr::V4(ip)), Ok(len)) if len <= 32 => {
+            let mask = Ipv4Addr::from(u32::MAX.checked_shl(32 - len).unwrap_or(0));
+            format!("v4 && isInNet(host, \"{}\", \"{}\")", ip, mask)
+        }
+        // isInNetEx is not in every browser
+        (Ok(IpAddr::V6(_)), Ok(_)) => format!(
+            "v6 && typeof isInNetEx == \"function\" && isInNetEx(host, \"{}\")",
+            pattern
+        ),
+        _ => format!("host == \"{}\"", pattern),
+    }
+}
@@ -0,0 +1,120 @@
+// src/throttle.rs
+// A budget for what the session sends third parties of its own accord: the
+// IP services, Tor's check page, probes, heartbeats, the anonymity judge,
+// geolocation lookups and decoy requests. Asked too often, a service
+// blocks the exit IP, which defeats the checks. Each bucket refills evenly
+// over a minute and starts full; a request over budget waits for a token
+// rather than being dropped. Traffic clients send through the listeners
+// never comes through here.
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+struct Bucket {
+    per_minute: u32,
+    tokens: f64,
+    filled: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: u32) -> Self {
+        Bucket {
+            per_minute,
+            tokens: per_minute as f64,
+            filled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let earned = now.duration_since(self.filled).as_secs_f64() * self.per_minute as f64 / 60.0;
+        self.tokens = (self.tokens + earned).min(self.per_minute as f64);
+        self.filled = now;
+    }
+
+    /// How long until a token is there to take.
+    fn shortfall(&self) -> Duration {
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) * 60.0 / self.per_minute as f64)
+    }
+}
+
+struct Throttle {
+    /// Shared by every destination, with --max-checks-per-minute.
+    total: Option<Bucket>,
+    /// Each host's own, with --max-checks-per-host.
+    per_host: Option<u32>,
+    hosts: HashMap<String, Bucket>,
+}
+
+static THROTTLE: Mutex<Option<Throttle>> = Mutex::new(None);
+
+/// Lets at most `per_minute` checks out a minute in all, and `per_host`
+/// to any one host. `None` leaves that limit off.
+pub fn configure(per_minute: Option<u32>, per_host: Option<u32>) {
+    let throttle = (per_minute.is_some() || per_host.is_some()).then(|| Throttle {
+        total: per_minute.map(|n| Bucket::new(n.max(1))),
+        per_host: per_host.map(|n| n.max(1)),
+        hosts: HashMap::new(),
+    });
+    *THROTTLE.lock().unwrap_or_else(|e| e.into_inner()) = throttle;
+}
+
+/// Blocks until a check to `destination`, a URL or a host name, fits the
+/// budget, and takes its share.
+pub fn wait(destination: &str) {
+    let host = host_of(destination);
+    let started = Instant::now();
+    let mut waited = false;
+    loop {
+        let shortfall = {
+            let mut guard = THROTTLE.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(throttle) = guard.as_mut() else {
+                return;
+            };
+            let now = Instant::now();
+            let per_host = throttle.per_host;
+            let host_bucket = per_host.map(|n| {
+                throttle
+                    .hosts
+                    .entry(host.to_string())
+                    .or_insert_with(|| Bucket::new(n))
+            });
+            let mut buckets: Vec<&mut Bucket> =
+                throttle.total.iter_mut().chain(host_bucket).collect();
+            buckets.iter_mut().for_each(|bucket| bucket.refill(now));
+            match buckets.iter().map(|bucket| bucket.shortfall()).max() {
+                Some(needed) if !needed.is_zero() => needed,
+                _ => {
+                    buckets.iter_mut().for_each(|bucket| bucket.tokens -= 1.0);
+                    break;
+                }
+            }
+        };
+        waited = true;
+        thread::sleep(shortfall);
+    }
+    if waited {
+        log::debug!(
+            "Check to {} delayed {}ms by the outbound rate limit",
+            host,
+            started.elapsed().as_millis()
+        );
+    }
+}
+
+/// The host part of `destination`, or all of it when it is no URL.
+fn host_of(destination: &str) -> &str {
+    let rest = destination
+        .split_once("://")
+        .map_or(destination, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or(v6),
+        None => host.split(':').next().unwrap_or(host),
+    }
+}
@@ -0,0 +1,414 @@
+// src/tls.rs
+// The TLS every client speaks, built with rustls from the security
+// profile: the protocol versions it may use and the cipher suites it
+// offers, in order. Paranoid speaks TLS 1.3 alone. Names of suites in a
+// profile are checked when it is loaded, so a typo ends the start instead
+// of quietly offering rustls's defaults. Certificates are checked against
+// the webpki roots unless --insecure-tls is given.
+use rustls::{
+    cipher_suite,
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName, SupportedCipherSuite,
+    SupportedProtocolVersion, ALL_CIPHER_SUITES,
+};
+use serde::{de, Deserialize, Deserializer};
+use std::{fmt, sync::Arc, time::SystemTime};
+
+/// A TLS version rustls speaks.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    Tls12,
+    Tls13,
+}
+
+impl Version {
+    const ALL: [Version; 2] = [Version::Tls12, Version::Tls13];
+
+    fn supported(self) -> &'static SupportedProtocolVersion {
+        match self {
+            Version::Tls12 => &rustls::version::TLS12,
+            Version::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Version::Tls12 => "1.2",
+            Version::Tls13 => "1.3",
+        })
+    }
+}
+
+fn suite_name(suite: SupportedCipherSuite) -> &'static str {
+    suite.suite().as_str().unwrap_or("unknown")
+}
+
+/// The TLS a profile's connections use: its [profile.NAME.tls] table.
+/// What is left out is left open, every version rustls speaks and its
+/// suites for them.
+#[derive(Clone, Default, Deserialize)]
+pub struct Settings {
+    #[serde(default, deserialize_with = "version")]
+    pub min_version: Option<Version>,
+    #[serde(default, deserialize_with = "version")]
+    pub max_version: Option<Version>,
+    /// Offered in this order.
+    #[serde(default, deserialize_with = "cipher_suites")]
+    pub cipher_suites: Option<Vec<SupportedCipherSuite>>,
+    /// Set by --insecure-tls: any certificate is taken.
+    #[serde(skip)]
+    pub insecure: bool,
+}
+
+/// The config keys of [`Settings`].
+pub const KEYS: &[&str] = &["min_version", "max_version", "cipher_suites"];
+
+impl Settings {
+    /// TLS 1.3 only, with the suites Chrome offers for it, in its order.
+    pub fn paranoid() -> Self {
+        Settings {
+            min_version: Some(Version::Tls13),
+            max_version: Some(Version::Tls13),
+            cipher_suites: Some(vec![
+                cipher_suite::TLS13_AES_128_GCM_SHA256,
+                cipher_suite::TLS13_AES_256_GCM_SHA384,
+                cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+            ]),
+            insecure: false,
+        }
+    }
+
+    fn versions(&self) -> Vec<Version> {
+        let min = self.min_version.unwrap_or(Version::Tls12);
+        let max = self.max_version.unwrap_or(Version::Tls13);
+        Version::ALL
+            .into_iter()
+            .filter(|version| (min..=max).contains(version))
+            .collect()
+    }
+
+    fn suites(&self, versions: &[Version]) -> Vec<SupportedCipherSuite> {
+        match &self.cipher_suites {
+            Some(suites) => suites.clone(),
+            None => ALL_CIPHER_SUITES
+                .iter()
+                .copied()
+                .filter(|suite| {
+                    versions
+                        .iter()
+                        .any(|version| version.supported() == suite.version())
+                })
+                .collect(),
+        }
+    }
+
+    /// One line for --show-profile and the start log.
+    pub fn describe(&self) -> String {
+        let versions = self.versions();
+        let shown: Vec<String> = versions.iter().map(Version::to_string).collect();
+        let suites: Vec<&str> = self.suites(&versions).into_iter().map(suite_name).collect();
+        let certificates = match self.insecure {
+            true => "NOT checked",
+            false => "checked",
+        };
+        format!(
+            "TLS {}; suites {}; certificates {}",
+            shown.join(", "),
+            suites.join(", "),
+            certificates
+        )
+    }
+
+    /// The rustls config of a client with these settings, offering the
+    /// `alpn` protocols. Fails when the versions or suites leave nothing to
+    /// connect with.
+    pub fn client_config(&self, alpn: &[&[u8]]) -> Result<ClientConfig, String> {
+        let versions = self.versions();
+        if versions.is_empty() {
+            return Err(format!(
+                "tls.min_version {} is above tls.max_version {}",
+                self.min_version.unwrap_or(Version::Tls12),
+                self.max_version.unwrap_or(Version::Tls13)
+            ));
+        }
+        let supported: Vec<&'static SupportedProtocolVersion> =
+            versions.iter().map(|version| version.supported()).collect();
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        let mut config = ClientConfig::builder()
+            .with_cipher_suites(&self.suites(&versions))
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&supported)
+            .map_err(|_| {
+                let shown: Vec<String> = versions.iter().map(Version::to_string).collect();
+                format!(
+                    "None of tls.cipher_suites can be used with TLS {}",
+                    shown.join(" or ")
+                )
+            })?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        if self.insecure {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(AnyCertificate));
+        }
+        Ok(config)
+    }
+}
+
+fn version<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Version>, D::Error> {
+    match String::deserialize(d)?.as_str() {
+        "1.2" => Ok(Some(Version::Tls12)),
+        "1.3" => Ok(Some(Version::Tls13)),
+        other => Err(de::Error::custom(format!(
+            "unsupported TLS version {:?}, expected \"1.2\" or \"1.3\"",
+            other
+        ))),
+    }
+}
+
+fn cipher_suites<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Option<Vec<SupportedCipherSuite>>, D::Error> {
+    let names = Vec::<String>::deserialize(d)?;
+    if names.is_empty() {
+        return Err(de::Error::custom("cipher_suites is empty"));
+    }
+    names
+        .iter()
+        .map(|name| {
+            ALL_CIPHER_SUITES
+                .iter()
+                .copied()
+                .find(|suite| suite_name(*suite) == name)
+                .ok_or_else(|| {
+                    let known: Vec<&str> =
+                        ALL_CIPHER_SUITES.iter().copied().map(suite_name).collect();
+                    de::Error::custom(format!(
+                        "unknown cipher suite {:?}, expected one of {}",
+                        name,
+                        known.join(", ")
+                    ))
+                })
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Takes whatever certificate the server shows.
+struct AnyCertificate;
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::SecurityProfile;
+    use rustls::ClientConnection;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::{env, fs, process};
+
+    /// Renegotiation signalling value rustls adds to TLS 1.2 hellos; no
+    /// suite of its own.
+    const EMPTY_RENEGOTIATION_INFO: u16 = 0x00ff;
+    const TLS12: u16 = 0x0303;
+    const TLS13: u16 = 0x0304;
+
+    /// What a ClientHello offers.
+    struct Hello {
+        suites: Vec<u16>,
+        versions: Vec<u16>,
+        alpn: Vec<Vec<u8>>,
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_be_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    /// The ClientHello a client with `config` opens a connection with.
+    fn hello(config: ClientConfig) -> Hello {
+        let name = ServerName::try_from("example.com").unwrap();
+        let mut connection = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut record = Vec::new();
+        connection.write_tls(&mut record).unwrap();
+        // Record header, handshake header, version and random
+        let mut at = 5 + 4 + 2 + 32;
+        at += 1 + record[at] as usize;
+        let suites_end = at + 2 + u16_at(&record, at) as usize;
+        let suites = (at + 2..suites_end)
+            .step_by(2)
+            .map(|i| u16_at(&record, i))
+            .filter(|suite| *suite != EMPTY_RENEGOTIATION_INFO)
+            .collect();
+        at = suites_end;
+        at += 1 + record[at] as usize;
+        let extensions_end = at + 2 + u16_at(&record, at) as usize;
+        at += 2;
+        // A hello without supported_versions speaks the version it names
+        let mut hello = Hello {
+            suites,
+            versions: vec![u16_at(&record, 9)],
+            alpn: Vec::new(),
+        };
+        while at < extensions_end {
+            let (kind, len) = (u16_at(&record, at), u16_at(&record, at + 2) as usize);
+            let data = &record[at + 4..at + 4 + len];
+            match kind {
+                0x002b => {
+                    hello.versions = (1..data.len())
+                        .step_by(2)
+                        .map(|i| u16_at(data, i))
+                        .collect()
+                }
+                0x0010 => {
+                    let mut i = 2;
+                    while i < data.len() {
+                        let n = data[i] as usize;
+                        hello.alpn.push(data[i + 1..i + 1 + n].to_vec());
+                        i += 1 + n;
+                    }
+                }
+                _ => {}
+            }
+            at += 4 + len;
+        }
+        hello
+    }
+
+    fn ids(suites: &[SupportedCipherSuite]) -> Vec<u16> {
+        suites.iter().map(|suite| suite.suite().get_u16()).collect()
+    }
+
+    /// The TLS of --mode paranoid, or of a profile file with `tls` as its
+    /// [tls] table.
+    fn mode_tls(tls: Option<&str>, insecure: bool) -> Result<Settings, String> {
+        let Some(tls) = tls else {
+            return SecurityProfile::for_mode("paranoid", None, insecure).map(|p| p.tls);
+        };
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "veko-tls-test-{}-{}.toml",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::write(&path, format!("[tls]\n{}", tls)).unwrap();
+        let found = SecurityProfile::for_mode(path.to_str().unwrap(), None, insecure);
+        let _ = fs::remove_file(&path);
+        found.map(|p| p.tls)
+    }
+
+    #[test]
+    fn paranoid_offers_tls13_alone_with_its_suites_in_order() {
+        let tls = mode_tls(None, false).unwrap();
+        let offered = hello(tls.client_config(&[b"h2", b"http/1.1"]).unwrap());
+        assert_eq!(offered.versions, [TLS13]);
+        assert_eq!(
+            offered.suites,
+            ids(&Settings::paranoid().cipher_suites.unwrap())
+        );
+        assert_eq!(offered.alpn, [b"h2".to_vec(), b"http/1.1".to_vec()]);
+    }
+
+    #[test]
+    fn a_tls12_profile_offers_tls12_and_its_suites_only() {
+        let tls = mode_tls(
+            Some(
+                "min_version = \"1.2\"\nmax_version = \"1.2\"\ncipher_suites = [\
+                 \"TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384\", \
+                 \"TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256\"]\n",
+            ),
+            false,
+        )
+        .unwrap();
+        let offered = hello(tls.client_config(&[]).unwrap());
+        assert_eq!(offered.versions, [TLS12]);
+        assert_eq!(
+            offered.suites,
+            ids(&[
+                cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+                cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            ])
+        );
+    }
+
+    #[test]
+    fn an_open_profile_offers_every_version_and_suite() {
+        let tls = mode_tls(Some(""), false).unwrap();
+        let offered = hello(tls.client_config(&[]).unwrap());
+        assert_eq!(offered.versions, [TLS13, TLS12]);
+        assert_eq!(offered.suites, ids(ALL_CIPHER_SUITES));
+    }
+
+    #[test]
+    fn insecure_tls_leaves_the_suites_alone() {
+        let tls = mode_tls(Some("min_version = \"1.3\"\n"), true).unwrap();
+        assert!(tls.insecure);
+        let offered = hello(tls.client_config(&[]).unwrap());
+        assert_eq!(offered.versions, [TLS13]);
+        assert!(offered.suites.iter().all(|suite| suite >> 8 == 0x13));
+        assert!(mode_tls(None, true)
+            .err()
+            .unwrap()
+            .contains("--insecure-tls"));
+    }
+
+    #[test]
+    fn an_unknown_suite_names_the_supported_ones() {
+        let e = mode_tls(
+            Some("cipher_suites = [\"TLS_RSA_WITH_RC4_128_MD5\"]\n"),
+            false,
+        )
+        .err()
+        .unwrap();
+        assert!(
+            e.contains("unknown cipher suite \"TLS_RSA_WITH_RC4_128_MD5\""),
+            "{}",
+            e
+        );
+        assert!(e.contains("TLS13_AES_256_GCM_SHA384"), "{}", e);
+    }
+
+    #[test]
+    fn versions_or_suites_that_leave_nothing_fail() {
+        let e = mode_tls(
+            Some("min_version = \"1.3\"\nmax_version = \"1.2\"\n"),
+            false,
+        )
+        .err()
+        .unwrap();
+        assert!(
+            e.contains("tls.min_version 1.3 is above tls.max_version 1.2"),
+            "{}",
+            e
+        );
+        let e = mode_tls(
+            Some("max_version = \"1.2\"\ncipher_suites = [\"TLS13_AES_128_GCM_SHA256\"]\n"),
+            false,
+        )
+        .err()
+        .unwrap();
+        assert!(e.contains("can be used with TLS 1.2"), "{}", e);
+    }
+}
@@ -1,34 +1,757 @@
 // src/tor_integration.rs
 use std::{
-    io,
-    process::{Command, Stdio},
+    fmt, fs,
+    io::{self, BufRead, BufReader, Write},
+    net::{IpAddr, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-pub struct TorManager;
+/// Where the spawned Tor listens for SOCKS connections.
+pub const SOCKS_ADDR: &str = "127.0.0.1:9050";
+/// `SOCKS_ADDR` as a proxy URL, with hostnames resolved by Tor.
+pub const SOCKS_URL: &str = "socks5h://127.0.0.1:9050";
+/// Where the spawned Tor accepts controller connections, when enabled with
+/// `control_args`.
+pub const CONTROL_ADDR: &str = "127.0.0.1:9051";
+/// How long to give a freshly spawned Tor to bootstrap.
+const BOOTSTRAP_WAIT: Duration = Duration::from_secs(3);
+/// How often the supervisor checks whether the Tor child is still alive.
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// NEWNYM signals Tor accepted this session.
+static NEW_IDENTITIES: AtomicU64 = AtomicU64::new(0);
+
+/// How many new identities Tor was given this session.
+pub fn new_identities() -> u64 {
+    NEW_IDENTITIES.load(Ordering::Relaxed)
+}
+
+/// Counts a new identity a backend gave, towards [`new_identities`].
+pub(crate) fn count_new_identity() {
+    NEW_IDENTITIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The newest circuit Tor had when it last accepted NEWNYM. Circuits up to
+/// it belong to an earlier identity, even while they stay built.
+static NEWNYM_CIRCUIT: AtomicU64 = AtomicU64::new(0);
+
+pub struct TorOptions {
+    /// The tor executable; see [`find_tor`].
+    pub binary: PathBuf,
+    /// How long `stop()` waits after SIGTERM before killing Tor.
+    pub grace_period: Duration,
+    /// How many times the supervisor relaunches Tor after it dies.
+    pub max_restarts: u32,
+    /// Extra torrc options passed on the command line, e.g. `--Socks5Proxy`.
+    pub extra_args: Vec<String>,
+    /// Where the cookie of a control port goes, to enable one with
+    /// [`control_args`].
+    pub control_cookie: Option<PathBuf>,
+}
+
+impl Default for TorOptions {
+    fn default() -> Self {
+        TorOptions {
+            binary: find_tor(None),
+            grace_period: Duration::from_secs(10),
+            max_restarts: 3,
+            extra_args: Vec::new(),
+            control_cookie: None,
+        }
+    }
+}
+
+/// Things the supervisor noticed about the Tor child.
+pub enum TorEvent {
+    /// Tor exited without being asked to.
+    Exited(ExitStatus),
+    /// Tor was relaunched; `attempt` counts from 1.
+    Restarted { attempt: u32 },
+    /// Relaunching failed or the restart budget is used up.
+    GaveUp { reason: String },
+    /// How far bootstrapping has come, from a backend that reports it;
+    /// `status` is like "40%: fetching a consensus".
+    Bootstrap { percent: u8, status: String },
+}
+
+/// The Tor a session runs: the tor binary under [`TorManager`], or Arti
+/// in-process with the `arti` feature. Either accepts SOCKS connections on
+/// [`SOCKS_ADDR`], taking SOCKS credentials to isolate circuits by.
+pub trait TorBackend: Send + Sync {
+    /// Where it accepts SOCKS connections.
+    fn socks_addr(&self) -> &str {
+        SOCKS_ADDR
+    }
+
+    /// Whether it can be asked for new circuits, its bootstrap state and
+    /// its circuits. The binary can only with a control port.
+    fn controllable(&self) -> bool;
+
+    /// Fresh circuits for new connections, as NEWNYM gives.
+    fn new_identity(&self) -> io::Result<()>;
+
+    /// Whether it has bootstrapped and can carry traffic.
+    fn bootstrapped(&self) -> io::Result<bool>;
+
+    fn circuits(&self) -> io::Result<Circuits>;
+
+    /// True once it died and could not be brought back.
+    fn has_failed(&self) -> bool;
+
+    fn restarts(&self) -> u32;
+
+    fn stop(&self);
+
+    /// Ends it at once, as for a forced exit: [`stop`](Self::stop) without
+    /// the grace period, cutting short a stop already under way.
+    fn kill(&self) {
+        self.stop();
+    }
+}
+
+struct Shared {
+    child: Mutex<Option<Child>>,
+    stopping: AtomicBool,
+    /// Set by `kill()`, for a stop under way to give up its grace period.
+    killing: AtomicBool,
+    failed: AtomicBool,
+    restarts: AtomicU32,
+}
+
+impl Shared {
+    fn child(&self) -> MutexGuard<'_, Option<Child>> {
+        match self.child.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// A Tor process, relaunched when it dies until its restart budget runs
+/// out, and stopped when dropped.
+pub struct TorManager {
+    shared: Arc<Shared>,
+    grace_period: Duration,
+    control_cookie: Option<PathBuf>,
+}
 
 impl TorManager {
-    pub fn start() -> Self {
+    /// Launches Tor and keeps it running, telling `on_event` when it exits
+    /// or is relaunched. Fails when the tor binary cannot be started.
+    pub fn start<F>(options: TorOptions, on_event: F) -> Result<Self, String>
+    where
+        F: Fn(TorEvent) + Send + 'static,
+    {
+        let mut args = options.extra_args;
+        if let Some(cookie) = &options.control_cookie {
+            args.extend(control_args(cookie));
+        }
         // Start Tor in the background
-        Command::new("tor")
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-            .expect("Failed to start Tor. Make sure Tor is installed.");
+        let child = spawn_tor(&options.binary, &args)
+            .map_err(|e| format!("Failed to start Tor ({}). Make sure Tor is installed.", e))?;
 
         // Wait for Tor to initialize
-        thread::sleep(Duration::from_secs(3));
+        thread::sleep(BOOTSTRAP_WAIT);
         log::info!("Tor service started");
-        TorManager
+
+        let shared = Arc::new(Shared {
+            child: Mutex::new(Some(child)),
+            stopping: AtomicBool::new(false),
+            killing: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+            restarts: AtomicU32::new(0),
+        });
+        let supervised = shared.clone();
+        let binary = options.binary;
+        let max_restarts = options.max_restarts;
+        thread::spawn(move || supervise(supervised, &binary, &args, max_restarts, on_event));
+
+        Ok(TorManager {
+            shared,
+            grace_period: options.grace_period,
+            control_cookie: options.control_cookie,
+        })
+    }
+
+    fn control(&self) -> io::Result<TorControl> {
+        match &self.control_cookie {
+            Some(cookie) => TorControl::connect(cookie),
+            None => Err(io::Error::other("Tor runs without its control port")),
+        }
+    }
+}
+
+impl TorBackend for TorManager {
+    fn controllable(&self) -> bool {
+        self.control_cookie.is_some()
+    }
+
+    fn new_identity(&self) -> io::Result<()> {
+        self.control()?.new_identity()
+    }
+
+    fn bootstrapped(&self) -> io::Result<bool> {
+        self.control()?.bootstrapped()
+    }
+
+    fn circuits(&self) -> io::Result<Circuits> {
+        self.control()?.circuits()
+    }
+
+    fn has_failed(&self) -> bool {
+        self.shared.failed.load(Ordering::SeqCst)
+    }
+
+    fn restarts(&self) -> u32 {
+        self.shared.restarts.load(Ordering::SeqCst)
+    }
+
+    fn stop(&self) {
+        // Never panic here: stop() also runs from Drop
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        // Held until Tor is gone, so kill() waits for it to be reaped
+        let mut guard = self.shared.child();
+        let Some(child) = guard.as_mut() else {
+            return;
+        };
+
+        match terminate_unless(child, self.grace_period, &self.shared.killing) {
+            Ok(()) => log::info!("Tor service stopped"),
+            Err(e) => log::warn!("Failed to stop Tor cleanly: {}", e),
+        }
+        *guard = None;
+    }
+
+    fn kill(&self) {
+        self.shared.killing.store(true, Ordering::SeqCst);
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        let Some(mut child) = self.shared.child().take() else {
+            return;
+        };
+        let _ = child.kill();
+        let _ = child.wait();
+        log::info!("Tor service killed");
+    }
+}
+
+impl Drop for TorManager {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Stands in for a Tor that could not be started, for a session that can
+/// carry on without it. It has failed from the start.
+pub struct FailedTor;
+
+impl TorBackend for FailedTor {
+    fn controllable(&self) -> bool {
+        false
+    }
+
+    fn new_identity(&self) -> io::Result<()> {
+        Err(io::Error::other("Tor is not running"))
+    }
+
+    fn bootstrapped(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    fn circuits(&self) -> io::Result<Circuits> {
+        Err(io::Error::other("Tor is not running"))
+    }
+
+    fn has_failed(&self) -> bool {
+        true
+    }
+
+    fn restarts(&self) -> u32 {
+        0
+    }
+
+    fn stop(&self) {}
+}
+
+/// The tor executable to run: `explicit` when given, else `tor` from
+/// PATH. Windows installs rarely put it on PATH, so there the usual
+/// install locations are tried before falling back to `tor`.
+pub fn find_tor(explicit: Option<&Path>) -> PathBuf {
+    if let Some(path) = explicit {
+        return path.to_path_buf();
+    }
+    #[cfg(windows)]
+    if let Some(installed) = crate::windows::installed_tor() {
+        return installed;
+    }
+    PathBuf::from("tor")
+}
+
+/// Has Tor check the options a session would start it with, which it does
+/// without connecting anywhere, and returns what it said when it turns
+/// them down.
+pub fn verify_config(options: &TorOptions) -> Result<(), String> {
+    let mut args = vec!["--verify-config".to_string()];
+    args.extend(options.extra_args.iter().cloned());
+    if let Some(cookie) = &options.control_cookie {
+        args.extend(control_args(cookie));
+    }
+    let output = Command::new(&options.binary)
+        .args(&args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("cannot run {}: {}", options.binary.display(), e))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let said = String::from_utf8_lossy(&output.stdout);
+    let complaint = said
+        .lines()
+        .rev()
+        .find(|line| line.contains("[err]") || line.contains("[warn]"))
+        .map(|line| line.trim().to_string());
+    Err(complaint.unwrap_or_else(|| format!("tor --verify-config exited with {}", output.status)))
+}
+
+fn spawn_tor(binary: &Path, args: &[String]) -> io::Result<Child> {
+    Command::new(binary)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+}
+
+fn supervise<F>(shared: Arc<Shared>, binary: &Path, args: &[String], max_restarts: u32, on_event: F)
+where
+    F: Fn(TorEvent),
+{
+    loop {
+        thread::sleep(SUPERVISE_INTERVAL);
+
+        let mut guard = shared.child();
+        if shared.stopping.load(Ordering::SeqCst) {
+            return;
+        }
+        let Some(child) = guard.as_mut() else {
+            return;
+        };
+        let status = match child.try_wait() {
+            Ok(Some(status)) => status,
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("Unable to poll Tor process: {}", e);
+                continue;
+            }
+        };
+        on_event(TorEvent::Exited(status));
+
+        let attempt = shared.restarts.load(Ordering::SeqCst) + 1;
+        if attempt > max_restarts {
+            *guard = None;
+            shared.failed.store(true, Ordering::SeqCst);
+            on_event(TorEvent::GaveUp {
+                reason: format!("restart limit of {} reached", max_restarts),
+            });
+            return;
+        }
+
+        match spawn_tor(binary, args) {
+            Ok(child) => *guard = Some(child),
+            Err(e) => {
+                *guard = None;
+                shared.failed.store(true, Ordering::SeqCst);
+                on_event(TorEvent::GaveUp {
+                    reason: format!("relaunch failed: {}", e),
+                });
+                return;
+            }
+        }
+        shared.restarts.store(attempt, Ordering::SeqCst);
+        drop(guard);
+
+        // Same bootstrap wait as the initial launch, without holding the lock
+        thread::sleep(BOOTSTRAP_WAIT);
+        on_event(TorEvent::Restarted { attempt });
+    }
+}
+
+/// Asks `child` to exit with SIGTERM, waits up to `grace_period` for it to
+/// do so, and only then falls back to killing it.
+pub fn terminate(child: &mut Child, grace_period: Duration) -> io::Result<()> {
+    terminate_unless(child, grace_period, &AtomicBool::new(false))
+}
+
+/// [`terminate`], killing `child` as soon as `kill_now` is set rather
+/// than waiting out the rest of the grace period.
+fn terminate_unless(
+    child: &mut Child,
+    grace_period: Duration,
+    kill_now: &AtomicBool,
+) -> io::Result<()> {
+    if child.try_wait()?.is_some() {
+        return Ok(());
+    }
+
+    send_term(child)?;
+    let deadline = Instant::now() + grace_period;
+    while Instant::now() < deadline && !kill_now.load(Ordering::SeqCst) {
+        if child.try_wait()?.is_some() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    if !kill_now.load(Ordering::SeqCst) {
+        log::warn!(
+            "Process {} did not exit within {}s, killing it",
+            child.id(),
+            grace_period.as_secs()
+        );
+    }
+    child.kill()?;
+    child.wait()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn send_term(child: &mut Child) -> io::Result<()> {
+    if unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn send_term(child: &mut Child) -> io::Result<()> {
+    // No SIGTERM equivalent for console-less children; kill right away
+    child.kill()
+}
+
+/// torrc options enabling a cookie-authenticated control port on
+/// `CONTROL_ADDR`, with the cookie written to `cookie`.
+pub fn control_args(cookie: &Path) -> Vec<String> {
+    vec![
+        "--ControlPort".to_string(),
+        CONTROL_ADDR.to_string(),
+        "--CookieAuthentication".to_string(),
+        "1".to_string(),
+        "--CookieAuthFile".to_string(),
+        cookie.display().to_string(),
+    ]
+}
+
+/// torrc options giving every SOCKS username and password Tor is handed
+/// circuits of their own (IsolateSOCKSAuth), so a rotation only needs new
+/// credentials, from [`isolated_socks_url`], to leave the last identity's
+/// circuits behind.
+pub fn isolation_args() -> Vec<String> {
+    vec![
+        "--SocksPort".to_string(),
+        format!("{} IsolateSOCKSAuth", SOCKS_ADDR),
+    ]
+}
+
+/// `SOCKS_URL` with a fresh random username and password. They only tell
+/// Tor which circuits to use, so they are never logged.
+pub fn isolated_socks_url() -> String {
+    let token = || -> String { (0..16).map(|_| fastrand::alphanumeric()).collect() };
+    format!("socks5h://{}:{}@{}", token(), token(), SOCKS_ADDR)
+}
+
+/// A relay as Tor's consensus lists it.
+pub struct Relay {
+    pub nickname: String,
+    pub fingerprint: String,
+    /// Two-letter code from Tor's GeoIP data, when it has one for the relay.
+    pub country: Option<String>,
+}
+
+/// What Tor's circuits look like, as the status shows them.
+pub struct Circuits {
+    /// Circuits built and ready to carry streams.
+    pub built: usize,
+    /// The exit of the circuit the latest stream went through, else of the
+    /// newest circuit built for the current identity. `None` until there
+    /// is one.
+    pub exit: Option<Relay>,
+    /// Where Tor accepts SOCKS connections.
+    pub socks_port: String,
+}
+
+impl fmt::Display for Circuits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} built, ", self.built)?;
+        match &self.exit {
+            Some(exit) => write!(
+                f,
+                "exit {} (${}, {})",
+                exit.nickname,
+                exit.fingerprint,
+                exit.country.as_deref().unwrap_or("country unknown")
+            )?,
+            None => write!(f, "no exit circuit yet")?,
+        }
+        write!(f, ", SocksPort {}", self.socks_port)
+    }
+}
+
+/// A line of `GETINFO circuit-status`: ID, status, path and flags. Only
+/// the exit of the path is kept, as its fingerprint and nickname.
+struct CircuitLine<'a> {
+    id: u64,
+    built: bool,
+    /// Built for streams leaving Tor, not for directory or onion service
+    /// traffic.
+    exits: bool,
+    exit: Option<(&'a str, &'a str)>,
+}
+
+impl<'a> CircuitLine<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let mut fields = line.split(' ');
+        let id = fields.next()?.parse().ok()?;
+        let built = fields.next()? == "BUILT";
+        let mut exit = None;
+        let mut exits = true;
+        for field in fields {
+            if let Some(path) = field.strip_prefix('$') {
+                // $FINGERPRINT~nickname, or = before Tor 0.2.2
+                let last = path.rsplit(",$").next().unwrap_or(path);
+                exit = Some(last.split_once(['~', '=']).unwrap_or((last, "")));
+            } else if let Some(purpose) = field.strip_prefix("PURPOSE=") {
+                exits &= purpose == "GENERAL";
+            } else if let Some(flags) = field.strip_prefix("BUILD_FLAGS=") {
+                exits &= !flags
+                    .split(',')
+                    .any(|flag| flag == "IS_INTERNAL" || flag == "ONEHOP_TUNNEL");
+            }
+        }
+        Some(CircuitLine {
+            id,
+            built,
+            exits,
+            exit,
+        })
+    }
+}
+
+/// Just enough of the Tor control protocol to ask for new circuits, check
+/// bootstrap progress and describe the circuits in use.
+pub struct TorControl {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl TorControl {
+    pub fn connect(cookie: &Path) -> io::Result<Self> {
+        let cookie = fs::read(cookie)?;
+        let stream = TcpStream::connect(CONTROL_ADDR)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut control = TorControl { stream, reader };
+        let hex: String = cookie.iter().map(|b| format!("{:02x}", b)).collect();
+        control.command(&format!("AUTHENTICATE {}", hex))?;
+        Ok(control)
+    }
+
+    /// Sends one command and returns its reply lines without status codes.
+    fn command(&mut self, command: &str) -> io::Result<Vec<String>> {
+        self.stream
+            .write_all(format!("{}\r\n", command).as_bytes())?;
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Tor closed the control connection",
+                ));
+            }
+            let line = line.trim_end();
+            if line.len() < 4 {
+                continue;
+            }
+            let (code, rest) = line.split_at(3);
+            if !code.starts_with('2') {
+                return Err(io::Error::other(format!(
+                    "Tor refused {}: {}",
+                    command.split(' ').next().unwrap_or(""),
+                    line
+                )));
+            }
+            lines.push(rest[1..].to_string());
+            match &rest[..1] {
+                " " => return Ok(lines),
+                // Data block, terminated by a lone "."
+                "+" => loop {
+                    let mut data = String::new();
+                    if self.reader.read_line(&mut data)? == 0 || data.trim_end() == "." {
+                        break;
+                    }
+                    lines.push(data.trim_end().to_string());
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// The value of `GETINFO key`, one line per element; a value of one
+    /// line comes after `key=`, one of several after a line of its own.
+    fn getinfo(&mut self, key: &str) -> io::Result<Vec<String>> {
+        let mut lines = self.command(&format!("GETINFO {}", key))?;
+        // The final line is the closing OK
+        lines.pop();
+        if let Some(first) = lines.first_mut() {
+            *first = first
+                .strip_prefix(key)
+                .and_then(|rest| rest.strip_prefix('='))
+                .unwrap_or(first)
+                .to_string();
+        }
+        lines.retain(|line| !line.is_empty());
+        Ok(lines)
+    }
+
+    /// Asks Tor to use fresh circuits for new connections.
+    pub fn new_identity(&mut self) -> io::Result<()> {
+        let newest = self
+            .getinfo("circuit-status")?
+            .iter()
+            .filter_map(|line| CircuitLine::parse(line))
+            .map(|circuit| circuit.id)
+            .max();
+        self.command("SIGNAL NEWNYM")?;
+        count_new_identity();
+        NEWNYM_CIRCUIT.fetch_max(newest.unwrap_or(0), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The circuits Tor has built and the exit streams currently leave by.
+    pub fn circuits(&mut self) -> io::Result<Circuits> {
+        let status = self.getinfo("circuit-status")?;
+        let circuits: Vec<CircuitLine> = status
+            .iter()
+            .filter_map(|line| CircuitLine::parse(line))
+            .collect();
+        // A stream line is ID, status, circuit ID and target
+        let streams = self.getinfo("stream-status")?;
+        let latest_stream = streams.iter().rev().find_map(|line| {
+            let mut fields = line.split(' ').skip(1);
+            let succeeded = fields.next()? == "SUCCEEDED";
+            let circuit: u64 = fields.next()?.parse().ok()?;
+            (succeeded && circuit != 0).then_some(circuit)
+        });
+        let newnym = NEWNYM_CIRCUIT.load(Ordering::Relaxed);
+        let exit = match latest_stream {
+            Some(id) => circuits.iter().find(|c| c.id == id),
+            None => circuits
+                .iter()
+                .filter(|c| c.built && c.exits && c.id > newnym)
+                .max_by_key(|c| c.id),
+        }
+        .and_then(|circuit| circuit.exit)
+        .map(|(fingerprint, nickname)| self.relay(fingerprint, nickname));
+        let socks_port = self
+            .getinfo("net/listeners/socks")
+            .ok()
+            .and_then(|lines| lines.first().cloned())
+            .map(|listeners| {
+                listeners
+                    .replace("\" \"", ", ")
+                    .trim_matches('"')
+                    .to_string()
+            })
+            .filter(|listeners| !listeners.is_empty())
+            .unwrap_or_else(|| SOCKS_ADDR.to_string());
+        Ok(Circuits {
+            built: circuits.iter().filter(|c| c.built).count(),
+            exit,
+            socks_port,
+        })
+    }
+
+    /// What the consensus says of the relay with `fingerprint`, known from
+    /// a circuit path by `nickname`.
+    fn relay(&mut self, fingerprint: &str, nickname: &str) -> Relay {
+        let entry = self
+            .getinfo(&format!("ns/id/{}", fingerprint))
+            .unwrap_or_default();
+        // "r " then NICKNAME IDENTITY [DIGEST] DATE TIME IP ORPORT DIRPORT
+        let router: Vec<&str> = entry
+            .iter()
+            .find_map(|line| line.strip_prefix("r "))
+            .map(|line| line.split(' ').collect())
+            .unwrap_or_default();
+        let ip = router
+            .iter()
+            .skip(1)
+            .find_map(|field| field.parse::<IpAddr>().ok());
+        let country = ip
+            .and_then(|ip| self.getinfo(&format!("ip-to-country/{}", ip)).ok())
+            .and_then(|lines| lines.first().cloned())
+            .filter(|code| code.len() == 2 && code != "??");
+        let nickname = match router.first() {
+            Some(name) => name,
+            None if !nickname.is_empty() => nickname,
+            None => "unnamed",
+        };
+        Relay {
+            nickname: nickname.to_string(),
+            fingerprint: fingerprint.to_string(),
+            country: country.map(|code| code.to_ascii_uppercase()),
+        }
+    }
+
+    /// Whether Tor has finished bootstrapping and has a usable consensus.
+    pub fn bootstrapped(&mut self) -> io::Result<bool> {
+        let lines = self.command("GETINFO status/bootstrap-phase")?;
+        Ok(lines.iter().any(|l| l.contains("PROGRESS=100")))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn spawn_sh(script: &str) -> Child {
+        Command::new("sh").arg("-c").arg(script).spawn().unwrap()
+    }
+
+    #[test]
+    fn terminate_stops_a_child_with_sigterm() {
+        let mut child = spawn_sh("exec sleep 30");
+        let started = Instant::now();
+        terminate(&mut child, Duration::from_secs(10)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+    }
+
+    #[test]
+    fn terminate_kills_a_child_ignoring_sigterm() {
+        // SIG_IGN survives the exec, so sleep itself ignores SIGTERM
+        let mut child = spawn_sh("trap '' TERM; exec sleep 30");
+        thread::sleep(Duration::from_millis(200));
+        let started = Instant::now();
+        terminate(&mut child, Duration::from_millis(500)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
     }
 
-    pub fn stop(&self) {
-        // Gracefully stop Tor
-        Command::new("pkill")
-            .arg("tor")
-            .output()
-            .expect("Failed to stop Tor");
-        log::info!("Tor service stopped");
+    #[test]
+    fn terminate_leaves_an_exited_child_alone() {
+        let mut child = spawn_sh("exit 3");
+        child.wait().unwrap();
+        terminate(&mut child, Duration::from_secs(10)).unwrap();
+        assert_eq!(child.wait().unwrap().code(), Some(3));
     }
 }
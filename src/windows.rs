@@ -0,0 +1,270 @@
+// src/windows.rs
+// What Windows does differently: the control channel is a named pipe
+// rather than a unix socket, Tor is usually found in a Tor Browser or
+// expert bundle install rather than on PATH, console events stand in for
+// signals, and closing the console window ends the process a few seconds
+// after telling it, so the session has to clean up within that time.
+use std::{
+    env,
+    ffi::OsStr,
+    fs::File,
+    io,
+    os::windows::{
+        ffi::OsStrExt,
+        io::{AsRawHandle, FromRawHandle},
+    },
+    path::PathBuf,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use windows_sys::Win32::{
+    Foundation::{
+        GetLastError, BOOL, ERROR_ACCESS_DENIED, ERROR_PIPE_CONNECTED, HANDLE, INVALID_HANDLE_VALUE,
+    },
+    Storage::FileSystem::{FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX},
+    System::{
+        Console::{
+            SetConsoleCtrlHandler, CTRL_BREAK_EVENT, CTRL_CLOSE_EVENT, CTRL_C_EVENT,
+            CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT,
+        },
+        Pipes::{
+            ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+        },
+    },
+};
+
+/// Buffer size of each pipe instance; a request or reply is one line.
+const PIPE_BUFFER: u32 = 64 * 1024;
+/// Windows ends the process 5s after a close event; cleanup gets most of it.
+const CLOSE_GRACE: Duration = Duration::from_millis(4500);
+
+/// The pipe the control channel of `session` listens on.
+pub fn pipe_name(session: &str) -> String {
+    format!(r"\\.\pipe\veko-dome-{}", session)
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain([0]).collect()
+}
+
+/// Serving side of a named pipe: one instance waits for the next client
+/// while the ones already connected are answered.
+pub struct PipeListener {
+    name: Vec<u16>,
+    /// The instance the next client connects to.
+    next: Mutex<Option<File>>,
+}
+
+impl PipeListener {
+    /// Creates the pipe `name`, refusing when another process has it.
+    pub fn bind(name: &str) -> io::Result<Self> {
+        let name = wide(name);
+        let first = create_instance(&name, true).map_err(|e| {
+            if e.raw_os_error() == Some(ERROR_ACCESS_DENIED as i32) {
+                io::Error::new(io::ErrorKind::AddrInUse, "the pipe is already in use")
+            } else {
+                e
+            }
+        })?;
+        Ok(PipeListener {
+            name,
+            next: Mutex::new(Some(first)),
+        })
+    }
+
+    /// Waits for a client and returns its end of the pipe. Another
+    /// instance is opened before it is handed out, so the next client
+    /// does not find the pipe busy.
+    pub fn accept(&self) -> io::Result<File> {
+        let pending = self.next.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let instance = match pending {
+            Some(instance) => instance,
+            None => create_instance(&self.name, false)?,
+        };
+        connect_instance(&instance)?;
+        *self.next.lock().unwrap_or_else(|e| e.into_inner()) =
+            create_instance(&self.name, false).ok();
+        Ok(instance)
+    }
+}
+
+fn create_instance(name: &[u16], first: bool) -> io::Result<File> {
+    let mut flags = PIPE_ACCESS_DUPLEX;
+    if first {
+        flags |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+    }
+    // SAFETY: `name` is NUL-terminated and outlives the call; a null
+    // security descriptor gives the pipe the creator's default DACL
+    let handle: HANDLE = unsafe {
+        CreateNamedPipeW(
+            name.as_ptr(),
+            flags,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            PIPE_BUFFER,
+            PIPE_BUFFER,
+            0,
+            ptr::null(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the handle was just created and nothing else owns it
+    Ok(unsafe { File::from_raw_handle(handle as _) })
+}
+
+fn connect_instance(instance: &File) -> io::Result<()> {
+    // SAFETY: the handle belongs to `instance`, which outlives the call
+    let connected =
+        unsafe { ConnectNamedPipe(instance.as_raw_handle() as HANDLE, ptr::null_mut()) };
+    // A client that connected before the wait started is connected too
+    if connected != 0 || unsafe { GetLastError() } == ERROR_PIPE_CONNECTED {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// An installed tor.exe to run when there is none on PATH.
+pub fn installed_tor() -> Option<PathBuf> {
+    let on_path = env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join("tor.exe").is_file()));
+    if on_path {
+        return None;
+    }
+    tor_locations().into_iter().find(|path| path.is_file())
+}
+
+/// Where Tor is commonly installed: Tor Browser under Program Files or on
+/// the desktop, where its installer puts it by default, and the expert
+/// bundle.
+fn tor_locations() -> Vec<PathBuf> {
+    const BROWSER: &str = r"Tor Browser\Browser\TorBrowser\Tor\tor.exe";
+    let var = |name: &str| env::var_os(name).map(PathBuf::from);
+    [
+        var("ProgramFiles").map(|p| p.join(BROWSER)),
+        var("ProgramFiles(x86)").map(|p| p.join(BROWSER)),
+        var("USERPROFILE").map(|p| p.join("Desktop").join(BROWSER)),
+        var("LOCALAPPDATA").map(|p| p.join(BROWSER)),
+        var("ProgramFiles").map(|p| p.join(r"Tor\tor.exe")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+static CLEANED_UP: AtomicBool = AtomicBool::new(false);
+
+/// Hands Ctrl-C and Ctrl-Break to [`shutdown::request`](crate::shutdown::request)
+/// as unix signals are. Closing the console window, logging off or the
+/// machine shutting down does the same, and holds the process open until
+/// [`cleaned_up`] or the grace time runs out.
+pub fn on_console_events() -> io::Result<()> {
+    // SAFETY: the handler is a plain function that lives for the process
+    if unsafe { SetConsoleCtrlHandler(Some(console_handler), 1) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Tells a pending close event that the session has shut down.
+pub fn cleaned_up() {
+    CLEANED_UP.store(true, Ordering::SeqCst);
+}
+
+unsafe extern "system" fn console_handler(event: u32) -> BOOL {
+    let what = match event {
+        CTRL_C_EVENT => "Received Ctrl-C",
+        CTRL_BREAK_EVENT => "Received Ctrl-Break",
+        CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT => "The console is closing",
+        _ => return 0,
+    };
+    crate::shutdown::request(what);
+    if matches!(event, CTRL_C_EVENT | CTRL_BREAK_EVENT) {
+        return 1;
+    }
+    // Windows ends the process once this returns
+    let deadline = Instant::now() + CLOSE_GRACE;
+    while !CLEANED_UP.load(Ordering::SeqCst) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control;
+    use std::{
+        fs::OpenOptions,
+        io::{BufRead, BufReader, Write},
+    };
+
+    #[test]
+    fn pipe_names_are_local_and_per_session() {
+        assert_eq!(pipe_name("default"), r"\\.\pipe\veko-dome-default");
+        assert_ne!(pipe_name("work"), pipe_name("home"));
+        assert_eq!(
+            control::socket_path("work"),
+            PathBuf::from(pipe_name("work"))
+        );
+    }
+
+    #[test]
+    fn pid_files_live_in_the_runtime_dir() {
+        let path = control::pid_path("work");
+        assert!(path.is_absolute());
+        assert_eq!(path.parent(), Some(control::runtime_dir().as_path()));
+        assert_eq!(path.file_name().unwrap(), "work.pid");
+    }
+
+    #[test]
+    fn wide_names_end_in_nul() {
+        assert_eq!(wide("ab"), [b'a' as u16, b'b' as u16, 0]);
+    }
+
+    #[test]
+    fn tor_is_looked_for_under_the_install_dirs() {
+        let locations = tor_locations();
+        assert!(locations.iter().all(|path| path.ends_with("tor.exe")));
+        if let Some(program_files) = env::var_os("ProgramFiles") {
+            assert!(locations[0].starts_with(program_files));
+        }
+    }
+
+    #[test]
+    fn a_pipe_carries_a_line_each_way() {
+        let name = pipe_name(&format!("test-{}", std::process::id()));
+        let listener = PipeListener::bind(&name).unwrap();
+        let taken = PipeListener::bind(&name).err().unwrap();
+        assert_eq!(taken.kind(), io::ErrorKind::AddrInUse);
+
+        let client = thread::spawn({
+            let name = name.clone();
+            move || {
+                let mut pipe = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&name)
+                    .unwrap();
+                pipe.write_all(b"ping\n").unwrap();
+                let mut answer = String::new();
+                BufReader::new(pipe).read_line(&mut answer).unwrap();
+                answer
+            }
+        });
+        let mut server = listener.accept().unwrap();
+        let mut request = String::new();
+        BufReader::new(&server).read_line(&mut request).unwrap();
+        assert_eq!(request, "ping\n");
+        server.write_all(b"pong\n").unwrap();
+        assert_eq!(client.join().unwrap(), "pong\n");
+    }
+}
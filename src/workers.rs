@@ -0,0 +1,179 @@
+// src/workers.rs
+// Background threads that survive their own panics: each crash is reported,
+// restartable workers come back with backoff, and the rest mark the session
+// as degraded.
+use std::{
+    any::Any,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A worker that ran this long before crashing gets its restart budget back.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// Told about every crash: worker name, panic message and whether the worker
+/// is being restarted.
+pub type CrashHook = Arc<dyn Fn(&str, &str, bool) + Send + Sync>;
+
+static RESTARTS: AtomicU64 = AtomicU64::new(0);
+static FAILED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+static CRASH_HOOK: Mutex<Option<CrashHook>> = Mutex::new(None);
+
+pub fn set_crash_hook(hook: CrashHook) {
+    *CRASH_HOOK.lock().unwrap_or_else(|e| e.into_inner()) = Some(hook);
+}
+
+/// Worker restarts so far this session.
+pub fn restarts() -> u64 {
+    RESTARTS.load(Ordering::SeqCst)
+}
+
+/// Workers that crashed more often than they may be restarted, with their panic messages.
+pub fn failed() -> Vec<(String, String)> {
+    FAILED.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+fn report(name: &str, message: &str, restarting: bool) {
+    let hook = CRASH_HOOK.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    if let Some(hook) = hook {
+        hook(name, message, restarting);
+    }
+}
+
+/// Runs `body` on a named thread. A panic is caught and reported, and the
+/// body is run again after a growing delay, up to `max_restarts` times in a
+/// row. Returning normally ends the worker.
+pub fn spawn<F>(name: &str, max_restarts: u32, body: F) -> JoinHandle<()>
+where
+    F: Fn() + Send + 'static,
+{
+    try_spawn(name, max_restarts, body).expect("failed to spawn worker thread")
+}
+
+/// [`spawn`], handing back the error when the thread cannot be started.
+pub fn try_spawn<F>(name: &str, max_restarts: u32, body: F) -> io::Result<JoinHandle<()>>
+where
+    F: Fn() + Send + 'static,
+{
+    let name = name.to_string();
+    thread::Builder::new().name(name.clone()).spawn(move || {
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let Err(payload) = panic::catch_unwind(AssertUnwindSafe(&body)) else {
+                return;
+            };
+            let message = panic_message(payload.as_ref());
+            if started.elapsed() >= STABLE_RUN {
+                attempt = 0;
+            }
+            let restarting = attempt < max_restarts;
+            report(&name, &message, restarting);
+            if !restarting {
+                FAILED
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push((name, message));
+                return;
+            }
+            thread::sleep((INITIAL_BACKOFF * 2u32.pow(attempt)).min(MAX_BACKOFF));
+            attempt += 1;
+            RESTARTS.fetch_add(1, Ordering::SeqCst);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    static CRASHES: Mutex<Vec<(String, String, bool)>> = Mutex::new(Vec::new());
+
+    fn record_crashes() {
+        set_crash_hook(Arc::new(|name, message, restarting| {
+            CRASHES
+                .lock()
+                .unwrap()
+                .push((name.to_string(), message.to_string(), restarting));
+        }));
+    }
+
+    /// Tests run in parallel and share the hook, so each looks only at its own worker.
+    fn crashes_of(name: &str) -> Vec<(String, bool)> {
+        CRASHES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(n, _, _)| n == name)
+            .map(|(_, m, r)| (m.clone(), *r))
+            .collect()
+    }
+
+    #[test]
+    fn a_crashed_worker_is_reported_and_restarted() {
+        record_crashes();
+        let runs = Arc::new(AtomicU32::new(0));
+        let counted = runs.clone();
+        let before = restarts();
+        spawn("test-restarted", 1, move || {
+            if counted.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("first run fails");
+            }
+        })
+        .join()
+        .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert!(restarts() > before);
+        assert_eq!(
+            crashes_of("test-restarted"),
+            vec![("first run fails".to_string(), true)]
+        );
+        assert!(!failed().iter().any(|(n, _)| n == "test-restarted"));
+    }
+
+    #[test]
+    fn a_worker_out_of_restarts_is_marked_failed() {
+        record_crashes();
+        let runs = Arc::new(AtomicU32::new(0));
+        let counted = runs.clone();
+        spawn("test-failed", 0, move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            panic!("{} always fails", "it");
+        })
+        .join()
+        .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            crashes_of("test-failed"),
+            vec![("it always fails".to_string(), false)]
+        );
+        assert!(failed().contains(&("test-failed".to_string(), "it always fails".to_string())));
+    }
+
+    #[test]
+    fn a_worker_that_returns_is_not_reported() {
+        record_crashes();
+        spawn("test-returned", 3, || {}).join().unwrap();
+        assert!(crashes_of("test-returned").is_empty());
+        assert!(!failed().iter().any(|(n, _)| n == "test-returned"));
+    }
+}